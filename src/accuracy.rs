@@ -0,0 +1,59 @@
+use std::str::FromStr;
+
+/// Trade-off between emulation accuracy and speed, so that low-end devices
+/// and accuracy testing can share the same core.
+///
+/// Each profile turns into a set of switches for the subsystems. The APU
+/// has none: it only emulates the channel state and mixes no sound, so
+/// there is no simplified mixer to pick over a full one until it does.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Accuracy {
+    Fast,
+    #[default]
+    Balanced,
+    Accurate,
+}
+
+impl Accuracy {
+    /// Instructions advance the rest of the system on each memory access,
+    /// rather than all at once when they complete
+    pub fn per_access_timing(self) -> bool {
+        self != Accuracy::Fast
+    }
+
+    /// The PPU renders dot by dot through the pixel FIFO instead of a
+    /// scanline at a time
+    pub fn pixel_fifo(self) -> bool {
+        self == Accuracy::Accurate
+    }
+}
+
+impl FromStr for Accuracy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(Accuracy::Fast),
+            "balanced" => Ok(Accuracy::Balanced),
+            "accurate" => Ok(Accuracy::Accurate),
+            _ => Err(format!("Invalid accuracy: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        assert_eq!("fast".parse(), Ok(Accuracy::Fast));
+        assert_eq!("accurate".parse(), Ok(Accuracy::Accurate));
+        assert!("exact".parse::<Accuracy>().is_err());
+        assert_eq!(Accuracy::default(), Accuracy::Balanced);
+        assert!(!Accuracy::Fast.per_access_timing());
+        assert!(Accuracy::Balanced.per_access_timing());
+        assert!(!Accuracy::Balanced.pixel_fifo());
+        assert!(Accuracy::Accurate.pixel_fifo());
+    }
+}
@@ -0,0 +1,599 @@
+use std::fmt;
+
+use crate::json::Value;
+
+/// Audio registers
+/// Following
+/// https://gbdev.io/pandocs/Audio_Registers.html
+pub const NR10: u16 = 0xFF10;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM: u16 = 0xFF30;
+pub const END: u16 = 0xFF3F;
+
+const NR52_POWER: u8 = 1 << 7;
+const TRIGGER: u8 = 1 << 7;
+const LENGTH_ENABLE: u8 = 1 << 6;
+
+/// Dots between two steps of the frame sequencer, which runs at 512 Hz
+const SEQUENCER_DOTS: u16 = 8192;
+
+// the noise channel timer periods for the NR43 divisor codes, in dots
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// bits that always read as 1, including the write-only registers and the
+// unused holes in the range
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+const NAMES: [&str; 0x17] = [
+    "NR10", "NR11", "NR12", "NR13", "NR14", "----", "NR21", "NR22", "NR23", "NR24", "NR30", "NR31",
+    "NR32", "NR33", "NR34", "----", "NR41", "NR42", "NR43", "NR44", "NR50", "NR51", "NR52",
+];
+
+/// What the frame sequencer keeps for a channel between register writes
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Channel {
+    // reported in NR52
+    enabled: bool,
+    // length steps left, 64 at most or 256 for the wave channel
+    length: u16,
+    // envelope volume and steps until it changes, unused by the wave channel
+    volume: u8,
+    envelope_timer: u8,
+}
+
+/// Frequency sweep of channel 1
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Sweep {
+    enabled: bool,
+    // the frequency the sweep works from, NR13 and NR14 only get a copy
+    shadow: u16,
+    timer: u8,
+}
+
+/// Audio processing unit. The registers, the frame sequencer and the channel
+/// state it drives are emulated: length counters, envelopes, the channel 1
+/// sweep and the noise LFSR. No sound is generated yet.
+///
+/// The frame sequencer is clocked by the master clock rather than by DIV,
+/// so writing DIV does not shift its steps.
+///
+/// The whole struct goes into savestates and state hashes, so channel state
+/// added here is captured without further plumbing.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Apu {
+    // NR10-NR52 as written
+    registers: [u8; 0x17],
+    wave: [u8; 0x10],
+    channels: [Channel; 4],
+    sweep: Sweep,
+    // noise channel shift register, 15 bits, and dots until it shifts
+    lfsr: u16,
+    noise_timer: u32,
+    // next frame sequencer step, 0-7, and dots until it
+    sequencer_step: u8,
+    sequencer_timer: u16,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    /// Registers as left by the DMG boot ROM
+    pub fn new() -> Self {
+        let mut apu = Self::cleared([0x00; 0x10]);
+        apu.registers = [
+            0x80, 0xBF, 0xF3, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF,
+            0xBF, 0xFF, 0xFF, 0x00, 0x00, 0xBF, 0x77, 0xF3, 0x80,
+        ];
+        // the boot chime leaves channel 1 running, faded out by its envelope
+        apu.channels[0].enabled = true;
+        apu
+    }
+
+    // as powering off leaves it, only wave RAM survives
+    fn cleared(wave: [u8; 0x10]) -> Self {
+        Self {
+            registers: [0x00; 0x17],
+            wave,
+            channels: [Channel::default(); 4],
+            sweep: Sweep::default(),
+            lfsr: 0,
+            noise_timer: NOISE_DIVISORS[0],
+            sequencer_step: 0,
+            sequencer_timer: SEQUENCER_DOTS,
+        }
+    }
+
+    pub fn powered(&self) -> bool {
+        self.registers[(NR52 - NR10) as usize] & NR52_POWER != 0
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NR10..=NR52 => {
+                let index = (address - NR10) as usize;
+                let value = self.registers[index] | READ_MASKS[index];
+                if address == NR52 {
+                    // with the channels still running in the low bits
+                    let status = self.channels.iter().enumerate();
+                    status.fold(value, |value, (n, channel)| {
+                        value | (channel.enabled as u8) << n
+                    })
+                } else {
+                    value
+                }
+            }
+            WAVE_RAM..=END => self.wave[(address - WAVE_RAM) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            NR52 => {
+                if value & NR52_POWER == 0 {
+                    // powering off clears every register and stops the channels
+                    *self = Self::cleared(self.wave);
+                }
+                self.registers[(NR52 - NR10) as usize] = value & NR52_POWER;
+            }
+            // ignored while powered off
+            NR10..=NR52 if self.powered() => {
+                let index = (address - NR10) as usize;
+                self.registers[index] = value;
+                // NR50 and NR51 come after the 4 channels
+                if index < 20 {
+                    self.write_channel(index / 5, index % 5, value);
+                }
+            }
+            WAVE_RAM..=END => self.wave[(address - WAVE_RAM) as usize] = value,
+            _ => {}
+        }
+    }
+
+    // NRxy of channel x, counted from 0
+    fn nr(&self, channel: usize, y: usize) -> u8 {
+        self.registers[channel * 5 + y]
+    }
+
+    fn write_channel(&mut self, channel: usize, y: usize, value: u8) {
+        match y {
+            1 if channel == 2 => self.channels[channel].length = 256 - value as u16,
+            1 => self.channels[channel].length = 64 - (value & 0x3F) as u16,
+            4 if value & TRIGGER != 0 => self.trigger(channel),
+            // turning the DAC off stops the channel until the next trigger
+            _ if !self.dac_enabled(channel) => self.channels[channel].enabled = false,
+            _ => {}
+        }
+    }
+
+    fn dac_enabled(&self, channel: usize) -> bool {
+        match channel {
+            2 => self.nr(2, 0) & 0x80 != 0,
+            _ => self.nr(channel, 2) & 0xF8 != 0,
+        }
+    }
+
+    fn trigger(&mut self, channel: usize) {
+        let enabled = self.dac_enabled(channel);
+        let envelope = self.nr(channel, 2);
+        let state = &mut self.channels[channel];
+        state.enabled = enabled;
+        if state.length == 0 {
+            state.length = if channel == 2 { 256 } else { 64 };
+        }
+        if channel != 2 {
+            state.volume = envelope >> 4;
+            state.envelope_timer = envelope & 0x07;
+        }
+        match channel {
+            0 => {
+                let (period, shift) = self.sweep_settings();
+                self.sweep = Sweep {
+                    enabled: period != 0 || shift != 0,
+                    shadow: self.frequency(),
+                    timer: if period == 0 { 8 } else { period },
+                };
+                if shift != 0 && self.sweep_target() > 0x7FF {
+                    self.channels[0].enabled = false;
+                }
+            }
+            3 => {
+                self.lfsr = 0x7FFF;
+                self.noise_timer = self.noise_period();
+            }
+            _ => {}
+        }
+    }
+
+    // channel 1 frequency from NR13 and NR14
+    fn frequency(&self) -> u16 {
+        ((self.nr(0, 4) & 0x07) as u16) << 8 | self.nr(0, 3) as u16
+    }
+
+    fn set_frequency(&mut self, frequency: u16) {
+        self.registers[3] = frequency as u8;
+        self.registers[4] = self.registers[4] & !0x07 | (frequency >> 8) as u8;
+    }
+
+    // NR10 period and shift
+    fn sweep_settings(&self) -> (u8, u8) {
+        let nr10 = self.nr(0, 0);
+        (nr10 >> 4 & 0x07, nr10 & 0x07)
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let delta = self.sweep.shadow >> (self.nr(0, 0) & 0x07);
+        if self.nr(0, 0) & 0x08 != 0 {
+            self.sweep.shadow - delta
+        } else {
+            self.sweep.shadow + delta
+        }
+    }
+
+    fn noise_period(&self) -> u32 {
+        let nr43 = self.nr(3, 3);
+        NOISE_DIVISORS[(nr43 & 0x07) as usize] << (nr43 >> 4)
+    }
+
+    /// Advances the frame sequencer and the noise channel by `dots`
+    pub fn tick(&mut self, dots: u32) {
+        if !self.powered() {
+            return;
+        }
+        let mut left = dots;
+        while left >= self.sequencer_timer as u32 {
+            left -= self.sequencer_timer as u32;
+            self.sequencer_timer = SEQUENCER_DOTS;
+            self.step_sequencer();
+        }
+        self.sequencer_timer -= left as u16;
+        // shifts 14 and 15 never clock the LFSR
+        if !self.channels[3].enabled || self.nr(3, 3) >> 4 >= 14 {
+            return;
+        }
+        let mut left = dots;
+        while left >= self.noise_timer {
+            left -= self.noise_timer;
+            self.noise_timer = self.noise_period();
+            let bit = (self.lfsr ^ self.lfsr >> 1) & 0x01;
+            self.lfsr = self.lfsr >> 1 | bit << 14;
+            // 7 bit mode
+            if self.nr(3, 3) & 0x08 != 0 {
+                self.lfsr = self.lfsr & !(1 << 6) | bit << 6;
+            }
+        }
+        self.noise_timer -= left;
+    }
+
+    // length on even steps, the sweep on 2 and 6, envelopes on 7
+    fn step_sequencer(&mut self) {
+        let step = self.sequencer_step;
+        self.sequencer_step = (step + 1) % 8;
+        if step.is_multiple_of(2) {
+            for channel in 0..4 {
+                self.clock_length(channel);
+            }
+        }
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+        if step == 7 {
+            for channel in [0, 1, 3] {
+                self.clock_envelope(channel);
+            }
+        }
+    }
+
+    fn clock_length(&mut self, channel: usize) {
+        let enabled = self.nr(channel, 4) & LENGTH_ENABLE != 0;
+        let state = &mut self.channels[channel];
+        if enabled && state.length > 0 {
+            state.length -= 1;
+            if state.length == 0 {
+                state.enabled = false;
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self, channel: usize) {
+        let envelope = self.nr(channel, 2);
+        let period = envelope & 0x07;
+        let state = &mut self.channels[channel];
+        if period == 0 {
+            return;
+        }
+        state.envelope_timer = state.envelope_timer.saturating_sub(1);
+        if state.envelope_timer == 0 {
+            state.envelope_timer = period;
+            if envelope & 0x08 != 0 && state.volume < 15 {
+                state.volume += 1;
+            } else if envelope & 0x08 == 0 && state.volume > 0 {
+                state.volume -= 1;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        let (period, shift) = self.sweep_settings();
+        self.sweep.timer = if period == 0 { 8 } else { period };
+        if !self.sweep.enabled || period == 0 {
+            return;
+        }
+        let target = self.sweep_target();
+        if target > 0x7FF {
+            self.channels[0].enabled = false;
+        } else if shift != 0 {
+            self.sweep.shadow = target;
+            self.set_frequency(target);
+            // checked again with the new frequency
+            if self.sweep_target() > 0x7FF {
+                self.channels[0].enabled = false;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Apu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // one line per channel, then the control registers
+        for line in (0..NAMES.len()).collect::<Vec<_>>().chunks(5) {
+            let registers = line
+                .iter()
+                .filter(|&&i| NAMES[i] != "----")
+                .map(|&i| format!("{} {:02x}", NAMES[i], self.read(NR10 + i as u16)))
+                .collect::<Vec<_>>();
+            writeln!(f, "{}", registers.join(" "))?;
+        }
+        // then what the frame sequencer keeps
+        for (n, channel) in self.channels.iter().enumerate() {
+            writeln!(
+                f,
+                "ch{} {} length {} volume {} envelope {}",
+                n + 1,
+                if channel.enabled { "on" } else { "off" },
+                channel.length,
+                channel.volume,
+                channel.envelope_timer
+            )?;
+        }
+        writeln!(
+            f,
+            "sweep {} shadow {:03x} timer {}",
+            if self.sweep.enabled { "on" } else { "off" },
+            self.sweep.shadow,
+            self.sweep.timer
+        )?;
+        writeln!(f, "lfsr {:04x} timer {}", self.lfsr, self.noise_timer)?;
+        writeln!(
+            f,
+            "sequencer step {} timer {}",
+            self.sequencer_step, self.sequencer_timer
+        )?;
+        write!(f, "wave")?;
+        for byte in self.wave {
+            write!(f, " {:02x}", byte)?;
+        }
+        writeln!(f)
+    }
+}
+
+impl Apu {
+    pub(crate) fn to_json(&self) -> Value {
+        let channels = self.channels.iter().map(|channel| {
+            Value::object([
+                ("enabled", Value::Bool(channel.enabled)),
+                ("length", Value::Number(channel.length as u64)),
+                ("volume", Value::Number(channel.volume as u64)),
+                (
+                    "envelope_timer",
+                    Value::Number(channel.envelope_timer as u64),
+                ),
+            ])
+        });
+        Value::object([
+            ("registers", Value::bytes(&self.registers)),
+            ("wave", Value::bytes(&self.wave)),
+            ("channels", Value::Array(channels.collect())),
+            (
+                "sweep",
+                Value::object([
+                    ("enabled", Value::Bool(self.sweep.enabled)),
+                    ("shadow", Value::hex(self.sweep.shadow as u64, 3)),
+                    ("timer", Value::Number(self.sweep.timer as u64)),
+                ]),
+            ),
+            ("lfsr", Value::hex(self.lfsr as u64, 4)),
+            ("noise_timer", Value::Number(self.noise_timer as u64)),
+            ("sequencer_step", Value::Number(self.sequencer_step as u64)),
+            (
+                "sequencer_timer",
+                Value::Number(self.sequencer_timer as u64),
+            ),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let mut apu = Apu::new();
+        value.get("registers")?.read_bytes(&mut apu.registers)?;
+        value.get("wave")?.read_bytes(&mut apu.wave)?;
+        let channels = match value.get("channels")? {
+            Value::Array(channels) if channels.len() == apu.channels.len() => channels,
+            _ => return Err("Expected an array of 4 channels".to_string()),
+        };
+        for (n, channel) in channels.iter().enumerate() {
+            let length = channel.get("length")?.as_u16()?;
+            let volume = channel.get("volume")?.as_u8()?;
+            if length > 256 || volume > 15 {
+                return Err(format!("Invalid channel {} length or volume", n + 1));
+            }
+            apu.channels[n] = Channel {
+                enabled: channel.get("enabled")?.as_bool()?,
+                length,
+                volume,
+                envelope_timer: channel.get("envelope_timer")?.as_u8()? & 0x07,
+            };
+        }
+        let sweep = value.get("sweep")?;
+        apu.sweep = Sweep {
+            enabled: sweep.get("enabled")?.as_bool()?,
+            shadow: sweep.get("shadow")?.as_u16()? & 0x7FF,
+            timer: sweep.get("timer")?.as_u8()? & 0x0F,
+        };
+        apu.lfsr = value.get("lfsr")?.as_u16()? & 0x7FFF;
+        apu.noise_timer = value.get("noise_timer")?.as_u32()?;
+        apu.sequencer_step = value.get("sequencer_step")?.as_u8()?;
+        apu.sequencer_timer = value.get("sequencer_timer")?.as_u16()?;
+        // both timers count down to 0 and reload at once
+        if apu.noise_timer == 0 {
+            return Err("Invalid noise timer: 0".to_string());
+        }
+        if apu.sequencer_step > 7 || !(1..=SEQUENCER_DOTS).contains(&apu.sequencer_timer) {
+            return Err(format!(
+                "Invalid frame sequencer position: {}, {}",
+                apu.sequencer_step, apu.sequencer_timer
+            ));
+        }
+        Ok(apu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apu_read_masks() {
+        let mut apu = Apu::new();
+        apu.write(0xFF11, 0x00);
+        assert_eq!(apu.read(0xFF11), 0x3F);
+        // write-only and unused registers
+        apu.write(0xFF13, 0x12);
+        assert_eq!(apu.read(0xFF13), 0xFF);
+        assert_eq!(apu.read(0xFF15), 0xFF);
+        assert_eq!(apu.read(0xFF27), 0xFF);
+        apu.write(0xFF24, 0x12);
+        assert_eq!(apu.read(0xFF24), 0x12);
+        // channel 1 is still running after the boot chime
+        assert_eq!(apu.read(NR52), 0xF1);
+    }
+
+    #[test]
+    fn test_apu_power_off() {
+        let mut apu = Apu::new();
+        apu.write(NR52, 0x00);
+        assert!(!apu.powered());
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        // wave RAM stays accessible
+        apu.write(WAVE_RAM, 0xAB);
+        assert_eq!(apu.read(WAVE_RAM), 0xAB);
+        apu.write(NR52, 0x80);
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x77);
+    }
+
+    #[test]
+    fn test_apu_display() {
+        let mut apu = Apu::new();
+        apu.write(WAVE_RAM, 0x01);
+        let dump = apu.to_string();
+        assert!(dump.starts_with("NR10 80 NR11 bf NR12 f3 NR13 ff NR14 bf\n"));
+        assert!(dump.contains("NR50 77 NR51 f3 NR52 f1\n"));
+        assert!(dump.contains("ch1 on length 0 volume 0 envelope 0\n"));
+        assert!(dump.contains("sequencer step 0 timer 8192\n"));
+        assert!(dump.ends_with("wave 01 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n"));
+    }
+
+    #[test]
+    fn test_apu_length() {
+        let mut apu = Apu::new();
+        // channel 2 with 2 length steps left
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x3E);
+        apu.write(0xFF19, TRIGGER | LENGTH_ENABLE);
+        assert_eq!(apu.read(NR52), 0xF3);
+        // steps 0 and 1
+        apu.tick(2 * SEQUENCER_DOTS as u32);
+        assert_eq!(apu.read(NR52), 0xF3);
+        // step 2
+        apu.tick(SEQUENCER_DOTS as u32 - 1);
+        assert_eq!(apu.read(NR52), 0xF3);
+        apu.tick(1);
+        assert_eq!(apu.read(NR52), 0xF1);
+        // turning the DAC off stops channel 1
+        apu.write(0xFF12, 0x00);
+        assert_eq!(apu.read(NR52), 0xF0);
+    }
+
+    #[test]
+    fn test_apu_envelope_and_sweep() {
+        let mut apu = Apu::new();
+        // sweep up by half every step, volume 15 going down every step
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF12, 0xF1);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, TRIGGER | 0x01);
+        apu.tick(8 * SEQUENCER_DOTS as u32);
+        assert_eq!(apu.channels[0].volume, 14);
+        assert_eq!(apu.sweep.shadow, 0x240);
+        assert_eq!(apu.frequency(), 0x240);
+        // 0x360 then 0x510
+        apu.tick(8 * SEQUENCER_DOTS as u32);
+        assert_eq!(apu.channels[0].volume, 13);
+        assert_eq!(apu.frequency(), 0x510);
+        assert_eq!(apu.read(NR52) & 0x01, 0x01);
+        // 0x798 goes past 0x7FF once checked again
+        apu.tick(3 * SEQUENCER_DOTS as u32);
+        assert_eq!(apu.read(NR52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_apu_lfsr() {
+        let mut apu = Apu::new();
+        apu.write(0xFF21, 0xF0);
+        // 7 bit mode, shifting every 8 dots
+        apu.write(0xFF22, 0x08);
+        apu.write(0xFF23, TRIGGER);
+        apu.tick(15);
+        assert_eq!(apu.lfsr, 0x3FBF);
+        apu.tick(1);
+        assert_eq!(apu.lfsr, 0x1F9F);
+    }
+
+    #[test]
+    fn test_apu_state_json_roundtrip() {
+        let mut apu = Apu::new();
+        apu.write(0xFF10, 0x21);
+        apu.write(0xFF12, 0x5A);
+        apu.write(0xFF14, TRIGGER);
+        apu.write(0xFF21, 0x83);
+        apu.write(0xFF23, TRIGGER | LENGTH_ENABLE);
+        apu.tick(3 * SEQUENCER_DOTS as u32 + 100);
+        let json = Value::parse(&apu.to_json().to_string()).unwrap();
+        assert_eq!(Apu::from_json(&json), Ok(apu.clone()));
+        // and it carries on the same
+        let mut restored = Apu::from_json(&json).unwrap();
+        restored.tick(5 * SEQUENCER_DOTS as u32);
+        apu.tick(5 * SEQUENCER_DOTS as u32);
+        assert_eq!(restored, apu);
+    }
+}
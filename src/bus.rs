@@ -0,0 +1,129 @@
+use crate::{compat::Compat, mmu::Mmu};
+
+/// What the CPU sees of the rest of the system: a 16-bit address space and
+/// the devices that run alongside it. Interrupts go through IF and IE like
+/// any other register.
+pub trait Bus {
+    fn read8(&self, address: u16) -> u8;
+    fn write8(&mut self, address: u16, value: u8);
+
+    /// Little endian, the high byte at `address + 1`
+    fn read16(&self, address: u16) -> u16 {
+        u16::from_le_bytes([self.read8(address), self.read8(address.wrapping_add(1))])
+    }
+
+    fn write16(&mut self, address: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write8(address, low);
+        self.write8(address.wrapping_add(1), high);
+    }
+
+    /// Advances the devices by `cycles` CPU T-cycles, `dots` of which went
+    /// by on the master clock
+    fn tick(&mut self, cycles: u32, dots: u32);
+
+    /// Dots a DMA engine took the bus from the CPU for since the last call,
+    /// the CPU waits them out before its next instruction
+    fn take_stall(&mut self) -> u32 {
+        0
+    }
+
+    /// Whether a button was pressed since the last call, which ends STOP.
+    /// Without a joypad nothing ever does.
+    fn take_button_press(&mut self) -> bool {
+        false
+    }
+
+    /// CGB mode or the compatibility registers changed. Without a PPU
+    /// nothing depends on them.
+    fn set_cgb_mode(&mut self, _cgb: bool, _compat: &Compat) {}
+}
+
+impl Bus for Mmu {
+    fn read8(&self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    fn write8(&mut self, address: u16, value: u8) {
+        self.write(address, value)
+    }
+
+    fn tick(&mut self, cycles: u32, dots: u32) {
+        Mmu::tick(self, cycles, dots)
+    }
+
+    fn take_stall(&mut self) -> u32 {
+        self.hdma.take_stall()
+    }
+
+    fn take_button_press(&mut self) -> bool {
+        self.joypad.take_interrupt()
+    }
+
+    fn set_cgb_mode(&mut self, cgb: bool, compat: &Compat) {
+        self.renderer.set_cgb(cgb);
+        self.renderer.set_priority_by_x(compat.priority_by_x());
+    }
+}
+
+/// 64 KiB of RAM with nothing mapped in it, to run instructions without a
+/// cartridge or devices getting in the way
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlatBus {
+    pub memory: Vec<u8>,
+    /// T-cycles ticked so far
+    pub cycles: u64,
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0x00; 0x10000],
+            cycles: 0,
+        }
+    }
+
+    /// With `program` copied at `address`
+    pub fn with_program(address: u16, program: &[u8]) -> Self {
+        let mut bus = Self::new();
+        let start = address as usize;
+        bus.memory[start..start + program.len()].copy_from_slice(program);
+        bus
+    }
+}
+
+impl Bus for FlatBus {
+    fn read8(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write8(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+
+    fn tick(&mut self, cycles: u32, _dots: u32) {
+        self.cycles += cycles as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_bus_words() {
+        let mut bus = FlatBus::with_program(0x0100, &[0x34, 0x12]);
+        assert_eq!(bus.read16(0x0100), 0x1234);
+        bus.write16(0xFFFF, 0xABCD);
+        assert_eq!((bus.read8(0xFFFF), bus.read8(0x0000)), (0xCD, 0xAB));
+        assert_eq!(bus.read16(0xFFFF), 0xABCD);
+        bus.tick(4, 4);
+        assert_eq!(bus.cycles, 4);
+    }
+}
@@ -1,128 +1,630 @@
-use std::fs::File;
-use std::io::Read;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use log::{debug, info};
+use log::{debug, info, warn};
 
-#[repr(usize)]
-enum Address {
-    ROMSize = 0x148,
-    CartridgeType = 0x147,
-    HeaderCheckSum = 0x14D,
+use crate::json::Value;
+
+mod archive;
+mod header;
+mod huc1;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod mbc7;
+mod registry;
+
+pub use header::{global_checksum, header_checksum, CartridgeHeader, Licensee};
+pub use huc1::Huc1;
+pub use mbc1::Mbc1;
+pub use mbc2::{Mbc2, MBC2_RAM_SIZE};
+pub use mbc3::{Mbc3, RTC_FOOTER_SIZE};
+pub use mbc5::Mbc5;
+pub use mbc7::{Mbc7, EEPROM_SIZE};
+pub use registry::{MapperDetector, MapperFactory, MapperRegistry};
+
+/// Size of a ROM bank, bank 0 is at 0x0000-0x3FFF and the switchable one
+/// at 0x4000-0x7FFF
+pub const ROM_BANK_SIZE: usize = 0x4000;
+/// Size of an external RAM bank, mapped at 0xA000-0xBFFF
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Smallest ROM that holds a whole header
+const HEADER_END: usize = 0x0150;
+
+// the boot ROM refuses to start cartridges without it at 0x0104
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// Why `load` could not make a cartridge out of a file
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // a zip or gzip file that could not be extracted
+    InvalidArchive(String),
+    // the logo, a checksum or a size code is wrong
+    InvalidHeader(String),
+    UnsupportedMapper(u8),
+    // too short to even hold a header
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "{}", e),
+            CartridgeError::InvalidArchive(reason) => write!(f, "{}", reason),
+            CartridgeError::InvalidHeader(reason) => write!(f, "Invalid header: {}", reason),
+            CartridgeError::UnsupportedMapper(cartridge_type) => {
+                write!(f, "Unsupported cartridge type: {:#04x}", cartridge_type)
+            }
+            CartridgeError::SizeMismatch { expected, actual } => write!(
+                f,
+                "ROM is too small: {:#x} bytes but at least {:#x} are needed",
+                actual, expected
+            ),
+        }
+    }
 }
-#[allow(non_snake_case)]
-mod AddressRanges {
-    use std::ops::RangeInclusive;
-    pub const TITLE: RangeInclusive<u16> = 0x0134..=0x0143;
-    pub const CHECKSUM: RangeInclusive<u16> = 0x0134..=0x014C;
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
-impl From<Address> for usize {
-    fn from(a: Address) -> usize {
-        a as usize
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
     }
 }
+
+/// Banking registers of the mapper, as seen by the CPU
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BankState {
+    // bank mapped at 0x4000-0x7FFF
+    pub rom_bank: u16,
+    // bank mapped at 0xA000-0xBFFF
+    pub ram_bank: u8,
+    pub ram_enabled: bool,
+    // mapper specific mode bits, e.g. the MBC1 banking mode
+    pub mode: u8,
+}
+
+impl Default for BankState {
+    fn default() -> Self {
+        Self {
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            mode: 0,
+        }
+    }
+}
+
 pub trait Cartdrige: Send {
     fn read(&self, address: u16) -> u8;
-    fn read_word(&self, address: u16) -> u16;
     fn set(&mut self, address: u16, value: u8);
 
-    fn ensure_nintendo_logo(&self) {
-        const NINTENDO_LOGO: [u8; 48] = [
-            0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C,
-            0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6,
-            0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC,
-            0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
-        ];
+    // Whole ROM image, regardless of the banks currently mapped
+    fn rom(&self) -> &[u8];
+
+    // Whole external RAM, regardless of the banks currently mapped
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    // Cartridges without a mapper always expose the same banks
+    fn bank_state(&self) -> BankState {
+        BankState::default()
+    }
+
+    // Puts the banking registers back as saved, e.g. when loading a savestate
+    fn set_bank_state(&mut self, _state: BankState) {}
+
+    // What the mapper keeps besides the banking registers and the RAM, such
+    // as a clock, in a layout of its own that is always the same size, for
+    // savestates and state hashes
+    fn mapper_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Puts back what `mapper_state` returned, values out of range are
+    // brought back into it
+    fn set_mapper_state(&mut self, _state: &[u8]) {}
+
+    // As parsed when the ROM was loaded. Mappers made directly rather than
+    // through `load` or `from_rom` parse it from the ROM on every call.
+    fn header(&self) -> CartridgeHeader {
+        CartridgeHeader::parse(self.rom())
+    }
+
+    // Whether a battery keeps the external RAM, and so the saves, when the
+    // console is off
+    fn has_battery(&self) -> bool {
+        matches!(
+            self.header().cartridge_type,
+            0x03 | 0x06 | 0x09 | 0x0F..=0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        )
+    }
+
+    // Advances the devices on the cartridge, such as a clock, by `dots` of
+    // the master clock
+    fn tick(&mut self, _dots: u32) {}
+
+    // What a battery keeps besides the RAM, such as a clock, saved after it
+    // along with `now` in seconds since the Unix epoch
+    fn battery_footer(&self, _now: u64) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Restores what `battery_footer` saved, `now` tells how long the
+    // console was off
+    fn load_battery_footer(&mut self, _footer: &[u8], _now: u64) {}
+
+    // Tilts the cartridge by `x` and `y` g, if it has an accelerometer.
+    // Positive values are to the right and towards the player.
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    // Whether the rumble motor of the cartridge is running, if it has one
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    fn ensure_nintendo_logo(&self) -> Result<(), CartridgeError> {
         for i in NINTENDO_LOGO.iter().enumerate() {
             if self.read(0x0104 + i.0 as u16) != *i.1 {
-                panic!("Invalid Nintendo logo");
+                return Err(CartridgeError::InvalidHeader(
+                    "wrong Nintendo logo".to_string(),
+                ));
             }
         }
         debug!("Nintendo logo is valid");
+        Ok(())
     }
 
-    fn ensure_header_checksum(&self) {
-        let mut checksum: u8 = 0;
-        for i in AddressRanges::CHECKSUM {
-            checksum = checksum.wrapping_sub(self.read(i)).wrapping_sub(1);
-        }
-        if checksum != self.read(Address::HeaderCheckSum as u16) {
-            panic!(
-                "Invalid header checksum, expected {:#04x} but got {:#04x}",
-                checksum,
-                self.read(Address::HeaderCheckSum as u16)
-            );
+    fn ensure_header_checksum(&self) -> Result<(), CartridgeError> {
+        let expected = header::header_checksum(self.rom());
+        let checksum = self.header().header_checksum;
+        if checksum != expected {
+            return Err(CartridgeError::InvalidHeader(format!(
+                "checksum is {:#04x} but should be {:#04x}",
+                checksum, expected
+            )));
         }
         debug!("Header checksum is valid");
+        Ok(())
     }
+}
 
-    fn get_title(&self) -> String {
-        let mut title = String::new();
-        for i in AddressRanges::TITLE {
-            match self.read(i) {
-                0 => break,
-                c => title.push(c as char),
-            }
-        }
-        title
+/// Where the battery backed RAM of `rom` is saved, next to it
+pub fn save_path(rom: &Path) -> PathBuf {
+    rom.with_extension("sav")
+}
+
+/// `title` reduced to characters that are safe in a file name everywhere,
+/// for save files and screenshots named after the game
+pub fn file_name(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_matches(|c| c == '_' || c == '.');
+    if name.is_empty() {
+        "untitled".to_string()
+    } else {
+        name.to_string()
     }
 }
 
 pub struct RomOnly(pub Vec<u8>);
 
 impl Cartdrige for RomOnly {
+    // no external RAM, nothing drives the bus there
     fn read(&self, address: u16) -> u8 {
-        self.0[address as usize]
+        match address {
+            0x0000..=0x7FFF => self.0.get(address as usize).copied().unwrap_or(0xFF),
+            _ => 0xFF,
+        }
     }
 
-    fn read_word(&self, address: u16) -> u16 {
-        let low = self.read(address) as u16;
-        let high = self.read(address + 1) as u16;
-        low | (high << 8)
+    fn set(&mut self, _address: u16, _value: u8) {}
+
+    fn rom(&self) -> &[u8] {
+        &self.0
     }
+}
 
-    fn set(&mut self, _address: u16, _value: u8) {}
+// A loaded cartridge along with its header, so that it is parsed once
+struct Loaded {
+    cartdrige: Box<dyn Cartdrige>,
+    header: CartridgeHeader,
 }
 
-fn rom_size(rom_max: usize) -> usize {
-    let v = 16384; // 32 KiB / 2
-    match rom_max {
-        // 32 KiB × (1 << <value>)
-        0x00..=0x08 => 2 * v * (1 << rom_max),
-        0x52 => 72 * v,
-        0x53 => 80 * v,
-        0x54 => 96 * v,
-        _ => panic!("Invalid ROM size: {:#04x}", rom_max),
+// `has_battery` and the header checks are left to the defaults, which go
+// through the stored header
+impl Cartdrige for Loaded {
+    fn read(&self, address: u16) -> u8 {
+        self.cartdrige.read(address)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        self.cartdrige.set(address, value)
+    }
+
+    fn rom(&self) -> &[u8] {
+        self.cartdrige.rom()
+    }
+
+    fn ram(&self) -> &[u8] {
+        self.cartdrige.ram()
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        self.cartdrige.ram_mut()
+    }
+
+    fn bank_state(&self) -> BankState {
+        self.cartdrige.bank_state()
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.cartdrige.set_bank_state(state)
+    }
+
+    fn mapper_state(&self) -> Vec<u8> {
+        self.cartdrige.mapper_state()
+    }
+
+    fn set_mapper_state(&mut self, state: &[u8]) {
+        self.cartdrige.set_mapper_state(state)
+    }
+
+    fn header(&self) -> CartridgeHeader {
+        self.header.clone()
+    }
+
+    fn tick(&mut self, dots: u32) {
+        self.cartdrige.tick(dots)
+    }
+
+    fn battery_footer(&self, now: u64) -> Vec<u8> {
+        self.cartdrige.battery_footer(now)
+    }
+
+    fn load_battery_footer(&mut self, footer: &[u8], now: u64) {
+        self.cartdrige.load_battery_footer(footer, now)
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cartdrige.set_tilt(x, y)
+    }
+
+    fn rumble(&self) -> bool {
+        self.cartdrige.rumble()
     }
 }
 
-pub fn load(path: &str) -> Box<dyn Cartdrige> {
-    let mut rom = Vec::new();
-    let mut f = File::open(path).unwrap();
-    f.read_to_end(&mut rom).unwrap();
-    if rom.len() < 0x0150 {
-        panic!("ROM is too small: {:#06x}", rom.len());
+// Most external RAM the mapper of `cartridge_type` can address, `None` for
+// the types not supported yet. MBC2 has its RAM built in and none outside.
+fn max_ram_size(cartridge_type: u8) -> Option<usize> {
+    match cartridge_type {
+        0x00 | 0x01 | 0x05 | 0x06 | 0x19 | 0x1C => Some(0),
+        0x02 | 0x03 => Some(4 * RAM_BANK_SIZE),
+        0x0F | 0x11 => Some(0),
+        // MBC30 boards have twice the RAM
+        0x10 | 0x12 | 0x13 => Some(8 * RAM_BANK_SIZE),
+        0x1A | 0x1B => Some(16 * RAM_BANK_SIZE),
+        // a bank bit less for the motor
+        0x1D | 0x1E => Some(8 * RAM_BANK_SIZE),
+        // the EEPROM is not in the header
+        0x22 => Some(0),
+        0xFF => Some(4 * RAM_BANK_SIZE),
+        _ => None,
     }
+}
+
+/// External RAM to allocate for the RAM size in `header`, checked against
+/// what the mapper of the cartridge can address
+fn ram_size(header: &CartridgeHeader) -> Result<usize, String> {
+    let cartridge_type = header.cartridge_type;
+    let Some(size) = header.ram_size() else {
+        return Err(format!("Invalid RAM size: {:#04x}", header.ram_size_code));
+    };
+    let Some(max) = max_ram_size(cartridge_type) else {
+        return Ok(size);
+    };
+    if max == 0 && size > 0 {
+        Err(format!(
+            "Cartridge type {:#04x} has no external RAM but the header gives it {:#x} bytes",
+            cartridge_type, size
+        ))
+    } else if max > 0 && size == 0 {
+        Err(format!(
+            "Cartridge type {:#04x} has external RAM but the header gives it none",
+            cartridge_type
+        ))
+    } else if size > max {
+        Err(format!(
+            "Cartridge type {:#04x} addresses up to {:#x} bytes of RAM but the header gives it {:#x}",
+            cartridge_type, max, size
+        ))
+    } else {
+        Ok(size)
+    }
+}
+
+/// Plenty of dumps in the wild don't match their header: overdumps repeat the
+/// ROM or carry filler after it, and trimmed dumps drop the trailing 0xFF
+/// filler. Resizes `rom` to the `size` the header declares and explains what
+/// was off.
+fn fit_to_header(rom: &mut Vec<u8>, size: usize) -> Option<String> {
+    let len = rom.len();
+    if len > size {
+        let extra = &rom[size..];
+        let kind = if extra.chunks(size).all(|chunk| chunk == &rom[..chunk.len()]) {
+            "mirrored"
+        } else if extra.iter().all(|&b| b == 0xFF) || extra.iter().all(|&b| b == 0x00) {
+            "padded"
+        } else {
+            "unknown data"
+        };
+        rom.truncate(size);
+        Some(format!(
+            "Overdumped ROM ({}): {:#x} bytes but the header says {:#x}, truncating",
+            kind, len, size
+        ))
+    } else if len < size {
+        rom.resize(size, 0xFF);
+        Some(format!(
+            "Trimmed ROM: {:#x} bytes but the header says {:#x}, padding with 0xFF",
+            len, size
+        ))
+    } else {
+        None
+    }
+}
+
+/// Reads the ROM at `path`, extracting it first from zip and gzip files.
+/// The global checksum is only checked when `strict`, plenty of homebrew
+/// never sets it and hardware ignores it.
+/// Only the built-in mappers are known, see `MapperRegistry` for others.
+pub fn load(path: &Path, strict: bool) -> Result<Box<dyn Cartdrige>, CartridgeError> {
+    MapperRegistry::new().load(path, strict)
+}
+
+/// Same as `load`, for a ROM image already in memory
+pub fn from_rom(rom: Vec<u8>, strict: bool) -> Result<Box<dyn Cartdrige>, CartridgeError> {
+    MapperRegistry::new().from_rom(rom, strict)
+}
 
-    let rom_size = rom_size(rom[Address::ROMSize as usize] as usize);
-    if rom.len() > rom_size {
-        panic!("ROM size is bigger than expected: {:#06x}", rom.len());
+// The header of `rom`, once `rom` is resized to what it declares and the
+// global checksum checked
+fn check_rom(rom: &mut Vec<u8>, strict: bool) -> Result<CartridgeHeader, CartridgeError> {
+    if rom.len() < HEADER_END {
+        return Err(CartridgeError::SizeMismatch {
+            expected: HEADER_END,
+            actual: rom.len(),
+        });
     }
 
-    let res: Box<dyn Cartdrige>;
-    match rom[Address::CartridgeType as usize] {
-        0x00 => {
-            res = Box::new(RomOnly(rom));
+    let header = CartridgeHeader::parse(rom);
+    let rom_size = header.rom_size().ok_or_else(|| {
+        CartridgeError::InvalidHeader(format!("ROM size {:#04x}", header.rom_size_code))
+    })?;
+    if let Some(warning) = fit_to_header(rom, rom_size) {
+        warn!("{}", warning);
+    }
+    let expected = global_checksum(rom);
+    if header.global_checksum != expected {
+        let reason = format!(
+            "global checksum is {:#06x} but should be {:#06x}",
+            header.global_checksum, expected
+        );
+        if strict {
+            return Err(CartridgeError::InvalidHeader(reason));
         }
-        _ => {
-            panic!(
-                "Unsupported cartdrige type: {:#04x}",
-                rom[Address::CartridgeType as usize]
-            );
+        warn!("{}", reason);
+    }
+    Ok(header)
+}
+
+// The cartridge with one of the mappers supported out of the box
+fn builtin(rom: Vec<u8>, header: &CartridgeHeader) -> Result<Box<dyn Cartdrige>, CartridgeError> {
+    let ram_size = ram_size(header).map_err(CartridgeError::InvalidHeader)?;
+    Ok(match header.cartridge_type {
+        0x00 => Box::new(RomOnly(rom)),
+        0x01..=0x03 if mbc1::is_multicart(&rom) => {
+            info!("MBC1 multicart detected");
+            Box::new(Mbc1::multicart(rom, ram_size))
         }
+        0x01..=0x03 => Box::new(Mbc1::new(rom, ram_size)),
+        0x05 | 0x06 => Box::new(Mbc2::new(rom)),
+        0x0F | 0x10 => Box::new(Mbc3::new(rom, ram_size, true)),
+        0x11..=0x13 => Box::new(Mbc3::new(rom, ram_size, false)),
+        0x19..=0x1B => Box::new(Mbc5::new(rom, ram_size, false)),
+        0x1C..=0x1E => Box::new(Mbc5::new(rom, ram_size, true)),
+        0x22 => Box::new(Mbc7::new(rom)),
+        0xFF => Box::new(Huc1::new(rom, ram_size)),
+        cartridge_type => return Err(CartridgeError::UnsupportedMapper(cartridge_type)),
+    })
+}
+
+impl BankState {
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("rom_bank", Value::hex(self.rom_bank as u64, 3)),
+            ("ram_bank", Value::hex(self.ram_bank as u64, 2)),
+            ("ram_enabled", Value::Bool(self.ram_enabled)),
+            ("mode", Value::Number(self.mode as u64)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            rom_bank: value.get("rom_bank")?.as_u16()?,
+            ram_bank: value.get("ram_bank")?.as_u8()?,
+            ram_enabled: value.get("ram_enabled")?.as_bool()?,
+            mode: value.get("mode")?.as_u8()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fit_to_header() {
+        let rom: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        let mut fitted = rom.clone();
+        assert_eq!(fit_to_header(&mut fitted, 0x8000), None);
+        // dumped twice over
+        let mut mirrored = rom.repeat(2);
+        let warning = fit_to_header(&mut mirrored, 0x8000).unwrap();
+        assert!(warning.contains("mirrored"), "{}", warning);
+        assert_eq!(mirrored, rom);
+        let mut padded = rom.clone();
+        padded.extend([0xFF; 0x100]);
+        let warning = fit_to_header(&mut padded, 0x8000).unwrap();
+        assert!(warning.contains("padded"), "{}", warning);
+        assert_eq!(padded, rom);
+        // trailing filler cut off
+        let mut trimmed = rom[..0x7000].to_vec();
+        assert!(fit_to_header(&mut trimmed, 0x8000).is_some());
+        assert_eq!(trimmed.len(), 0x8000);
+        assert_eq!(trimmed[..0x7000], rom[..0x7000]);
+        assert!(trimmed[0x7000..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_ram_size() {
+        let ram_size = |cartridge_type, code| {
+            let mut rom = vec![0x00; 0x150];
+            rom[0x147] = cartridge_type;
+            rom[0x149] = code;
+            ram_size(&CartridgeHeader::parse(&rom))
+        };
+        // MBC1+RAM+BATTERY
+        assert_eq!(ram_size(0x03, 0x03), Ok(4 * RAM_BANK_SIZE));
+        assert_eq!(ram_size(0x03, 0x01), Ok(0x800));
+        assert!(ram_size(0x03, 0x04).is_err());
+        assert!(ram_size(0x03, 0x00).is_err());
+        assert!(ram_size(0x03, 0x06).is_err());
+        // MBC2 has its RAM built in
+        assert_eq!(ram_size(0x06, 0x00), Ok(0));
+        assert!(ram_size(0x06, 0x02).is_err());
+        // MBC5+RUMBLE+RAM has a bank bit less
+        assert_eq!(ram_size(0x1D, 0x05), Ok(8 * RAM_BANK_SIZE));
+        assert!(ram_size(0x1D, 0x04).is_err());
+        assert_eq!(ram_size(0x1B, 0x04), Ok(16 * RAM_BANK_SIZE));
+    }
+
+    // a ROM that loads, once the header checksum is fixed up
+    pub(super) fn valid_rom(cartridge_type: u8) -> Vec<u8> {
+        let mut rom = vec![0x00; 2 * ROM_BANK_SIZE];
+        rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x0147] = cartridge_type;
+        rom[0x014D] = header_checksum(&rom);
+        let checksum = global_checksum(&rom);
+        rom[0x014E..=0x014F].copy_from_slice(&checksum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn test_header_is_kept_from_load() {
+        let mut rom = valid_rom(0x03);
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0149] = 0x02;
+        rom[0x014D] = header_checksum(&rom);
+        let cartdrige = from_rom(rom.clone(), false).unwrap();
+        assert_eq!(cartdrige.header(), CartridgeHeader::parse(&rom));
+        assert_eq!(cartdrige.header().title, "TEST");
+        assert!(cartdrige.has_battery());
+        assert!(cartdrige.ensure_header_checksum().is_ok());
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert!(from_rom(valid_rom(0x00), true).is_ok());
+        assert!(matches!(
+            from_rom(vec![0x00; 0x100], false),
+            Err(CartridgeError::SizeMismatch {
+                expected: 0x150,
+                actual: 0x100
+            })
+        ));
+        assert!(matches!(
+            from_rom(valid_rom(0xFD), false),
+            Err(CartridgeError::UnsupportedMapper(0xFD))
+        ));
+        let mut rom = valid_rom(0x00);
+        rom[0x014D] ^= 0xFF;
+        assert!(matches!(
+            from_rom(rom, false),
+            Err(CartridgeError::InvalidHeader(_))
+        ));
+        let mut rom = valid_rom(0x00);
+        rom[0x0104] = 0x00;
+        assert!(matches!(
+            from_rom(rom, false),
+            Err(CartridgeError::InvalidHeader(_))
+        ));
+        // MBC1+RAM without any
+        assert!(matches!(
+            from_rom(valid_rom(0x02), false),
+            Err(CartridgeError::InvalidHeader(_))
+        ));
+        let missing = std::env::temp_dir().join("gameboy-missing.gb");
+        assert!(matches!(load(&missing, false), Err(CartridgeError::Io(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::env::temp_dir().join(OsStr::from_bytes(b"gameboy-\xff.gb"));
+        std::fs::write(&path, valid_rom(0x00)).unwrap();
+        let loaded = load(&path, false);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_ok());
+    }
+
+    #[test]
+    fn test_global_checksum_is_only_enforced_when_strict() {
+        let mut rom = valid_rom(0x00);
+        rom[0x4000] = 0x01;
+        assert!(from_rom(rom.clone(), false).is_ok());
+        assert!(matches!(
+            from_rom(rom, true),
+            Err(CartridgeError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("POKEMON_SLV"), "POKEMON_SLV");
+        assert_eq!(file_name("DR.MARIO"), "DR.MARIO");
+        assert_eq!(file_name("A/B: C?"), "A_B__C");
+        assert_eq!(file_name(".."), "untitled");
+        assert_eq!(file_name("\u{FF76}\u{FF70}"), "untitled");
     }
-    res.ensure_nintendo_logo();
-    res.ensure_header_checksum();
-    info!("ROM title: {}", res.get_title());
-    res
 }
@@ -0,0 +1,208 @@
+use miniz_oxide::inflate::decompress_to_vec;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_LOCAL_HEADER: u32 = 0x04034B50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014B50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x06054B50;
+// the end of central directory record without its trailing comment
+const ZIP_END_SIZE: usize = 22;
+
+// gzip header flags
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+// compression methods, the same in both formats
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// The ROM inside `data` if it is a zip or gzip archive, told apart by
+/// their magic numbers, `data` itself otherwise. Zip archives give their
+/// first .gb or .gbc entry.
+pub fn extract(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if data.starts_with(&GZIP_MAGIC) {
+        gunzip(&data)
+    } else if data.starts_with(&ZIP_LOCAL_HEADER.to_le_bytes()) {
+        unzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "Truncated archive".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "Truncated archive".to_string())
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    decompress_to_vec(data).map_err(|e| format!("Corrupt archive: {}", e))
+}
+
+// https://www.rfc-editor.org/rfc/rfc1952
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.get(2) != Some(&(DEFLATED as u8)) {
+        return Err("Unsupported gzip compression method".to_string());
+    }
+    let flags = data.get(3).copied().unwrap_or(0);
+    let mut offset = 10;
+    if flags & FEXTRA != 0 {
+        offset += 2 + u16_at(data, offset)? as usize;
+    }
+    // zero terminated file name and comment
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .iter()
+                .skip(offset)
+                .position(|&b| b == 0)
+                .ok_or("Truncated archive")?;
+            offset += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+    // followed by the CRC and the size of the original data
+    let end = data
+        .len()
+        .checked_sub(8)
+        .filter(|&end| end >= offset)
+        .ok_or("Truncated archive")?;
+    let rom = inflate(&data[offset..end])?;
+    if u32_at(data, end + 4)? != rom.len() as u32 {
+        return Err("Corrupt archive: wrong size".to_string());
+    }
+    Ok(rom)
+}
+
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT, the sizes
+// are only reliable in the central directory
+fn unzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let end = (0..=data.len().saturating_sub(ZIP_END_SIZE))
+        .rev()
+        .find(|&offset| u32_at(data, offset) == Ok(ZIP_END_OF_DIRECTORY))
+        .ok_or("Truncated archive")?;
+    let entries = u16_at(data, end + 10)?;
+    let mut offset = u32_at(data, end + 16)? as usize;
+    for _ in 0..entries {
+        if u32_at(data, offset)? != ZIP_CENTRAL_HEADER {
+            return Err("Corrupt archive: bad central directory".to_string());
+        }
+        let method = u16_at(data, offset + 10)?;
+        let compressed = u32_at(data, offset + 20)? as usize;
+        let name_length = u16_at(data, offset + 28)? as usize;
+        let extra_length = u16_at(data, offset + 30)? as usize;
+        let comment_length = u16_at(data, offset + 32)? as usize;
+        let local = u32_at(data, offset + 42)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or("Truncated archive")?;
+        offset += 46 + name_length + extra_length + comment_length;
+        let name = String::from_utf8_lossy(name).to_lowercase();
+        if !name.ends_with(".gb") && !name.ends_with(".gbc") {
+            continue;
+        }
+
+        if u32_at(data, local)? != ZIP_LOCAL_HEADER {
+            return Err(format!("Corrupt archive: bad header for {}", name));
+        }
+        let start =
+            local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let contents = data
+            .get(start..start + compressed)
+            .ok_or("Truncated archive")?;
+        return match method {
+            STORED => Ok(contents.to_vec()),
+            DEFLATED => inflate(contents),
+            _ => Err(format!("Unsupported zip compression method {}", method)),
+        };
+    }
+    Err("No .gb or .gbc file in the archive".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::compress_to_vec;
+
+    use super::*;
+
+    // CRCs are left at 0, nothing checks them
+    fn zip(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, contents) in entries {
+            let stored = match method {
+                DEFLATED => compress_to_vec(contents, 6),
+                _ => contents.to_vec(),
+            };
+            let local = archive.len() as u32;
+            archive.extend(ZIP_LOCAL_HEADER.to_le_bytes());
+            archive.extend([20, 0, 0, 0]);
+            archive.extend(method.to_le_bytes());
+            archive.extend([0; 8]);
+            archive.extend((stored.len() as u32).to_le_bytes());
+            archive.extend((contents.len() as u32).to_le_bytes());
+            archive.extend((name.len() as u16).to_le_bytes());
+            archive.extend([0; 2]);
+            archive.extend(name.as_bytes());
+            archive.extend(&stored);
+
+            directory.extend(ZIP_CENTRAL_HEADER.to_le_bytes());
+            directory.extend([20, 0, 20, 0, 0, 0]);
+            directory.extend(method.to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend((stored.len() as u32).to_le_bytes());
+            directory.extend((contents.len() as u32).to_le_bytes());
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0; 12]);
+            directory.extend(local.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let directory_offset = archive.len() as u32;
+        archive.extend(&directory);
+        archive.extend(ZIP_END_OF_DIRECTORY.to_le_bytes());
+        archive.extend([0; 4]);
+        archive.extend((entries.len() as u16).to_le_bytes());
+        archive.extend((entries.len() as u16).to_le_bytes());
+        archive.extend((directory.len() as u32).to_le_bytes());
+        archive.extend(directory_offset.to_le_bytes());
+        archive.extend([0; 2]);
+        archive
+    }
+
+    #[test]
+    fn test_zip() {
+        let rom: Vec<u8> = (0..0x8000).map(|i| (i % 7) as u8).collect();
+        let archive = zip(&[
+            ("readme.txt", STORED, b"not a ROM"),
+            ("Game.GBC", DEFLATED, &rom),
+        ]);
+        assert_eq!(extract(archive), Ok(rom.clone()));
+        let archive = zip(&[("game.gb", STORED, &rom)]);
+        assert_eq!(extract(archive), Ok(rom));
+        assert!(extract(zip(&[("readme.txt", STORED, b"")])).is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let rom: Vec<u8> = (0..0x8000).map(|i| (i % 7) as u8).collect();
+        let mut archive = vec![0x1F, 0x8B, 0x08, FNAME, 0, 0, 0, 0, 0, 0xFF];
+        archive.extend(b"game.gb\0");
+        archive.extend(compress_to_vec(&rom, 6));
+        archive.extend([0; 4]);
+        archive.extend((rom.len() as u32).to_le_bytes());
+        assert_eq!(extract(archive.clone()), Ok(rom.clone()));
+        archive.truncate(archive.len() - 4);
+        assert!(extract(archive).is_err());
+        // not compressed at all
+        assert_eq!(extract(rom.clone()), Ok(rom));
+    }
+}
@@ -0,0 +1,241 @@
+use std::ops::RangeInclusive;
+
+use super::RAM_BANK_SIZE;
+
+const TITLE: RangeInclusive<usize> = 0x0134..=0x0143;
+// newer headers shorten the title to 11 characters to make room for it
+const MANUFACTURER_CODE: RangeInclusive<usize> = 0x013F..=0x0142;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE_CODE: RangeInclusive<usize> = 0x0144..=0x0145;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION_CODE: usize = 0x014A;
+const OLD_LICENSEE_CODE: usize = 0x014B;
+const VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+// bytes covered by the header checksum
+const CHECKSUMMED: RangeInclusive<usize> = 0x0134..=0x014C;
+
+/// Publisher of the game. The old one byte code is 0x33 when the two ASCII
+/// characters of the new one are used instead.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Licensee {
+    Old(u8),
+    New(String),
+}
+
+/// The cartridge header at 0x0100-0x014F, decoded
+/// https://gbdev.io/pandocs/The_Cartridge_Header.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CartridgeHeader {
+    /// Printable whatever the header contains, so it can go straight into a
+    /// window title or a log line
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    /// 0x80 (CGB enhanced) or 0xC0 (CGB only), older games have the last
+    /// title character here
+    pub cgb_flag: u8,
+    pub sgb: bool,
+    pub licensee: Licensee,
+    pub cartridge_type: u8,
+    /// As coded in the header, see `rom_size` and `ram_size`
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    /// 0x00 for Japan, 0x01 for everywhere else
+    pub destination_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    /// Decodes the header of `rom`, bytes past the end of a ROM too short to
+    /// have one read as 0x00
+    pub fn parse(rom: &[u8]) -> Self {
+        let byte = |address: usize| rom.get(address).copied().unwrap_or(0x00);
+        let cgb_flag = byte(CGB_FLAG);
+        let old_licensee_code = byte(OLD_LICENSEE_CODE);
+        let manufacturer_code = manufacturer_code(rom, cgb_flag, old_licensee_code);
+        let title_end = if manufacturer_code.is_some() {
+            *MANUFACTURER_CODE.start() - 1
+        } else if cgb_flag & 0x80 != 0 {
+            CGB_FLAG - 1
+        } else {
+            *TITLE.end()
+        };
+        let title: String = (*TITLE.start()..=title_end)
+            .map(byte)
+            .take_while(|&c| c != 0)
+            .map(title_char)
+            .collect();
+        let licensee = if old_licensee_code == 0x33 {
+            Licensee::New(NEW_LICENSEE_CODE.map(|i| byte(i) as char).collect())
+        } else {
+            Licensee::Old(old_licensee_code)
+        };
+        Self {
+            title: title.trim_end().to_string(),
+            manufacturer_code,
+            cgb_flag,
+            sgb: byte(SGB_FLAG) == 0x03,
+            licensee,
+            cartridge_type: byte(CARTRIDGE_TYPE),
+            rom_size_code: byte(ROM_SIZE),
+            ram_size_code: byte(RAM_SIZE),
+            destination_code: byte(DESTINATION_CODE),
+            version: byte(VERSION),
+            header_checksum: byte(HEADER_CHECKSUM),
+            // stored big-endian, unlike everything else
+            global_checksum: u16::from_be_bytes([byte(GLOBAL_CHECKSUM), byte(GLOBAL_CHECKSUM + 1)]),
+        }
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    pub fn japanese(&self) -> bool {
+        self.destination_code == 0x00
+    }
+
+    /// In bytes, `None` for an unknown code
+    pub fn rom_size(&self) -> Option<usize> {
+        let bank_pair = 0x8000;
+        match self.rom_size_code {
+            // 32 KiB × (1 << <value>)
+            code @ 0x00..=0x08 => Some(bank_pair << code),
+            0x52 => Some(bank_pair * 36),
+            0x53 => Some(bank_pair * 40),
+            0x54 => Some(bank_pair * 48),
+            _ => None,
+        }
+    }
+
+    /// In bytes, `None` for an unknown code
+    pub fn ram_size(&self) -> Option<usize> {
+        match self.ram_size_code {
+            0x00 => Some(0),
+            // unofficial, but used by a few homebrew games
+            0x01 => Some(0x800),
+            0x02 => Some(RAM_BANK_SIZE),
+            0x03 => Some(4 * RAM_BANK_SIZE),
+            0x04 => Some(16 * RAM_BANK_SIZE),
+            0x05 => Some(8 * RAM_BANK_SIZE),
+            _ => None,
+        }
+    }
+}
+
+/// What the header checksum of `rom` should be
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    CHECKSUMMED.fold(0u8, |checksum, i| {
+        checksum
+            .wrapping_sub(rom.get(i).copied().unwrap_or(0x00))
+            .wrapping_sub(1)
+    })
+}
+
+/// What the global checksum of `rom` should be: the sum of every byte but
+/// the checksum's own two
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    let own = rom
+        .get(GLOBAL_CHECKSUM..=GLOBAL_CHECKSUM + 1)
+        .unwrap_or_default();
+    let sum = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+    };
+    sum(rom).wrapping_sub(sum(own))
+}
+
+// Only CGB era games using the new licensee code have one, and nothing but
+// its shape tells it apart from the end of a 15 character title
+fn manufacturer_code(rom: &[u8], cgb_flag: u8, old_licensee_code: u8) -> Option<String> {
+    if cgb_flag & 0x80 == 0 || old_licensee_code != 0x33 {
+        return None;
+    }
+    let code: String = MANUFACTURER_CODE
+        .map(|i| rom.get(i).copied().unwrap_or(0x00) as char)
+        .collect();
+    code.chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        .then_some(code)
+}
+
+// Titles are ASCII, except for Japanese ones that may use the half-width
+// katakana from the single byte range of Shift-JIS
+fn title_char(byte: u8) -> char {
+    match byte {
+        0x20..=0x7E => byte as char,
+        0xA1..=0xDF => char::from_u32(0xFF61 + (byte - 0xA1) as u32).unwrap(),
+        _ => '?',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_header(title: &[u8], cgb_flag: u8, licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0x00; 0x8000];
+        rom[CGB_FLAG] = cgb_flag;
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[OLD_LICENSEE_CODE] = licensee;
+        rom
+    }
+
+    #[test]
+    fn test_title_layouts() {
+        // 16 characters on DMG games
+        let header = CartridgeHeader::parse(&with_header(b"SUPER MARIOLAND2", 0x00, 0x33));
+        assert_eq!(header.title, "SUPER MARIOLAND2");
+        // 15 characters followed by the CGB flag
+        let header = CartridgeHeader::parse(&with_header(b"LONG CGB TITLE!", 0x80, 0x01));
+        assert_eq!(header.title, "LONG CGB TITLE!");
+        assert_eq!(header.manufacturer_code, None);
+        // 11 characters and a manufacturer code
+        let header = CartridgeHeader::parse(&with_header(b"POKEMON_SLVAAXE", 0x80, 0x33));
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("AAXE"));
+        let header = CartridgeHeader::parse(&with_header(b"ZELDA\0\0\0\0\0\0AZ7E", 0xC0, 0x33));
+        assert_eq!(header.title, "ZELDA");
+        assert!(header.cgb_only());
+    }
+
+    #[test]
+    fn test_title_is_sanitized() {
+        let header = CartridgeHeader::parse(&with_header(b"\xB6\xB0\xCB\xDE\x01GB  ", 0x00, 0x01));
+        assert_eq!(header.title, "\u{FF76}\u{FF70}\u{FF8B}\u{FF9E}?GB");
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let mut rom = with_header(b"TEST", 0x80, 0x33);
+        rom[0x0144..=0x014F].copy_from_slice(&[
+            b'0', b'1', 0x03, 0x1B, 0x05, 0x04, 0x01, 0x33, 0x02, 0x00, 0x12, 0x34,
+        ]);
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.licensee, Licensee::New("01".to_string()));
+        assert!(header.sgb && header.supports_cgb() && !header.cgb_only());
+        assert_eq!(header.cartridge_type, 0x1B);
+        assert_eq!(header.rom_size(), Some(0x100000));
+        assert_eq!(header.ram_size(), Some(16 * RAM_BANK_SIZE));
+        assert!(!header.japanese());
+        assert_eq!(header.version, 0x02);
+        assert_eq!(header.header_checksum, header_checksum(&rom));
+        assert_eq!(header.global_checksum, 0x1234);
+        let sum = rom.iter().map(|&b| b as u16).fold(0u16, u16::wrapping_add);
+        assert_eq!(global_checksum(&rom), sum.wrapping_sub(0x12 + 0x34));
+        // too short to have a header
+        assert_eq!(CartridgeHeader::parse(&[]).cgb_flag, 0x00);
+    }
+}
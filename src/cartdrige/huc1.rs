@@ -0,0 +1,147 @@
+use super::{BankState, Cartdrige, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+// written to 0x0000-0x1FFF, maps the IR port instead of RAM
+const IR_SELECT: u8 = 0x0E;
+// what the IR port reads without light from another Game Boy or a remote
+const IR_DARK: u8 = 0xC0;
+
+/// Hudson's HuC1: banking like an MBC1 in mode 1, up to 1 MiB of ROM and
+/// 32 KiB of RAM, and an infrared LED and sensor in place of the RAM
+/// enable. Nothing is ever seen on the sensor.
+/// https://gbdev.io/pandocs/HuC1.html
+pub struct Huc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    // 0xA000-0xBFFF is the IR port instead of RAM
+    ir_mode: bool,
+    led: bool,
+    // 6 bits, 0 selects 1 like on MBC1
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+impl Huc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            rom,
+            ram: vec![0x00; ram_size],
+            ir_mode: false,
+            led: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    /// Whether the game turned the IR LED on
+    pub fn led(&self) -> bool {
+        self.led
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize % (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize % (self.ram.len() / RAM_BANK_SIZE).max(1)
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Cartdrige for Huc1 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+            0xA000..=0xBFFF if self.ir_mode => return IR_DARK,
+            0xA000..=0xBFFF => {
+                return match self.ram_offset(address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            // there is no RAM enable, anything but the IR select maps RAM
+            0x0000..=0x1FFF => self.ir_mode = value & 0x0F == IR_SELECT,
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x3F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            0xA000..=0xBFFF if self.ir_mode => self.led = value & 0x01 != 0,
+            0xA000..=0xBFFF => {
+                if let Some(offset) = self.ram_offset(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom_bank: self.high_rom_bank() as u16,
+            ram_bank: self.ram_bank() as u8,
+            ram_enabled: !self.ir_mode,
+            mode: self.ir_mode as u8,
+        }
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.rom_bank = (state.rom_bank as u8 & 0x3F).max(1);
+        self.ram_bank = state.ram_bank & 0x03;
+        self.ir_mode = state.mode & 0x01 != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banking_and_ir() {
+        let mut rom = vec![0x00; 64 * ROM_BANK_SIZE];
+        rom[0x3F * ROM_BANK_SIZE] = 0x3F;
+        rom[ROM_BANK_SIZE] = 0x01;
+        let mut huc1 = Huc1::new(rom, 4 * RAM_BANK_SIZE);
+        huc1.set(0x2000, 0xFF);
+        assert_eq!(huc1.read(0x4000), 0x3F);
+        huc1.set(0x2000, 0x00);
+        assert_eq!(huc1.read(0x4000), 0x01);
+        // RAM needs no enabling
+        huc1.set(0x4000, 0x02);
+        huc1.set(0xA000, 0x22);
+        assert_eq!(huc1.ram()[2 * RAM_BANK_SIZE], 0x22);
+        huc1.set(0x0000, 0x0E);
+        assert_eq!(huc1.read(0xA000), IR_DARK);
+        huc1.set(0xA000, 0x01);
+        assert!(huc1.led());
+        assert_eq!(huc1.ram()[2 * RAM_BANK_SIZE], 0x22);
+        let state = huc1.bank_state();
+        huc1.set(0x0000, 0x00);
+        assert_eq!(huc1.read(0xA000), 0x22);
+        huc1.set_bank_state(state);
+        assert_eq!(huc1.read(0xA000), IR_DARK);
+    }
+}
@@ -0,0 +1,324 @@
+use super::{BankState, Cartdrige, NINTENDO_LOGO, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+// first bank of each game on a multicart, but the menu's
+const MULTICART_GAME_BANK: usize = 0x10;
+// the multicarts out there are all 8 Mbit
+const MULTICART_ROM_SIZE: usize = 64 * ROM_BANK_SIZE;
+
+/// Whether `rom` is an MBC1M multicart, several games behind a menu. Their
+/// boards leave bit 4 of the ROM bank register unconnected, and each game
+/// brings its own header, logo included.
+pub fn is_multicart(rom: &[u8]) -> bool {
+    let logo = MULTICART_GAME_BANK * ROM_BANK_SIZE + 0x0104;
+    rom.len() == MULTICART_ROM_SIZE && rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+}
+
+/// MBC1, the most common mapper: up to 2 MiB of ROM and 32 KiB of RAM
+/// https://gbdev.io/pandocs/MBC1.html
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // 5 bit ROM bank register, 0 selects 1
+    bank_low: u8,
+    // 2 bit register, the RAM bank or the upper bits of the ROM bank
+    bank_high: u8,
+    // banking mode 1 applies `bank_high` to 0x0000-0x3FFF and RAM as well
+    advanced: bool,
+    // MBC1M wiring, `bank_high` goes to bits 4-5 of the ROM bank
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            rom,
+            ram: vec![0x00; ram_size],
+            ram_enabled: false,
+            bank_low: 1,
+            bank_high: 0,
+            advanced: false,
+            multicart: false,
+        }
+    }
+
+    /// With the MBC1M wiring, see `is_multicart`
+    pub fn multicart(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            multicart: true,
+            ..Self::new(rom, ram_size)
+        }
+    }
+
+    // bits of the ROM bank below `bank_high`
+    fn low_bits(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    // banks that do not exist mirror the ones that do
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        (self.ram.len() / RAM_BANK_SIZE).max(1)
+    }
+
+    fn rom_bank(&self, bank: u8) -> usize {
+        bank as usize % self.rom_bank_count()
+    }
+
+    // bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> usize {
+        if self.advanced {
+            self.rom_bank(self.bank_high << self.low_bits())
+        } else {
+            0
+        }
+    }
+
+    // bank mapped at 0x4000-0x7FFF
+    fn high_rom_bank(&self) -> usize {
+        // on multicarts the zero check still sees bit 4, so 0x10 maps the
+        // first bank of a game
+        let low = self.bank_low & ((1 << self.low_bits()) - 1);
+        self.rom_bank(self.bank_high << self.low_bits() | low)
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced {
+            self.bank_high as usize % self.ram_bank_count()
+        } else {
+            0
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        // 2 KiB RAM chips repeat over the whole window
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Cartdrige for Mbc1 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => self.low_rom_bank() * ROM_BANK_SIZE + address as usize,
+            0x4000..=0x7FFF => self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+            0xA000..=0xBFFF => {
+                return match self.ram_offset(address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            // only the 5 bits of the register are compared with 0, so banks
+            // 0x20, 0x40 and 0x60 can't be mapped there
+            0x2000..=0x3FFF => self.bank_low = (value & 0x1F).max(1),
+            0x4000..=0x5FFF => self.bank_high = value & 0x03,
+            0x6000..=0x7FFF => self.advanced = value & 0x01 != 0,
+            0xA000..=0xBFFF => {
+                if let Some(offset) = self.ram_offset(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom_bank: self.high_rom_bank() as u16,
+            ram_bank: self.ram_bank() as u8,
+            ram_enabled: self.ram_enabled,
+            mode: self.advanced as u8,
+        }
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.advanced = state.mode & 0x01 != 0;
+        self.ram_enabled = state.ram_enabled;
+        let low_bits = self.low_bits();
+        self.bank_low = match state.rom_bank as u8 & ((1 << low_bits) - 1) {
+            // on multicarts that is the unwired bit 4 set, as 0 maps bank 1
+            0 if self.multicart => 0x10,
+            low => low.max(1),
+        };
+        // both banks are reported modulo the chip sizes, so each holds the
+        // bits of the register that its chip decodes
+        let ram_bits = if self.advanced { state.ram_bank } else { 0 };
+        self.bank_high = ((state.rom_bank >> low_bits) as u8 | ram_bits) & 0x03;
+    }
+
+    // the registers as written, the mapped banks do not always show all
+    // of `bank_high`
+    fn mapper_state(&self) -> Vec<u8> {
+        vec![self.bank_low, self.bank_high]
+    }
+
+    fn set_mapper_state(&mut self, state: &[u8]) {
+        self.bank_low = (state[0] & 0x1F).max(1);
+        self.bank_high = state[1] & 0x03;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each bank starts with its number
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0x00; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_rom_banking() {
+        let mut mbc = Mbc1::new(banked_rom(128), 0);
+        assert_eq!(mbc.read(0x4000), 1);
+        mbc.set(0x2000, 0x05);
+        assert_eq!(mbc.read(0x4000), 5);
+        // 0 selects bank 1, and so do 0x20, 0x40 and 0x60
+        mbc.set(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 1);
+        mbc.set(0x4000, 0x01);
+        assert_eq!(mbc.read(0x4000), 0x21);
+        assert_eq!(mbc.read(0x0000), 0);
+        // mode 1 maps the upper bits at 0x0000 too
+        mbc.set(0x6000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x20);
+        assert_eq!(mbc.bank_state().rom_bank, 0x21);
+        // banks past the end of the ROM wrap around
+        let mut mbc = Mbc1::new(banked_rom(4), 0);
+        mbc.set(0x2000, 0x06);
+        assert_eq!(mbc.read(0x4000), 2);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let mut mbc = Mbc1::new(banked_rom(4), 4 * RAM_BANK_SIZE);
+        mbc.set(0xA000, 0x11);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0xA000, 0x11);
+        // only mode 1 switches RAM banks
+        mbc.set(0x4000, 0x02);
+        assert_eq!(mbc.read(0xA000), 0x11);
+        mbc.set(0x6000, 0x01);
+        assert_eq!(mbc.read(0xA000), 0x00);
+        mbc.set(0xA000, 0x22);
+        assert_eq!(mbc.ram()[2 * RAM_BANK_SIZE], 0x22);
+        mbc.set(0x0000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_multicart() {
+        let mut rom = banked_rom(64);
+        assert!(!is_multicart(&rom));
+        let logo = 0x10 * ROM_BANK_SIZE + 0x0104;
+        rom[logo..logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        assert!(is_multicart(&rom));
+        let mut mbc = Mbc1::multicart(rom, 0);
+        mbc.set(0x2000, 0x03);
+        mbc.set(0x4000, 0x01);
+        assert_eq!(mbc.read(0x4000), 0x13);
+        // bit 4 is not wired, but still counts as not 0
+        mbc.set(0x2000, 0x10);
+        assert_eq!(mbc.read(0x4000), 0x10);
+        let mut restored = Mbc1::multicart(banked_rom(64), 0);
+        restored.set_bank_state(mbc.bank_state());
+        assert_eq!(restored.bank_state(), mbc.bank_state());
+        assert_eq!(restored.read(0x4000), 0x10);
+        mbc.set(0x4000, 0x02);
+        mbc.set(0x2000, 0x05);
+        let mut restored = Mbc1::multicart(banked_rom(64), 0);
+        restored.set_bank_state(mbc.bank_state());
+        assert_eq!(restored.bank_state(), mbc.bank_state());
+        assert_eq!(restored.read(0x4000), 0x25);
+        // mode 1 maps the menu or a game at 0x0000
+        mbc.set(0x6000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x20);
+    }
+
+    #[test]
+    fn test_bank_state_round_trip() {
+        let mut mbc = Mbc1::new(banked_rom(64), 4 * RAM_BANK_SIZE);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x2000, 0x03);
+        mbc.set(0x4000, 0x01);
+        let state = mbc.bank_state();
+        assert_eq!(state.rom_bank, 0x23);
+        let mut restored = Mbc1::new(banked_rom(64), 4 * RAM_BANK_SIZE);
+        restored.set_bank_state(state);
+        assert_eq!(restored.bank_state(), state);
+        assert_eq!(restored.read(0x4000), 0x23);
+        mbc.set(0x6000, 0x01);
+        restored.set_bank_state(mbc.bank_state());
+        assert_eq!(restored.read(0xA000), mbc.read(0xA000));
+        assert_eq!(restored.bank_state().ram_bank, 1);
+    }
+
+    #[test]
+    fn test_bank_state_round_trip_advanced_large_rom() {
+        for ram_size in [0, RAM_BANK_SIZE] {
+            let mut mbc = Mbc1::new(banked_rom(64), ram_size);
+            mbc.set(0x6000, 0x01);
+            mbc.set(0x4000, 0x01);
+            mbc.set(0x2000, 0x02);
+            assert_eq!(mbc.read(0x4000), 0x22);
+            let mut restored = Mbc1::new(banked_rom(64), ram_size);
+            restored.set_bank_state(mbc.bank_state());
+            assert_eq!(restored.bank_state(), mbc.bank_state());
+            assert_eq!(restored.read(0x4000), 0x22);
+            assert_eq!(restored.read(0x0000), 0x20);
+        }
+    }
+
+    #[test]
+    fn test_mapper_state_keeps_the_ram_bank_in_mode_0() {
+        // 256 KiB of ROM, the 2 bit register only shows as a RAM bank
+        let mut mbc = Mbc1::new(banked_rom(16), 4 * RAM_BANK_SIZE);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, 0x02);
+        let mut restored = Mbc1::new(banked_rom(16), 4 * RAM_BANK_SIZE);
+        restored.set_bank_state(mbc.bank_state());
+        restored.set_mapper_state(&mbc.mapper_state());
+        assert_eq!(restored.mapper_state(), mbc.mapper_state());
+        for mbc in [&mut mbc, &mut restored] {
+            mbc.set(0x6000, 0x01);
+            assert_eq!(mbc.bank_state().ram_bank, 2);
+        }
+        restored.set(0xA000, 0x42);
+        assert_eq!(restored.ram()[2 * RAM_BANK_SIZE], 0x42);
+    }
+}
@@ -0,0 +1,125 @@
+use super::{BankState, Cartdrige, ROM_BANK_SIZE};
+
+/// Half-bytes of RAM built into the mapper
+pub const MBC2_RAM_SIZE: usize = 512;
+
+/// MBC2: up to 256 KiB of ROM and 512 half-bytes of RAM inside the mapper
+/// https://gbdev.io/pandocs/MBC2.html
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    // one half-byte per byte, in the low bits
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // 4 bit ROM bank register, 0 selects 1
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Self {
+            rom,
+            ram: vec![0x00; MBC2_RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize % (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    // only the low 9 bits of the address are wired, the RAM repeats over
+    // the whole window
+    fn ram_offset(address: u16) -> usize {
+        (address & 0x01FF) as usize
+    }
+}
+
+impl Cartdrige for Mbc2 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+            // the upper half-byte is not connected
+            0xA000..=0xBFFF if self.ram_enabled => {
+                return self.ram[Self::ram_offset(address)] | 0xF0
+            }
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            // bit 8 of the address selects the register
+            0x0000..=0x3FFF if address & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => self.rom_bank = (value & 0x0F).max(1),
+            0xA000..=0xBFFF if self.ram_enabled => {
+                self.ram[Self::ram_offset(address)] = value & 0x0F
+            }
+            _ => {}
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom_bank: self.high_rom_bank() as u16,
+            ram_bank: 0,
+            ram_enabled: self.ram_enabled,
+            mode: 0,
+        }
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.rom_bank = (state.rom_bank as u8 & 0x0F).max(1);
+        self.ram_enabled = state.ram_enabled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_selected_by_address_bit_8() {
+        let mut rom = vec![0x00; 16 * ROM_BANK_SIZE];
+        rom[3 * ROM_BANK_SIZE] = 0x33;
+        let mut mbc = Mbc2::new(rom);
+        // bit 8 clear, the RAM enable register whatever the range
+        mbc.set(0x2000, 0x03);
+        assert_eq!(mbc.bank_state().rom_bank, 1);
+        mbc.set(0x2100, 0x03);
+        assert_eq!(mbc.read(0x4000), 0x33);
+        mbc.set(0x0100, 0x00);
+        assert_eq!(mbc.bank_state().rom_bank, 1);
+        assert!(!mbc.bank_state().ram_enabled);
+        mbc.set(0x3E0A, 0x0A);
+        assert!(mbc.bank_state().ram_enabled);
+    }
+
+    #[test]
+    fn test_half_byte_ram() {
+        let mut mbc = Mbc2::new(vec![0x00; 2 * ROM_BANK_SIZE]);
+        mbc.set(0xA000, 0x05);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0xA000, 0x35);
+        assert_eq!(mbc.read(0xA000), 0xF5);
+        // repeated every 512 bytes
+        assert_eq!(mbc.read(0xA200), 0xF5);
+        assert_eq!(mbc.read(0xBE00), 0xF5);
+        assert_eq!(mbc.ram()[0], 0x05);
+    }
+}
@@ -0,0 +1,448 @@
+use super::{BankState, Cartdrige, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+// the clock crystal runs at 32768 Hz, a tick every 128 dots
+const DOTS_PER_SECOND: u32 = 4_194_304;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// the day counter has 9 bits
+const DAYS: u64 = 512;
+
+// registers mapped at 0xA000-0xBFFF by writing their number to 0x4000
+const RTC_S: u8 = 0x08;
+const RTC_M: u8 = 0x09;
+const RTC_H: u8 = 0x0A;
+const RTC_DL: u8 = 0x0B;
+const RTC_DH: u8 = 0x0C;
+
+// bits of RTC_DH besides the top bit of the day counter
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+
+/// What most emulators append to the .sav file of MBC3 games with a clock:
+/// the five registers then the five latched ones as 32-bit words, and the
+/// Unix time the file was written at as a 64-bit one, all little endian
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+/// Real time clock of the MBC3. It counts emulated time while the emulator
+/// runs and catches up with the host clock when a save is loaded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Rtc {
+    // dots since the last whole second
+    sub_second: u32,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+    // what the game reads, copied from the counters on a latch
+    latched: [u8; 5],
+}
+
+impl Rtc {
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            (self.days >> 8) as u8 | (self.halted as u8) << 6 | (self.day_carry as u8) << 7,
+        ]
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            // writing the seconds restarts the current one
+            RTC_S => {
+                self.seconds = value & 0x3F;
+                self.sub_second = 0;
+            }
+            RTC_M => self.minutes = value & 0x3F,
+            RTC_H => self.hours = value & 0x1F,
+            RTC_DL => self.days = self.days & 0x100 | value as u16,
+            RTC_DH => {
+                self.days = ((value & 0x01) as u16) << 8 | self.days & 0xFF;
+                self.halted = value & HALT != 0;
+                self.day_carry = value & DAY_CARRY != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, dots: u32) {
+        if self.halted {
+            return;
+        }
+        self.sub_second += dots;
+        while self.sub_second >= DOTS_PER_SECOND {
+            self.sub_second -= DOTS_PER_SECOND;
+            self.next_second();
+        }
+    }
+
+    // Counters set past their range keep counting up to what their bits
+    // hold and wrap to 0 without carrying over
+    fn next_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days = (self.days + 1) & 0x1FF;
+        if self.days == 0 {
+            self.day_carry = true;
+        }
+    }
+
+    // Catches up with `seconds` that went by at once, counters out of range
+    // are brought back into it
+    fn advance(&mut self, seconds: u64) {
+        if self.halted || seconds == 0 {
+            return;
+        }
+        let total = self.days as u64 * SECONDS_PER_DAY
+            + (self.hours % 24) as u64 * 3600
+            + (self.minutes % 60) as u64 * 60
+            + (self.seconds % 60) as u64
+            + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / SECONDS_PER_DAY;
+        self.days = (days % DAYS) as u16;
+        self.day_carry |= days >= DAYS;
+    }
+
+    fn footer(&self, now: u64) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for register in self.registers().into_iter().chain(self.latched) {
+            footer.extend((register as u32).to_le_bytes());
+        }
+        footer.extend(now.to_le_bytes());
+        footer
+    }
+
+    fn load_footer(&mut self, footer: &[u8], now: u64) {
+        let word = |i: usize| footer[i * 4];
+        for (register, i) in (RTC_S..=RTC_DH).zip(0..5) {
+            self.write(register, word(i));
+        }
+        for (i, latched) in self.latched.iter_mut().enumerate() {
+            *latched = word(5 + i);
+        }
+        let saved = u64::from_le_bytes(footer[40..48].try_into().unwrap());
+        self.sub_second = 0;
+        // a clock set back on the host does not turn the game's back
+        self.advance(now.saturating_sub(saved));
+    }
+
+    // the counters, then the latched registers
+    fn state(&self) -> Vec<u8> {
+        let mut state = self.sub_second.to_le_bytes().to_vec();
+        state.extend(self.registers());
+        state.extend(self.latched);
+        state
+    }
+
+    fn set_state(&mut self, state: &[u8]) {
+        for (register, &value) in (RTC_S..=RTC_DH).zip(&state[4..9]) {
+            self.write(register, value);
+        }
+        self.sub_second = u32::from_le_bytes(state[..4].try_into().unwrap()) % DOTS_PER_SECOND;
+        self.latched.copy_from_slice(&state[9..14]);
+    }
+}
+
+/// MBC3: up to 2 MiB of ROM, 32 KiB of RAM and, on some cartridges, a real
+/// time clock mapped in place of the RAM
+/// https://gbdev.io/pandocs/MBC3.html
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    // RAM and clock registers alike
+    ram_enabled: bool,
+    // 7 bits, 0 selects 1
+    rom_bank: u8,
+    // a RAM bank, or one of the clock registers from 0x08
+    ram_bank: u8,
+    // the clock latches when 0x01 follows 0x00
+    latch_armed: bool,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Self {
+        Self {
+            rom,
+            ram: vec![0x00; ram_size],
+            rtc: has_rtc.then(Rtc::default),
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch_armed: false,
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize % (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize % (self.ram.len() / RAM_BANK_SIZE).max(1)
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() || self.ram_bank >= RTC_S {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+
+    // the clock register mapped at 0xA000-0xBFFF, if any
+    fn rtc_register(&self) -> Option<u8> {
+        match self.ram_bank {
+            RTC_S..=RTC_DH if self.ram_enabled && self.rtc.is_some() => Some(self.ram_bank),
+            _ => None,
+        }
+    }
+}
+
+impl Cartdrige for Mbc3 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+            0xA000..=0xBFFF => {
+                if let (Some(register), Some(rtc)) = (self.rtc_register(), &self.rtc) {
+                    return rtc.latched[(register - RTC_S) as usize];
+                }
+                return match self.ram_offset(address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                };
+            }
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = self
+                    .rtc
+                    .as_mut()
+                    .filter(|_| self.latch_armed && value == 0x01)
+                {
+                    rtc.latched = rtc.registers();
+                }
+                self.latch_armed = value == 0x00;
+            }
+            0xA000..=0xBFFF => {
+                if let Some(register) = self.rtc_register() {
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.write(register, value);
+                    }
+                } else if let Some(offset) = self.ram_offset(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom_bank: self.high_rom_bank() as u16,
+            // the clock registers are reported as is
+            ram_bank: if self.ram_bank >= RTC_S {
+                self.ram_bank
+            } else {
+                self.ram_bank() as u8
+            },
+            ram_enabled: self.ram_enabled,
+            mode: 0,
+        }
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.rom_bank = (state.rom_bank as u8 & 0x7F).max(1);
+        self.ram_bank = state.ram_bank & 0x0F;
+        self.ram_enabled = state.ram_enabled;
+    }
+
+    fn mapper_state(&self) -> Vec<u8> {
+        let mut state = vec![self.latch_armed as u8];
+        if let Some(rtc) = &self.rtc {
+            state.extend(rtc.state());
+        }
+        state
+    }
+
+    fn set_mapper_state(&mut self, state: &[u8]) {
+        self.latch_armed = state[0] != 0;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_state(&state[1..]);
+        }
+    }
+
+    fn tick(&mut self, dots: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(dots);
+        }
+    }
+
+    fn battery_footer(&self, now: u64) -> Vec<u8> {
+        self.rtc
+            .as_ref()
+            .map_or_else(Vec::new, |rtc| rtc.footer(now))
+    }
+
+    fn load_battery_footer(&mut self, footer: &[u8], now: u64) {
+        // some emulators write a 44 byte footer with a 32-bit time, that
+        // one starts the clock afresh
+        if let Some(rtc) = self
+            .rtc
+            .as_mut()
+            .filter(|_| footer.len() >= RTC_FOOTER_SIZE)
+        {
+            rtc.load_footer(footer, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(mbc: &mut Mbc3) {
+        mbc.set(0x6000, 0x00);
+        mbc.set(0x6000, 0x01);
+    }
+
+    // the latched register `register`
+    fn read_rtc(mbc: &mut Mbc3, register: u8) -> u8 {
+        mbc.set(0x4000, register);
+        mbc.read(0xA000)
+    }
+
+    #[test]
+    fn test_rom_and_ram_banking() {
+        let mut rom = vec![0x00; 128 * ROM_BANK_SIZE];
+        rom[0x7F * ROM_BANK_SIZE] = 0x7F;
+        rom[ROM_BANK_SIZE] = 0x01;
+        let mut mbc = Mbc3::new(rom, 4 * RAM_BANK_SIZE, false);
+        mbc.set(0x2000, 0x7F);
+        assert_eq!(mbc.read(0x4000), 0x7F);
+        mbc.set(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 0x01);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, 0x03);
+        mbc.set(0xA000, 0x33);
+        assert_eq!(mbc.ram()[3 * RAM_BANK_SIZE], 0x33);
+        // no clock to map
+        mbc.set(0x4000, RTC_S);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_rtc_counts_and_latches() {
+        let mut mbc = Mbc3::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, RTC_H);
+        mbc.set(0xA000, 23);
+        mbc.set(0x4000, RTC_M);
+        mbc.set(0xA000, 59);
+        mbc.set(0x4000, RTC_S);
+        mbc.set(0xA000, 59);
+        mbc.tick(DOTS_PER_SECOND - 1);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, RTC_S), 59);
+        mbc.tick(1);
+        // still the latched time until the next latch
+        assert_eq!(read_rtc(&mut mbc, RTC_S), 59);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, RTC_S), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_H), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_DL), 1);
+        // halted clocks don't count
+        mbc.set(0x4000, RTC_DH);
+        mbc.set(0xA000, HALT);
+        mbc.tick(10 * DOTS_PER_SECOND);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, RTC_S), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_DH), HALT);
+    }
+
+    #[test]
+    fn test_rtc_footer_catches_up() {
+        let mut mbc = Mbc3::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, RTC_DL);
+        mbc.set(0xA000, 0xFF);
+        mbc.set(0x4000, RTC_DH);
+        mbc.set(0xA000, 0x01);
+        let footer = mbc.battery_footer(1_000_000);
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+        let mut restored = Mbc3::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        restored.set(0x0000, 0x0A);
+        // a day and a minute later the 9-bit day counter overflows
+        restored.load_battery_footer(&footer, 1_000_000 + SECONDS_PER_DAY + 60);
+        latch(&mut restored);
+        assert_eq!(read_rtc(&mut restored, RTC_M), 1);
+        assert_eq!(read_rtc(&mut restored, RTC_DL), 0);
+        assert_eq!(read_rtc(&mut restored, RTC_DH), DAY_CARRY);
+        // without a clock there is nothing to save
+        assert!(Mbc3::new(vec![], 0, false).battery_footer(0).is_empty());
+    }
+
+    #[test]
+    fn test_rtc_mapper_state() {
+        let mut mbc = Mbc3::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, RTC_M);
+        mbc.set(0xA000, 42);
+        mbc.tick(3 * DOTS_PER_SECOND / 2);
+        latch(&mut mbc);
+        mbc.set(0x6000, 0x00);
+        let state = mbc.mapper_state();
+
+        let mut restored = Mbc3::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        restored.set_bank_state(mbc.bank_state());
+        restored.set_mapper_state(&state);
+        assert_eq!(restored.mapper_state(), state);
+        // the armed latch and the half second carry over
+        restored.set(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut restored, RTC_S), 1);
+        restored.tick(DOTS_PER_SECOND / 2);
+        latch(&mut restored);
+        assert_eq!(read_rtc(&mut restored, RTC_S), 2);
+        assert_eq!(read_rtc(&mut restored, RTC_M), 42);
+    }
+}
@@ -0,0 +1,181 @@
+use super::{BankState, Cartdrige, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+/// MBC5: up to 8 MiB of ROM and 128 KiB of RAM, and on some cartridges a
+/// rumble motor driven by a bit of the RAM bank register
+/// https://gbdev.io/pandocs/MBC5.html
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // 9 bits, bank 0 can be mapped at 0x4000 too
+    rom_bank: u16,
+    ram_bank: u8,
+    // whether bit 3 of the RAM bank register drives a motor
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Self {
+        Self {
+            rom,
+            ram: vec![0x00; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize % (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn ram_bank(&self) -> usize {
+        self.ram_bank as usize % (self.ram.len() / RAM_BANK_SIZE).max(1)
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Cartdrige for Mbc5 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+            0xA000..=0xBFFF => {
+                return match self.ram_offset(address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = self.rom_bank & 0x100 | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = ((value & 0x01) as u16) << 8 | self.rom_bank & 0xFF,
+            // the motor takes bit 3 away from the RAM bank
+            0x4000..=0x5FFF if self.has_rumble => {
+                self.rumble = value & 0x08 != 0;
+                self.ram_bank = value & 0x07;
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0xA000..=0xBFFF => {
+                if let Some(offset) = self.ram_offset(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom_bank: self.high_rom_bank() as u16,
+            ram_bank: self.ram_bank() as u8,
+            ram_enabled: self.ram_enabled,
+            mode: 0,
+        }
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.rom_bank = state.rom_bank & 0x1FF;
+        self.ram_bank = state.ram_bank & 0x0F;
+        self.ram_enabled = state.ram_enabled;
+    }
+
+    fn mapper_state(&self) -> Vec<u8> {
+        vec![self.rumble as u8]
+    }
+
+    fn set_mapper_state(&mut self, state: &[u8]) {
+        self.rumble = self.has_rumble && state[0] != 0;
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nine_bit_rom_bank() {
+        let mut rom = vec![0x00; 512 * ROM_BANK_SIZE];
+        rom[0x123 * ROM_BANK_SIZE] = 0x23;
+        rom[0x100 * ROM_BANK_SIZE] = 0x01;
+        let mut mbc = Mbc5::new(rom, 0, false);
+        mbc.set(0x2000, 0x23);
+        mbc.set(0x3000, 0x01);
+        assert_eq!(mbc.read(0x4000), 0x23);
+        assert_eq!(mbc.bank_state().rom_bank, 0x123);
+        // no bank 0 quirk
+        mbc.set(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 0x01);
+        mbc.set(0x3000, 0x00);
+        assert_eq!(mbc.bank_state().rom_bank, 0);
+    }
+
+    #[test]
+    fn test_ram_banks_and_rumble() {
+        let mut mbc = Mbc5::new(vec![0x00; 2 * ROM_BANK_SIZE], 16 * RAM_BANK_SIZE, false);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, 0x0F);
+        mbc.set(0xA000, 0x42);
+        assert_eq!(mbc.ram()[15 * RAM_BANK_SIZE], 0x42);
+        assert!(!mbc.rumble());
+
+        let mut mbc = Mbc5::new(vec![0x00; 2 * ROM_BANK_SIZE], 8 * RAM_BANK_SIZE, true);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, 0x0B);
+        assert!(mbc.rumble());
+        mbc.set(0xA000, 0x42);
+        assert_eq!(mbc.ram()[3 * RAM_BANK_SIZE], 0x42);
+        mbc.set(0x4000, 0x03);
+        assert!(!mbc.rumble());
+    }
+
+    #[test]
+    fn test_rumble_mapper_state() {
+        let mut mbc = Mbc5::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        mbc.set(0x4000, 0x08);
+        let state = mbc.mapper_state();
+        assert_eq!(state, vec![1]);
+
+        let mut restored = Mbc5::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, true);
+        restored.set_mapper_state(&state);
+        assert!(restored.rumble());
+        restored.set_mapper_state(&[0]);
+        assert!(!restored.rumble());
+        // a cartridge without a motor never reports one
+        let mut plain = Mbc5::new(vec![0x00; 2 * ROM_BANK_SIZE], 0, false);
+        plain.set_mapper_state(&state);
+        assert!(!plain.rumble());
+    }
+}
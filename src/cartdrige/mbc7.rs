@@ -0,0 +1,478 @@
+use super::{BankState, Cartdrige, ROM_BANK_SIZE};
+
+/// Size of the 93LC56 EEPROM, 128 words of 16 bits
+pub const EEPROM_SIZE: usize = 256;
+
+// accelerometer readings when level, and how much 1 g moves them
+const ACCELEROMETER_CENTER: u16 = 0x81D0;
+const ACCELEROMETER_G: f32 = 0x70 as f32;
+
+// bits of the EEPROM register
+const CS: u8 = 0x80;
+const CLK: u8 = 0x40;
+const DI: u8 = 0x02;
+const DO: u8 = 0x01;
+
+// start bit, 2 bits of opcode and 8 of address
+const COMMAND_BITS: u8 = 10;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum EepromState {
+    // waiting for a start bit
+    Idle,
+    Command,
+    // shifting out the word, MSB first
+    Read { word: u16 },
+    // shifting in the word for `address`, or for all of them
+    Write { address: Option<u8> },
+}
+
+/// The serial EEPROM of the MBC7, driven bit by bit through its register
+/// http://www.ww1.microchip.com/downloads/en/devicedoc/21794f.pdf
+#[derive(Clone, Debug)]
+struct Eeprom {
+    // words stored little endian, so the .sav is the plain contents
+    data: Vec<u8>,
+    state: EepromState,
+    // bits shifted in or out in the current state
+    shift: u16,
+    bits: u8,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    output: bool,
+    write_enabled: bool,
+}
+
+impl Eeprom {
+    fn new() -> Self {
+        Self {
+            data: vec![0xFF; EEPROM_SIZE],
+            state: EepromState::Idle,
+            shift: 0,
+            bits: 0,
+            cs: false,
+            clk: false,
+            di: false,
+            // ready
+            output: true,
+            write_enabled: false,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        let bit = |set: bool, bit: u8| if set { bit } else { 0 };
+        bit(self.cs, CS) | bit(self.clk, CLK) | bit(self.di, DI) | bit(self.output, DO)
+    }
+
+    fn word(&self, address: u8) -> u16 {
+        let offset = address as usize * 2;
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    fn set_word(&mut self, address: u8, word: u16) {
+        if self.write_enabled {
+            let offset = address as usize * 2;
+            self.data[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        let rising = !self.clk && value & CLK != 0;
+        self.cs = value & CS != 0;
+        self.clk = value & CLK != 0;
+        self.di = value & DI != 0;
+        if !self.cs {
+            self.state = EepromState::Idle;
+            return;
+        }
+        if rising {
+            self.clock();
+        }
+    }
+
+    fn start(&mut self, state: EepromState) {
+        self.state = state;
+        self.shift = 0;
+        self.bits = 0;
+    }
+
+    fn clock(&mut self) {
+        match self.state {
+            EepromState::Idle if self.di => self.start(EepromState::Command),
+            EepromState::Idle => {}
+            EepromState::Command => {
+                self.shift = self.shift << 1 | self.di as u16;
+                self.bits += 1;
+                if self.bits == COMMAND_BITS {
+                    self.command((self.shift >> 8) as u8, self.shift as u8);
+                }
+            }
+            EepromState::Read { word } => {
+                self.output = word << self.bits & 0x8000 != 0;
+                self.bits += 1;
+                if self.bits == 16 {
+                    self.start(EepromState::Idle);
+                }
+            }
+            EepromState::Write { address } => {
+                self.shift = self.shift << 1 | self.di as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    match address {
+                        Some(address) => self.set_word(address, self.shift),
+                        None => (0..128).for_each(|address| self.set_word(address, self.shift)),
+                    }
+                    self.output = true;
+                    self.start(EepromState::Idle);
+                }
+            }
+        }
+    }
+
+    // the state and its argument, the shift register, then the pins and the
+    // write enable
+    fn state(&self) -> [u8; 7] {
+        let (tag, argument) = match self.state {
+            EepromState::Idle => (0, 0),
+            EepromState::Command => (1, 0),
+            EepromState::Read { word } => (2, word),
+            EepromState::Write {
+                address: Some(address),
+            } => (3, address as u16),
+            EepromState::Write { address: None } => (4, 0),
+        };
+        let [argument_low, argument_high] = argument.to_le_bytes();
+        let [shift_low, shift_high] = self.shift.to_le_bytes();
+        let flags = [self.cs, self.clk, self.di, self.output, self.write_enabled]
+            .iter()
+            .enumerate()
+            .fold(0, |flags, (i, &set)| flags | (set as u8) << i);
+        [
+            tag,
+            argument_low,
+            argument_high,
+            shift_low,
+            shift_high,
+            self.bits,
+            flags,
+        ]
+    }
+
+    fn set_state(&mut self, state: &[u8]) {
+        let argument = u16::from_le_bytes([state[1], state[2]]);
+        // a full count would have ended the state already
+        let (restored, bits) = match state[0] {
+            1 => (EepromState::Command, COMMAND_BITS - 1),
+            2 => (EepromState::Read { word: argument }, 15),
+            3 => (
+                EepromState::Write {
+                    address: Some(argument as u8 & 0x7F),
+                },
+                15,
+            ),
+            4 => (EepromState::Write { address: None }, 15),
+            _ => (EepromState::Idle, 0),
+        };
+        self.state = restored;
+        self.shift = u16::from_le_bytes([state[3], state[4]]);
+        self.bits = state[5].min(bits);
+        let flag = |i: usize| state[6] >> i & 1 != 0;
+        self.cs = flag(0);
+        self.clk = flag(1);
+        self.di = flag(2);
+        self.output = flag(3);
+        self.write_enabled = flag(4);
+    }
+
+    // the top bit of the address is not used with 128 words
+    fn command(&mut self, opcode: u8, address: u8) {
+        let word_address = address & 0x7F;
+        self.start(EepromState::Idle);
+        match opcode {
+            // READ, a dummy 0 comes out before the word
+            0b10 => {
+                self.output = false;
+                self.start(EepromState::Read {
+                    word: self.word(word_address),
+                });
+            }
+            0b01 => self.start(EepromState::Write {
+                address: Some(word_address),
+            }),
+            // ERASE
+            0b11 => {
+                self.set_word(word_address, 0xFFFF);
+                self.output = true;
+            }
+            // the rest are told apart by the top address bits
+            _ => match address >> 6 {
+                // EWEN, EWDS
+                0b11 => self.write_enabled = true,
+                0b00 => self.write_enabled = false,
+                // ERAL
+                0b10 => {
+                    (0..128).for_each(|address| self.set_word(address, 0xFFFF));
+                    self.output = true;
+                }
+                // WRAL
+                _ => self.start(EepromState::Write { address: None }),
+            },
+        }
+    }
+}
+
+/// MBC7, from Kirby Tilt 'n' Tumble: up to 2 MiB of ROM, a two-axis
+/// accelerometer, and an EEPROM instead of RAM, all behind a couple of
+/// registers at 0xA000-0xA0FF
+/// https://gbdev.io/pandocs/MBC7.html
+pub struct Mbc7 {
+    rom: Vec<u8>,
+    eeprom: Eeprom,
+    // both have to be enabled for the registers to show
+    ram_enabled: bool,
+    ram_enabled2: bool,
+    rom_bank: u8,
+    // in g, set by the frontend
+    tilt: (f32, f32),
+    // readings latched by the game
+    x: u16,
+    y: u16,
+    // a latch only happens after an erase
+    latch_erased: bool,
+}
+
+impl Mbc7 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Self {
+            rom,
+            eeprom: Eeprom::new(),
+            ram_enabled: false,
+            ram_enabled2: false,
+            rom_bank: 1,
+            tilt: (0.0, 0.0),
+            x: ACCELEROMETER_CENTER,
+            y: ACCELEROMETER_CENTER,
+            latch_erased: false,
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        self.rom_bank as usize % (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn registers_enabled(&self) -> bool {
+        self.ram_enabled && self.ram_enabled2
+    }
+
+    fn reading(tilt: f32) -> u16 {
+        (ACCELEROMETER_CENTER as f32 + tilt * ACCELEROMETER_G) as u16
+    }
+}
+
+impl Cartdrige for Mbc7 {
+    fn read(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize,
+            0xA000..=0xA0FF if self.registers_enabled() => {
+                return match address >> 4 & 0x0F {
+                    0x2 => self.x as u8,
+                    0x3 => (self.x >> 8) as u8,
+                    0x4 => self.y as u8,
+                    0x5 => (self.y >> 8) as u8,
+                    0x6 => 0x00,
+                    0x8 => self.eeprom.read(),
+                    _ => 0xFF,
+                }
+            }
+            _ => return 0xFF,
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_enabled2 = value == 0x40,
+            0xA000..=0xA0FF if self.registers_enabled() => match address >> 4 & 0x0F {
+                0x0 if value == 0x55 => {
+                    self.x = ACCELEROMETER_CENTER;
+                    self.y = ACCELEROMETER_CENTER;
+                    self.latch_erased = true;
+                }
+                0x1 if value == 0xAA && self.latch_erased => {
+                    self.x = Self::reading(self.tilt.0);
+                    self.y = Self::reading(self.tilt.1);
+                    self.latch_erased = false;
+                }
+                0x8 => self.eeprom.write(value),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.eeprom.data
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.eeprom.data
+    }
+
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom_bank: self.high_rom_bank() as u16,
+            ram_bank: 0,
+            ram_enabled: self.registers_enabled(),
+            mode: 0,
+        }
+    }
+
+    fn set_bank_state(&mut self, state: BankState) {
+        self.rom_bank = state.rom_bank as u8 & 0x7F;
+        self.ram_enabled = state.ram_enabled;
+        self.ram_enabled2 = state.ram_enabled;
+    }
+
+    // the EEPROM, then the latched readings and the enables
+    fn mapper_state(&self) -> Vec<u8> {
+        let mut state = self.eeprom.state().to_vec();
+        state.extend(self.x.to_le_bytes());
+        state.extend(self.y.to_le_bytes());
+        let flags = [self.latch_erased, self.ram_enabled, self.ram_enabled2];
+        let flags = flags.iter().enumerate();
+        state.push(flags.fold(0, |flags, (i, &set)| flags | (set as u8) << i));
+        state
+    }
+
+    fn set_mapper_state(&mut self, state: &[u8]) {
+        self.eeprom.set_state(&state[..7]);
+        self.x = u16::from_le_bytes([state[7], state[8]]);
+        self.y = u16::from_le_bytes([state[9], state[10]]);
+        self.latch_erased = state[11] & 0x01 != 0;
+        self.ram_enabled = state[11] & 0x02 != 0;
+        self.ram_enabled2 = state[11] & 0x04 != 0;
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EEPROM: u16 = 0xA080;
+
+    fn enabled() -> Mbc7 {
+        let mut mbc = Mbc7::new(vec![0x00; 2 * ROM_BANK_SIZE]);
+        mbc.set(0x0000, 0x0A);
+        mbc.set(0x4000, 0x40);
+        mbc
+    }
+
+    // clocks `count` bits of `value` into the EEPROM, MSB first
+    fn send(mbc: &mut Mbc7, value: u32, count: u32) {
+        for bit in (0..count).rev() {
+            let di = if value >> bit & 1 != 0 { DI } else { 0 };
+            mbc.set(EEPROM, CS | di);
+            mbc.set(EEPROM, CS | CLK | di);
+        }
+    }
+
+    // a start bit, then the opcode and the address
+    fn command(mbc: &mut Mbc7, opcode: u8, address: u8) {
+        send(mbc, 1 << 10 | (opcode as u32) << 8 | address as u32, 11);
+    }
+
+    fn receive(mbc: &mut Mbc7) -> u16 {
+        (0..16).fold(0, |word, _| {
+            mbc.set(EEPROM, CS);
+            mbc.set(EEPROM, CS | CLK);
+            word << 1 | (mbc.read(EEPROM) & DO) as u16
+        })
+    }
+
+    #[test]
+    fn test_accelerometer() {
+        let mut mbc = enabled();
+        mbc.set_tilt(0.5, -2.0);
+        // no latch without an erase first
+        mbc.set(0xA010, 0xAA);
+        assert_eq!(
+            mbc.read(0xA020) as u16 | (mbc.read(0xA030) as u16) << 8,
+            0x81D0
+        );
+        mbc.set(0xA000, 0x55);
+        mbc.set(0xA010, 0xAA);
+        assert_eq!(
+            mbc.read(0xA020) as u16 | (mbc.read(0xA030) as u16) << 8,
+            0x8208
+        );
+        // clamped to 1 g
+        assert_eq!(
+            mbc.read(0xA040) as u16 | (mbc.read(0xA050) as u16) << 8,
+            0x8160
+        );
+        mbc.set(0x4000, 0x00);
+        assert_eq!(mbc.read(0xA020), 0xFF);
+    }
+
+    #[test]
+    fn test_eeprom_protocol() {
+        let mut mbc = enabled();
+        // WRITE is ignored until EWEN
+        command(&mut mbc, 0b01, 0b00000101);
+        send(&mut mbc, 0x1234, 16);
+        mbc.set(EEPROM, 0x00);
+        assert_eq!(mbc.ram()[10..12], [0xFF, 0xFF]);
+        command(&mut mbc, 0b00, 0b11000000);
+        mbc.set(EEPROM, 0x00);
+        command(&mut mbc, 0b01, 0b00000101);
+        send(&mut mbc, 0x1234, 16);
+        mbc.set(EEPROM, 0x00);
+        assert_eq!(mbc.ram()[10..12], [0x34, 0x12]);
+        assert_eq!(mbc.read(EEPROM) & DO, DO);
+        // READ
+        command(&mut mbc, 0b10, 0b00000101);
+        assert_eq!(mbc.read(EEPROM) & DO, 0);
+        assert_eq!(receive(&mut mbc), 0x1234);
+        mbc.set(EEPROM, 0x00);
+        // ERAL
+        command(&mut mbc, 0b00, 0b10000000);
+        mbc.set(EEPROM, 0x00);
+        assert!(mbc.ram().iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_mapper_state() {
+        let mut mbc = enabled();
+        mbc.set_tilt(0.5, 0.0);
+        mbc.set(0xA000, 0x55);
+        command(&mut mbc, 0b00, 0b11000000);
+        mbc.set(EEPROM, 0x00);
+        // halfway through a WRITE
+        command(&mut mbc, 0b01, 0b00000011);
+        send(&mut mbc, 0xAB, 8);
+        let state = mbc.mapper_state();
+
+        let mut restored = Mbc7::new(vec![0x00; 2 * ROM_BANK_SIZE]);
+        restored.set_mapper_state(&state);
+        assert_eq!(restored.mapper_state(), state);
+        restored.set_tilt(0.5, 0.0);
+        // the erase before the rollback still allows a latch
+        restored.set(0xA010, 0xAA);
+        assert_eq!(restored.read(0xA020), 0x08);
+        send(&mut restored, 0xCD, 8);
+        restored.set(EEPROM, 0x00);
+        assert_eq!(restored.ram()[6..8], [0xCD, 0xAB]);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::Path;
+
+use log::info;
+
+use super::{archive, builtin, check_rom, Cartdrige, CartridgeError, CartridgeHeader, Loaded};
+
+/// Makes the cartridge for a ROM a mapper was registered for
+pub type MapperFactory = Box<
+    dyn Fn(Vec<u8>, &CartridgeHeader) -> Result<Box<dyn Cartdrige>, CartridgeError> + Send + Sync,
+>;
+
+/// Tells whether a mapper handles a ROM, from its header and contents
+pub type MapperDetector = Box<dyn Fn(&CartridgeHeader, &[u8]) -> bool + Send + Sync>;
+
+/// Mappers supported on top of the built-in ones, for exotic or homebrew
+/// boards. They are tried in the order they were registered and before the
+/// built-in ones, so they can also replace those.
+#[derive(Default)]
+pub struct MapperRegistry {
+    mappers: Vec<(MapperDetector, MapperFactory)>,
+}
+
+impl MapperRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `factory` for the ROMs with `cartridge_type` in their header
+    pub fn register_type(&mut self, cartridge_type: u8, factory: MapperFactory) {
+        self.register(
+            Box::new(move |header, _| header.cartridge_type == cartridge_type),
+            factory,
+        );
+    }
+
+    /// Uses `factory` for the ROMs `detect` accepts
+    pub fn register(&mut self, detect: MapperDetector, factory: MapperFactory) {
+        self.mappers.push((detect, factory));
+    }
+
+    /// Same as `cartdrige::load`, with the registered mappers
+    pub fn load(&self, path: &Path, strict: bool) -> Result<Box<dyn Cartdrige>, CartridgeError> {
+        let rom = archive::extract(fs::read(path)?).map_err(CartridgeError::InvalidArchive)?;
+        self.from_rom(rom, strict)
+    }
+
+    /// Same as `cartdrige::from_rom`, with the registered mappers
+    pub fn from_rom(
+        &self,
+        mut rom: Vec<u8>,
+        strict: bool,
+    ) -> Result<Box<dyn Cartdrige>, CartridgeError> {
+        let header = check_rom(&mut rom, strict)?;
+        let factory = self
+            .mappers
+            .iter()
+            .find(|(detect, _)| detect(&header, &rom))
+            .map(|(_, factory)| factory);
+        let cartdrige = match factory {
+            Some(factory) => factory(rom, &header)?,
+            None => builtin(rom, &header)?,
+        };
+        let res = Loaded { cartdrige, header };
+        res.ensure_nintendo_logo()?;
+        res.ensure_header_checksum()?;
+        info!("ROM title: {}", res.header.title);
+        Ok(Box::new(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartdrige::{header_checksum, tests::valid_rom};
+
+    // the ROM of a board that maps bank 1 upside down
+    struct Mirrored(Vec<u8>);
+
+    impl Cartdrige for Mirrored {
+        fn read(&self, address: u16) -> u8 {
+            match address {
+                0x4000..=0x7FFF => self.0[0x7FFF - (address - 0x4000) as usize],
+                _ => self.0[address as usize],
+            }
+        }
+
+        fn set(&mut self, _address: u16, _value: u8) {}
+
+        fn rom(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_registered_mappers() {
+        let mut rom = valid_rom(0xFC);
+        rom[0x4000] = 0x42;
+        let mut registry = MapperRegistry::new();
+        assert!(matches!(
+            registry.from_rom(rom.clone(), false),
+            Err(CartridgeError::UnsupportedMapper(0xFC))
+        ));
+        registry.register_type(0xFC, Box::new(|rom, _| Ok(Box::new(Mirrored(rom)))));
+        let cartdrige = registry.from_rom(rom, false).unwrap();
+        assert_eq!(cartdrige.read(0x7FFF), 0x42);
+
+        // detected ROMs take precedence over the built-in mappers too
+        registry.register(
+            Box::new(|header, _| header.title == "CUSTOM"),
+            Box::new(|_, _| Err(CartridgeError::UnsupportedMapper(0x00))),
+        );
+        let mut rom = valid_rom(0x00);
+        assert!(registry.from_rom(rom.clone(), false).is_ok());
+        rom[0x0134..0x013A].copy_from_slice(b"CUSTOM");
+        rom[0x014D] = header_checksum(&rom);
+        assert!(registry.from_rom(rom.clone(), false).is_err());
+        assert!(MapperRegistry::new().from_rom(rom, false).is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use crate::json::Value;
+
+/// Frequency of the master clock, in Hz
+/// https://gbdev.io/pandocs/Specifications.html
+pub const CLOCK_SPEED: u64 = 4_194_304;
+
+/// Emulated time shared by every subsystem.
+///
+/// `cycles` counts T-cycles executed by the CPU, while `dots` counts
+/// T-cycles of the master clock that drives the PPU and APU. Both are equal
+/// unless the CGB double speed mode is enabled, in which case the CPU runs
+/// two cycles per dot.
+///
+/// DMA engines steal cycles from the CPU through `steal`: the CPU waits them
+/// out before its next instruction while the rest of the system keeps
+/// running, so timers and the PPU move on during the transfer.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Clock {
+    cycles: u64,
+    dots: u64,
+    double_speed: bool,
+    // cycles the CPU still has to wait for
+    stall: u32,
+    // every cycle stolen so far
+    stolen: u64,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advances time by `cycles` CPU T-cycles and returns the number of dots
+    /// that elapsed, which is what the speed-independent subsystems consume.
+    pub fn tick(&mut self, cycles: u32) -> u32 {
+        let dots = if self.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        };
+        self.cycles += cycles as u64;
+        self.dots += dots as u64;
+        dots
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn dots(&self) -> u64 {
+        self.dots
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    /// Keeps the CPU off the bus for `cycles` more T-cycles
+    pub fn steal(&mut self, cycles: u32) {
+        self.stall += cycles;
+        self.stolen += cycles as u64;
+    }
+
+    /// Cycles the CPU has to wait before executing, clears them
+    pub fn take_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+
+    /// Total cycles taken away from the CPU since power on
+    pub fn stolen(&self) -> u64 {
+        self.stolen
+    }
+
+    /// Real time that would have elapsed on hardware
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.dots * 1_000_000_000 / CLOCK_SPEED)
+    }
+}
+
+impl Clock {
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("cycles", Value::Number(self.cycles)),
+            ("dots", Value::Number(self.dots)),
+            ("double_speed", Value::Bool(self.double_speed)),
+            ("stall", Value::Number(self.stall as u64)),
+            ("stolen", Value::Number(self.stolen)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            cycles: value.get("cycles")?.as_u64()?,
+            dots: value.get("dots")?.as_u64()?,
+            double_speed: value.get("double_speed")?.as_bool()?,
+            stall: value.get("stall")?.as_u32()?,
+            stolen: value.get("stolen")?.as_u64()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_single_speed() {
+        let mut clock = Clock::new();
+        assert_eq!(clock.tick(4), 4);
+        assert_eq!(clock.tick(12), 12);
+        assert_eq!(clock.cycles(), 16);
+        assert_eq!(clock.dots(), 16);
+    }
+
+    #[test]
+    fn test_clock_double_speed() {
+        let mut clock = Clock::new();
+        clock.set_double_speed(true);
+        assert_eq!(clock.tick(8), 4);
+        assert_eq!(clock.cycles(), 8);
+        assert_eq!(clock.dots(), 4);
+    }
+
+    #[test]
+    fn test_clock_steal() {
+        let mut clock = Clock::new();
+        clock.steal(640);
+        clock.steal(32);
+        assert_eq!(clock.take_stall(), 672);
+        assert_eq!(clock.take_stall(), 0);
+        assert_eq!(clock.stolen(), 672);
+        // stealing is not time passing by itself
+        assert_eq!(clock.cycles(), 0);
+    }
+
+    #[test]
+    fn test_clock_elapsed() {
+        let mut clock = Clock::new();
+        for _ in 0..CLOCK_SPEED / 4 {
+            clock.tick(4);
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::json::Value;
+
+/// CGB compatibility registers, set up by the CGB boot ROM according to the
+/// cartridge header then locked for the game
+/// https://gbdev.io/pandocs/CGB_Registers.html
+pub const KEY0: u16 = 0xFF4C;
+pub const OPRI: u16 = 0xFF6C;
+
+// KEY0 bit 2, the CGB runs a DMG game with the DMG palettes and registers
+const DMG_MODE: u8 = 1 << 2;
+
+/// What the CGB boot ROM chose for the game. DMG games run in compatibility
+/// mode, where objects overlap by X coordinate like on a DMG.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Compat {
+    key0: u8,
+    opri: u8,
+    // once the boot ROM is unmapped, neither register can be written
+    locked: bool,
+}
+
+impl Compat {
+    /// Writable, as the boot ROM finds them at reset
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// As the CGB boot ROM leaves them for a game with the header CGB flag
+    /// `cgb_flag`, when booting without running it
+    pub fn post_boot(cgb_flag: u8) -> Self {
+        let dmg = cgb_flag & 0x80 == 0;
+        Self {
+            key0: if dmg { DMG_MODE } else { cgb_flag },
+            opri: dmg as u8,
+            locked: true,
+        }
+    }
+
+    /// Called when the boot ROM unmaps itself
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    /// The CGB emulates a DMG for a game without CGB support
+    pub fn dmg_mode(&self) -> bool {
+        self.key0 & DMG_MODE != 0
+    }
+
+    /// OPRI bit 0: overlapping objects are ordered by X coordinate like on
+    /// a DMG, rather than by their position in OAM
+    pub fn priority_by_x(&self) -> bool {
+        self.opri & 0x01 != 0
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            // only the boot ROM gets to see it
+            KEY0 if !self.locked => self.key0,
+            OPRI => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if self.locked {
+            return;
+        }
+        match address {
+            KEY0 => self.key0 = value,
+            OPRI => self.opri = value & 0x01,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("key0", Value::Number(self.key0 as u64)),
+            ("opri", Value::Number(self.opri as u64)),
+            ("locked", Value::Bool(self.locked)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            key0: value.get("key0")?.as_u8()?,
+            opri: value.get("opri")?.as_u8()? & 0x01,
+            locked: value.get("locked")?.as_bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_rom_sets_up_compatibility_mode() {
+        let mut compat = Compat::new();
+        compat.write(KEY0, DMG_MODE);
+        compat.write(OPRI, 0x01);
+        compat.lock();
+        compat.write(KEY0, 0x80);
+        compat.write(OPRI, 0x00);
+        assert!(compat.dmg_mode());
+        assert!(compat.priority_by_x());
+        assert_eq!(compat.read(KEY0), 0xFF);
+        assert_eq!(compat.read(OPRI), 0xFF);
+        assert_eq!(compat, Compat::post_boot(0x00));
+    }
+
+    #[test]
+    fn test_post_boot() {
+        let cgb = Compat::post_boot(0xC0);
+        assert!(!cgb.dmg_mode());
+        assert!(!cgb.priority_by_x());
+        assert_eq!(cgb.read(OPRI), 0xFE);
+        assert!(Compat::post_boot(0x00).priority_by_x());
+    }
+}
@@ -1,308 +1,962 @@
-use lazy_static::lazy_static;
-use log::debug;
-use std::collections::HashMap;
+use log::{debug, warn};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
 /// Main logic for the CPU
 /// Following
 /// https://gbdev.io/pandocs/CPU_Registers_and_Flags.html#the-flags-register-lower-8-bits-of-af-register
 use crate::{
-    cartdrige::Cartdrige,
+    accuracy::Accuracy,
+    apu::Apu,
+    bus::Bus,
+    cartdrige::{BankState, Cartdrige},
+    clock::Clock,
+    compat::{self, Compat},
+    debug::MemoryAccess,
+    dma::OamDma,
+    emulator::DOTS_PER_FRAME,
+    fill::MemoryFill,
+    hdma::{self, Hdma},
+    interrupt::{self, Interrupts},
+    io::IoRegisters,
+    joypad::Joypad,
+    json::Value,
+    mmu::{self, Mmu, HRAM_SIZE, OAM_SIZE, VRAM_SIZE, WRAM_SIZE},
+    op::{self, AluOp, Condition, Op, Operand, Rotate},
+    palette::{self, CgbPalettes},
+    ppu::Ppu,
     register::{self, ProgramCounter, Registers, StackPointer},
+    renderer::FifoLine,
+    rng::{self, RngHook},
+    serial::SerialState,
+    session::RomPatch,
 };
 
-pub struct Cpu {
+// CGB speed switch register
+pub const KEY1: u16 = 0xFF4D;
+
+/// The CPU and the bus it reaches the rest of the system through, the
+/// memory map of a Game Boy unless told otherwise
+pub struct Cpu<B: Bus = Mmu> {
     pub registers: Registers,
-    pub cartdrige: Box<dyn Cartdrige>,
+    pub bus: B,
+    pub clock: Clock,
+    rng_hook: Option<Box<dyn RngHook>>,
+    // Game Genie codes, applied to the program's reads of ROM
+    rom_patches: Vec<RomPatch>,
+    // the program's accesses to these addresses go to `accesses`
+    watched: Vec<u16>,
+    accesses: Vec<MemoryAccess>,
+    // Interrupt Master Enable
+    pub ime: bool,
+    // set by EI, IME is enabled once the following instruction completes
+    ime_scheduled: bool,
+    pub halted: bool,
+    // clocks are stopped until a button is pressed
+    pub stopped: bool,
+    // Game Boy Color mode, enables the CGB only registers
+    pub cgb: bool,
+    // KEY1 bit 0, STOP switches the CPU speed when set
+    speed_switch_armed: bool,
+    // KEY0 and OPRI, only present with `cgb`
+    pub compat: Compat,
+    // the byte following HALT will be read twice
+    halt_bug: bool,
+    // set by conditional instructions to select Instruction::cycles_taken
+    branch_taken: bool,
+    // the illegal instruction the CPU hung on, only a reset recovers
+    locked: Option<StepInfo>,
+    pub accuracy: Accuracy,
+    // cycles not yet seen by the rest of the system without per-access timing
+    deferred: u32,
+}
+
+/// Everything `Cpu::load_state` needs to resume execution exactly where
+/// `Cpu::save_state` left it. Attached devices are not part of it.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    registers: Registers,
+    clock: Clock,
+    interrupts: Interrupts,
+    joypad: Joypad,
+    serial: SerialState,
+    apu: Apu,
+    ppu: Ppu,
+    // the line the pixel FIFO is drawing, if it is not through yet
+    fifo: Option<FifoLine>,
+    ime: bool,
+    ime_scheduled: bool,
+    halted: bool,
+    stopped: bool,
+    cgb: bool,
+    speed_switch_armed: bool,
+    compat: Compat,
+    halt_bug: bool,
+    #[cfg_attr(feature = "serde", serde(with = "serde_state::locked"))]
+    locked: Option<StepInfo>,
+    io: IoRegisters,
+    dma: OamDma,
+    hdma: Hdma,
+    palettes: CgbPalettes,
+    bank_state: BankState,
+    // see `Cartdrige::mapper_state`
+    mapper: Vec<u8>,
+    boot_rom_mapped: bool,
+    ram: Vec<u8>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "serde_state::memory::<_, VRAM_SIZE>")
+    )]
+    vram: Vec<u8>,
+    vbk: u8,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "serde_state::memory::<_, WRAM_SIZE>")
+    )]
+    wram: Vec<u8>,
+    svbk: u8,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "serde_state::memory::<_, OAM_SIZE>")
+    )]
+    oam: Vec<u8>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "serde_state::memory::<_, HRAM_SIZE>")
+    )]
+    hram: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+mod serde_state {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{StepInfo, INSTRUCTIONS};
+
+    /// The instruction the CPU hung on, as its opcode and address
+    pub mod locked {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            locked: &Option<StepInfo>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            locked
+                .map(|info| (info.instruction.opcode, info.address))
+                .serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<StepInfo>, D::Error> {
+            let locked = Option::<(u8, u16)>::deserialize(deserializer)?;
+            Ok(locked
+                .map(|(opcode, address)| StepInfo::new(&INSTRUCTIONS[opcode as usize], address)))
+        }
+    }
+
+    /// Memory of a fixed `SIZE`, the CPU indexes it without checking
+    pub fn memory<'de, D: Deserializer<'de>, const SIZE: usize>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let memory = Vec::<u8>::deserialize(deserializer)?;
+        if memory.len() != SIZE {
+            return Err(D::Error::custom(format!(
+                "Expected {} bytes, got {}",
+                SIZE,
+                memory.len()
+            )));
+        }
+        Ok(memory)
+    }
+}
+
+impl CpuState {
+    pub(crate) fn to_json(&self) -> Value {
+        let locked = match self.locked {
+            Some(info) => Value::object([
+                ("opcode", Value::hex(info.instruction.opcode as u64, 2)),
+                ("address", Value::hex(info.address as u64, 4)),
+            ]),
+            None => Value::Null,
+        };
+        Value::object([
+            ("registers", self.registers.to_json()),
+            ("clock", self.clock.to_json()),
+            ("interrupts", self.interrupts.to_json()),
+            ("joypad", self.joypad.to_json()),
+            ("serial", self.serial.to_json()),
+            ("apu", self.apu.to_json()),
+            ("ppu", self.ppu.to_json()),
+            (
+                "fifo",
+                self.fifo.as_ref().map_or(Value::Null, FifoLine::to_json),
+            ),
+            ("ime", Value::Bool(self.ime)),
+            ("ime_scheduled", Value::Bool(self.ime_scheduled)),
+            ("halted", Value::Bool(self.halted)),
+            ("stopped", Value::Bool(self.stopped)),
+            ("cgb", Value::Bool(self.cgb)),
+            ("speed_switch_armed", Value::Bool(self.speed_switch_armed)),
+            ("compat", self.compat.to_json()),
+            ("halt_bug", Value::Bool(self.halt_bug)),
+            ("locked", locked),
+            ("io", self.io.to_json()),
+            ("dma", self.dma.to_json()),
+            ("hdma", self.hdma.to_json()),
+            ("palettes", self.palettes.to_json()),
+            ("bank_state", self.bank_state.to_json()),
+            ("mapper", Value::bytes(&self.mapper)),
+            ("boot_rom_mapped", Value::Bool(self.boot_rom_mapped)),
+            ("ram", Value::bytes(&self.ram)),
+            ("vram", Value::bytes(&self.vram)),
+            ("wram", Value::bytes(&self.wram)),
+            ("vbk", Value::hex(self.vbk as u64, 2)),
+            ("svbk", Value::hex(self.svbk as u64, 2)),
+            ("oam", Value::bytes(&self.oam)),
+            ("hram", Value::bytes(&self.hram)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let locked = match value.get("locked")? {
+            Value::Null => None,
+            locked => Some(StepInfo::new(
+                &INSTRUCTIONS[locked.get("opcode")?.as_u8()? as usize],
+                locked.get("address")?.as_u16()?,
+            )),
+        };
+        let memory = |name: &str, size: usize| {
+            let mut memory = vec![0x00; size];
+            value.get(name)?.read_bytes(&mut memory)?;
+            Ok::<_, String>(memory)
+        };
+        Ok(Self {
+            registers: Registers::from_json(value.get("registers")?)?,
+            clock: Clock::from_json(value.get("clock")?)?,
+            interrupts: Interrupts::from_json(value.get("interrupts")?)?,
+            joypad: Joypad::from_json(value.get("joypad")?)?,
+            serial: SerialState::from_json(value.get("serial")?)?,
+            apu: Apu::from_json(value.get("apu")?)?,
+            ppu: Ppu::from_json(value.get("ppu")?)?,
+            fifo: match value.get("fifo")? {
+                Value::Null => None,
+                fifo => Some(FifoLine::from_json(fifo)?),
+            },
+            ime: value.get("ime")?.as_bool()?,
+            ime_scheduled: value.get("ime_scheduled")?.as_bool()?,
+            halted: value.get("halted")?.as_bool()?,
+            stopped: value.get("stopped")?.as_bool()?,
+            cgb: value.get("cgb")?.as_bool()?,
+            speed_switch_armed: value.get("speed_switch_armed")?.as_bool()?,
+            compat: Compat::from_json(value.get("compat")?)?,
+            halt_bug: value.get("halt_bug")?.as_bool()?,
+            locked,
+            io: IoRegisters::from_json(value.get("io")?)?,
+            dma: OamDma::from_json(value.get("dma")?)?,
+            hdma: Hdma::from_json(value.get("hdma")?)?,
+            palettes: CgbPalettes::from_json(value.get("palettes")?)?,
+            bank_state: BankState::from_json(value.get("bank_state")?)?,
+            mapper: value.get("mapper")?.as_bytes()?,
+            boot_rom_mapped: value.get("boot_rom_mapped")?.as_bool()?,
+            ram: value.get("ram")?.as_bytes()?,
+            vram: memory("vram", VRAM_SIZE)?,
+            wram: memory("wram", WRAM_SIZE)?,
+            vbk: value.get("vbk")?.as_u8()? & 0x01,
+            svbk: value.get("svbk")?.as_u8()? & 0x07,
+            oam: memory("oam", OAM_SIZE)?,
+            hram: memory("hram", HRAM_SIZE)?,
+        })
+    }
+
+    /// Size of the external RAM of the cartridge the state was saved with
+    pub(crate) fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    /// Size of the mapper state of the cartridge the state was saved with
+    pub(crate) fn mapper_size(&self) -> usize {
+        self.mapper.len()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegisterPair {
+    BC,
+    DE,
+    HL,
+    SP,
+    // only used by PUSH and POP
+    AF,
+}
+
+/// What `Cpu::step` executed. Once locked up the CPU keeps reporting the
+/// illegal instruction it hung on.
+#[derive(Copy, Clone, Debug)]
+pub struct StepInfo {
+    pub instruction: &'static Instruction,
+    // where the opcode was fetched from
+    pub address: u16,
+    /// T-cycles the step took, DMA stalls, interrupt dispatch and taken
+    /// branches included, for the subsystems that run alongside the CPU
+    pub cycles: u32,
+}
+
+impl StepInfo {
+    // the cycles are filled in by `Cpu::step`
+    fn new(instruction: &'static Instruction, address: u16) -> Self {
+        Self {
+            instruction,
+            address,
+            cycles: 0,
+        }
+    }
+}
+
+/// Why `Cpu::step` could not execute the next instruction. PC is left on
+/// the faulting instruction so that it can be inspected.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CpuError {
+    UnknownOpcode { opcode: u8, address: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { opcode, address } => {
+                write!(f, "Unknown opcode {:#04x} at {:#06x}", opcode, address)
+            }
+        }
+    }
 }
 
+impl std::error::Error for CpuError {}
+
+/// Decoded opcode with its timing
+#[derive(Copy, Clone, Debug)]
 pub struct Instruction {
     pub opcode: u8,
-    pub mnemonic: &'static str,
+    pub op: Op,
     pub length: u8, // in bytes
     pub cycles: u8,
-    pub execute: fn(&mut Cpu),
+    // conditional instructions take longer when the branch is taken
+    pub cycles_taken: u8,
 }
 
-lazy_static! {
-    pub static ref INSTRUCTION_MAP: HashMap<u8, Instruction> = {
-        let m: HashMap<u8, Instruction> = HashMap::from([
-            (
-                0x00,
-                Instruction {
-                    opcode: 0x00,
-                    mnemonic: "NOP",
-                    length: 1,
-                    cycles: 4,
-                    execute: |_cpu: &mut Cpu| {},
-                },
-            ),
-            (
-                0x10,
-                Instruction {
-                    opcode: 0x10,
-                    mnemonic: "STOP 0",
-                    length: 2,
-                    cycles: 4,
-                    execute: |_cpu: &mut Cpu| {},
-                },
-            ),
-            (
-                0x20,
-                Instruction {
-                    opcode: 0x20,
-                    mnemonic: "JR NZ,r8",
-                    length: 2,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        let offset = cpu.fetch() as i8;
-                        if !cpu.registers.f.contains(register::Flags::ZERO) {
-                            cpu.registers.pc.0 = (cpu.registers.pc.0 as i32 + offset as i32) as u16;
-                        }
-                    },
-                },
-            ),
-            (
-                0x30,
-                Instruction {
-                    opcode: 0x30,
-                    mnemonic: "JR NC,r8",
-                    length: 2,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        let offset = cpu.fetch() as i8;
-                        if !cpu.registers.f.contains(register::Flags::CARRY) {
-                            cpu.registers.pc.0 = (cpu.registers.pc.0 as i32 + offset as i32) as u16;
-                        }
-                    },
-                },
-            ),
-            (
-                0x40,
-                Instruction {
-                    opcode: 0x40,
-                    mnemonic: "LD B,B",
-                    length: 1,
-                    cycles: 4,
-                    execute: |_cpu: &mut Cpu| {},
-                },
-            ),
-            (
-                0x50,
-                Instruction {
-                    opcode: 0x50,
-                    mnemonic: "LD D,B",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.d = cpu.registers.b;
-                        cpu.registers.pc.0 += 1;
-                    },
-                },
-            ),
-            (
-                0x60,
-                Instruction {
-                    opcode: 0x60,
-                    mnemonic: "LD H,B",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.h = cpu.registers.b;
-                        cpu.registers.pc.0 += 1;
-                    },
-                },
-            ),
-            (
-                0x70,
-                Instruction {
-                    opcode: 0x70,
-                    mnemonic: "LD (HL),B",
-                    length: 1,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        let hl = (cpu.registers.h as u16) << 8 | cpu.registers.l as u16;
-                        cpu.cartdrige.set(hl, cpu.registers.b);
-                        cpu.registers.pc.0 += 1;
-                    },
-                },
-            ),
-            (
-                0x80,
-                Instruction {
-                    opcode: 0x80,
-                    mnemonic: "ADD A,B",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.a = cpu.alu_add(cpu.registers.b);
-                    },
-                },
-            ),
-            (
-                0x60,
-                Instruction {
-                    opcode: 0x60,
-                    mnemonic: "LD H,B",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.h = cpu.registers.b;
-                        cpu.registers.pc.0 += 1;
-                    },
-                },
-            ),
-            (
-                0x05,
-                Instruction {
-                    opcode: 0x05,
-                    mnemonic: "DEC B",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.b = cpu.alu_dec(cpu.registers.b);
-                    },
-                },
-            ),
-            (
-                0x06,
-                Instruction {
-                    opcode: 0x06,
-                    mnemonic: "LD B,d8",
-                    length: 2,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.b = cpu.fetch();
-                    },
-                },
-            ),
-            (
-                0x0D,
-                Instruction {
-                    opcode: 0x0D,
-                    mnemonic: "DEC C",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.c = cpu.alu_dec(cpu.registers.c);
-                    },
-                },
-            ),
-            (
-                0x0E,
-                Instruction {
-                    opcode: 0x0E,
-                    mnemonic: "LD C,d8",
-                    length: 2,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.c = cpu.fetch();
-                    },
-                },
-            ),
-            (
-                0x21,
-                Instruction {
-                    opcode: 0x21,
-                    mnemonic: "LD HL,d16",
-                    length: 3,
-                    cycles: 12,
-                    execute: |cpu: &mut Cpu| {
-                        let word = cpu.fetch_word();
-                        cpu.registers.h = (word >> 8) as u8;
-                        cpu.registers.l = word as u8;
-                    },
-                },
-            ),
-            (
-                0x2F,
-                Instruction {
-                    opcode: 0x2F,
-                    mnemonic: "CPL",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.a = !cpu.registers.a;
-                        cpu.registers.f.set(register::Flags::SUBTRACTION, true);
-                        cpu.registers.f.set(register::Flags::HALFCARRY, true);
-                        cpu.registers.pc.0 += 1;
-                    },
-                },
-            ),
-            (
-                0x32,
-                Instruction {
-                    opcode: 0x32,
-                    mnemonic: "LD (HL-),A",
-                    length: 1,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        let mut hl = (cpu.registers.h as u16) << 8 | cpu.registers.l as u16;
-                        cpu.cartdrige.set(hl, cpu.registers.a);
-                        hl -= 1;
-                        cpu.registers.h = (hl >> 8) as u8;
-                        cpu.registers.l = hl as u8;
-                    },
-                },
-            ),
-            (
-                0x3E,
-                Instruction {
-                    opcode: 0x3E,
-                    mnemonic: "LD A,d8",
-                    length: 2,
-                    cycles: 8,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.a = cpu.fetch();
-                    },
-                },
-            ),
-            (
-                0xaf,
-                Instruction {
-                    opcode: 0xaf,
-                    mnemonic: "XOR A, A",
-                    length: 1,
-                    cycles: 4,
-                    execute: |cpu: &mut Cpu| {
-                        cpu.registers.a ^= cpu.registers.a;
-                        cpu.registers.f.set(register::Flags::ZERO, true);
-                        cpu.registers.f.set(register::Flags::SUBTRACTION, false);
-                        cpu.registers.f.set(register::Flags::HALFCARRY, false);
-                        cpu.registers.f.set(register::Flags::CARRY, false);
-                    },
-                },
-            ),
-            (
-                0xc3,
-                Instruction {
-                    opcode: 0xc3,
-                    mnemonic: "JP a16",
-                    length: 3,
-                    cycles: 16,
-                    execute: |cpu: &mut Cpu| {
-                        let word = cpu.cartdrige.read_word(cpu.registers.pc.value());
-                        cpu.registers.pc.0 = word;
-                    },
-                },
-            ),
-        ]);
-        m
-    };
+impl Instruction {
+    const fn new(opcode: u8) -> Self {
+        let op = op::decode(opcode);
+        let (cycles, cycles_taken) = op.cycles();
+        Self {
+            opcode,
+            op,
+            length: op.length(),
+            cycles,
+            cycles_taken,
+        }
+    }
+
+    pub fn mnemonic(&self) -> String {
+        self.op.to_string()
+    }
+
+    pub fn is_illegal(&self) -> bool {
+        self.op == Op::Illegal
+    }
 }
 
-impl Cpu {
+/// Instruction table indexed by opcode
+pub static INSTRUCTIONS: [Instruction; 256] = {
+    let mut table = [Instruction::new(0x00); 256];
+    let mut opcode = 0;
+    while opcode < table.len() {
+        table[opcode] = Instruction::new(opcode as u8);
+        opcode += 1;
+    }
+    table
+};
+
+impl<B: Bus> Cpu<B> {
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            KEY1 if self.cgb => {
+                0x7E | (self.clock.double_speed() as u8) << 7 | self.speed_switch_armed as u8
+            }
+            compat::KEY0 | compat::OPRI if self.cgb => self.compat.read(address),
+            hdma::HDMA1..=hdma::HDMA5 | palette::BCPS..=palette::OCPD | mmu::VBK | mmu::SVBK
+                if !self.cgb =>
+            {
+                0xFF
+            }
+            _ => self.bus.read8(address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            KEY1 if self.cgb => self.speed_switch_armed = value & 0x01 != 0,
+            compat::KEY0 | compat::OPRI if self.cgb => {
+                self.compat.write(address, value);
+                self.sync_cgb_mode();
+            }
+            hdma::HDMA1..=hdma::HDMA5 | palette::BCPS..=palette::OCPD | mmu::VBK | mmu::SVBK
+                if !self.cgb => {}
+            mmu::BOOT => {
+                self.bus.write8(address, value);
+                // bit 0 reads as set once the boot ROM is unmapped
+                if self.bus.read8(address) & 0x01 != 0 {
+                    self.compat.lock();
+                    // the boot ROM chose to run the game as on a DMG
+                    if self.compat.dmg_mode() {
+                        self.cgb = false;
+                    }
+                    self.sync_cgb_mode();
+                }
+            }
+            _ => self.bus.write8(address, value),
+        }
+    }
+
+    /// Passes `cgb` and `compat` on to the PPU, which keeps copies of both
+    pub(crate) fn sync_cgb_mode(&mut self) {
+        self.bus.set_cgb_mode(self.cgb, &self.compat);
+    }
+
+    // IE and IF as the program sees them
+    fn interrupts(&self) -> Interrupts {
+        Interrupts {
+            enable: self.bus.read8(interrupt::IE),
+            flag: self.bus.read8(interrupt::IF) & 0x1F,
+        }
+    }
+
+    fn set_interrupt_flag(&mut self, flag: u8) {
+        self.bus.write8(interrupt::IF, flag);
+    }
+
+    fn read_pair(&self, pair: RegisterPair) -> u16 {
+        match pair {
+            RegisterPair::BC => self.registers.bc(),
+            RegisterPair::DE => self.registers.de(),
+            RegisterPair::HL => self.registers.hl(),
+            RegisterPair::SP => self.registers.sp.0,
+            RegisterPair::AF => self.registers.af(),
+        }
+    }
+
+    fn write_pair(&mut self, pair: RegisterPair, value: u16) {
+        match pair {
+            RegisterPair::BC => self.registers.set_bc(value),
+            RegisterPair::DE => self.registers.set_de(value),
+            RegisterPair::HL => self.registers.set_hl(value),
+            RegisterPair::SP => self.registers.sp.0 = value,
+            RegisterPair::AF => self.registers.set_af(value),
+        }
+    }
+
+    // Operands are consumed with `fetch`/`fetch_word` as they are decoded, so
+    // PC only ever moves through fetches and jumps
+    fn read_operand(&mut self, operand: Operand) -> u8 {
+        match operand {
+            Operand::A => self.registers.a,
+            Operand::B => self.registers.b,
+            Operand::C => self.registers.c,
+            Operand::D => self.registers.d,
+            Operand::E => self.registers.e,
+            Operand::H => self.registers.h,
+            Operand::L => self.registers.l,
+            Operand::Indirect(pair) => self.read_cycle(self.read_pair(pair)),
+            Operand::HlIncrement | Operand::HlDecrement => {
+                let address = self.step_hl(operand);
+                self.read_cycle(address)
+            }
+            Operand::Immediate => self.fetch(),
+            Operand::Absolute => {
+                let address = self.fetch_word();
+                self.read_cycle(address)
+            }
+            Operand::High => {
+                let offset = self.fetch();
+                self.read_cycle(0xFF00 | offset as u16)
+            }
+            Operand::HighC => self.read_cycle(0xFF00 | self.registers.c as u16),
+        }
+    }
+
+    fn write_operand(&mut self, operand: Operand, value: u8) {
+        match operand {
+            Operand::A => self.registers.a = value,
+            Operand::B => self.registers.b = value,
+            Operand::C => self.registers.c = value,
+            Operand::D => self.registers.d = value,
+            Operand::E => self.registers.e = value,
+            Operand::H => self.registers.h = value,
+            Operand::L => self.registers.l = value,
+            Operand::Indirect(pair) => self.write_cycle(self.read_pair(pair), value),
+            Operand::HlIncrement | Operand::HlDecrement => {
+                let address = self.step_hl(operand);
+                self.write_cycle(address, value);
+            }
+            Operand::Immediate => panic!("Immediate operands are read only"),
+            Operand::Absolute => {
+                let address = self.fetch_word();
+                self.write_cycle(address, value);
+            }
+            Operand::High => {
+                let offset = self.fetch();
+                self.write_cycle(0xFF00 | offset as u16, value);
+            }
+            Operand::HighC => self.write_cycle(0xFF00 | self.registers.c as u16, value),
+        }
+    }
+
+    // returns HL as it was before the increment or decrement
+    fn step_hl(&mut self, operand: Operand) -> u16 {
+        let hl = self.registers.hl();
+        let next = match operand {
+            Operand::HlIncrement => hl.wrapping_add(1),
+            _ => hl.wrapping_sub(1),
+        };
+        self.registers.set_hl(next);
+        hl
+    }
+
+    fn condition(&self, condition: Condition) -> bool {
+        let flags = self.registers.f;
+        match condition {
+            Condition::Always => true,
+            Condition::NZ => !flags.contains(register::Flags::ZERO),
+            Condition::Z => flags.contains(register::Flags::ZERO),
+            Condition::NC => !flags.contains(register::Flags::CARRY),
+            Condition::C => flags.contains(register::Flags::CARRY),
+        }
+    }
+
+    fn execute(&mut self, op: Op) {
+        match op {
+            Op::Nop => {}
+            Op::Stop => self.stop(),
+            Op::Halt => self.halt(),
+            Op::Di => {
+                self.ime = false;
+                // also cancels an EI from the previous instruction
+                self.ime_scheduled = false;
+            }
+            // IME is only set after the next instruction
+            Op::Ei => self.ime_scheduled = true,
+            Op::Ld(destination, source) => {
+                let value = self.read_operand(source);
+                self.write_operand(destination, value);
+            }
+            Op::LdWord(pair) => {
+                let value = self.fetch_word();
+                self.write_pair(pair, value);
+            }
+            Op::LdAbsoluteSp => {
+                let address = self.fetch_word();
+                self.write_word(address, self.registers.sp.0);
+            }
+            Op::LdHlSpOffset => {
+                let offset = self.fetch() as i8;
+                let value = self.alu_add_sp(offset);
+                self.internal_cycle();
+                self.registers.set_hl(value);
+            }
+            Op::LdSpHl => {
+                self.registers.sp.0 = self.registers.hl();
+                self.internal_cycle();
+            }
+            Op::Push(pair) => self.push_word(self.read_pair(pair)),
+            Op::Pop(pair) => {
+                let value = self.pop_word();
+                self.write_pair(pair, value);
+            }
+            Op::Alu(op, source) => {
+                let value = self.read_operand(source);
+                match op {
+                    AluOp::Add => self.registers.a = self.alu_add(value),
+                    AluOp::Adc => self.registers.a = self.alu_adc(value),
+                    AluOp::Sub => self.registers.a = self.alu_sub(value),
+                    AluOp::Sbc => self.registers.a = self.alu_sbc(value),
+                    AluOp::And => self.alu_and(value),
+                    AluOp::Xor => self.alu_xor(value),
+                    AluOp::Or => self.alu_or(value),
+                    AluOp::Cp => self.alu_cp(value),
+                }
+            }
+            Op::Inc(operand) => {
+                let value = self.read_operand(operand);
+                let result = self.alu_inc(value);
+                self.write_operand(operand, result);
+            }
+            Op::Dec(operand) => {
+                let value = self.read_operand(operand);
+                let result = self.alu_dec(value);
+                self.write_operand(operand, result);
+            }
+            // 16-bit arithmetic takes an extra machine cycle
+            Op::IncWord(pair) => {
+                let value = self.read_pair(pair).wrapping_add(1);
+                self.write_pair(pair, value);
+                self.internal_cycle();
+            }
+            Op::DecWord(pair) => {
+                let value = self.read_pair(pair).wrapping_sub(1);
+                self.write_pair(pair, value);
+                self.internal_cycle();
+            }
+            Op::AddHl(pair) => {
+                self.alu_add_hl(self.read_pair(pair));
+                self.internal_cycle();
+            }
+            Op::AddSp => {
+                let offset = self.fetch() as i8;
+                self.registers.sp.0 = self.alu_add_sp(offset);
+                self.internal_cycle();
+                self.internal_cycle();
+            }
+            Op::RotateA(rotate) => {
+                let a = self.registers.a;
+                self.registers.a = match rotate {
+                    Rotate::Rlc => self.alu_rlc(a),
+                    Rotate::Rrc => self.alu_rrc(a),
+                    Rotate::Rl => self.alu_rl(a),
+                    Rotate::Rr => self.alu_rr(a),
+                };
+                // unlike the CB prefixed rotates, Z is always cleared
+                self.registers.f.set(register::Flags::ZERO, false);
+            }
+            Op::Daa => self.registers.a = self.alu_daa(self.registers.a),
+            Op::Cpl => {
+                self.registers.a = !self.registers.a;
+                self.registers.f.set(register::Flags::SUBTRACTION, true);
+                self.registers.f.set(register::Flags::HALFCARRY, true);
+            }
+            // Z is left untouched by both
+            Op::Scf | Op::Ccf => {
+                let carry = op == Op::Scf || !self.registers.f.contains(register::Flags::CARRY);
+                self.registers.f.set(register::Flags::SUBTRACTION, false);
+                self.registers.f.set(register::Flags::HALFCARRY, false);
+                self.registers.f.set(register::Flags::CARRY, carry);
+            }
+            Op::Jr(condition) => self.jr(self.condition(condition)),
+            Op::Jp(condition) => self.jp(self.condition(condition)),
+            Op::JpHl => self.registers.pc.0 = self.registers.hl(),
+            Op::Call(condition) => self.call(self.condition(condition)),
+            Op::Ret(Condition::Always) => self.ret(true),
+            Op::Ret(condition) => {
+                // evaluating the condition costs a machine cycle of its own
+                self.internal_cycle();
+                self.ret(self.condition(condition));
+            }
+            Op::Reti => {
+                self.ret(true);
+                self.ime = true;
+            }
+            Op::Rst(vector) => self.rst(vector as u16),
+            Op::Prefix | Op::Illegal => unreachable!("{:?} is rejected by step", op),
+        }
+    }
+
+    fn write_word(&mut self, address: u16, value: u16) {
+        self.write_cycle(address, value as u8);
+        self.write_cycle(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
     fn fetch(&mut self) -> u8 {
-        let value = self.cartdrige.read(self.registers.pc.value());
-        self.registers.pc.0 += 1;
+        let value = self.read_cycle(self.registers.pc.value());
+        self.registers.pc.0 = self.registers.pc.0.wrapping_add(1);
         value
     }
 
     fn fetch_word(&mut self) -> u16 {
-        let value = self.cartdrige.read_word(self.registers.pc.value());
-        self.registers.pc.0 += 2;
+        let low = self.fetch() as u16;
+        let high = self.fetch() as u16;
+        low | (high << 8)
+    }
+
+    /// Advances every device by `cycles` T-cycles. Instructions call it once
+    /// per machine cycle, so memory accesses are seen by the rest of the
+    /// system on the cycle they happen on hardware. The fast accuracy profile
+    /// saves them up until the step completes instead.
+    fn tick(&mut self, cycles: u32) {
+        if !self.accuracy.per_access_timing() {
+            self.deferred += cycles;
+            return;
+        }
+        self.advance(cycles);
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        let dots = self.clock.tick(cycles);
+        self.bus.tick(cycles, dots);
+    }
+
+    // machine cycle without a memory access
+    fn internal_cycle(&mut self) {
+        self.tick(4);
+    }
+
+    // the access completes at the end of its machine cycle
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.tick(4);
+        let mut value = self.read(address);
+        if address < 0x8000 {
+            value = self
+                .rom_patches
+                .iter()
+                .fold(value, |value, patch| patch.apply(address, value));
+        }
+        let value = match &mut self.rng_hook {
+            Some(hook) if rng::SOURCES.contains(&address) => {
+                hook.read(self.clock.dots() / DOTS_PER_FRAME, address, value)
+            }
+            _ => value,
+        };
+        self.watch(address, false, value);
         value
     }
 
+    /// Routes the program's reads of `rng::SOURCES` through `hook`, `None`
+    /// removes it. Returns the previous hook.
+    pub fn set_rng_hook(&mut self, hook: Option<Box<dyn RngHook>>) -> Option<Box<dyn RngHook>> {
+        std::mem::replace(&mut self.rng_hook, hook)
+    }
+
+    /// Makes the program read the values of `patches` from ROM instead of
+    /// what is there. Debugger reads see the ROM as it is.
+    pub fn set_rom_patches(&mut self, patches: Vec<RomPatch>) {
+        self.rom_patches = patches;
+    }
+
+    /// Records the program's reads and writes of `addresses` until
+    /// `take_accesses` is called
+    pub fn set_watched(&mut self, addresses: Vec<u16>) {
+        self.watched = addresses;
+        self.accesses.clear();
+    }
+
+    /// The accesses to the watched addresses since the last call, oldest
+    /// first
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+
+    fn watch(&mut self, address: u16, write: bool, value: u8) {
+        if self.watched.contains(&address) {
+            self.accesses.push(MemoryAccess {
+                address,
+                write,
+                value,
+            });
+        }
+    }
+
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.tick(4);
+        self.write(address, value);
+        self.watch(address, true, value);
+    }
+
+    // STOP is followed by a padding byte that is skipped. On CGB it doubles as
+    // the speed switch when armed through KEY1, otherwise the clocks are
+    // stopped until a button is pressed.
+    // https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+    fn stop(&mut self) {
+        // the padding byte is never read, STOP takes a single machine cycle
+        self.registers.pc.0 = self.registers.pc.0.wrapping_add(1);
+        if self.speed_switch_armed {
+            self.speed_switch_armed = false;
+            let double_speed = !self.clock.double_speed();
+            self.clock.set_double_speed(double_speed);
+        } else {
+            self.stopped = true;
+        }
+    }
+
+    // With IME=0 and an interrupt already pending HALT does not halt at all,
+    // instead the CPU fails to increment PC after fetching the next opcode
+    // https://gbdev.io/pandocs/halt.html#halt-bug
+    fn halt(&mut self) {
+        if !self.ime && self.interrupts().pending() != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
+
+    // SP is decremented on a machine cycle of its own before the writes
+    fn push_word(&mut self, value: u16) {
+        self.internal_cycle();
+        self.registers.sp.0 = self.registers.sp.0.wrapping_sub(1);
+        self.write_cycle(self.registers.sp.0, (value >> 8) as u8);
+        self.registers.sp.0 = self.registers.sp.0.wrapping_sub(1);
+        self.write_cycle(self.registers.sp.0, value as u8);
+    }
+
+    fn pop_word(&mut self) -> u16 {
+        let low = self.read_cycle(self.registers.sp.0) as u16;
+        self.registers.sp.0 = self.registers.sp.0.wrapping_add(1);
+        let high = self.read_cycle(self.registers.sp.0) as u16;
+        self.registers.sp.0 = self.registers.sp.0.wrapping_add(1);
+        low | (high << 8)
+    }
+
+    // the offset is always fetched, even when the jump is not taken
+    fn jr(&mut self, condition: bool) {
+        let offset = self.fetch() as i8;
+        if condition {
+            self.registers.pc.0 = self.registers.pc.0.wrapping_add(offset as u16);
+            self.internal_cycle();
+            self.branch_taken = true;
+        }
+    }
+
+    fn jp(&mut self, condition: bool) {
+        let address = self.fetch_word();
+        if condition {
+            self.registers.pc.0 = address;
+            self.internal_cycle();
+            self.branch_taken = true;
+        }
+    }
+
+    fn call(&mut self, condition: bool) {
+        let address = self.fetch_word();
+        if condition {
+            self.push_word(self.registers.pc.0);
+            self.registers.pc.0 = address;
+            self.branch_taken = true;
+        }
+    }
+
+    fn ret(&mut self, condition: bool) {
+        if condition {
+            self.registers.pc.0 = self.pop_word();
+            self.internal_cycle();
+            self.branch_taken = true;
+        }
+    }
+
+    fn rst(&mut self, vector: u16) {
+        self.push_word(self.registers.pc.0);
+        self.registers.pc.0 = vector;
+    }
+
+    // Adjusts the result of the previous addition or subtraction so that it is
+    // valid BCD again, N tells which one it was and H/C which digits overflowed
+    // https://ehaskins.com/2018-01-30%20Z80%20DAA/
+    fn alu_daa(&mut self, value: u8) -> u8 {
+        let mut result = value;
+        let mut carry = self.registers.f.contains(register::Flags::CARRY);
+        let half_carry = self.registers.f.contains(register::Flags::HALFCARRY);
+        if self.registers.f.contains(register::Flags::SUBTRACTION) {
+            if carry {
+                result = result.wrapping_sub(0x60);
+            }
+            if half_carry {
+                result = result.wrapping_sub(0x06);
+            }
+        } else {
+            if carry || value > 0x99 {
+                result = result.wrapping_add(0x60);
+                carry = true;
+            }
+            if half_carry || (value & 0x0F) > 0x09 {
+                result = result.wrapping_add(0x06);
+            }
+        }
+        self.registers.f.set(register::Flags::ZERO, result == 0);
+        self.registers.f.set(register::Flags::HALFCARRY, false);
+        self.registers.f.set(register::Flags::CARRY, carry);
+        result
+    }
+
+    fn set_logic_flags(&mut self, half_carry: bool) {
+        self.registers
+            .f
+            .set(register::Flags::ZERO, self.registers.a == 0);
+        self.registers.f.set(register::Flags::SUBTRACTION, false);
+        self.registers.f.set(register::Flags::HALFCARRY, half_carry);
+        self.registers.f.set(register::Flags::CARRY, false);
+    }
+
+    // unlike OR and XOR, AND always sets H
+    fn alu_and(&mut self, value: u8) {
+        self.registers.a &= value;
+        self.set_logic_flags(true);
+    }
+
+    fn alu_or(&mut self, value: u8) {
+        self.registers.a |= value;
+        self.set_logic_flags(false);
+    }
+
+    fn alu_xor(&mut self, value: u8) {
+        self.registers.a ^= value;
+        self.set_logic_flags(false);
+    }
+
+    // a subtraction that only keeps the flags, A is left untouched
+    fn alu_cp(&mut self, value: u8) {
+        let a = self.registers.a;
+        self.registers.f.set(register::Flags::ZERO, a == value);
+        self.registers.f.set(register::Flags::SUBTRACTION, true);
+        self.registers
+            .f
+            .set(register::Flags::HALFCARRY, (a & 0x0F) < (value & 0x0F));
+        self.registers.f.set(register::Flags::CARRY, a < value);
+    }
+
+    fn set_rotate_flags(&mut self, result: u8, carry: bool) {
+        self.registers.f.set(register::Flags::ZERO, result == 0);
+        self.registers.f.set(register::Flags::SUBTRACTION, false);
+        self.registers.f.set(register::Flags::HALFCARRY, false);
+        self.registers.f.set(register::Flags::CARRY, carry);
+    }
+
+    // bit 7 goes both to the carry and to bit 0
+    fn alu_rlc(&mut self, value: u8) -> u8 {
+        let result = value.rotate_left(1);
+        self.set_rotate_flags(result, value & 0x80 != 0);
+        result
+    }
+
+    // bit 0 goes both to the carry and to bit 7
+    fn alu_rrc(&mut self, value: u8) -> u8 {
+        let result = value.rotate_right(1);
+        self.set_rotate_flags(result, value & 0x01 != 0);
+        result
+    }
+
+    // rotates through the carry, the old carry becomes bit 0
+    fn alu_rl(&mut self, value: u8) -> u8 {
+        let carry = self.registers.f.contains(register::Flags::CARRY) as u8;
+        let result = value << 1 | carry;
+        self.set_rotate_flags(result, value & 0x80 != 0);
+        result
+    }
+
+    // rotates through the carry, the old carry becomes bit 7
+    fn alu_rr(&mut self, value: u8) -> u8 {
+        let carry = self.registers.f.contains(register::Flags::CARRY) as u8;
+        let result = value >> 1 | carry << 7;
+        self.set_rotate_flags(result, value & 0x01 != 0);
+        result
+    }
+
+    // C is left untouched
+    fn alu_inc(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.registers.f.set(register::Flags::ZERO, result == 0);
+        self.registers.f.set(register::Flags::SUBTRACTION, false);
+        self.registers
+            .f
+            .set(register::Flags::HALFCARRY, (value & 0x0F) == 0x0F);
+        result
+    }
+
     fn alu_dec(&mut self, value: u8) -> u8 {
         let result = value.wrapping_sub(1);
         self.registers.f.set(register::Flags::ZERO, result == 0);
@@ -316,6 +970,71 @@ impl Cpu {
         result
     }
 
+    // Z is left untouched, H and C come from bits 11 and 15
+    fn alu_add_hl(&mut self, value: u16) {
+        let hl = self.registers.hl();
+        let result = hl.wrapping_add(value);
+        self.registers.f.set(register::Flags::SUBTRACTION, false);
+        self.registers.f.set(
+            register::Flags::HALFCARRY,
+            (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF,
+        );
+        self.registers
+            .f
+            .set(register::Flags::CARRY, hl as u32 + value as u32 > 0xFFFF);
+        self.registers.set_hl(result);
+    }
+
+    // Shared by ADD SP,r8 and LD HL,SP+r8: the offset is signed, but H and C
+    // are computed as an unsigned addition on the low byte of SP
+    fn alu_add_sp(&mut self, offset: i8) -> u16 {
+        let sp = self.registers.sp.0;
+        let value = offset as u8 as u16;
+        self.registers.f.set(register::Flags::ZERO, false);
+        self.registers.f.set(register::Flags::SUBTRACTION, false);
+        self.registers.f.set(
+            register::Flags::HALFCARRY,
+            (sp & 0x000F) + (value & 0x000F) > 0x000F,
+        );
+        self.registers
+            .f
+            .set(register::Flags::CARRY, (sp & 0x00FF) + value > 0x00FF);
+        sp.wrapping_add(offset as i16 as u16)
+    }
+
+    // The incoming carry takes part in both the half-carry and carry checks
+    fn alu_adc(&mut self, value: u8) -> u8 {
+        let carry = self.registers.f.contains(register::Flags::CARRY) as u8;
+        let result = self.registers.a.wrapping_add(value).wrapping_add(carry);
+        self.registers.f.set(register::Flags::ZERO, result == 0);
+        self.registers.f.set(register::Flags::SUBTRACTION, false);
+        self.registers.f.set(
+            register::Flags::HALFCARRY,
+            (self.registers.a & 0x0F) + (value & 0x0F) + carry > 0x0F,
+        );
+        self.registers.f.set(
+            register::Flags::CARRY,
+            self.registers.a as u16 + value as u16 + carry as u16 > 0xFF,
+        );
+        result
+    }
+
+    fn alu_sbc(&mut self, value: u8) -> u8 {
+        let carry = self.registers.f.contains(register::Flags::CARRY) as u8;
+        let result = self.registers.a.wrapping_sub(value).wrapping_sub(carry);
+        self.registers.f.set(register::Flags::ZERO, result == 0);
+        self.registers.f.set(register::Flags::SUBTRACTION, true);
+        self.registers.f.set(
+            register::Flags::HALFCARRY,
+            (self.registers.a & 0x0F) < (value & 0x0F) + carry,
+        );
+        self.registers.f.set(
+            register::Flags::CARRY,
+            (self.registers.a as u16) < value as u16 + carry as u16,
+        );
+        result
+    }
+
     fn alu_add(&mut self, value: u8) -> u8 {
         let result = self.registers.a.wrapping_add(value);
         self.registers.f.set(register::Flags::ZERO, result == 0);
@@ -330,7 +1049,23 @@ impl Cpu {
         );
         result
     }
-    pub fn new(cartdrige: Box<dyn Cartdrige>) -> Self {
+
+    fn alu_sub(&mut self, value: u8) -> u8 {
+        let result = self.registers.a.wrapping_sub(value);
+        self.registers.f.set(register::Flags::ZERO, result == 0);
+        self.registers.f.set(register::Flags::SUBTRACTION, true);
+        self.registers.f.set(
+            register::Flags::HALFCARRY,
+            (self.registers.a & 0x0F) < (value & 0x0F),
+        );
+        self.registers
+            .f
+            .set(register::Flags::CARRY, self.registers.a < value);
+        result
+    }
+
+    /// With the registers the DMG boot ROM leaves behind
+    pub fn with_bus(bus: B) -> Self {
         Self {
             // Following DMG
             // https://gbdev.io/pandocs/Power_Up_Sequence.html?highlight=half#cpu-registers
@@ -346,41 +1081,322 @@ impl Cpu {
                 sp: StackPointer(0xFFFE),
                 pc: ProgramCounter(0x0100),
             },
-            cartdrige,
+            bus,
+            clock: Clock::new(),
+            rng_hook: None,
+            rom_patches: Vec::new(),
+            watched: Vec::new(),
+            accesses: Vec::new(),
+            ime: false,
+            ime_scheduled: false,
+            halted: false,
+            stopped: false,
+            cgb: false,
+            speed_switch_armed: false,
+            compat: Compat::new(),
+            halt_bug: false,
+            branch_taken: false,
+            locked: None,
+            accuracy: Accuracy::default(),
+            deferred: 0,
+        }
+    }
+}
+
+impl Cpu {
+    pub fn new(cartdrige: Box<dyn Cartdrige>) -> Self {
+        Self::with_bus(Mmu::new(cartdrige))
+    }
+
+    /// Resets to the power-on state and runs `boot_rom` from 0x0000 instead
+    /// of starting with the registers it leaves behind
+    pub fn boot(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        self.bus.map_boot_rom(boot_rom)?;
+        self.registers = Registers {
+            a: 0x00,
+            f: register::Flags::empty(),
+            b: 0x00,
+            c: 0x00,
+            d: 0x00,
+            e: 0x00,
+            h: 0x00,
+            l: 0x00,
+            sp: StackPointer(0x0000),
+            pc: ProgramCounter(0x0000),
+        };
+        self.bus.interrupts.flag = 0x00;
+        // the boot ROM sets up KEY0 and OPRI itself
+        self.compat = Compat::new();
+        self.sync_cgb_mode();
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            clock: self.clock,
+            interrupts: self.bus.interrupts,
+            joypad: self.bus.joypad.clone(),
+            serial: self.bus.serial.state(),
+            apu: self.bus.apu.clone(),
+            ppu: self.bus.ppu,
+            fifo: self.bus.renderer.fifo_line().cloned(),
+            ime: self.ime,
+            ime_scheduled: self.ime_scheduled,
+            halted: self.halted,
+            stopped: self.stopped,
+            cgb: self.cgb,
+            speed_switch_armed: self.speed_switch_armed,
+            compat: self.compat,
+            halt_bug: self.halt_bug,
+            locked: self.locked,
+            io: self.bus.io.clone(),
+            dma: self.bus.dma,
+            hdma: self.bus.hdma,
+            palettes: self.bus.palettes.clone(),
+            bank_state: self.bus.cartdrige.bank_state(),
+            mapper: self.bus.cartdrige.mapper_state(),
+            boot_rom_mapped: self.bus.boot_rom_mapped(),
+            ram: self.bus.cartdrige.ram().to_vec(),
+            vram: self.bus.vram.clone(),
+            wram: self.bus.wram.clone(),
+            vbk: self.bus.vbk,
+            svbk: self.bus.svbk,
+            oam: self.bus.oam.clone(),
+            hram: self.bus.hram.clone(),
+        }
+    }
+
+    /// Restores a state saved with the same cartridge inserted
+    pub fn load_state(&mut self, state: &CpuState) {
+        self.registers = state.registers;
+        self.clock = state.clock;
+        self.bus.interrupts = state.interrupts;
+        self.bus.joypad = state.joypad.clone();
+        self.bus.serial.set_state(state.serial);
+        self.bus.apu = state.apu.clone();
+        self.bus.ppu = state.ppu;
+        self.bus.renderer.set_fifo_line(state.fifo.clone());
+        self.ime = state.ime;
+        self.ime_scheduled = state.ime_scheduled;
+        self.halted = state.halted;
+        self.stopped = state.stopped;
+        self.cgb = state.cgb;
+        self.speed_switch_armed = state.speed_switch_armed;
+        self.compat = state.compat;
+        self.sync_cgb_mode();
+        self.halt_bug = state.halt_bug;
+        self.locked = state.locked;
+        self.bus.io = state.io.clone();
+        self.bus.dma = state.dma;
+        self.bus.hdma = state.hdma;
+        self.bus.palettes = state.palettes.clone();
+        self.bus.cartdrige.set_bank_state(state.bank_state);
+        self.bus.cartdrige.set_mapper_state(&state.mapper);
+        self.bus.set_boot_rom_mapped(state.boot_rom_mapped);
+        self.bus.cartdrige.ram_mut().copy_from_slice(&state.ram);
+        self.bus.vram.copy_from_slice(&state.vram);
+        self.bus.wram.copy_from_slice(&state.wram);
+        self.bus.vbk = state.vbk;
+        self.bus.svbk = state.svbk;
+        self.bus.oam.copy_from_slice(&state.oam);
+        self.bus.hram.copy_from_slice(&state.hram);
+    }
+
+    /// Initializes the memory that has no defined power-on value
+    pub fn fill_memory(&mut self, fill: MemoryFill) {
+        self.bus.fill_memory(fill);
+    }
+
+    /// Feeds everything that influences future execution to `state`
+    pub fn hash_state<H: Hasher>(&self, state: &mut H) {
+        self.registers.hash(state);
+        self.clock.hash(state);
+        self.bus.hash_state(state);
+        self.ime.hash(state);
+        self.ime_scheduled.hash(state);
+        self.halted.hash(state);
+        self.halt_bug.hash(state);
+        self.locked.map(|info| info.address).hash(state);
+        self.stopped.hash(state);
+        self.speed_switch_armed.hash(state);
+        self.compat.hash(state);
+    }
+}
+
+impl<B: Bus> Cpu<B> {
+    // Takes 5 machine cycles: two wait states, PC pushed on two, and one more
+    // to jump. The interrupt is only picked once the high byte of PC has been
+    // pushed, so a push overwriting IE can change it or cancel the dispatch
+    // altogether, in which case the CPU jumps to 0x0000.
+    // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+    fn dispatch_interrupt(&mut self) {
+        self.ime = false;
+        self.internal_cycle();
+        self.internal_cycle();
+        let pc = self.registers.pc.0;
+        self.registers.sp.0 = self.registers.sp.0.wrapping_sub(1);
+        self.write_cycle(self.registers.sp.0, (pc >> 8) as u8);
+        let interrupts = self.interrupts();
+        self.registers.sp.0 = self.registers.sp.0.wrapping_sub(1);
+        self.write_cycle(self.registers.sp.0, pc as u8);
+        self.registers.pc.0 = match interrupts.highest_priority() {
+            Some((interrupt, vector)) => {
+                self.set_interrupt_flag(interrupts.flag & !interrupt);
+                vector
+            }
+            None => 0x0000,
+        };
+        self.internal_cycle();
+    }
+
+    /// Address of the illegal opcode the CPU locked up on, if any
+    pub fn locked(&self) -> Option<u16> {
+        self.locked.map(|info| info.address)
+    }
+
+    /// Executes the next instruction, or waits 4 cycles when the CPU is
+    /// halted or locked up
+    pub fn step(&mut self) -> Result<StepInfo, CpuError> {
+        let start = self.clock.cycles();
+        let info = self.step_instruction();
+        // the whole instruction at once without per-access timing
+        let deferred = std::mem::take(&mut self.deferred);
+        if deferred > 0 {
+            self.advance(deferred);
+        }
+        let info = info?;
+        Ok(StepInfo {
+            cycles: (self.clock.cycles() - start) as u32,
+            ..info
+        })
+    }
+
+    fn step_instruction(&mut self) -> Result<StepInfo, CpuError> {
+        if self.bus.take_button_press() {
+            let flag = self.interrupts().flag;
+            self.set_interrupt_flag(flag | interrupt::JOYPAD);
+            self.stopped = false;
+        }
+        if self.stopped {
+            return Ok(StepInfo::new(
+                &INSTRUCTIONS[0x10],
+                self.registers.pc.value(),
+            ));
+        }
+        // waiting for a DMA transfer to release the bus, the same time in
+        // both speed modes for the ones on the bus
+        let stall = self.bus.take_stall();
+        if stall > 0 {
+            let factor = if self.clock.double_speed() { 2 } else { 1 };
+            self.clock.steal(stall * factor);
+        }
+        let stall = self.clock.take_stall();
+        if stall > 0 {
+            self.tick(stall);
+        }
+        if let Some(info) = self.locked {
+            // the rest of the system keeps running
+            self.tick(4);
+            return Ok(info);
         }
-    }
-
-    pub fn step(&mut self) -> &Instruction {
+        if self.halted {
+            // any pending interrupt wakes the CPU up, even with IME=0
+            if self.interrupts().pending() == 0 {
+                self.tick(4);
+                return Ok(StepInfo::new(
+                    &INSTRUCTIONS[0x76],
+                    self.registers.pc.value(),
+                ));
+            }
+            self.halted = false;
+        }
+        if self.ime && self.interrupts().pending() != 0 {
+            self.dispatch_interrupt();
+        }
+        // the first instruction of the handler runs in the same step
+        let address = self.registers.pc.value();
         let opcode = self.fetch();
-        let instruction = INSTRUCTION_MAP
-            .get(&opcode)
-            .expect(format!("Unknown opcode: {:#04x}", opcode).as_str());
-        (instruction.execute)(self);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc.0 = self.registers.pc.0.wrapping_sub(1);
+        }
+        let instruction = &INSTRUCTIONS[opcode as usize];
+        if instruction.is_illegal() {
+            // the undefined opcodes hang the CPU for good, interrupts included
+            warn!("CPU locked up on {:#04x} at {:#06x}", opcode, address);
+            let info = StepInfo::new(instruction, address);
+            self.locked = Some(info);
+            return Ok(info);
+        }
+        if instruction.op == Op::Prefix {
+            self.registers.pc.0 = address;
+            return Err(CpuError::UnknownOpcode { opcode, address });
+        }
+        self.branch_taken = false;
+        let enable_ime = self.ime_scheduled;
+        self.execute(instruction.op);
+        // DI or a second EI may have touched the schedule meanwhile
+        if enable_ime && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
         debug!("Opcode: {:#04x}", opcode);
-        debug!("Instruction: {:?}", instruction.mnemonic);
+        debug!("Instruction: {}", instruction.op);
         debug!("Registers: {:#?}", self.registers);
-        instruction
+        Ok(StepInfo::new(instruction, address))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cartdrige::RomOnly;
+    use crate::{bus::FlatBus, cartdrige::RomOnly, joypad, ppu, serial};
+
+    // Writable cartridge space so that tests can patch code in ROM
+    struct Ram(Vec<u8>);
+
+    impl Cartdrige for Ram {
+        fn read(&self, address: u16) -> u8 {
+            self.0[address as usize]
+        }
+
+        fn set(&mut self, address: u16, value: u8) {
+            self.0[address as usize] = value;
+        }
+
+        fn rom(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    fn cpu_with_program(program: &[u8]) -> Cpu {
+        let mut memory = vec![0x00; 0x10000];
+        memory[0x100..0x100 + program.len()].copy_from_slice(program);
+        Cpu::new(Box::new(Ram(memory)))
+    }
 
     #[test]
     fn test_cpu_step() {
         let mut cpu = Cpu::new(Box::new(RomOnly(vec![0x00; 0x101])));
-        cpu.step();
+        cpu.step().unwrap();
         assert_eq!(cpu.registers.pc.value(), 0x0101);
     }
 
+    #[test]
+    fn test_cpu_step_ticks_clock() {
+        let mut cpu = cpu_with_program(&[0x00, 0x21, 0x00, 0x00]); // NOP; LD HL,d16
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.clock.cycles(), 16);
+    }
+
     #[test]
     fn test_cpu_step_nop() {
         let mut cpu = Cpu::new(Box::new(RomOnly(vec![0x00; 0x101])));
         let tmp_registers = cpu.registers;
-        let instruction = cpu.step();
-        assert_eq!(instruction.mnemonic, "NOP");
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "NOP");
         assert_eq!(cpu.registers.pc.value(), 0x0101);
         assert_eq!(
             cpu.registers,
@@ -398,8 +1414,8 @@ mod tests {
         fake_rom_data[0x101] = 0xFF; // value to jump
         let mut cpu = Cpu::new(Box::new(RomOnly(fake_rom_data)));
         let tmp_registers = cpu.registers;
-        let instruction = cpu.step();
-        assert_eq!(instruction.mnemonic, "JP a16");
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "JP a16");
         assert_eq!(cpu.registers.pc.value(), 0xFF);
         assert_eq!(
             cpu.registers,
@@ -416,8 +1432,8 @@ mod tests {
         fake_rom_data[0x100] = 0xAF; // XOR A, A
         let mut cpu = Cpu::new(Box::new(RomOnly(fake_rom_data)));
         let tmp_registers = cpu.registers;
-        let instruction = cpu.step();
-        assert_eq!(instruction.mnemonic, "XOR A, A");
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "XOR A,A");
         assert_eq!(cpu.registers.pc.value(), 0x101);
         assert_eq!(
             cpu.registers,
@@ -438,8 +1454,8 @@ mod tests {
         fake_rom_data[0x101] = 0x34; // L register value
         let mut cpu = Cpu::new(Box::new(RomOnly(fake_rom_data)));
         let tmp_registers = cpu.registers;
-        let instruction = cpu.step();
-        assert_eq!(instruction.mnemonic, "LD HL,d16");
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "LD HL,d16");
         assert_eq!(cpu.registers.pc.value(), 0x103);
         assert_eq!(
             cpu.registers,
@@ -459,8 +1475,8 @@ mod tests {
         fake_rom_data[0x101] = 0x12; // C register value
         let mut cpu = Cpu::new(Box::new(RomOnly(fake_rom_data)));
         let tmp_registers = cpu.registers;
-        let instruction = cpu.step();
-        assert_eq!(instruction.mnemonic, "LD C,d8");
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "LD C,d8");
         assert_eq!(cpu.registers.pc.value(), 0x102);
         assert_eq!(
             cpu.registers,
@@ -479,8 +1495,8 @@ mod tests {
         fake_rom_data[0x101] = 0x12; // B register value
         let mut cpu = Cpu::new(Box::new(RomOnly(fake_rom_data)));
         let tmp_registers = cpu.registers;
-        let instruction = cpu.step();
-        assert_eq!(instruction.mnemonic, "LD B,d8");
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "LD B,d8");
         assert_eq!(cpu.registers.pc.value(), 0x102);
         assert_eq!(
             cpu.registers,
@@ -491,4 +1507,1127 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_cpu_step_call_a16() {
+        let mut cpu = cpu_with_program(&[0xCD, 0x34, 0x12]); // CALL 0x1234
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "CALL a16");
+        assert_eq!(cpu.registers.pc.value(), 0x1234);
+        assert_eq!(cpu.registers.sp.0, 0xFFFC);
+        // return address is the byte following the CALL
+        assert_eq!(
+            u16::from_le_bytes([cpu.read(0xFFFC), cpu.read(0xFFFD)]),
+            0x0103
+        );
+    }
+
+    #[test]
+    fn test_cpu_step_call_ret_roundtrip() {
+        let mut cpu = cpu_with_program(&[0xCD, 0x00, 0x02]); // CALL 0x0200
+        cpu.bus.cartdrige.set(0x0200, 0xC9); // RET
+        cpu.step().unwrap();
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "RET");
+        assert_eq!(cpu.registers.pc.value(), 0x0103);
+        assert_eq!(cpu.registers.sp.0, 0xFFFE);
+    }
+
+    #[test]
+    fn test_cpu_step_call_nz_not_taken() {
+        // Z is set after power-up, so the call must be skipped
+        let mut cpu = cpu_with_program(&[0xC4, 0x34, 0x12]); // CALL NZ,0x1234
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x0103);
+        assert_eq!(cpu.registers.sp.0, 0xFFFE);
+    }
+
+    #[test]
+    fn test_cpu_step_ret_cc() {
+        let mut cpu = cpu_with_program(&[0xC0, 0xC8]); // RET NZ; RET Z
+        cpu.push_word(0x4321);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x4321);
+        assert_eq!(cpu.registers.sp.0, 0xFFFE);
+    }
+
+    #[test]
+    fn test_cpu_step_reti_enables_ime() {
+        let mut cpu = cpu_with_program(&[0xD9]); // RETI
+        cpu.push_word(0x4321);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x4321);
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn test_cpu_step_rst() {
+        for (opcode, vector) in [
+            (0xC7, 0x00),
+            (0xCF, 0x08),
+            (0xD7, 0x10),
+            (0xDF, 0x18),
+            (0xE7, 0x20),
+            (0xEF, 0x28),
+            (0xF7, 0x30),
+            (0xFF, 0x38),
+        ] {
+            let mut cpu = cpu_with_program(&[opcode]);
+            cpu.step().unwrap();
+            assert_eq!(cpu.registers.pc.value(), vector);
+            assert_eq!(cpu.pop_word(), 0x0101);
+        }
+    }
+
+    #[test]
+    fn test_cpu_serial_goes_through_attached_device() {
+        // LD A,'!'; LD (HL-),A with HL pointing at SB
+        let mut cpu = cpu_with_program(&[0x3E, b'!', 0x32]);
+        let capture = serial::Capture::default();
+        let output = capture.output();
+        cpu.bus.serial.attach(Box::new(capture));
+        cpu.registers.set_hl(0xFF01);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.write(serial::SC, 0x81);
+        cpu.tick(serial::TRANSFER_CYCLES);
+        assert_eq!(*output.lock().unwrap(), b"!");
+        assert_ne!(cpu.bus.interrupts.flag & interrupt::SERIAL, 0);
+    }
+
+    #[test]
+    fn test_cpu_step_daa_vectors() {
+        // (A, N, H, C) before DAA => (A, C) after, with operands in comments
+        let vectors: [(u8, bool, bool, bool, u8, bool); 12] = [
+            (0x45, false, false, false, 0x45, false), // 0x22 + 0x23
+            (0x4A, false, false, false, 0x50, false), // 0x45 + 0x05
+            (0x9A, false, false, false, 0x00, true),  // 0x91 + 0x09
+            (0xA5, false, false, false, 0x05, true),  // 0x50 + 0x55
+            (0x42, false, true, false, 0x48, false),  // 0x39 + 0x09
+            (0x25, false, false, true, 0x85, true),   // 0x90 + 0x95
+            (0x32, false, true, true, 0x98, true),    // 0x99 + 0x99
+            (0x45, true, false, false, 0x45, false),  // 0x47 - 0x02
+            (0x0F, true, true, false, 0x09, false),   // 0x10 - 0x01
+            (0xA0, true, false, true, 0x40, true),    // 0x10 - 0x70
+            (0x9B, true, true, true, 0x35, true),     // 0x10 - 0x75
+            (0x00, true, false, false, 0x00, false),  // 0x10 - 0x10
+        ];
+        for (a, n, h, c, expected_a, expected_c) in vectors {
+            let mut cpu = cpu_with_program(&[0x27]); // DAA
+            cpu.registers.a = a;
+            cpu.registers.f = register::Flags::empty();
+            cpu.registers.f.set(register::Flags::SUBTRACTION, n);
+            cpu.registers.f.set(register::Flags::HALFCARRY, h);
+            cpu.registers.f.set(register::Flags::CARRY, c);
+            let instruction = cpu.step().unwrap().instruction;
+            assert_eq!(instruction.mnemonic(), "DAA");
+            assert_eq!(
+                cpu.registers.a, expected_a,
+                "A={:#04x} N={} H={} C={}",
+                a, n, h, c
+            );
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), expected_c);
+            assert_eq!(
+                cpu.registers.f.contains(register::Flags::ZERO),
+                expected_a == 0
+            );
+            // N is preserved, H is always cleared
+            assert_eq!(cpu.registers.f.contains(register::Flags::SUBTRACTION), n);
+            assert!(!cpu.registers.f.contains(register::Flags::HALFCARRY));
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_daa_after_add_is_bcd_sum() {
+        for x in 0..100u8 {
+            for y in 0..100u8 {
+                let bcd = |v: u8| (v / 10) << 4 | (v % 10);
+                let mut cpu = cpu_with_program(&[0x80, 0x27]); // ADD A,B; DAA
+                cpu.registers.a = bcd(x);
+                cpu.registers.b = bcd(y);
+                cpu.step().unwrap();
+                cpu.step().unwrap();
+                assert_eq!(cpu.registers.a, bcd((x + y) % 100));
+                assert_eq!(
+                    cpu.registers.f.contains(register::Flags::CARRY),
+                    x + y >= 100
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_inc_dec_rr() {
+        let mut cpu = cpu_with_program(&[0x03, 0x1B, 0x23, 0x3B]); // INC BC; DEC DE; INC HL; DEC SP
+        cpu.registers.set_bc(0xFFFF);
+        cpu.registers.set_de(0x0000);
+        cpu.registers.set_hl(0x12FF);
+        let flags = cpu.registers.f;
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.registers.bc(), 0x0000);
+        assert_eq!(cpu.registers.de(), 0xFFFF);
+        assert_eq!(cpu.registers.hl(), 0x1300);
+        assert_eq!(cpu.registers.sp.0, 0xFFFD);
+        // flags are never affected
+        assert_eq!(cpu.registers.f, flags);
+    }
+
+    #[test]
+    fn test_cpu_step_add_hl_rr_flags() {
+        // (HL, BC) => (HL, H, C)
+        let vectors = [
+            (0x0FFF, 0x0001, 0x1000, true, false),
+            (0x8000, 0x8000, 0x0000, false, true),
+            (0xFFFF, 0x0001, 0x0000, true, true),
+            (0x1234, 0x0101, 0x1335, false, false),
+        ];
+        for (hl, bc, expected, h, c) in vectors {
+            let mut cpu = cpu_with_program(&[0x09]); // ADD HL,BC
+            cpu.registers.set_hl(hl);
+            cpu.registers.set_bc(bc);
+            cpu.registers.f = register::Flags::ZERO | register::Flags::SUBTRACTION;
+            let instruction = cpu.step().unwrap().instruction;
+            assert_eq!(instruction.mnemonic(), "ADD HL,BC");
+            assert_eq!(cpu.registers.hl(), expected);
+            assert_eq!(cpu.registers.f.contains(register::Flags::HALFCARRY), h);
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), c);
+            // Z is preserved even when the result is zero, N is cleared
+            assert!(cpu.registers.f.contains(register::Flags::ZERO));
+            assert!(!cpu.registers.f.contains(register::Flags::SUBTRACTION));
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_add_hl_hl() {
+        let mut cpu = cpu_with_program(&[0x29]); // ADD HL,HL
+        cpu.registers.set_hl(0x4321);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.hl(), 0x8642);
+    }
+
+    #[test]
+    fn test_cpu_step_add_sp_r8_flags() {
+        // (SP, offset) => (SP, H, C)
+        let vectors = [
+            (0xFFF8, 0x08, 0x0000, true, true),
+            (0x000F, 0x01, 0x0010, true, false),
+            (0x00F0, 0x10, 0x0100, false, true),
+            // negative offsets still compute the flags on the unsigned low byte
+            (0x0000, 0xFF, 0xFFFF, false, false),
+            (0x0001, 0xFF, 0x0000, true, true),
+            (0x1234, 0x00, 0x1234, false, false),
+        ];
+        for (sp, offset, expected, h, c) in vectors {
+            let mut cpu = cpu_with_program(&[0xE8, offset]); // ADD SP,r8
+            cpu.registers.sp.0 = sp;
+            cpu.registers.f = register::Flags::all();
+            let instruction = cpu.step().unwrap().instruction;
+            assert_eq!(instruction.mnemonic(), "ADD SP,r8");
+            assert_eq!(cpu.registers.sp.0, expected);
+            assert_eq!(cpu.registers.pc.value(), 0x0102);
+            assert_eq!(cpu.registers.f.contains(register::Flags::HALFCARRY), h);
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), c);
+            assert!(!cpu.registers.f.contains(register::Flags::ZERO));
+            assert!(!cpu.registers.f.contains(register::Flags::SUBTRACTION));
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_ld_hl_sp_r8() {
+        let mut cpu = cpu_with_program(&[0xF8, 0xFE]); // LD HL,SP-2
+        cpu.registers.sp.0 = 0xD002;
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "LD HL,SP+r8");
+        assert_eq!(cpu.registers.hl(), 0xD000);
+        assert_eq!(cpu.registers.sp.0, 0xD002);
+        assert!(cpu.registers.f.contains(register::Flags::CARRY));
+        assert!(cpu.registers.f.contains(register::Flags::HALFCARRY));
+    }
+
+    #[test]
+    fn test_alu_adc() {
+        // (A, value, carry in) => (result, Z, H, C)
+        let vectors = [
+            (0x00, 0x00, false, 0x00, true, false, false),
+            (0x0E, 0x01, true, 0x10, false, true, false),
+            (0x0F, 0x00, true, 0x10, false, true, false),
+            (0xFF, 0x00, true, 0x00, true, true, true),
+            (0xF0, 0x0F, true, 0x00, true, true, true),
+            (0x80, 0x80, false, 0x00, true, false, true),
+            (0x12, 0x34, true, 0x47, false, false, false),
+        ];
+        for (a, value, carry, expected, z, h, c) in vectors {
+            let mut cpu = cpu_with_program(&[]);
+            cpu.registers.a = a;
+            cpu.registers.f = register::Flags::SUBTRACTION;
+            cpu.registers.f.set(register::Flags::CARRY, carry);
+            assert_eq!(cpu.alu_adc(value), expected);
+            assert_eq!(cpu.registers.f.contains(register::Flags::ZERO), z);
+            assert!(!cpu.registers.f.contains(register::Flags::SUBTRACTION));
+            assert_eq!(cpu.registers.f.contains(register::Flags::HALFCARRY), h);
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), c);
+        }
+    }
+
+    #[test]
+    fn test_alu_sbc() {
+        // (A, value, carry in) => (result, Z, H, C)
+        let vectors = [
+            (0x00, 0x00, false, 0x00, true, false, false),
+            (0x10, 0x00, true, 0x0F, false, true, false),
+            (0x10, 0x0F, true, 0x00, true, true, false),
+            (0x00, 0x00, true, 0xFF, false, true, true),
+            (0x00, 0xFF, true, 0x00, true, true, true),
+            (0x45, 0x23, true, 0x21, false, false, false),
+        ];
+        for (a, value, carry, expected, z, h, c) in vectors {
+            let mut cpu = cpu_with_program(&[]);
+            cpu.registers.a = a;
+            cpu.registers.f = register::Flags::empty();
+            cpu.registers.f.set(register::Flags::CARRY, carry);
+            assert_eq!(cpu.alu_sbc(value), expected);
+            assert_eq!(cpu.registers.f.contains(register::Flags::ZERO), z);
+            assert!(cpu.registers.f.contains(register::Flags::SUBTRACTION));
+            assert_eq!(cpu.registers.f.contains(register::Flags::HALFCARRY), h);
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), c);
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_adc_sbc_operands() {
+        // ADC A,d8; ADC A,(HL); SBC A,C; SBC A,d8
+        let mut cpu = cpu_with_program(&[0xCE, 0x01, 0x8E, 0x99, 0xDE, 0x01]);
+        cpu.registers.a = 0x10;
+        cpu.registers.c = 0x05;
+        cpu.registers.f = register::Flags::CARRY;
+        cpu.registers.set_hl(0xC000);
+        cpu.write(0xC000, 0x20);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "ADC A,d8");
+        assert_eq!(cpu.registers.a, 0x12);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "ADC A,(HL)");
+        assert_eq!(cpu.registers.a, 0x32);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "SBC A,C");
+        assert_eq!(cpu.registers.a, 0x2D);
+        cpu.registers.f.set(register::Flags::CARRY, true);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "SBC A,d8");
+        assert_eq!(cpu.registers.a, 0x2B);
+        assert_eq!(cpu.registers.pc.value(), 0x0106);
+    }
+
+    #[test]
+    fn test_cpu_step_accumulator_rotates() {
+        // (opcode, A, carry in) => (A, carry out)
+        let vectors = [
+            (0x07, 0x85, false, 0x0B, true), // RLCA
+            (0x07, 0x00, true, 0x00, false), // RLCA
+            (0x0F, 0x01, false, 0x80, true), // RRCA
+            (0x0F, 0x02, true, 0x01, false), // RRCA
+            (0x17, 0x80, false, 0x00, true), // RLA
+            (0x17, 0x40, true, 0x81, false), // RLA
+            (0x1F, 0x01, false, 0x00, true), // RRA
+            (0x1F, 0x02, true, 0x81, false), // RRA
+        ];
+        for (opcode, a, carry, expected, expected_carry) in vectors {
+            let mut cpu = cpu_with_program(&[opcode]);
+            cpu.registers.a = a;
+            cpu.registers.f = register::Flags::SUBTRACTION | register::Flags::HALFCARRY;
+            cpu.registers.f.set(register::Flags::CARRY, carry);
+            let instruction = cpu.step().unwrap().instruction;
+            assert_eq!(cpu.registers.a, expected, "{}", instruction.mnemonic());
+            assert_eq!(
+                cpu.registers.f.contains(register::Flags::CARRY),
+                expected_carry
+            );
+            // Z stays cleared even when the result is zero
+            assert!(!cpu.registers.f.contains(register::Flags::ZERO));
+            assert!(!cpu.registers.f.contains(register::Flags::SUBTRACTION));
+            assert!(!cpu.registers.f.contains(register::Flags::HALFCARRY));
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_jr() {
+        // JR -2 loops on itself
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "JR r8");
+        assert_eq!(cpu.registers.pc.value(), 0x0100);
+        // JR Z,+5 with Z set after power-up
+        let mut cpu = cpu_with_program(&[0x28, 0x05]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x0107);
+    }
+
+    #[test]
+    fn test_cpu_step_jp_cc() {
+        let mut cpu = cpu_with_program(&[0xC2, 0x00, 0x20, 0xCA, 0x00, 0x30]); // JP NZ; JP Z
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x0103);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x3000);
+    }
+
+    #[test]
+    fn test_cpu_step_conditional_cycles() {
+        // program => cycles when the branch is not taken and when it is
+        let vectors: [(&[u8], u64, u64); 6] = [
+            (&[0x20, 0x00], 8, 12),        // JR NZ,r8
+            (&[0x38, 0x00], 8, 12),        // JR C,r8
+            (&[0xC2, 0x00, 0x00], 12, 16), // JP NZ,a16
+            (&[0xDC, 0x00, 0x00], 12, 24), // CALL C,a16
+            (&[0xC0], 8, 20),              // RET NZ
+            (&[0xD8], 8, 20),              // RET C
+        ];
+        for (program, not_taken, taken) in vectors {
+            // Z set and C cleared: every NZ/C condition fails
+            let mut cpu = cpu_with_program(program);
+            cpu.registers.f = register::Flags::ZERO;
+            let info = cpu.step().unwrap();
+            assert_eq!(
+                info.cycles as u64,
+                not_taken,
+                "{}",
+                info.instruction.mnemonic()
+            );
+            assert_eq!(cpu.clock.cycles(), not_taken);
+
+            let mut cpu = cpu_with_program(program);
+            cpu.registers.f = register::Flags::CARRY;
+            let info = cpu.step().unwrap();
+            assert_eq!(info.cycles as u64, taken, "{}", info.instruction.mnemonic());
+            assert_eq!(cpu.clock.cycles(), taken);
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_halt_until_interrupt() {
+        let mut cpu = cpu_with_program(&[0x76, 0x05]); // HALT; DEC B
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        for _ in 0..3 {
+            assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "HALT");
+        }
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        assert_eq!(cpu.clock.cycles(), 16);
+        // requesting an enabled interrupt resumes execution, even with IME=0
+        cpu.write(interrupt::IE, 0x01);
+        cpu.write(interrupt::IF, 0x01);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "DEC B");
+        assert!(!cpu.halted);
+        assert_eq!(cpu.registers.pc.value(), 0x0102);
+    }
+
+    #[test]
+    fn test_cpu_step_halt_ignores_disabled_interrupts() {
+        let mut cpu = cpu_with_program(&[0x76]); // HALT
+        cpu.step().unwrap();
+        cpu.write(interrupt::IF, 0x1F);
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn test_cpu_step_halt_bug() {
+        let mut cpu = cpu_with_program(&[0x76, 0x05, 0x00]); // HALT; DEC B; NOP
+        cpu.write(interrupt::IE, 0x04);
+        cpu.write(interrupt::IF, 0x04);
+        cpu.step().unwrap();
+        assert!(!cpu.halted);
+        // DEC B is executed twice because PC did not move after the first fetch
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "DEC B");
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "DEC B");
+        assert_eq!(cpu.registers.pc.value(), 0x0102);
+        assert_eq!(cpu.registers.b, 0xFE);
+    }
+
+    #[test]
+    fn test_cpu_step_stop_until_button_press() {
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x05]); // STOP; DEC B
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "STOP 0");
+        assert!(cpu.stopped);
+        assert_eq!(cpu.registers.pc.value(), 0x0102);
+        let cycles = cpu.clock.cycles();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // the clocks do not run while stopped
+        assert_eq!(cpu.clock.cycles(), cycles);
+        cpu.bus.joypad.press(joypad::Button::Start);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "DEC B");
+        assert!(!cpu.stopped);
+        assert_ne!(cpu.bus.interrupts.flag & interrupt::JOYPAD, 0);
+    }
+
+    #[test]
+    fn test_cpu_boot_rom_unmaps_itself() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0000] = 0xAA;
+        let mut cpu = Cpu::new(Box::new(RomOnly(rom)));
+        assert!(cpu.boot(vec![0x00; 0x80]).is_err());
+        // NOPs up to LD A,0x01; LDH (0x50),A
+        let mut boot_rom = vec![0x00; mmu::BOOT_ROM_SIZE];
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        cpu.boot(boot_rom).unwrap();
+        assert_eq!(cpu.registers.pc.value(), 0x0000);
+        assert_eq!(cpu.read(0x0000), 0x00);
+        while cpu.registers.pc.value() < 0x0100 {
+            cpu.step().unwrap();
+        }
+        assert!(!cpu.bus.boot_rom_mapped());
+        assert_eq!(cpu.read(0x0000), 0xAA);
+        // for good
+        cpu.write(mmu::BOOT, 0x00);
+        assert_eq!(cpu.read(0x0000), 0xAA);
+    }
+
+    #[test]
+    fn test_cpu_step_stop_speed_switch() {
+        let mut cpu = cpu_with_program(&[0x10, 0x00]); // STOP
+        cpu.cgb = true;
+        assert_eq!(cpu.read(KEY1), 0x7E);
+        cpu.write(KEY1, 0x01);
+        assert_eq!(cpu.read(KEY1), 0x7F);
+        cpu.step().unwrap();
+        assert!(!cpu.stopped);
+        assert!(cpu.clock.double_speed());
+        assert_eq!(cpu.read(KEY1), 0xFE);
+    }
+
+    #[test]
+    fn test_cpu_step_ei_delay() {
+        let mut cpu = cpu_with_program(&[0xFB, 0x00, 0x00]); // EI; NOP; NOP
+        cpu.step().unwrap();
+        // not enabled right after EI
+        assert!(!cpu.ime);
+        cpu.step().unwrap();
+        assert!(cpu.ime);
+    }
+
+    #[test]
+    fn test_cpu_step_di_cancels_ei() {
+        let mut cpu = cpu_with_program(&[0xFB, 0xF3, 0x00]); // EI; DI; NOP
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(!cpu.ime);
+        cpu.step().unwrap();
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn test_cpu_step_di() {
+        let mut cpu = cpu_with_program(&[0xF3]); // DI
+        cpu.ime = true;
+        let instruction = cpu.step().unwrap().instruction;
+        assert_eq!(instruction.mnemonic(), "DI");
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn test_cpu_step_ei_ei() {
+        let mut cpu = cpu_with_program(&[0xFB, 0xFB, 0x00]); // EI; EI; NOP
+        cpu.step().unwrap();
+        assert!(!cpu.ime);
+        cpu.step().unwrap();
+        assert!(cpu.ime);
+    }
+
+    // DEC B at each of the five vectors
+    fn cpu_with_handlers(program: &[u8]) -> Cpu {
+        let mut cpu = cpu_with_program(program);
+        for vector in (0x0040..=0x0060).step_by(8) {
+            cpu.write(vector, 0x05);
+        }
+        cpu.registers.sp.0 = 0xD000;
+        cpu.bus.interrupts.flag = 0x00;
+        cpu
+    }
+
+    #[test]
+    fn test_cpu_step_dispatch_interrupt() {
+        let mut cpu = cpu_with_handlers(&[0x00, 0x00]);
+        cpu.ime = true;
+        cpu.write(interrupt::IE, 0x1F);
+        cpu.bus
+            .interrupts
+            .request(interrupt::SERIAL | interrupt::JOYPAD);
+        let info = cpu.step().unwrap();
+        // the handler's first instruction runs in the same step
+        assert_eq!(info.address, 0x0058);
+        assert_eq!(info.instruction.mnemonic(), "DEC B");
+        assert_eq!(info.cycles, 24);
+        assert_eq!(cpu.clock.cycles(), 24);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.bus.interrupts.flag, interrupt::JOYPAD);
+        assert_eq!(cpu.registers.sp.0, 0xCFFE);
+        assert_eq!(cpu.pop_word(), 0x0100);
+    }
+
+    #[test]
+    fn test_cpu_on_flat_bus() {
+        // LD HL,0xC000; LD (HL),0x42; INC (HL)
+        let bus = FlatBus::with_program(0x0100, &[0x21, 0x00, 0xC0, 0x36, 0x42, 0x34]);
+        let mut cpu = Cpu::with_bus(bus);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.bus.read8(0xC000), 0x43);
+        assert_eq!(cpu.bus.cycles, 12 + 12 + 12);
+        // interrupts go through IF and IE in memory
+        cpu.ime = true;
+        cpu.registers.sp.0 = 0xD000;
+        cpu.bus.write8(interrupt::IE, interrupt::TIMER);
+        cpu.bus
+            .write8(interrupt::IF, interrupt::TIMER | interrupt::SERIAL);
+        assert_eq!(cpu.step().unwrap().address, 0x0050);
+        assert_eq!(cpu.bus.read8(interrupt::IF), interrupt::SERIAL);
+        assert_eq!(cpu.bus.read16(0xCFFE), 0x0106);
+    }
+
+    #[test]
+    fn test_cpu_step_interrupt_after_ei_delay() {
+        let mut cpu = cpu_with_handlers(&[0xFB, 0x00, 0x00]); // EI; NOP; NOP
+        cpu.write(interrupt::IE, interrupt::TIMER);
+        cpu.bus.interrupts.request(interrupt::TIMER);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().address, 0x0101);
+        assert_eq!(cpu.step().unwrap().address, 0x0050);
+    }
+
+    #[test]
+    fn test_cpu_step_halt_dispatches_with_ime() {
+        let mut cpu = cpu_with_handlers(&[0x76, 0x00]); // HALT; NOP
+        cpu.ime = true;
+        cpu.write(interrupt::IE, interrupt::VBLANK);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        cpu.bus.interrupts.request(interrupt::VBLANK);
+        assert_eq!(cpu.step().unwrap().address, 0x0040);
+        assert_eq!(cpu.pop_word(), 0x0101);
+    }
+
+    #[test]
+    fn test_cpu_step_dispatch_cancelled_by_ie_push() {
+        // the high byte of PC lands in IE and disables every interrupt
+        let mut cpu = cpu_with_handlers(&[]);
+        cpu.registers.pc.0 = 0x00C0;
+        cpu.registers.sp.0 = 0x0000;
+        cpu.ime = true;
+        cpu.write(interrupt::IE, interrupt::VBLANK);
+        cpu.bus.interrupts.request(interrupt::VBLANK);
+        assert_eq!(cpu.step().unwrap().address, 0x0000);
+        assert_eq!(cpu.bus.interrupts.flag, interrupt::VBLANK);
+    }
+
+    #[test]
+    fn test_cpu_step_ldh() {
+        // LDH (0x80),A; LD A,d8; LDH A,(0x80)
+        let mut cpu = cpu_with_program(&[0xE0, 0x80, 0x3E, 0x00, 0xF0, 0x80]);
+        cpu.registers.a = 0x42;
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "LDH (a8),A");
+        assert_eq!(cpu.read(0xFF80), 0x42);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "LDH A,(a8)");
+        assert_eq!(cpu.registers.a, 0x42);
+        assert_eq!(cpu.registers.pc.value(), 0x0106);
+    }
+
+    #[test]
+    fn test_cpu_step_ld_c_indirect() {
+        // LD (C),A; LD A,(C)
+        let mut cpu = cpu_with_program(&[0xE2, 0xF2]);
+        // C points at IE
+        cpu.registers.c = 0xFF;
+        cpu.registers.a = 0x15;
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "LD (C),A");
+        assert_eq!(cpu.bus.interrupts.enable, 0x15);
+        // and now at IF, whose upper bits read as 1
+        cpu.registers.c = 0x0F;
+        cpu.bus.interrupts.flag = 0x04;
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "LD A,(C)");
+        assert_eq!(cpu.registers.a, 0xE4);
+        assert_eq!(cpu.registers.pc.value(), 0x0102);
+    }
+
+    #[test]
+    fn test_cpu_step_ld_a16_sp() {
+        let mut cpu = cpu_with_program(&[0x08, 0x00, 0xC0]); // LD (0xC000),SP
+        cpu.registers.sp.0 = 0xBEEF;
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "LD (a16),SP");
+        assert_eq!(cpu.read(0xC000), 0xEF);
+        assert_eq!(cpu.read(0xC001), 0xBE);
+        assert_eq!(cpu.registers.pc.value(), 0x0103);
+        assert_eq!(cpu.clock.cycles(), 20);
+    }
+
+    #[test]
+    fn test_cpu_step_ld_a16_a() {
+        // LD (0xC123),A; LD A,d8; LD A,(0xC123)
+        let mut cpu = cpu_with_program(&[0xEA, 0x23, 0xC1, 0x3E, 0x00, 0xFA, 0x23, 0xC1]);
+        cpu.registers.a = 0x99;
+        cpu.step().unwrap();
+        assert_eq!(cpu.read(0xC123), 0x99);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x99);
+        assert_eq!(cpu.registers.pc.value(), 0x0108);
+    }
+
+    #[test]
+    fn test_cpu_step_ld_rr_indirect() {
+        // LD (BC),A; LD (DE),A; LD A,(BC); LD A,(DE)
+        let mut cpu = cpu_with_program(&[0x02, 0x12, 0x0A, 0x1A]);
+        cpu.registers.set_bc(0xC000);
+        cpu.registers.set_de(0xC001);
+        cpu.registers.a = 0x11;
+        cpu.step().unwrap();
+        cpu.registers.a = 0x22;
+        cpu.step().unwrap();
+        assert_eq!(cpu.read(0xC000), 0x11);
+        assert_eq!(cpu.read(0xC001), 0x22);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x11);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x22);
+    }
+
+    #[test]
+    fn test_cpu_step_ld_hl_increment_decrement() {
+        // LD (HL+),A; LD (HL+),A; LD A,(HL-); LD A,(HL-); LD A,(HL+)
+        let mut cpu = cpu_with_program(&[0x22, 0x22, 0x3A, 0x3A, 0x2A]);
+        cpu.registers.set_hl(0xC000);
+        cpu.registers.a = 0x55;
+        cpu.step().unwrap();
+        cpu.registers.a = 0x66;
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.hl(), 0xC002);
+        assert_eq!(cpu.read(0xC000), 0x55);
+        assert_eq!(cpu.read(0xC001), 0x66);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x66);
+        assert_eq!(cpu.registers.hl(), 0xC000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x55);
+        assert_eq!(cpu.registers.hl(), 0xC001);
+    }
+
+    #[test]
+    fn test_cpu_step_logic_ops() {
+        use register::Flags;
+        // (opcode, a, b, result, flags)
+        let cases = [
+            (0xA0, 0xF0, 0x3C, 0x30, Flags::HALFCARRY), // AND A,B
+            (0xA0, 0xF0, 0x0F, 0x00, Flags::ZERO | Flags::HALFCARRY), // AND A,B
+            (0xA8, 0xFF, 0x0F, 0xF0, Flags::empty()),   // XOR A,B
+            (0xA8, 0x5A, 0x5A, 0x00, Flags::ZERO),      // XOR A,B
+            (0xB0, 0x50, 0x05, 0x55, Flags::empty()),   // OR A,B
+            (0xB0, 0x00, 0x00, 0x00, Flags::ZERO),      // OR A,B
+            (0xB8, 0x42, 0x42, 0x42, Flags::ZERO | Flags::SUBTRACTION), // CP A,B
+            (
+                0xB8,
+                0x10,
+                0x01,
+                0x10,
+                Flags::SUBTRACTION | Flags::HALFCARRY,
+            ), // CP A,B
+            (0xB8, 0x10, 0x20, 0x10, Flags::SUBTRACTION | Flags::CARRY), // CP A,B
+        ];
+        for (opcode, a, b, result, flags) in cases {
+            let mut cpu = cpu_with_program(&[opcode]);
+            cpu.registers.a = a;
+            cpu.registers.b = b;
+            cpu.registers.f = Flags::all();
+            cpu.step().unwrap();
+            assert_eq!(cpu.registers.a, result, "opcode {:#04x}", opcode);
+            assert_eq!(cpu.registers.f, flags, "opcode {:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_logic_ops_operands() {
+        // AND A,(HL); OR A,d8; XOR A,d8; CP A,d8
+        let mut cpu = cpu_with_program(&[0xA6, 0xF6, 0x81, 0xEE, 0xFF, 0xFE, 0x72]);
+        cpu.registers.set_hl(0xC000);
+        cpu.write(0xC000, 0x0F);
+        cpu.registers.a = 0x3C;
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "AND A,(HL)");
+        assert_eq!(cpu.registers.a, 0x0C);
+        assert_eq!(cpu.clock.cycles(), 8);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x8D);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x72);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x72);
+        assert!(cpu.registers.f.contains(register::Flags::ZERO));
+        assert!(!cpu.registers.f.contains(register::Flags::CARRY));
+        assert_eq!(cpu.registers.pc.value(), 0x0107);
+    }
+
+    #[test]
+    fn test_cpu_logic_ops_cover_every_operand() {
+        for opcode in (0xA0..=0xBF).chain([0xE6, 0xEE, 0xF6, 0xFE]) {
+            assert!(!INSTRUCTIONS[opcode].is_illegal(), "{:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_alu_add() {
+        // (A, value) => (result, Z, H, C)
+        let vectors = [
+            (0x00, 0x00, 0x00, true, false, false),
+            (0x0F, 0x01, 0x10, false, true, false),
+            (0xFF, 0x01, 0x00, true, true, true),
+            (0xF0, 0x10, 0x00, true, false, true),
+            (0x08, 0x08, 0x10, false, true, false),
+            (0x12, 0x34, 0x46, false, false, false),
+        ];
+        for (a, value, expected, z, h, c) in vectors {
+            let mut cpu = cpu_with_program(&[]);
+            cpu.registers.a = a;
+            cpu.registers.f = register::Flags::SUBTRACTION;
+            assert_eq!(cpu.alu_add(value), expected);
+            assert_eq!(cpu.registers.f.contains(register::Flags::ZERO), z);
+            assert!(!cpu.registers.f.contains(register::Flags::SUBTRACTION));
+            assert_eq!(cpu.registers.f.contains(register::Flags::HALFCARRY), h);
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), c);
+        }
+    }
+
+    #[test]
+    fn test_alu_sub() {
+        // (A, value) => (result, Z, H, C)
+        let vectors = [
+            (0x10, 0x01, 0x0F, false, true, false),
+            (0x00, 0x01, 0xFF, false, true, true),
+            (0x42, 0x42, 0x00, true, false, false),
+            (0x10, 0x20, 0xF0, false, false, true),
+            (0x3E, 0x0F, 0x2F, false, true, false),
+            (0x0F, 0x0F, 0x00, true, false, false),
+        ];
+        for (a, value, expected, z, h, c) in vectors {
+            let mut cpu = cpu_with_program(&[]);
+            cpu.registers.a = a;
+            cpu.registers.f = register::Flags::empty();
+            assert_eq!(cpu.alu_sub(value), expected);
+            assert_eq!(cpu.registers.f.contains(register::Flags::ZERO), z);
+            assert!(cpu.registers.f.contains(register::Flags::SUBTRACTION));
+            assert_eq!(cpu.registers.f.contains(register::Flags::HALFCARRY), h);
+            assert_eq!(cpu.registers.f.contains(register::Flags::CARRY), c);
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_add_sub_operands() {
+        // ADD A,C; ADD A,d8; SUB A,(HL); SUB A,A
+        let mut cpu = cpu_with_program(&[0x81, 0xC6, 0x20, 0x96, 0x97]);
+        cpu.registers.a = 0x01;
+        cpu.registers.c = 0x02;
+        cpu.registers.set_hl(0xC000);
+        cpu.write(0xC000, 0x13);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "ADD A,C");
+        assert_eq!(cpu.registers.a, 0x03);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "ADD A,d8");
+        assert_eq!(cpu.registers.a, 0x23);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "SUB A,(HL)");
+        assert_eq!(cpu.registers.a, 0x10);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "SUB A,A");
+        assert_eq!(cpu.registers.a, 0x00);
+        assert!(cpu.registers.f.contains(register::Flags::ZERO));
+        assert_eq!(cpu.registers.pc.value(), 0x0105);
+        assert_eq!(cpu.clock.cycles(), 4 + 8 + 8 + 4);
+    }
+
+    #[test]
+    fn test_cpu_step_scf_ccf() {
+        use register::Flags;
+        // SCF; CCF; CCF
+        let mut cpu = cpu_with_program(&[0x37, 0x3F, 0x3F]);
+        cpu.registers.f = Flags::ZERO | Flags::SUBTRACTION | Flags::HALFCARRY;
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "SCF");
+        assert_eq!(cpu.registers.f, Flags::ZERO | Flags::CARRY);
+        assert_eq!(cpu.step().unwrap().instruction.mnemonic(), "CCF");
+        assert_eq!(cpu.registers.f, Flags::ZERO);
+        cpu.registers
+            .f
+            .insert(Flags::SUBTRACTION | Flags::HALFCARRY);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.f, Flags::ZERO | Flags::CARRY);
+        assert_eq!(cpu.clock.cycles(), 12);
+    }
+
+    #[test]
+    fn test_cpu_step_pop_af_masks_flags() {
+        // PUSH BC; POP AF; PUSH AF; POP DE
+        let mut cpu = cpu_with_program(&[0xC5, 0xF1, 0xF5, 0xD1]);
+        cpu.registers.sp.0 = 0xD000;
+        cpu.registers.set_bc(0x12FF);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x12);
+        assert_eq!(cpu.registers.f.bits(), 0xF0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.de(), 0x12F0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_cpu_state_serde_roundtrip() {
+        let mut cpu = cpu_with_program(&[0xFB, 0x76]); // EI; HALT
+        cpu.write(0xC123, 0x45);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let json = serde_json::to_string(&cpu.save_state()).unwrap();
+
+        let mut restored = cpu_with_program(&[]);
+        restored.load_state(&serde_json::from_str(&json).unwrap());
+        assert!(restored.ime && restored.halted);
+        assert_eq!(restored.registers, cpu.registers);
+        assert_eq!(restored.read(0xC123), 0x45);
+        assert_eq!(serde_json::to_string(&restored.save_state()).unwrap(), json);
+        // HRAM has a fixed size
+        let truncated = json.replace("\"hram\":[0,", "\"hram\":[");
+        assert!(serde_json::from_str::<CpuState>(&truncated).is_err());
+        // nor can F hold its low nibble
+        let flags: register::Flags = serde_json::from_str("255").unwrap();
+        assert_eq!(flags.bits(), 0xF0);
+    }
+
+    #[test]
+    fn test_cpu_reads_ly_on_access_cycle() {
+        // LDH A,(LY) reads on its third machine cycle
+        let vblank = ppu::VBLANK_LINE * ppu::DOTS_PER_LINE;
+        let mut cpu = cpu_with_program(&[0xF0, 0x44]);
+        cpu.bus.ppu.tick(vblank - 13);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 143);
+        let mut cpu = cpu_with_program(&[0xF0, 0x44]);
+        cpu.bus.ppu.tick(vblank - 12);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 144);
+        // LY is read-only
+        cpu.write(ppu::LY, 0x00);
+        assert_eq!(cpu.read(ppu::LY), 144);
+    }
+
+    #[test]
+    fn test_cpu_fast_accuracy_ticks_once_per_step() {
+        // LDH A,(LY) reads before the rest of the system moves on
+        let vblank = ppu::VBLANK_LINE * ppu::DOTS_PER_LINE;
+        let mut cpu = cpu_with_program(&[0xF0, 0x44]);
+        cpu.accuracy = Accuracy::Fast;
+        cpu.bus.ppu.tick(vblank - 12);
+        assert_eq!(cpu.step().unwrap().cycles, 12);
+        assert_eq!(cpu.registers.a, 143);
+        assert_eq!(cpu.clock.cycles(), 12);
+        assert_eq!(cpu.bus.ppu.ly(), 144);
+    }
+
+    #[test]
+    fn test_cpu_rng_hook() {
+        // LDH A,(DIV); LDH A,(DIV)
+        let mut cpu = cpu_with_program(&[0xF0, 0x04, 0xF0, 0x04]);
+        let div = cpu.read(rng::DIV);
+        cpu.set_rng_hook(Some(Box::new(|frame: u64, address: u16, value: u8| {
+            assert_eq!((frame, address), (0, rng::DIV));
+            value ^ 0xFF
+        })));
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, div ^ 0xFF);
+        // debugger reads are left alone
+        assert_eq!(cpu.read(rng::DIV), div);
+        assert!(cpu.set_rng_hook(None).is_some());
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, div);
+    }
+
+    #[test]
+    fn test_cpu_step_waits_for_stolen_cycles() {
+        let mut cpu = cpu_with_program(&[0x00, 0x00]);
+        cpu.write(serial::SC, 0x81);
+        cpu.clock.steal(serial::TRANSFER_CYCLES);
+        assert_eq!(cpu.step().unwrap().cycles, serial::TRANSFER_CYCLES + 4);
+        assert_eq!(cpu.clock.cycles(), serial::TRANSFER_CYCLES as u64 + 4);
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        // the serial port kept shifting during the stall
+        assert_ne!(cpu.bus.interrupts.flag & interrupt::SERIAL, 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.clock.cycles(), serial::TRANSFER_CYCLES as u64 + 8);
+    }
+
+    #[test]
+    fn test_cpu_step_unknown_opcode() {
+        // CB prefixed instructions are not implemented
+        let mut cpu = cpu_with_program(&[0x00, 0xCB, 0x37]);
+        cpu.step().unwrap();
+        let error = CpuError::UnknownOpcode {
+            opcode: 0xCB,
+            address: 0x0101,
+        };
+        assert_eq!(cpu.step().unwrap_err(), error);
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        // stepping again reports the same fault instead of running past it
+        assert_eq!(cpu.step().unwrap_err(), error);
+        assert_eq!(error.to_string(), "Unknown opcode 0xcb at 0x0101");
+    }
+
+    #[test]
+    fn test_cpu_step_illegal_opcode_locks_up() {
+        let mut cpu = cpu_with_program(&[0xDD, 0x00]);
+        cpu.ime = true;
+        cpu.bus.interrupts.enable = interrupt::JOYPAD;
+        let info = cpu.step().unwrap();
+        assert_eq!(info.instruction.opcode, 0xDD);
+        assert_eq!(cpu.locked(), Some(0x0100));
+        // neither interrupts nor input bring it back
+        cpu.bus.joypad.press(crate::joypad::Button::Start);
+        for _ in 0..10 {
+            let info = cpu.step().unwrap();
+            assert!(info.instruction.is_illegal());
+            assert_eq!(info.address, 0x0100);
+        }
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        assert_eq!(cpu.clock.cycles(), 44);
+        assert!(!cpu.stopped);
+    }
+
+    #[test]
+    fn test_cpu_step_cycles_match_instruction_table() {
+        // elapsed time is only made of the machine cycles the instruction
+        // went through, so it has to add up to the documented timing
+        for flags in [register::Flags::empty(), register::Flags::all()] {
+            for instruction in INSTRUCTIONS
+                .iter()
+                .filter(|i| !matches!(i.op, Op::Prefix | Op::Illegal | Op::Halt))
+            {
+                let mut cpu = cpu_with_program(&[instruction.opcode, 0x00, 0x00]);
+                cpu.registers.sp.0 = 0xD000;
+                cpu.registers.f = flags;
+                cpu.step().unwrap();
+                let expected = if cpu.branch_taken {
+                    instruction.cycles_taken
+                } else {
+                    instruction.cycles
+                };
+                assert_eq!(
+                    cpu.clock.cycles(),
+                    expected as u64,
+                    "{} ({:#04x})",
+                    instruction.mnemonic(),
+                    instruction.opcode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_advances_pc_by_length() {
+        // with no branch taken every instruction must consume exactly its
+        // operands, whatever the addressing mode
+        for instruction in INSTRUCTIONS
+            .iter()
+            .filter(|i| !matches!(i.op, Op::Prefix | Op::Illegal))
+        {
+            let mut cpu = cpu_with_program(&[instruction.opcode, 0x00, 0x00]);
+            cpu.registers.sp.0 = 0xD000;
+            cpu.registers.f = register::Flags::empty();
+            cpu.step().unwrap();
+            if cpu.branch_taken || matches!(instruction.op, Op::Rst(_) | Op::JpHl) {
+                continue;
+            }
+            assert_eq!(
+                cpu.registers.pc.value(),
+                0x0100 + instruction.length as u16,
+                "{} ({:#04x})",
+                instruction.mnemonic(),
+                instruction.opcode
+            );
+        }
+    }
+
+    #[test]
+    fn test_cpu_step_addressing_modes() {
+        // register: LD D,B; immediate: LD B,d8; indirect: LD (HL),B;
+        // immediate word: LD HL,d16; absolute: LD A,(a16); implied: CPL
+        let mut cpu = cpu_with_program(&[
+            0x50, 0x06, 0x42, 0x70, 0x21, 0x00, 0xC0, 0xFA, 0x00, 0xC0, 0x2F,
+        ]);
+        cpu.registers.b = 0x11;
+        cpu.registers.set_hl(0xC000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.d, 0x11);
+        assert_eq!(cpu.registers.pc.value(), 0x0101);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.b, 0x42);
+        assert_eq!(cpu.registers.pc.value(), 0x0103);
+        cpu.step().unwrap();
+        assert_eq!(cpu.read(0xC000), 0x42);
+        assert_eq!(cpu.registers.pc.value(), 0x0104);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.hl(), 0xC000);
+        assert_eq!(cpu.registers.pc.value(), 0x0107);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, 0x42);
+        assert_eq!(cpu.registers.pc.value(), 0x010A);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.a, !0x42);
+        assert_eq!(cpu.registers.pc.value(), 0x010B);
+    }
+
+    #[test]
+    fn test_cpu_fetch_wraps_around() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.registers.pc.0 = 0xFFFF;
+        cpu.fetch();
+        assert_eq!(cpu.registers.pc.value(), 0x0000);
+        cpu.registers.pc.0 = 0xFFFF;
+        cpu.fetch_word();
+        assert_eq!(cpu.registers.pc.value(), 0x0001);
+    }
+
+    #[test]
+    fn test_cpu_fill_memory() {
+        let mut cpu = cpu_with_program(&[]);
+        assert_eq!(cpu.read(0xC000), 0x00);
+        cpu.fill_memory(MemoryFill::Ones);
+        for address in [0x8000, 0x9FFF, 0xC000, 0xDFFF, 0xFF80, 0xFFFE] {
+            assert_eq!(cpu.read(address), 0xFF, "{:#06x}", address);
+        }
+        // the cartridge is left alone
+        assert_eq!(cpu.read(0xA000), 0x00);
+        cpu.write(0xC123, 0x42);
+        assert_eq!(cpu.read(0xC123), 0x42);
+        assert_eq!(cpu.bus.cartdrige.read(0xC123), 0x00);
+    }
+
+    #[test]
+    fn test_instruction_table() {
+        for (index, instruction) in INSTRUCTIONS.iter().enumerate() {
+            assert_eq!(
+                instruction.opcode as usize,
+                index,
+                "{}",
+                instruction.mnemonic()
+            );
+            assert!((1..=3).contains(&instruction.length), "{:#04x}", index);
+            assert!(
+                instruction.cycles_taken >= instruction.cycles,
+                "{:#04x}",
+                index
+            );
+        }
+        // opcodes that don't exist on the hardware
+        for opcode in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ] {
+            assert!(INSTRUCTIONS[opcode].is_illegal(), "{:#04x}", opcode);
+        }
+    }
 }
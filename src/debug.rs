@@ -0,0 +1,453 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cartdrige::BankState;
+use crate::cpu::INSTRUCTIONS;
+use crate::debugger::parse_address;
+use crate::register::Registers;
+
+/// An address along with the bank mapped there. Banked games run different
+/// code at the same address in 0x4000-0x7FFF, so the debugging tools key on
+/// both. Written `bank:address` like in symbol files, e.g. `03:4a10`.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BankedAddress {
+    // 0 outside of the switchable windows
+    pub bank: u16,
+    pub address: u16,
+}
+
+impl BankedAddress {
+    /// The switchable ROM and external RAM windows
+    pub fn is_banked(address: u16) -> bool {
+        matches!(address, 0x4000..=0x7FFF | 0xA000..=0xBFFF)
+    }
+
+    /// `address` in the bank `banks` currently maps there
+    pub fn mapped(address: u16, banks: BankState) -> Self {
+        let bank = match address {
+            0x4000..=0x7FFF => banks.rom_bank,
+            0xA000..=0xBFFF => banks.ram_bank as u16,
+            _ => 0,
+        };
+        Self { bank, address }
+    }
+}
+
+impl fmt::Display for BankedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:04x}", self.bank, self.address)
+    }
+}
+
+impl FromStr for BankedAddress {
+    type Err = String;
+
+    /// The bank can only be left out outside of the switchable windows
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((bank, address)) = s.split_once(':') else {
+            let address = parse_address(s)?;
+            if Self::is_banked(address) {
+                return Err(format!("{} is banked, expected bank:address", s));
+            }
+            return Ok(Self { bank: 0, address });
+        };
+        let bank = u16::from_str_radix(bank, 16).map_err(|_| format!("Invalid bank: {}", bank))?;
+        Ok(Self {
+            bank,
+            address: parse_address(address)?,
+        })
+    }
+}
+
+/// A change of the mapper banking registers
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BankSwitch {
+    pub cycle: u64,
+    // address of the instruction following the write
+    pub pc: u16,
+    pub from: BankState,
+    pub to: BankState,
+}
+
+/// Live view of the cartridge mapping with the most recent bank switches,
+/// chasing the wrong bank being mapped is the most common MBC bug.
+pub struct BankPanel {
+    current: BankState,
+    history: VecDeque<BankSwitch>,
+    capacity: usize,
+}
+
+impl BankPanel {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            current: BankState::default(),
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `state` if it differs from the last one seen, returns whether it did.
+    pub fn observe(&mut self, state: BankState, pc: u16, cycle: u64) -> bool {
+        if state == self.current {
+            return false;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(BankSwitch {
+            cycle,
+            pc,
+            from: self.current,
+            to: state,
+        });
+        self.current = state;
+        true
+    }
+
+    pub fn current(&self) -> BankState {
+        self.current
+    }
+
+    /// Oldest switch first
+    pub fn history(&self) -> impl Iterator<Item = &BankSwitch> {
+        self.history.iter()
+    }
+}
+
+impl fmt::Display for BankPanel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "ROM bank {:#05x} | RAM bank {:#04x} ({}) | mode {}",
+            self.current.rom_bank,
+            self.current.ram_bank,
+            if self.current.ram_enabled {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            self.current.mode,
+        )?;
+        for switch in self.history.iter().rev() {
+            writeln!(
+                f,
+                "  cycle {:>10} pc {:#06x}: ROM {:#05x} -> {:#05x}, RAM {:#04x} -> {:#04x}",
+                switch.cycle,
+                switch.pc,
+                switch.from.rom_bank,
+                switch.to.rom_bank,
+                switch.from.ram_bank,
+                switch.to.ram_bank,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An executed instruction with the registers it started from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TraceEntry {
+    pub address: BankedAddress,
+    pub opcode: u8,
+    pub registers: Registers,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let registers = &self.registers;
+        write!(
+            f,
+            "{}: {:02x} {:<12} A:{:02x} F:{:02x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x}",
+            self.address,
+            self.opcode,
+            INSTRUCTIONS[self.opcode as usize].mnemonic(),
+            registers.a,
+            registers.f.bits(),
+            registers.bc(),
+            registers.de(),
+            registers.hl(),
+            registers.sp.0,
+        )
+    }
+}
+
+/// The last instructions executed, dumped when the emulator crashes so that
+/// bug reports show how the CPU got there. A capacity of 0 disables it.
+pub struct InstructionTrace {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+}
+
+impl InstructionTrace {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Oldest instruction first
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+}
+
+impl fmt::Display for InstructionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "last {} instructions:", self.entries.len())?;
+        for entry in &self.entries {
+            writeln!(f, "  {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// A read or write the program made at a watched address, see
+/// `Cpu::set_watched`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub write: bool,
+    // read or written
+    pub value: u8,
+}
+
+/// Why the emulator stopped before the end of the frame, see
+/// `Emulator::take_break`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Break {
+    /// The next instruction is at a breakpoint
+    Breakpoint(BankedAddress),
+    /// The last instruction accessed a watchpoint, from `pc`
+    Watchpoint {
+        address: BankedAddress,
+        write: bool,
+        value: u8,
+        pc: BankedAddress,
+    },
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Break::Breakpoint(address) => write!(f, "breakpoint at {}", address),
+            Break::Watchpoint {
+                address,
+                write,
+                value,
+                pc,
+            } => write!(
+                f,
+                "watchpoint at {}: {} {:#04x} from {}",
+                address,
+                if *write { "wrote" } else { "read" },
+                value,
+                pc
+            ),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DmaKind {
+    // 0xFF46, 160 bytes to OAM
+    Oam,
+    // CGB general purpose VRAM DMA, all at once
+    General,
+    // CGB VRAM DMA, 16 bytes per H-blank
+    HBlank,
+}
+
+/// A DMA transfer as reported by the DMA engines
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DmaTransfer {
+    pub kind: DmaKind,
+    pub source: u16,
+    pub destination: u16,
+    // in bytes
+    pub length: u16,
+    pub transferred: u16,
+    // waiting for the next H-blank
+    pub pending: bool,
+    // CPU cycles spent stalled because of this transfer
+    pub stolen_cycles: u64,
+}
+
+impl DmaTransfer {
+    fn same_transfer(&self, other: &DmaTransfer) -> bool {
+        self.kind == other.kind
+            && self.source == other.source
+            && self.destination == other.destination
+    }
+}
+
+impl fmt::Display for DmaTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:#06x} -> {:#06x} {:>4}/{:<4} bytes, {} cycles stolen{}",
+            self.kind,
+            self.source,
+            self.destination,
+            self.transferred,
+            self.length,
+            self.stolen_cycles,
+            if self.pending { " (pending)" } else { "" },
+        )
+    }
+}
+
+/// Active DMA transfers and the most recently finished ones, sprite
+/// corruption and H-blank DMA bugs are much easier to spot with it.
+pub struct DmaPanel {
+    active: Vec<DmaTransfer>,
+    finished: VecDeque<DmaTransfer>,
+    capacity: usize,
+}
+
+impl DmaPanel {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            active: Vec::new(),
+            finished: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Replaces the active transfers, the ones that are gone are moved to
+    /// the history. Returns whether a transfer started or finished.
+    pub fn update(&mut self, transfers: impl IntoIterator<Item = DmaTransfer>) -> bool {
+        let transfers: Vec<_> = transfers.into_iter().collect();
+        let previous = std::mem::replace(&mut self.active, transfers);
+        let started = self
+            .active
+            .iter()
+            .any(|t| !previous.iter().any(|p| p.same_transfer(t)));
+        let mut finished = false;
+        for transfer in previous {
+            if !self.active.iter().any(|t| t.same_transfer(&transfer)) {
+                if self.finished.len() == self.capacity {
+                    self.finished.pop_front();
+                }
+                self.finished.push_back(transfer);
+                finished = true;
+            }
+        }
+        started || finished
+    }
+
+    pub fn active(&self) -> &[DmaTransfer] {
+        &self.active
+    }
+
+    /// Oldest transfer first
+    pub fn finished(&self) -> impl Iterator<Item = &DmaTransfer> {
+        self.finished.iter()
+    }
+}
+
+impl fmt::Display for DmaPanel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Active DMA:")?;
+        for transfer in &self.active {
+            writeln!(f, "  {}", transfer)?;
+        }
+        writeln!(f, "Finished DMA:")?;
+        for transfer in self.finished.iter().rev() {
+            writeln!(f, "  {}", transfer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banked_address() {
+        let banks = BankState {
+            rom_bank: 3,
+            ..BankState::default()
+        };
+        let address = BankedAddress::mapped(0x4A10, banks);
+        assert_eq!(address.to_string(), "03:4a10");
+        assert_eq!("03:4a10".parse(), Ok(address));
+        assert_eq!(BankedAddress::mapped(0x0150, banks).bank, 0);
+        assert_eq!("$0150".parse(), Ok(BankedAddress::mapped(0x0150, banks)));
+        assert!("4a10".parse::<BankedAddress>().is_err());
+        assert!("zz:4a10".parse::<BankedAddress>().is_err());
+    }
+
+    #[test]
+    fn test_bank_panel_records_only_changes() {
+        let mut panel = BankPanel::new(4);
+        assert!(!panel.observe(BankState::default(), 0x0100, 0));
+        let switched = BankState {
+            rom_bank: 2,
+            ..BankState::default()
+        };
+        assert!(panel.observe(switched, 0x0150, 40));
+        assert!(!panel.observe(switched, 0x0152, 44));
+        assert_eq!(panel.current(), switched);
+        let history: Vec<_> = panel.history().collect();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].from.rom_bank, 1);
+        assert_eq!(history[0].to.rom_bank, 2);
+        assert_eq!(history[0].pc, 0x0150);
+    }
+
+    #[test]
+    fn test_bank_panel_history_is_bounded() {
+        let mut panel = BankPanel::new(2);
+        for bank in 2..6 {
+            let state = BankState {
+                rom_bank: bank,
+                ..BankState::default()
+            };
+            panel.observe(state, 0, bank as u64);
+        }
+        let banks: Vec<_> = panel.history().map(|s| s.to.rom_bank).collect();
+        assert_eq!(banks, vec![4, 5]);
+    }
+
+    fn oam_transfer(transferred: u16) -> DmaTransfer {
+        DmaTransfer {
+            kind: DmaKind::Oam,
+            source: 0xC100,
+            destination: 0xFE00,
+            length: 160,
+            transferred,
+            pending: false,
+            stolen_cycles: transferred as u64 * 4,
+        }
+    }
+
+    #[test]
+    fn test_dma_panel_tracks_transfers() {
+        let mut panel = DmaPanel::new(4);
+        assert!(!panel.update([]));
+        assert!(panel.update([oam_transfer(0)]));
+        // progress alone is not a change
+        assert!(!panel.update([oam_transfer(80)]));
+        assert_eq!(panel.active()[0].transferred, 80);
+        assert!(panel.update([]));
+        assert!(panel.active().is_empty());
+        let finished: Vec<_> = panel.finished().collect();
+        assert_eq!(finished, vec![&oam_transfer(80)]);
+        assert!(panel
+            .to_string()
+            .contains("Oam 0xc100 -> 0xfe00   80/160  bytes, 320 cycles stolen"));
+    }
+}
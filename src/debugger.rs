@@ -0,0 +1,320 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::debug::BankedAddress;
+use crate::session::{validate_cheat, Access, Session, Watchpoint};
+
+/// Lines kept in the history file
+pub const HISTORY_SIZE: usize = 1000;
+
+/// Directory of the debugger files, `$XDG_CONFIG_HOME/gameboy_emu` or
+/// `~/.config/gameboy_emu`
+pub fn config_dir() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("gameboy_emu"))
+}
+
+/// Accepts `0x150`, `$150` or a bare `150`, always in hexadecimal
+pub fn parse_address(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", value))
+}
+
+/// A debugger command, as typed at the prompt or in the init script
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// `break <addr>`, with the bank in the switchable windows
+    Break(BankedAddress),
+    /// `watch [r|w|rw] <addr>`, writes by default
+    Watch(Watchpoint),
+    /// `cheat <code>`
+    Cheat(String),
+}
+
+impl Command {
+    /// Adds the breakpoint, watchpoint or cheat to `session` unless it is
+    /// already there
+    pub fn apply(&self, session: &mut Session) {
+        fn add<T: PartialEq>(items: &mut Vec<T>, item: T) {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+        match self {
+            Command::Break(address) => add(&mut session.breakpoints, *address),
+            Command::Watch(watchpoint) => add(&mut session.watchpoints, *watchpoint),
+            Command::Cheat(code) => add(&mut session.cheats, code.clone()),
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        match words[..] {
+            ["break", address] => Ok(Command::Break(address.parse()?)),
+            ["watch", address] => Ok(Command::Watch(Watchpoint {
+                address: address.parse()?,
+                access: Access::Write,
+            })),
+            ["watch", access, address] => Ok(Command::Watch(Watchpoint {
+                address: address.parse()?,
+                access: Access::from_name(access)?,
+            })),
+            ["cheat", code] => {
+                validate_cheat(code)?;
+                Ok(Command::Cheat(code.to_string()))
+            }
+            _ => Err(format!("Unknown command: {}", s)),
+        }
+    }
+}
+
+/// Parses a script of commands, one per line. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn parse_script(script: &str) -> Result<Vec<Command>, String> {
+    script
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| line.parse().map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// Commands of the `debugger_init` script in `config_dir`, run when the
+/// debugger attaches. A missing script runs nothing.
+pub fn init_script() -> Result<Vec<Command>, String> {
+    let Some(path) = config_dir().map(|dir| dir.join("debugger_init")) else {
+        return Ok(Vec::new());
+    };
+    match fs::read_to_string(&path) {
+        Ok(script) => parse_script(&script).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Lines entered at the debugger prompt, kept across sessions
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct History {
+    lines: Vec<String>,
+}
+
+impl History {
+    /// `history` in `config_dir`
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("history"))
+    }
+
+    /// A missing file is an empty history
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Self {
+                lines: content.lines().map(str::to_string).collect(),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content: String = self
+            .lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(path, content)
+    }
+
+    /// Appends `line` unless it is blank or repeats the previous one, the
+    /// oldest lines go past `HISTORY_SIZE`
+    pub fn push(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.lines.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.lines.push(line.to_string());
+        if self.lines.len() > HISTORY_SIZE {
+            self.lines.drain(..self.lines.len() - HISTORY_SIZE);
+        }
+    }
+
+    /// Oldest line first
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// `line` with readline's `!!`, the previous line, or `!<n>`, line `n`
+    /// as numbered by the `history` command, replaced
+    pub fn expand(&self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let index = match line.strip_prefix('!') {
+            Some("!") => self.lines.len().checked_sub(1),
+            Some(n) => n.parse::<usize>().ok().and_then(|n| n.checked_sub(1)),
+            None => return Ok(line.to_string()),
+        };
+        index
+            .and_then(|index| self.lines.get(index))
+            .cloned()
+            .ok_or_else(|| format!("No such history line: {}", line))
+    }
+}
+
+/// Reads commands from `input` until `run` or the end of the input.
+/// Breakpoints, watchpoints and cheats go to `session`, and every line to
+/// `history`, which `history` lists.
+pub fn prompt(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    history: &mut History,
+    session: &mut Session,
+) -> io::Result<()> {
+    loop {
+        write!(output, "(gb) ")?;
+        output.flush()?;
+        let mut typed = String::new();
+        if input.read_line(&mut typed)? == 0 {
+            return Ok(());
+        }
+        let line = match history.expand(&typed) {
+            Ok(line) => line,
+            Err(e) => {
+                writeln!(output, "{}", e)?;
+                continue;
+            }
+        };
+        // like readline, the command a history line stands for is shown
+        if line != typed.trim() {
+            writeln!(output, "{}", line)?;
+        }
+        history.push(&line);
+        match line.as_str() {
+            "" => {}
+            "run" => return Ok(()),
+            "history" => {
+                for (i, line) in history.lines().iter().enumerate() {
+                    writeln!(output, "{:5}  {}", i + 1, line)?;
+                }
+            }
+            _ => match line.parse::<Command>() {
+                Ok(command) => command.apply(session),
+                Err(e) => writeln!(output, "{}", e)?,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let script = "# project setup\nbreak 0150\nbreak 1f:$4a10\n\nwatch rw $C000\nwatch 0xff80\ncheat 01FF16D0\n";
+        let commands = parse_script(script).unwrap();
+        let address = |bank, address| BankedAddress { bank, address };
+        assert_eq!(
+            commands,
+            [
+                Command::Break(address(0x00, 0x0150)),
+                Command::Break(address(0x1F, 0x4A10)),
+                Command::Watch(Watchpoint {
+                    address: address(0x00, 0xC000),
+                    access: Access::ReadWrite
+                }),
+                Command::Watch(Watchpoint {
+                    address: address(0x00, 0xFF80),
+                    access: Access::Write
+                }),
+                Command::Cheat("01FF16D0".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_script("break 0150\nstep"),
+            Err("line 2: Unknown command: step".to_string())
+        );
+        assert!(parse_script("watch x C000").is_err());
+        assert_eq!(
+            parse_script("break 0150\ncheat 01FF16"),
+            Err("line 2: Invalid cheat: 01FF16".to_string())
+        );
+        assert!(parse_script("break 4a10").is_err());
+
+        let mut session = Session::default();
+        for command in commands.iter().chain(&commands) {
+            command.apply(&mut session);
+        }
+        assert_eq!(session.breakpoints.len(), 2);
+        assert_eq!(session.watchpoints.len(), 2);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        history.push("break 0150");
+        history.push("break 0150");
+        history.push("  ");
+        history.push("watch C000");
+        assert_eq!(history.lines(), ["break 0150", "watch C000"]);
+        assert_eq!(history.expand("!!"), Ok("watch C000".to_string()));
+        assert_eq!(history.expand(" !1 "), Ok("break 0150".to_string()));
+        assert!(history.expand("!3").is_err());
+        assert!(history.expand("!0").is_err());
+        assert_eq!(history.expand("run"), Ok("run".to_string()));
+
+        let dir = env::temp_dir().join(format!("gameboy-history-{}", std::process::id()));
+        let path = dir.join("history");
+        assert_eq!(History::load(&path).unwrap(), History::default());
+        // an empty history is an empty file
+        History::default().save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        history.save(&path).unwrap();
+        assert_eq!(History::load(&path).unwrap(), history);
+        for i in 0..HISTORY_SIZE {
+            history.push(&format!("break {:04x}", i));
+        }
+        assert_eq!(history.lines().len(), HISTORY_SIZE);
+        assert_eq!(history.lines()[0], "break 0000");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut history = History::default();
+        history.push("cheat 01FF16D0");
+        let mut session = Session::default();
+        let mut output = Vec::new();
+        let input = "break 0150\nbreak zz\n!1\nhistory\nrun\nwatch C000\n";
+        prompt(
+            &mut input.as_bytes(),
+            &mut output,
+            &mut history,
+            &mut session,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Invalid address: zz"));
+        assert!(output.contains("(gb) cheat 01FF16D0\n"));
+        assert!(output.contains("    5  history\n"));
+        assert_eq!(session.cheats, ["01FF16D0"]);
+        assert_eq!(session.breakpoints.len(), 1);
+        // stopped at run
+        assert!(session.watchpoints.is_empty());
+        assert_eq!(history.lines().len(), 6);
+    }
+}
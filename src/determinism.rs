@@ -0,0 +1,101 @@
+use crate::{emulator::Emulator, movie::Movie};
+
+/// First checkpoint at which the two runs disagreed. A run without a hash
+/// stopped there on a CPU error while the other one went on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    pub frame: u64,
+    pub first: Option<u64>,
+    pub second: Option<u64>,
+}
+
+/// Runs the ROM twice with the same input and compares the state hashes
+/// every `interval` frames, so that nondeterminism (uninitialized memory,
+/// host time leaking in) is caught before it breaks replays.
+///
+/// `power_on` must return a freshly powered on emulator each time it is
+/// called, set up like a normal run so that its fill, boot ROM and accuracy
+/// are exercised too. Returns the frames both runs completed, fewer than
+/// `frames` if both stopped on the same CPU error.
+pub fn check(
+    power_on: impl Fn() -> Emulator,
+    movie: &Movie,
+    frames: u64,
+    interval: u64,
+) -> Result<u64, Divergence> {
+    assert!(interval > 0, "Interval must be at least one frame");
+    // in lockstep, so that a run ending early is seen where it ends
+    let mut runs = [power_on(), power_on()];
+    for frame in 0..frames {
+        let ran = runs.each_mut().map(|emulator| {
+            emulator.cpu.bus.joypad.set_state(movie.input(frame));
+            emulator.run_frame().is_ok()
+        });
+        let stopped = ran != [true, true];
+        if stopped || frame % interval == interval - 1 || frame == frames - 1 {
+            let [first, second] = [0, 1].map(|i| ran[i].then(|| runs[i].state_hash()));
+            if ran[0] != ran[1] || first != second {
+                return Err(Divergence {
+                    frame,
+                    first,
+                    second,
+                });
+            }
+        }
+        if stopped {
+            return Ok(frame);
+        }
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+    use crate::cartdrige::RomOnly;
+
+    fn rom(program: &[u8]) -> Emulator {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        Emulator::new(Box::new(RomOnly(rom)))
+    }
+
+    #[test]
+    fn test_check_deterministic() {
+        let movie = Movie::parse("A\n\nS").unwrap();
+        // JR -2, loops forever
+        assert_eq!(check(|| rom(&[0x18, 0xFE]), &movie, 5, 2), Ok(5));
+    }
+
+    #[test]
+    fn test_check_flags_divergence() {
+        let runs = AtomicU8::new(0);
+        let power_on = || match runs.fetch_add(1, Ordering::SeqCst) {
+            0 => rom(&[0x18, 0xFE]),
+            // the second run keeps decrementing B instead
+            _ => rom(&[0x05, 0x18, 0xFD]),
+        };
+        let divergence = check(power_on, &Movie::default(), 4, 2).unwrap_err();
+        assert_eq!(divergence.frame, 1);
+        assert!(divergence.first.is_some() && divergence.second.is_some());
+        assert_ne!(divergence.first, divergence.second);
+    }
+
+    #[test]
+    fn test_check_flags_early_cpu_error() {
+        let runs = AtomicU8::new(0);
+        let power_on = || match runs.fetch_add(1, Ordering::SeqCst) {
+            0 => rom(&[0x18, 0xFE]),
+            // CB prefixed instructions are not implemented
+            _ => rom(&[0xCB, 0x37]),
+        };
+        let divergence = check(power_on, &Movie::default(), 4, 2).unwrap_err();
+        assert_eq!(divergence.frame, 0);
+        assert!(divergence.first.is_some());
+        assert_eq!(divergence.second, None);
+        // both failing the same way is deterministic, but ends the check
+        assert_eq!(check(|| rom(&[0xCB, 0x37]), &Movie::default(), 4, 2), Ok(0));
+    }
+}
@@ -0,0 +1,110 @@
+use std::ops::Range;
+
+use crate::json::Value;
+
+/// OAM DMA source address high byte, writing it starts a transfer
+/// https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+pub const DMA: u16 = 0xFF46;
+/// Bytes copied to OAM by a transfer, the whole sprite attribute table
+pub const OAM_DMA_LENGTH: u16 = 160;
+
+// one byte per machine cycle, after a machine cycle to set up
+const CYCLES_PER_BYTE: u32 = 4;
+const STARTUP_CYCLES: u32 = 4;
+
+/// OAM DMA engine. It copies from `source` in the background while the CPU
+/// keeps running, OAM is unreachable for the CPU until it is done.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OamDma {
+    // last value written to DMA
+    register: u8,
+    // T-cycles since the transfer started
+    cycles: u32,
+    active: bool,
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    /// Starts a transfer from `value`00, restarting the current one if any
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+        self.cycles = 0;
+        self.active = true;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Address of the first byte copied
+    pub fn source(&self) -> u16 {
+        (self.register as u16) << 8
+    }
+
+    /// Bytes copied so far
+    pub fn transferred(&self) -> u16 {
+        let cycles = self.cycles.saturating_sub(STARTUP_CYCLES);
+        (cycles / CYCLES_PER_BYTE).min(OAM_DMA_LENGTH as u32) as u16
+    }
+
+    /// Advances by `cycles` CPU T-cycles and returns the offsets of the bytes
+    /// to copy in the meantime
+    pub fn tick(&mut self, cycles: u32) -> Range<u16> {
+        if !self.active {
+            return 0..0;
+        }
+        let start = self.transferred();
+        self.cycles += cycles;
+        let end = self.transferred();
+        if end == OAM_DMA_LENGTH {
+            self.active = false;
+        }
+        start..end
+    }
+
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("register", Value::hex(self.register as u64, 2)),
+            ("cycles", Value::Number(self.cycles as u64)),
+            ("active", Value::Bool(self.active)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            register: value.get("register")?.as_u8()?,
+            cycles: value.get("cycles")?.as_u32()?,
+            active: value.get("active")?.as_bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oam_dma_timing() {
+        let mut dma = OamDma::new();
+        assert_eq!(dma.tick(4), 0..0);
+        dma.write(0xC1);
+        assert_eq!(dma.source(), 0xC100);
+        // setup
+        assert_eq!(dma.tick(4), 0..0);
+        assert_eq!(dma.tick(8), 0..2);
+        assert_eq!(dma.tick(2), 2..2);
+        assert_eq!(dma.tick(2), 2..3);
+        assert!(dma.active());
+        assert_eq!(dma.tick(1000), 3..OAM_DMA_LENGTH);
+        assert!(!dma.active());
+        assert_eq!(dma.read(), 0xC1);
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{self, Write};
+
+use crate::{cpu::Cpu, ppu, rng::RngHook};
+
+/// LY as seen by the emulator the reference logs come from, stuck at the
+/// start of VBlank so that the test ROMs never wait for it
+pub const LY: u8 = 0x90;
+
+/// State before the instruction at PC runs, in the Gameboy Doctor format:
+/// the registers followed by the 4 bytes from PC
+/// https://github.com/robert/gameboy-doctor
+pub fn trace_line(cpu: &Cpu) -> String {
+    let registers = &cpu.registers;
+    let pc = registers.pc.value();
+    let memory: Vec<_> = (0..4)
+        .map(|i| format!("{:02X}", cpu.read(pc.wrapping_add(i))))
+        .collect();
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        registers.a,
+        registers.f.bits(),
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        registers.sp.0,
+        pc,
+        memory.join(",")
+    )
+}
+
+/// Pins the LY the program reads to `LY` like the reference emulator, to be
+/// installed with `Emulator::set_rng_hook` before tracing
+pub fn ly_hook() -> Box<dyn RngHook> {
+    Box::new(
+        |_frame: u64, address: u16, value: u8| {
+            if address == ppu::LY {
+                LY
+            } else {
+                value
+            }
+        },
+    )
+}
+
+/// Writes a trace line per instruction, to be diffed against a Gameboy
+/// Doctor reference log
+pub struct Doctor<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Doctor<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Call before every `Emulator::step`
+    pub fn trace(&mut self, cpu: &Cpu) -> io::Result<()> {
+        writeln!(self.out, "{}", trace_line(cpu))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartdrige::RomOnly;
+
+    #[test]
+    fn test_trace_line() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        let cpu = Cpu::new(Box::new(RomOnly(rom)));
+        assert_eq!(
+            trace_line(&cpu),
+            "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01"
+        );
+    }
+
+    #[test]
+    fn test_doctor_writes_lines() {
+        let cpu = Cpu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        let mut doctor = Doctor::new(Vec::new());
+        doctor.trace(&cpu).unwrap();
+        doctor.trace(&cpu).unwrap();
+        let log = String::from_utf8(doctor.out).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.ends_with("PC:0100 PCMEM:00,00,00,00\n"));
+    }
+}
@@ -0,0 +1,1088 @@
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::Receiver;
+
+use crate::{
+    accuracy::Accuracy,
+    cartdrige::{Cartdrige, RAM_BANK_SIZE, ROM_BANK_SIZE},
+    compat::Compat,
+    cpu::{Cpu, CpuError, StepInfo},
+    debug::{BankedAddress, Break, DmaKind, DmaTransfer, InstructionTrace, TraceEntry},
+    dma::OAM_DMA_LENGTH,
+    frame::Frame,
+    frames::Frames,
+    hash::Fnv1a,
+    hdma::{BLOCK_DOTS, BLOCK_SIZE},
+    layers::Layers,
+    notification::Notification,
+    palette::{CgbPalettes, ColorCorrection, Rgb555},
+    persistence::Storage,
+    rng::RngHook,
+    savestate::Savestate,
+    session::{Cheat, Session, Watchpoint},
+};
+
+/// Told whether the rumble motor of the cartridge runs, see
+/// `Emulator::set_rumble_hook`
+pub type RumbleHook = Box<dyn FnMut(bool) + Send>;
+
+/// Number of dots the PPU takes to draw a frame (154 lines of 456 dots)
+pub const DOTS_PER_FRAME: u64 = 70224;
+
+/// The whole console: the CPU with everything it is wired to, the frame
+/// being drawn included.
+pub struct Emulator {
+    pub cpu: Cpu,
+    frame_count: u64,
+    // CGB color outside the screen and while the LCD is off, white if unset
+    backdrop: Option<Rgb555>,
+    color_correction: ColorCorrection,
+    rumble_hook: Option<RumbleHook>,
+    // motor state last reported to `rumble_hook`
+    rumble: bool,
+    // debugging aid only, not part of the machine state
+    trace: InstructionTrace,
+    // nor are the debugger and cheats, see `set_session`
+    breakpoints: Vec<BankedAddress>,
+    watchpoints: Vec<Watchpoint>,
+    // GameShark codes, the Game Genie ones are patched in by the CPU
+    cheat_writes: Vec<Cheat>,
+    // the breakpoint or watchpoint `step` stopped at, see `take_break`
+    hit: Option<Break>,
+}
+
+impl Emulator {
+    pub fn new(cartdrige: Box<dyn Cartdrige>) -> Self {
+        // CGB flag, 0x80 for dual mode games and 0xC0 for CGB only ones
+        let cgb_flag = cartdrige.header().cgb_flag;
+        let mut cpu = Cpu::new(cartdrige);
+        cpu.cgb = cgb_flag & 0x80 != 0;
+        cpu.compat = Compat::post_boot(cgb_flag);
+        cpu.sync_cgb_mode();
+        Self {
+            cpu,
+            frame_count: 0,
+            backdrop: None,
+            color_correction: ColorCorrection::default(),
+            rumble_hook: None,
+            rumble: false,
+            trace: InstructionTrace::new(0),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            cheat_writes: Vec::new(),
+            hit: None,
+        }
+    }
+
+    /// `address` in the bank currently mapped there, what breakpoints and
+    /// traces compare against
+    pub fn banked(&self, address: u16) -> BankedAddress {
+        BankedAddress::mapped(address, self.cpu.bus.cartdrige.bank_state())
+    }
+
+    /// Starts from the boot ROM instead of the state it leaves behind, see
+    /// `Cpu::boot`
+    pub fn boot(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        self.cpu.boot(boot_rom)
+    }
+
+    pub fn step(&mut self) -> Result<StepInfo, CpuError> {
+        let registers = self.cpu.registers;
+        let halted = self.cpu.halted;
+        // before the instruction gets a chance to switch banks
+        let banks = self.cpu.bus.cartdrige.bank_state();
+        let result = self.cpu.step();
+        let accesses = self.cpu.take_accesses();
+        // the faulting instruction ends the trace
+        let (address, opcode) = match result {
+            Ok(info) => (info.address, info.instruction.opcode),
+            Err(CpuError::UnknownOpcode { opcode, address }) => (address, opcode),
+        };
+        self.trace.record(TraceEntry {
+            address: BankedAddress::mapped(address, banks),
+            opcode,
+            registers,
+        });
+        let info = result?;
+        let frame_count = self.cpu.clock.dots() / DOTS_PER_FRAME;
+        if frame_count != self.frame_count {
+            self.frame_count = frame_count;
+            self.apply_cheats();
+        }
+        for access in accesses {
+            let address = BankedAddress::mapped(access.address, banks);
+            let watched = self.watchpoints.iter().any(|watchpoint| {
+                watchpoint.address == address && watchpoint.access.covers(access.write)
+            });
+            if watched && self.hit.is_none() {
+                self.hit = Some(Break::Watchpoint {
+                    address,
+                    write: access.write,
+                    value: access.value,
+                    pc: BankedAddress::mapped(info.address, banks),
+                });
+            }
+        }
+        // not over and over while the CPU sleeps there
+        let asleep = halted && self.cpu.halted;
+        if !self.breakpoints.is_empty() && !asleep && self.hit.is_none() {
+            let pc = self.banked(self.cpu.registers.pc.value());
+            if self.breakpoints.contains(&pc) {
+                self.hit = Some(Break::Breakpoint(pc));
+            }
+        }
+        let rumble = self.cpu.bus.cartdrige.rumble();
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            if let Some(hook) = &mut self.rumble_hook {
+                hook(rumble);
+            }
+        }
+        Ok(info)
+    }
+
+    /// Runs until the next frame is complete, until the CPU is stopped and
+    /// waits for input, or until a breakpoint or watchpoint is hit
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        let frame = self.frame_count;
+        while self.frame_count == frame && !self.cpu.stopped && self.hit.is_none() {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Arms the breakpoints and watchpoints of `session` and applies its
+    /// cheats, in place of the previous ones. Codes that do not parse are
+    /// skipped, the session checks them as they are added.
+    pub fn set_session(&mut self, session: &Session) {
+        let mut patches = Vec::new();
+        self.cheat_writes.clear();
+        for cheat in session.cheats.iter().filter_map(|code| code.parse().ok()) {
+            match cheat {
+                Cheat::GameGenie(patch) => patches.push(patch),
+                cheat => self.cheat_writes.push(cheat),
+            }
+        }
+        self.cpu.set_rom_patches(patches);
+        self.breakpoints = session.breakpoints.clone();
+        self.watchpoints = session.watchpoints.clone();
+        let watched = self
+            .watchpoints
+            .iter()
+            .map(|watchpoint| watchpoint.address.address);
+        self.cpu.set_watched(watched.collect());
+        self.hit = None;
+    }
+
+    /// The breakpoint or watchpoint `step` stopped at, which `run_frame`
+    /// does not go past until it is taken
+    pub fn pending_break(&self) -> Option<Break> {
+        self.hit
+    }
+
+    /// Takes the breakpoint or watchpoint hit for the emulator to go on
+    pub fn take_break(&mut self) -> Option<Break> {
+        self.hit.take()
+    }
+
+    // GameShark codes write their value every frame. ROM is left alone, a
+    // write there would switch banks.
+    fn apply_cheats(&mut self) {
+        for i in 0..self.cheat_writes.len() {
+            let Cheat::GameShark {
+                bank,
+                address,
+                value,
+            } = self.cheat_writes[i]
+            else {
+                continue;
+            };
+            match address {
+                0x0000..=0x7FFF => {}
+                0xA000..=0xBFFF => {
+                    self.write_banked(bank as u16, address, value);
+                }
+                _ => self.write(address, value),
+            }
+        }
+    }
+
+    /// Iterates over the frames as they complete
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames::new(self)
+    }
+
+    /// The last frame drawn, complete whenever `run_frame` returns since
+    /// the next one starts drawing a few lines later
+    pub fn frame(&self) -> &Frame {
+        self.cpu.bus.renderer.frame()
+    }
+
+    /// Color to show around the screen, and over it while the LCD is off.
+    /// On a DMG that is always the blank LCD white.
+    pub fn backdrop(&self) -> Rgb555 {
+        match self.backdrop {
+            Some(color) if self.cpu.cgb => color,
+            _ => Rgb555::WHITE,
+        }
+    }
+
+    /// Overrides the CGB backdrop, `None` goes back to white
+    pub fn set_backdrop(&mut self, color: Option<Rgb555>) {
+        self.backdrop = color;
+        self.repaint_backdrop();
+    }
+
+    // until the PPU draws over it, and whenever the LCD is turned off
+    fn repaint_backdrop(&mut self) {
+        let rgb = if self.cpu.cgb {
+            self.color_correction.apply(self.backdrop())
+        } else {
+            Rgb555::WHITE.to_rgb24()
+        };
+        self.cpu.bus.renderer.set_backdrop(rgb);
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    /// How CGB colors end up in `frame`, DMG shades are left alone
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+        self.cpu.bus.renderer.set_color_correction(correction);
+        self.repaint_backdrop();
+    }
+
+    /// The CGB palette memories, raw 15-bit colors for frontends doing their
+    /// own color correction
+    pub fn cgb_palettes(&self) -> &CgbPalettes {
+        &self.cpu.bus.palettes
+    }
+
+    /// Layers the compositor draws into `frame`
+    pub fn layers(&self) -> Layers {
+        self.cpu.bus.renderer.layers()
+    }
+
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.cpu.bus.renderer.set_layers(layers);
+    }
+
+    pub fn accuracy(&self) -> Accuracy {
+        self.cpu.accuracy
+    }
+
+    pub fn set_accuracy(&mut self, accuracy: Accuracy) {
+        self.cpu.accuracy = accuracy;
+        self.cpu.bus.renderer.set_pixel_fifo(accuracy.pixel_fifo());
+    }
+
+    /// Keeps the last `capacity` executed instructions for crash reports, 0
+    /// stops tracing
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        self.trace = InstructionTrace::new(capacity);
+    }
+
+    pub fn trace(&self) -> &InstructionTrace {
+        &self.trace
+    }
+
+    /// What to attach to a bug report: the recent instructions and the
+    /// registers now
+    pub fn crash_dump(&self) -> String {
+        format!("{}{:#?}", self.trace, self.cpu.registers)
+    }
+
+    /// Lets `hook` observe or override the program's reads of the RNG
+    /// sources, see `rng::RngHook`
+    pub fn set_rng_hook(&mut self, hook: Option<Box<dyn RngHook>>) -> Option<Box<dyn RngHook>> {
+        self.cpu.set_rng_hook(hook)
+    }
+
+    /// Calls `hook` whenever the rumble motor of the cartridge starts or
+    /// stops, for the frontend to vibrate a gamepad. Returns the previous
+    /// hook.
+    pub fn set_rumble_hook(&mut self, hook: Option<RumbleHook>) -> Option<RumbleHook> {
+        std::mem::replace(&mut self.rumble_hook, hook)
+    }
+
+    /// Tilts a cartridge with an accelerometer by `x` and `y` g, from -1.0
+    /// to 1.0, e.g. from the analog stick of a gamepad. Positive values are
+    /// to the right and towards the player.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cpu.bus.cartdrige.set_tilt(x, y);
+    }
+
+    /// Whether the PPU completed a frame since the last call. `frame` is
+    /// whole from then until drawing starts over, which is earlier than
+    /// `run_frame` returns but does not happen while the LCD is off.
+    pub fn take_frame_ready(&mut self) -> bool {
+        self.cpu.bus.ppu.take_frame_ready()
+    }
+
+    /// Number of frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Routes the core notifications to the returned channel instead of the
+    /// log, replacing any previous subscriber
+    pub fn notifications(&mut self) -> Receiver<Notification> {
+        self.cpu.bus.notifier.subscribe()
+    }
+
+    /// Stable hash of the whole machine state, two emulators with the same
+    /// hash behave identically from there on
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        self.cpu.hash_state(&mut hasher);
+        self.frame_count.hash(&mut hasher);
+        self.frame().pixels.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Restores the external RAM saved under `key` by `save_battery`, and
+    /// brings a clock on the cartridge to `now`, in seconds since the Unix
+    /// epoch. Returns whether there was a save to load.
+    pub fn load_battery(
+        &mut self,
+        storage: &dyn Storage,
+        key: &str,
+        now: u64,
+    ) -> Result<bool, String> {
+        let cartdrige = &mut self.cpu.bus.cartdrige;
+        if !cartdrige.has_battery()
+            || cartdrige.ram().is_empty() && cartdrige.battery_footer(now).is_empty()
+        {
+            return Ok(false);
+        }
+        let Some(data) = storage.load(key).map_err(|e| e.to_string())? else {
+            return Ok(false);
+        };
+        let ram = cartdrige.ram_mut();
+        // other emulators may append the clock of the cartridge after it
+        if data.len() < ram.len() {
+            return Err(format!(
+                "Save is {} bytes but the cartridge has {} bytes of RAM",
+                data.len(),
+                ram.len()
+            ));
+        }
+        let size = ram.len();
+        ram.copy_from_slice(&data[..size]);
+        cartdrige.load_battery_footer(&data[size..], now);
+        Ok(true)
+    }
+
+    /// Writes the external RAM under `key` if a battery would keep it on the
+    /// cartridge, followed by the state of its clock at `now` if it has one.
+    /// Returns whether there was anything to save.
+    pub fn save_battery(
+        &self,
+        storage: &mut dyn Storage,
+        key: &str,
+        now: u64,
+    ) -> Result<bool, String> {
+        let cartdrige = &self.cpu.bus.cartdrige;
+        let mut data = cartdrige.ram().to_vec();
+        data.extend(cartdrige.battery_footer(now));
+        if !cartdrige.has_battery() || data.is_empty() {
+            return Ok(false);
+        }
+        storage.store(key, &data).map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    pub fn save_state(&self) -> Savestate {
+        Savestate {
+            cpu: self.cpu.save_state(),
+            frame: self.frame().clone(),
+            frame_count: self.frame_count,
+        }
+    }
+
+    /// Rolls the machine back (or forward) to `state`, which must have been
+    /// saved with the same cartridge
+    pub fn load_state(&mut self, state: &Savestate) {
+        self.cpu.load_state(&state.cpu);
+        self.cpu
+            .bus
+            .renderer
+            .frame_mut()
+            .pixels
+            .copy_from_slice(&state.frame.pixels);
+        self.frame_count = state.frame_count;
+        self.hit = None;
+    }
+
+    /// Same as `load_state`, but checks that `state` fits the inserted
+    /// cartridge since it may come from a hand edited dump
+    pub fn try_load_state(&mut self, state: &Savestate) -> Result<(), String> {
+        let expected = self.cpu.bus.cartdrige.ram().len();
+        if state.cpu.ram_size() != expected {
+            return Err(format!(
+                "State has {} bytes of cartridge RAM, the cartridge has {}",
+                state.cpu.ram_size(),
+                expected
+            ));
+        }
+        let expected = self.cpu.bus.cartdrige.mapper_state().len();
+        if state.cpu.mapper_size() != expected {
+            return Err(format!(
+                "State has {} bytes of mapper state, the cartridge has {}",
+                state.cpu.mapper_size(),
+                expected
+            ));
+        }
+        self.load_state(state);
+        Ok(())
+    }
+
+    /// DMA transfers currently in progress, for the debugger
+    pub fn dma_transfers(&self) -> Vec<DmaTransfer> {
+        let mut transfers = Vec::new();
+        let dma = &self.cpu.bus.dma;
+        if dma.active() {
+            transfers.push(DmaTransfer {
+                kind: DmaKind::Oam,
+                source: dma.source(),
+                destination: 0xFE00,
+                length: OAM_DMA_LENGTH,
+                transferred: dma.transferred(),
+                pending: false,
+                // the CPU keeps running during OAM DMA
+                stolen_cycles: 0,
+            });
+        }
+        // general purpose transfers are over before the CPU runs again
+        let hdma = &self.cpu.bus.hdma;
+        if hdma.active() {
+            let (copied, remaining) = hdma.progress();
+            let factor = if self.cpu.clock.double_speed() { 2 } else { 1 };
+            let length = BLOCK_SIZE * copied as u16;
+            transfers.push(DmaTransfer {
+                kind: DmaKind::HBlank,
+                // where the transfer started
+                source: hdma.source().wrapping_sub(length),
+                destination: 0x8000 | hdma.destination().wrapping_sub(length) & 0x1FFF,
+                length: length + BLOCK_SIZE * remaining as u16,
+                transferred: length,
+                pending: true,
+                stolen_cycles: (BLOCK_DOTS * factor * copied as u32) as u64,
+            });
+        }
+        transfers
+    }
+
+    /// Reads `address` as the CPU currently sees it
+    pub fn read(&self, address: u16) -> u8 {
+        self.cpu.read(address)
+    }
+
+    /// Writes `address` as the CPU would, side effects on I/O registers included
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.cpu.write(address, value)
+    }
+
+    /// Reads `address` from `bank`, whether or not that bank is currently
+    /// mapped. ROM banks are used for 0x0000-0x7FFF and external RAM banks for
+    /// 0xA000-0xBFFF, elsewhere only bank 0 exists. Returns `None` when the
+    /// bank does not exist.
+    pub fn read_banked(&self, bank: u16, address: u16) -> Option<u8> {
+        let cartdrige = &self.cpu.bus.cartdrige;
+        match address {
+            0x0000..=0x7FFF => cartdrige.rom().get(rom_offset(bank, address)).copied(),
+            0xA000..=0xBFFF => cartdrige.ram().get(ram_offset(bank, address)).copied(),
+            _ if bank == 0 => Some(self.read(address)),
+            _ => None,
+        }
+    }
+
+    /// Writes `value` to an external RAM bank, or anywhere in bank 0 like
+    /// `write`. ROM is left untouched. Returns whether the write happened.
+    pub fn write_banked(&mut self, bank: u16, address: u16, value: u8) -> bool {
+        match address {
+            0x0000..=0x7FFF => false,
+            0xA000..=0xBFFF => match self
+                .cpu
+                .bus
+                .cartdrige
+                .ram_mut()
+                .get_mut(ram_offset(bank, address))
+            {
+                Some(byte) => {
+                    *byte = value;
+                    true
+                }
+                None => false,
+            },
+            _ if bank == 0 => {
+                self.write(address, value);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// bank 0 lives at 0x0000-0x3FFF and every other bank at 0x4000-0x7FFF, so
+// only the offset inside the window matters
+fn rom_offset(bank: u16, address: u16) -> usize {
+    bank as usize * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE)
+}
+
+fn ram_offset(bank: u16, address: u16) -> usize {
+    bank as usize * RAM_BANK_SIZE + (address as usize - 0xA000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cartdrige::{Mbc3, RomOnly},
+        palette,
+        session::Access,
+    };
+
+    #[test]
+    fn test_read_banked_rom() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0150] = 0x11;
+        rom[0x4150] = 0x22;
+        let emulator = Emulator::new(Box::new(RomOnly(rom)));
+        assert_eq!(emulator.read_banked(0, 0x0150), Some(0x11));
+        // bank 1 can be addressed through either window
+        assert_eq!(emulator.read_banked(1, 0x4150), Some(0x22));
+        assert_eq!(emulator.read_banked(1, 0x0150), Some(0x22));
+        assert_eq!(emulator.read_banked(2, 0x4150), None);
+        // no external RAM
+        assert_eq!(emulator.read_banked(0, 0xA000), None);
+    }
+
+    #[test]
+    fn test_compat_registers() {
+        use crate::compat::{KEY0, OPRI};
+
+        let mut rom = vec![0x00; 0x8000];
+        let mut dmg = Emulator::new(Box::new(RomOnly(rom.clone())));
+        dmg.write(OPRI, 0x01);
+        // not there on a DMG
+        assert_eq!(dmg.read(OPRI), 0xFF);
+
+        rom[0x0143] = 0x80;
+        let mut cgb = Emulator::new(Box::new(RomOnly(rom)));
+        assert_eq!(cgb.read(KEY0), 0xFF);
+        assert_eq!(cgb.read(OPRI), 0xFE);
+        // locked since the boot ROM is over
+        cgb.write(OPRI, 0x01);
+        assert!(!cgb.cpu.compat.priority_by_x());
+
+        // a boot ROM running the game as on a DMG
+        cgb.cpu.compat = Compat::new();
+        cgb.write(KEY0, 0x04);
+        cgb.write(crate::mmu::BOOT, 0x01);
+        assert!(!cgb.cpu.cgb);
+        assert_eq!(cgb.read(OPRI), 0xFF);
+    }
+
+    #[test]
+    fn test_object_priority() {
+        use crate::compat::OPRI;
+        use crate::io::{LCDC, LCD_ENABLE, OBJ_ENABLE};
+        use crate::palette::{OCPD, OCPS};
+
+        // JR -2
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x0143] = 0x80;
+        let overlap = |opri: u8, accuracy: Accuracy| {
+            let mut emulator = Emulator::new(Box::new(RomOnly(rom.clone())));
+            emulator.set_accuracy(accuracy);
+            // as the boot ROM finds them
+            emulator.cpu.compat = Compat::new();
+            emulator.write(OPRI, opri);
+            emulator.write(LCDC, 0x00);
+            (0x8010..0x8020).for_each(|address| emulator.write(address, 0xFF));
+            // object 0 at X 4 with palette 0, object 1 at X 0 with palette 1
+            for (address, value) in (0xFE00..).zip([16, 12, 1, 0x00, 16, 8, 1, 0x01]) {
+                emulator.write(address, value);
+            }
+            // color 3 black in palette 0 and red in palette 1
+            emulator.write(OCPS, 0x80 | 0x06);
+            [0x00, 0x00]
+                .iter()
+                .for_each(|&byte| emulator.write(OCPD, byte));
+            emulator.write(OCPS, 0x80 | 0x0E);
+            [0x1F, 0x00]
+                .iter()
+                .for_each(|&byte| emulator.write(OCPD, byte));
+            emulator.write(LCDC, LCD_ENABLE | OBJ_ENABLE);
+            while emulator.cpu.bus.ppu.ly() == 0 {
+                emulator.step().unwrap();
+            }
+            emulator.frame().pixel(4, 0)
+        };
+        for accuracy in [Accuracy::Balanced, Accuracy::Accurate] {
+            // the first in OAM wins, then the leftmost one
+            assert_eq!(overlap(0x00, accuracy), [0x00, 0x00, 0x00]);
+            assert_eq!(overlap(0x01, accuracy), [0xFF, 0x00, 0x00]);
+        }
+    }
+
+    #[test]
+    fn test_wram_banks() {
+        use crate::mmu::SVBK;
+
+        let mut rom = vec![0x00; 0x8000];
+        let mut dmg = Emulator::new(Box::new(RomOnly(rom.clone())));
+        dmg.write(0xD000, 0x11);
+        dmg.write(SVBK, 0x02);
+        assert_eq!(dmg.read(SVBK), 0xFF);
+        assert_eq!(dmg.read(0xD000), 0x11);
+
+        rom[0x0143] = 0x80;
+        let mut cgb = Emulator::new(Box::new(RomOnly(rom)));
+        assert_eq!(cgb.read(SVBK), 0xF8);
+        cgb.write(0xD000, 0x11);
+        cgb.write(SVBK, 0xFA);
+        assert_eq!(cgb.read(SVBK), 0xFA);
+        assert_eq!(cgb.read(0xD000), 0x00);
+        cgb.write(0xD000, 0x22);
+        // bank 0 stays put, the echo follows the switched bank
+        cgb.write(0xC000, 0x33);
+        assert_eq!(cgb.read(0xF000), 0x22);
+        cgb.write(SVBK, 0x07);
+        assert_eq!((cgb.read(0xC000), cgb.read(0xD000)), (0x33, 0x00));
+        // selecting bank 0 maps bank 1
+        cgb.write(SVBK, 0x00);
+        assert_eq!(cgb.read(0xD000), 0x11);
+        let state = cgb.save_state();
+        cgb.write(SVBK, 0x02);
+        cgb.load_state(&state);
+        assert_eq!(cgb.read(0xD000), 0x11);
+    }
+
+    #[test]
+    fn test_vram_banks() {
+        use crate::mmu::VBK;
+
+        let mut rom = vec![0x00; 0x8000];
+        let mut dmg = Emulator::new(Box::new(RomOnly(rom.clone())));
+        dmg.write(0x8000, 0x11);
+        dmg.write(VBK, 0x01);
+        assert_eq!(dmg.read(VBK), 0xFF);
+        assert_eq!(dmg.read(0x8000), 0x11);
+
+        rom[0x0143] = 0x80;
+        let mut cgb = Emulator::new(Box::new(RomOnly(rom)));
+        assert_eq!(cgb.read(VBK), 0xFE);
+        cgb.write(0x9FFF, 0x11);
+        cgb.write(VBK, 0xFF);
+        assert_eq!(cgb.read(VBK), 0xFF);
+        assert_eq!(cgb.read(0x9FFF), 0x00);
+        cgb.write(0x9FFF, 0x22);
+        let state = cgb.save_state();
+        cgb.write(VBK, 0x00);
+        assert_eq!(cgb.read(0x9FFF), 0x11);
+        cgb.load_state(&state);
+        assert_eq!(cgb.read(0x9FFF), 0x22);
+    }
+
+    #[test]
+    fn test_vram_dma() {
+        use crate::hdma::{HDMA1, HDMA2, HDMA3, HDMA4, HDMA5};
+
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        for i in 0..0x40 {
+            emulator.write(0xC000 + i, i as u8 + 1);
+        }
+        for (register, value) in [(HDMA1, 0xC0), (HDMA2, 0x00), (HDMA3, 0x01), (HDMA4, 0x00)] {
+            emulator.write(register, value);
+        }
+        // general purpose, two blocks at once
+        emulator.write(HDMA5, 0x01);
+        assert_eq!(emulator.read(HDMA5), 0xFF);
+        assert_eq!(
+            emulator.cpu.bus.vram[0x0100..0x0120],
+            emulator.cpu.bus.wram[..0x20]
+        );
+        // the CPU waits for both
+        assert_eq!(emulator.step().unwrap().cycles, 2 * BLOCK_DOTS + 4);
+
+        // one block per H-blank, the PPU is scanning OAM
+        emulator.write(HDMA5, 0x81);
+        assert_eq!(emulator.read(HDMA5), 0x01);
+        assert_eq!(emulator.dma_transfers()[0].kind, DmaKind::HBlank);
+        while emulator.cpu.bus.ppu.mode() != crate::ppu::Mode::HBlank {
+            emulator.step().unwrap();
+        }
+        assert_eq!(emulator.read(HDMA5), 0x00);
+        assert_eq!(
+            emulator.cpu.bus.vram[0x0120..0x0130],
+            emulator.cpu.bus.wram[0x20..0x30]
+        );
+        let transfer = emulator.dma_transfers()[0];
+        assert_eq!((transfer.source, transfer.destination), (0xC020, 0x8120));
+        assert_eq!((transfer.transferred, transfer.length), (0x10, 0x20));
+        // not on DMG
+        let mut dmg = Emulator::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        dmg.write(HDMA5, 0x00);
+        assert_eq!(dmg.read(HDMA5), 0xFF);
+        assert!(dmg.dma_transfers().is_empty());
+    }
+
+    #[test]
+    fn test_battery_save() {
+        use crate::cartdrige::{Mbc1, RomOnly};
+        use crate::persistence::{MemoryStorage, Storage};
+
+        let mut rom = vec![0x00; 2 * ROM_BANK_SIZE];
+        rom[0x147] = 0x03;
+        let mut emulator = Emulator::new(Box::new(Mbc1::new(rom.clone(), RAM_BANK_SIZE)));
+        let mut storage = MemoryStorage::new();
+        assert_eq!(emulator.load_battery(&storage, "game.sav", 0), Ok(false));
+        emulator.cpu.write(0x0000, 0x0A);
+        emulator.cpu.write(0xA123, 0x42);
+        assert_eq!(emulator.save_battery(&mut storage, "game.sav", 0), Ok(true));
+
+        let mut emulator = Emulator::new(Box::new(Mbc1::new(rom.clone(), RAM_BANK_SIZE)));
+        assert_eq!(emulator.load_battery(&storage, "game.sav", 0), Ok(true));
+        assert_eq!(emulator.cpu.bus.cartdrige.ram()[0x0123], 0x42);
+
+        storage.store("short.sav", &[0x00; 16]).unwrap();
+        assert!(emulator.load_battery(&storage, "short.sav", 0).is_err());
+
+        // the same mapper without a battery loses its RAM
+        rom[0x147] = 0x02;
+        let emulator = Emulator::new(Box::new(Mbc1::new(rom, RAM_BANK_SIZE)));
+        assert_eq!(
+            emulator.save_battery(&mut storage, "other.sav", 0),
+            Ok(false)
+        );
+        let emulator = Emulator::new(Box::new(RomOnly(vec![0x00; 2 * ROM_BANK_SIZE])));
+        assert_eq!(
+            emulator.save_battery(&mut storage, "other.sav", 0),
+            Ok(false)
+        );
+        assert_eq!(storage.load("other.sav").unwrap(), None);
+    }
+
+    #[test]
+    fn test_battery_save_keeps_the_clock() {
+        use crate::cartdrige::{Mbc3, RTC_FOOTER_SIZE};
+        use crate::persistence::{MemoryStorage, Storage};
+
+        // MBC3+TIMER+BATTERY, no RAM
+        let mut rom = vec![0x00; 2 * ROM_BANK_SIZE];
+        rom[0x147] = 0x0F;
+        let emulator = Emulator::new(Box::new(Mbc3::new(rom.clone(), 0, true)));
+        let mut storage = MemoryStorage::new();
+        assert_eq!(
+            emulator.save_battery(&mut storage, "game.sav", 1000),
+            Ok(true)
+        );
+        let saved = storage.load("game.sav").unwrap().unwrap();
+        assert_eq!(saved.len(), RTC_FOOTER_SIZE);
+
+        // two hours later
+        let mut emulator = Emulator::new(Box::new(Mbc3::new(rom, 0, true)));
+        assert_eq!(
+            emulator.load_battery(&storage, "game.sav", 1000 + 2 * 3600),
+            Ok(true)
+        );
+        for (address, value) in [
+            (0x0000, 0x0A),
+            (0x6000, 0x00),
+            (0x6000, 0x01),
+            (0x4000, 0x0A),
+        ] {
+            emulator.cpu.write(address, value);
+        }
+        assert_eq!(emulator.cpu.read(0xA000), 2);
+    }
+
+    #[test]
+    fn test_rumble_hook() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::cartdrige::{Mbc5, ROM_BANK_SIZE};
+
+        let mut rom = vec![0x00; 2 * ROM_BANK_SIZE];
+        // LD A,0x08; LD (0x4000),A; XOR A; LD (0x4000),A
+        rom[0x100..0x108].copy_from_slice(&[0x3E, 0x08, 0xEA, 0x00, 0x40, 0xAF, 0xEA, 0x00]);
+        rom[0x108] = 0x40;
+        let mut emulator = Emulator::new(Box::new(Mbc5::new(rom, 0, true)));
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let seen = states.clone();
+        emulator.set_rumble_hook(Some(Box::new(move |on| seen.lock().unwrap().push(on))));
+        for _ in 0..4 {
+            emulator.step().unwrap();
+        }
+        assert_eq!(*states.lock().unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let mut rom = vec![0x00; 0x8000];
+        // LD A,0x42; LD (0xC000),A; LD A,(0xC000); JR -2
+        rom[0x100..0x10A]
+            .copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xFA, 0x00, 0xC0, 0x18, 0xFE]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        emulator.set_session(&Session {
+            breakpoints: vec!["0108".parse().unwrap()],
+            watchpoints: vec![Watchpoint {
+                address: "c000".parse().unwrap(),
+                access: Access::Write,
+            }],
+            ..Session::default()
+        });
+        emulator.step().unwrap();
+        assert_eq!(emulator.pending_break(), None);
+        emulator.step().unwrap();
+        assert_eq!(
+            emulator.take_break(),
+            Some(Break::Watchpoint {
+                address: "c000".parse().unwrap(),
+                write: true,
+                value: 0x42,
+                pc: "0102".parse().unwrap(),
+            })
+        );
+        // reads are not watched
+        emulator.step().unwrap();
+        assert_eq!(
+            emulator.pending_break(),
+            Some(Break::Breakpoint("0108".parse().unwrap()))
+        );
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.frame_count(), 0);
+        emulator.take_break();
+        // the loop comes back to it
+        emulator.step().unwrap();
+        assert!(emulator.take_break().is_some());
+
+        emulator.set_session(&Session::default());
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0150] = 0x11;
+        // LD A,(0x0150); JR -2
+        rom[0x100..0x105].copy_from_slice(&[0xFA, 0x50, 0x01, 0x18, 0xFE]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        emulator.set_session(&Session {
+            cheats: vec!["014200C0".to_string(), "991-50F-A0E".to_string()],
+            ..Session::default()
+        });
+        emulator.step().unwrap();
+        assert_eq!(emulator.cpu.registers.a, 0x99);
+        // debugger reads see the ROM
+        assert_eq!(emulator.read(0x0150), 0x11);
+        assert_eq!(emulator.read(0xC000), 0x00);
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.read(0xC000), 0x42);
+        // the compare value does not match
+        emulator.set_session(&Session {
+            cheats: vec!["991-50F-A1F".to_string()],
+            ..Session::default()
+        });
+        emulator.cpu.registers.pc.0 = 0x0100;
+        emulator.step().unwrap();
+        assert_eq!(emulator.cpu.registers.a, 0x11);
+    }
+
+    #[test]
+    fn test_backdrop() {
+        let mut rom = vec![0x00; 0x8000];
+        let mut dmg = Emulator::new(Box::new(RomOnly(rom.clone())));
+        dmg.set_backdrop(Some(Rgb555(0x0000)));
+        assert_eq!(dmg.backdrop(), Rgb555::WHITE);
+        rom[0x0143] = 0xC0;
+        let mut cgb = Emulator::new(Box::new(RomOnly(rom)));
+        cgb.set_backdrop(Some(Rgb555(0x001F)));
+        assert_eq!(cgb.frame().pixel(0, 0), [0xFF, 0x00, 0x00]);
+        cgb.set_color_correction(ColorCorrection::Cgb);
+        assert_eq!(cgb.frame().pixel(0, 0), [0xC9, 0x00, 0x2E]);
+        // palette data written by the game is exposed as is
+        cgb.write(palette::BCPS, 0x80);
+        cgb.write(palette::BCPD, 0x1F);
+        cgb.write(palette::BCPD, 0x00);
+        assert_eq!(cgb.cgb_palettes().background.color(0, 0), Rgb555(0x001F));
+    }
+
+    #[test]
+    fn test_notifications_unsupported_io() {
+        let mut emulator = Emulator::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        let notifications = emulator.notifications();
+        emulator.write(0xFF7F, 0x00);
+        emulator.write(0xFF7F, 0x01);
+        assert_eq!(
+            notifications.try_iter().collect::<Vec<_>>(),
+            vec![Notification::Unsupported {
+                feature: "I/O register 0xff7f".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_load_state() {
+        // DEC B; JR -3
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0x05, 0x18, 0xFD]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        emulator.run_frame().unwrap();
+        let state = emulator.save_state();
+        let hash = emulator.state_hash();
+        emulator.run_frame().unwrap();
+        assert_ne!(emulator.state_hash(), hash);
+        emulator.load_state(&state);
+        assert_eq!(emulator.state_hash(), hash);
+        assert_eq!(emulator.frame_count(), state.frame_count());
+    }
+
+    #[test]
+    fn test_state_hash_covers_the_mapped_banks() {
+        use crate::cartdrige::{Mbc5, ROM_BANK_SIZE};
+
+        let mut emulator =
+            Emulator::new(Box::new(Mbc5::new(vec![0x00; 4 * ROM_BANK_SIZE], 0, false)));
+        let hash = emulator.state_hash();
+        emulator.write(0x2000, 0x02);
+        assert_ne!(emulator.state_hash(), hash);
+        emulator.write(0x2000, 0x01);
+        assert_eq!(emulator.state_hash(), hash);
+    }
+
+    #[test]
+    fn test_load_state_restores_apu() {
+        let mut emulator = Emulator::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        emulator.write(0xFF24, 0x35);
+        emulator.write(0xFF30, 0x9A);
+        let state = emulator.save_state();
+        emulator.write(0xFF26, 0x00);
+        emulator.write(0xFF30, 0x00);
+        emulator.load_state(&state);
+        assert_eq!(emulator.read(0xFF24), 0x35);
+        assert_eq!(emulator.read(0xFF30), 0x9A);
+    }
+
+    #[test]
+    fn test_load_state_restores_mapper_state() {
+        let rom = vec![0x00; 0x8000];
+        let mut emulator = Emulator::new(Box::new(Mbc3::new(rom.clone(), 0, true)));
+        let state = emulator.save_state();
+        let hash = emulator.state_hash();
+        // a second on the cartridge clock
+        emulator.cpu.bus.cartdrige.tick(4_194_304);
+        assert_ne!(emulator.state_hash(), hash);
+        emulator.load_state(&state);
+        assert_eq!(emulator.state_hash(), hash);
+        // a cartridge without the clock keeps less
+        let mut other = Emulator::new(Box::new(Mbc3::new(rom, 0, false)));
+        assert!(other.try_load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_state_json_roundtrip() {
+        // DEC B; JR -3
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0x05, 0x18, 0xFD]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom.clone())));
+        emulator.write(0xC123, 0x45);
+        emulator.write(0xFF24, 0x35);
+        emulator.run_frame().unwrap();
+        let json = emulator.save_state().to_json();
+        assert!(json.contains("\"pc\": \"0x01"));
+
+        let mut restored = Emulator::new(Box::new(RomOnly(rom)));
+        let state = Savestate::from_json(&json).unwrap();
+        restored.try_load_state(&state).unwrap();
+        assert_eq!(restored.state_hash(), emulator.state_hash());
+        assert_eq!(restored.save_state().to_json(), json);
+        assert!(Savestate::from_json(&json.replace("\"halted\"", "\"x\"")).is_err());
+    }
+
+    #[test]
+    fn test_pixel_fifo_state_json_roundtrip() {
+        use crate::io::{BG_ENABLE, LCDC, LCD_ENABLE, OBJ_ENABLE, SCX};
+        use crate::ppu::{Mode, OAM_SCAN_DOTS};
+
+        // JR -2
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        let power_on = || {
+            let mut emulator = Emulator::new(Box::new(RomOnly(rom.clone())));
+            emulator.set_accuracy(Accuracy::Accurate);
+            emulator
+        };
+        let mut emulator = power_on();
+        emulator.write(LCDC, 0x00);
+        (0x8010..0x8020).for_each(|address| emulator.write(address, 0xFF));
+        // an object on the first line, and 3 pixels to throw away
+        for (address, value) in (0xFE00..).zip([16, 20, 1, 0x00]) {
+            emulator.write(address, value);
+        }
+        emulator.write(SCX, 0x03);
+        emulator.write(LCDC, LCD_ENABLE | BG_ENABLE | OBJ_ENABLE);
+        while emulator.cpu.bus.ppu.line_dot() < OAM_SCAN_DOTS + 40 {
+            emulator.step().unwrap();
+        }
+        assert!(emulator.cpu.bus.renderer.drawing());
+        let json = emulator.save_state().to_json();
+
+        let mut restored = power_on();
+        let state = Savestate::from_json(&json).unwrap();
+        restored.try_load_state(&state).unwrap();
+        assert_eq!(restored.save_state().to_json(), json);
+        // both take as long to finish the line, and draw it the same
+        for emulator in [&mut emulator, &mut restored] {
+            while emulator.cpu.bus.ppu.mode() != Mode::HBlank {
+                emulator.step().unwrap();
+            }
+        }
+        assert_eq!(
+            restored.cpu.bus.ppu.line_dot(),
+            emulator.cpu.bus.ppu.line_dot()
+        );
+        assert_eq!(restored.frame(), emulator.frame());
+        assert_eq!(restored.state_hash(), emulator.state_hash());
+    }
+
+    #[test]
+    fn test_trace_ends_with_faulting_instruction() {
+        // LD A,0x42; JP 0x4000; NOP; PREFIX CB in the switchable bank
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0105].copy_from_slice(&[0x3E, 0x42, 0xC3, 0x00, 0x40]);
+        rom[0x4000..0x4002].copy_from_slice(&[0x00, 0xCB]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        emulator.set_trace_capacity(2);
+        assert!(emulator.run_frame().is_err());
+        let entries: Vec<_> = emulator.trace().entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].address, emulator.banked(0x4000));
+        assert_eq!(entries[0].opcode, 0x00);
+        assert_eq!(entries[0].registers.a, 0x42);
+        assert_eq!(
+            (entries[1].address.address, entries[1].opcode),
+            (0x4001, 0xCB)
+        );
+        let dump = emulator.crash_dump();
+        assert!(dump.starts_with("last 2 instructions:\n  01:4000: 00 NOP"));
+        assert!(dump.contains("01:4001: cb PREFIX CB"));
+    }
+
+    #[test]
+    fn test_write_banked() {
+        let mut emulator = Emulator::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        assert!(!emulator.write_banked(0, 0x0150, 0x42));
+        assert_eq!(emulator.read(0x0150), 0x00);
+        assert!(emulator.write_banked(0, 0xFFFF, 0x1F));
+        assert_eq!(emulator.read_banked(0, 0xFFFF), Some(0x1F));
+        assert!(!emulator.write_banked(3, 0xFFFF, 0x00));
+    }
+}
@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+/// How memory without a defined power-on value (WRAM, VRAM and HRAM) is
+/// initialized. Some games seed their RNG from it, so runs meant to be
+/// reproduced need anything but a random fill without a seed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum MemoryFill {
+    #[default]
+    Zeros,
+    Ones,
+    /// Stripes of 0x00 and 0xFF, a stand-in for the noisy stripes real units
+    /// tend to power on with
+    Pattern,
+    /// Pseudo random bytes, the same for a given seed
+    Random(u64),
+}
+
+impl MemoryFill {
+    pub fn fill(&self, memory: &mut [u8]) {
+        match *self {
+            MemoryFill::Zeros => memory.fill(0x00),
+            MemoryFill::Ones => memory.fill(0xFF),
+            MemoryFill::Pattern => {
+                for (i, byte) in memory.iter_mut().enumerate() {
+                    *byte = if i & 0x08 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            MemoryFill::Random(seed) => {
+                let mut state = seed;
+                for chunk in memory.chunks_mut(8) {
+                    let bytes = splitmix64(&mut state).to_le_bytes();
+                    chunk.copy_from_slice(&bytes[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+// https://prng.di.unimi.it/splitmix64.c
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Accepts `zeros`, `ones`, `pattern` or `random:<seed>`
+impl FromStr for MemoryFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zeros" => Ok(MemoryFill::Zeros),
+            "ones" => Ok(MemoryFill::Ones),
+            "pattern" => Ok(MemoryFill::Pattern),
+            _ => s
+                .strip_prefix("random:")
+                .and_then(|seed| seed.parse().ok())
+                .map(MemoryFill::Random)
+                .ok_or_else(|| format!("Invalid memory fill: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill() {
+        let mut memory = [0x55; 20];
+        MemoryFill::Zeros.fill(&mut memory);
+        assert!(memory.iter().all(|&b| b == 0x00));
+        MemoryFill::Ones.fill(&mut memory);
+        assert!(memory.iter().all(|&b| b == 0xFF));
+        MemoryFill::Pattern.fill(&mut memory);
+        assert_eq!(memory[..8], [0x00; 8]);
+        assert_eq!(memory[8..16], [0xFF; 8]);
+        assert_eq!(memory[16..], [0x00; 4]);
+    }
+
+    #[test]
+    fn test_fill_random_is_seeded() {
+        let (mut a, mut b, mut c) = ([0; 13], [0; 13], [0; 13]);
+        MemoryFill::Random(1).fill(&mut a);
+        MemoryFill::Random(1).fill(&mut b);
+        MemoryFill::Random(2).fill(&mut c);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.iter().any(|&byte| byte != a[0]));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("zeros".parse(), Ok(MemoryFill::Zeros));
+        assert_eq!("ones".parse(), Ok(MemoryFill::Ones));
+        assert_eq!("pattern".parse(), Ok(MemoryFill::Pattern));
+        assert_eq!("random:42".parse(), Ok(MemoryFill::Random(42)));
+        assert!("random".parse::<MemoryFill>().is_err());
+        assert!("random:x".parse::<MemoryFill>().is_err());
+        assert!("0xFF".parse::<MemoryFill>().is_err());
+    }
+}
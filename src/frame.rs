@@ -0,0 +1,40 @@
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// A full screen of pixels, stored as RGB24 rows from top to bottom
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub pixels: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    /// A blank (white) screen, like the LCD when it is off
+    pub fn new() -> Self {
+        Self {
+            pixels: vec![0xFF; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+        }
+    }
+
+    /// Paints the whole screen `rgb`
+    pub fn fill(&mut self, rgb: [u8; 3]) {
+        for pixel in self.pixels.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&rgb);
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * SCREEN_WIDTH + x) * 3;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let i = (y * SCREEN_WIDTH + x) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&rgb);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use crate::frame::Frame;
+
+/// Slot shared by both ends; only ever held for a buffer swap
+#[derive(Default)]
+struct Shared {
+    middle: Frame,
+    fresh: bool,
+}
+
+/// Core side of the queue. Frames are drawn into a private back buffer and
+/// swapped into the shared slot on publish, so the core never waits on the
+/// renderer.
+pub struct FrameProducer {
+    back: Frame,
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// Renderer side of the queue. Holds its own front buffer, so a frame being
+/// presented can't be overwritten by the core.
+pub struct FrameConsumer {
+    front: Frame,
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// Creates a latest-wins triple buffer: frames the renderer didn't pick up in
+/// time are dropped in favour of newer ones.
+pub fn channel() -> (FrameProducer, FrameConsumer) {
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    (
+        FrameProducer {
+            back: Frame::new(),
+            shared: shared.clone(),
+        },
+        FrameConsumer {
+            front: Frame::new(),
+            shared,
+        },
+    )
+}
+
+impl FrameProducer {
+    /// Back buffer to draw the next frame into
+    pub fn back_mut(&mut self) -> &mut Frame {
+        &mut self.back
+    }
+
+    /// Hands the back buffer to the renderer, replacing any frame it hasn't
+    /// taken yet
+    pub fn publish(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        mem::swap(&mut self.back, &mut shared.middle);
+        shared.fresh = true;
+    }
+
+    /// Copies a finished frame into the back buffer and publishes it
+    pub fn publish_copy(&mut self, frame: &Frame) {
+        self.back.pixels.copy_from_slice(&frame.pixels);
+        self.publish();
+    }
+}
+
+impl FrameConsumer {
+    /// Swaps in the newest published frame, if any. Returns whether the
+    /// front buffer changed.
+    pub fn acquire(&mut self) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.fresh {
+            return false;
+        }
+        mem::swap(&mut self.front, &mut shared.middle);
+        shared.fresh = false;
+        true
+    }
+
+    /// Frame to present; stays valid until the next `acquire`
+    pub fn front(&self) -> &Frame {
+        &self.front
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_acquire_without_publish() {
+        let (_, mut consumer) = channel();
+        assert!(!consumer.acquire());
+        assert_eq!(consumer.front(), &Frame::new());
+    }
+
+    #[test]
+    fn test_latest_frame_wins() {
+        let (mut producer, mut consumer) = channel();
+        for shade in [0x10, 0x20, 0x30] {
+            producer.back_mut().set_pixel(0, 0, [shade; 3]);
+            producer.publish();
+        }
+        assert!(consumer.acquire());
+        assert_eq!(consumer.front().pixel(0, 0), [0x30; 3]);
+        assert!(!consumer.acquire());
+        assert_eq!(consumer.front().pixel(0, 0), [0x30; 3]);
+    }
+
+    #[test]
+    fn test_front_is_not_overwritten_by_publish() {
+        let (mut producer, mut consumer) = channel();
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 1, [1, 2, 3]);
+        producer.publish_copy(&frame);
+        consumer.acquire();
+        frame.set_pixel(1, 1, [4, 5, 6]);
+        producer.publish_copy(&frame);
+        assert_eq!(consumer.front().pixel(1, 1), [1, 2, 3]);
+        consumer.acquire();
+        assert_eq!(consumer.front().pixel(1, 1), [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_frames_are_never_torn_across_threads() {
+        let (mut producer, mut consumer) = channel();
+        let core = thread::spawn(move || {
+            for shade in 0..=255u8 {
+                producer.back_mut().pixels.fill(shade);
+                producer.publish();
+            }
+        });
+        while !core.is_finished() {
+            if consumer.acquire() {
+                let first = consumer.front().pixels[0];
+                assert!(consumer.front().pixels.iter().all(|&p| p == first));
+            }
+        }
+        core.join().unwrap();
+        consumer.acquire();
+        assert!(consumer.front().pixels.iter().all(|&p| p == 255));
+    }
+}
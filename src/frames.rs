@@ -0,0 +1,109 @@
+use crate::{cpu::CpuError, emulator::Emulator, frame::Frame};
+
+/// A frame as completed by the emulator
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompletedFrame {
+    // frames completed since power on, this one included
+    pub number: u64,
+    pub frame: Frame,
+}
+
+/// Runs the emulator a frame at a time, see `Emulator::frames`.
+///
+/// Ends when the CPU is stopped waiting for input, at a breakpoint or
+/// watchpoint, or after reporting an error.
+pub struct Frames<'a> {
+    emulator: &'a mut Emulator,
+    failed: bool,
+}
+
+impl<'a> Frames<'a> {
+    pub(crate) fn new(emulator: &'a mut Emulator) -> Self {
+        Self {
+            emulator,
+            failed: false,
+        }
+    }
+}
+
+impl Frames<'_> {
+    fn interrupted(&self) -> bool {
+        self.emulator.cpu.stopped || self.emulator.pending_break().is_some()
+    }
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Result<CompletedFrame, CpuError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.interrupted() {
+            return None;
+        }
+        if let Err(e) = self.emulator.run_frame() {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        if self.interrupted() {
+            // the frame never completed
+            return None;
+        }
+        Some(Ok(CompletedFrame {
+            number: self.emulator.frame_count(),
+            frame: self.emulator.frame().clone(),
+        }))
+    }
+}
+
+/// Emulation is synchronous, every frame is ready as soon as it is polled
+#[cfg(feature = "stream")]
+impl futures_core::Stream for Frames<'_> {
+    type Item = Result<CompletedFrame, CpuError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::task::Poll::Ready(self.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartdrige::RomOnly;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_frames() {
+        // JR -2
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        let numbers: Vec<_> = emulator
+            .frames()
+            .take(3)
+            .map(|frame| frame.unwrap().number)
+            .collect();
+        assert_eq!(numbers, [1, 2, 3]);
+        assert_eq!(emulator.frame_count(), 3);
+    }
+
+    #[test]
+    fn test_frames_end_on_error() {
+        // CB prefixed instructions are not implemented
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100] = 0xCB;
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        let mut frames = emulator.frames();
+        assert!(frames.next().unwrap().is_err());
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn test_frames_end_when_stopped() {
+        // STOP 0
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x10, 0x00]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom)));
+        assert_eq!(emulator.frames().count(), 0);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::json::Value;
+
+/// CGB VRAM DMA registers: the source and destination addresses, then the
+/// length and mode, writing it starts a transfer
+/// https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+pub const HDMA1: u16 = 0xFF51;
+pub const HDMA2: u16 = 0xFF52;
+pub const HDMA3: u16 = 0xFF53;
+pub const HDMA4: u16 = 0xFF54;
+pub const HDMA5: u16 = 0xFF55;
+
+/// Bytes copied at once, the whole transfer in general purpose mode and one
+/// of them per H-blank otherwise
+pub const BLOCK_SIZE: u16 = 0x10;
+/// Dots the CPU is stalled for per block, in both speed modes
+pub const BLOCK_DOTS: u32 = 32;
+
+const HBLANK_MODE: u8 = 0x80;
+
+/// VRAM DMA engine. The CPU is stopped while a block is copied, general
+/// purpose transfers copy every block at once while H-blank ones copy one
+/// block at the start of each H-blank.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hdma {
+    source: u16,
+    // offset in VRAM
+    destination: u16,
+    // blocks left to copy
+    remaining: u8,
+    // blocks copied since the transfer started
+    copied: u8,
+    hblank: bool,
+    active: bool,
+    // dots the CPU has yet to be stalled for
+    stall: u32,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            // bit 7 is clear while a transfer is going on, the low bits are
+            // the blocks left minus one, 0xFF once done
+            HDMA5 => (!self.active as u8) << 7 | self.remaining.wrapping_sub(1) & 0x7F,
+            // write-only
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            HDMA1 => self.source = (value as u16) << 8 | self.source & 0x00FF,
+            HDMA2 => self.source = self.source & 0xFF00 | (value & 0xF0) as u16,
+            HDMA3 => self.destination = ((value & 0x1F) as u16) << 8 | self.destination & 0x00FF,
+            HDMA4 => self.destination = self.destination & 0xFF00 | (value & 0xF0) as u16,
+            // clearing bit 7 during an H-blank transfer stops it
+            HDMA5 if self.active && self.hblank && value & HBLANK_MODE == 0 => {
+                self.active = false;
+            }
+            HDMA5 => {
+                self.remaining = (value & 0x7F) + 1;
+                self.copied = 0;
+                self.hblank = value & HBLANK_MODE != 0;
+                self.active = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Whether the transfer goes one block per H-blank
+    pub fn hblank(&self) -> bool {
+        self.hblank
+    }
+
+    /// Address the next block is read from
+    pub fn source(&self) -> u16 {
+        self.source
+    }
+
+    /// Address in VRAM the next block is written to
+    pub fn destination(&self) -> u16 {
+        0x8000 | self.destination
+    }
+
+    /// Blocks copied and left to copy by the current transfer
+    pub fn progress(&self) -> (u8, u8) {
+        (self.copied, self.remaining)
+    }
+
+    /// Source and destination of the next block, then moves past it and
+    /// stalls the CPU for it. `None` when there is nothing left to copy.
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if !self.active {
+            return None;
+        }
+        let block = (self.source(), self.destination());
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.destination = (self.destination + BLOCK_SIZE) & 0x1FF0;
+        self.remaining -= 1;
+        self.copied += 1;
+        self.active = self.remaining > 0;
+        self.stall += BLOCK_DOTS;
+        Some(block)
+    }
+
+    /// Dots the CPU has to wait for the blocks copied since the last call
+    pub fn take_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("source", Value::hex(self.source as u64, 4)),
+            ("destination", Value::hex(self.destination as u64, 4)),
+            ("remaining", Value::Number(self.remaining as u64)),
+            ("copied", Value::Number(self.copied as u64)),
+            ("hblank", Value::Bool(self.hblank)),
+            ("active", Value::Bool(self.active)),
+            ("stall", Value::Number(self.stall as u64)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let remaining = value.get("remaining")?.as_u8()?;
+        let active = value.get("active")?.as_bool()?;
+        // a transfer ends with its last block
+        if active && remaining == 0 {
+            return Err("Active HDMA transfer with no blocks left".to_string());
+        }
+        Ok(Self {
+            source: value.get("source")?.as_u16()? & 0xFFF0,
+            destination: value.get("destination")?.as_u16()? & 0x1FF0,
+            remaining,
+            copied: value.get("copied")?.as_u8()?,
+            hblank: value.get("hblank")?.as_bool()?,
+            active,
+            stall: value.get("stall")?.as_u32()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hdma_registers() {
+        let mut hdma = Hdma::new();
+        hdma.write(HDMA1, 0xC1);
+        hdma.write(HDMA2, 0x2F);
+        hdma.write(HDMA3, 0xFF);
+        hdma.write(HDMA4, 0xFF);
+        assert_eq!((hdma.source(), hdma.destination()), (0xC120, 0x9FF0));
+        assert_eq!(hdma.read(HDMA1), 0xFF);
+        assert_eq!(hdma.read(HDMA5), 0xFF);
+        // two blocks, one per H-blank
+        hdma.write(HDMA5, 0x81);
+        assert_eq!(hdma.read(HDMA5), 0x01);
+        assert_eq!(hdma.next_block(), Some((0xC120, 0x9FF0)));
+        // the destination wraps around VRAM
+        assert_eq!(hdma.destination(), 0x8000);
+        assert_eq!(hdma.read(HDMA5), 0x00);
+        assert_eq!(hdma.take_stall(), BLOCK_DOTS);
+        assert_eq!(hdma.take_stall(), 0);
+        assert_eq!(hdma.next_block(), Some((0xC130, 0x8000)));
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read(HDMA5), 0xFF);
+    }
+
+    #[test]
+    fn test_hblank_dma_can_be_stopped() {
+        let mut hdma = Hdma::new();
+        hdma.write(HDMA5, 0x83);
+        hdma.next_block();
+        hdma.write(HDMA5, 0x00);
+        assert!(!hdma.active());
+        // the blocks that were left
+        assert_eq!(hdma.read(HDMA5), 0x82);
+        assert_eq!(hdma.next_block(), None);
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let mut hdma = Hdma::new();
+        hdma.write(HDMA5, 0x83);
+        hdma.next_block();
+        assert_eq!(Hdma::from_json(&hdma.to_json()), Ok(hdma));
+        // hand edited to be active with nothing left
+        let edited = hdma
+            .to_json()
+            .to_string()
+            .replace("\"remaining\": 3", "\"remaining\": 0");
+        assert!(Hdma::from_json(&Value::parse(&edited).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::json::Value;
+
+/// Interrupt registers
+/// Following
+/// https://gbdev.io/pandocs/Interrupts.html
+pub const IF: u16 = 0xFF0F;
+pub const IE: u16 = 0xFFFF;
+
+// bits of IE and IF, from highest to lowest priority
+pub const VBLANK: u8 = 1 << 0;
+pub const STAT: u8 = 1 << 1;
+pub const TIMER: u8 = 1 << 2;
+pub const SERIAL: u8 = 1 << 3;
+pub const JOYPAD: u8 = 1 << 4;
+
+// handler addresses, one every 8 bytes from 0x0040 in priority order
+const VECTORS: u16 = 0x0040;
+
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Interrupts {
+    // IE, which interrupts are allowed to fire
+    pub enable: u8,
+    // IF, which interrupts are requested
+    pub flag: u8,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self {
+            enable: 0x00,
+            // reads as 0xE1 after the boot ROM
+            flag: 0x01,
+        }
+    }
+
+    pub fn request(&mut self, interrupt: u8) {
+        self.flag |= interrupt;
+    }
+
+    /// Interrupts both requested and enabled, regardless of IME
+    pub fn pending(&self) -> u8 {
+        self.enable & self.flag & 0x1F
+    }
+
+    /// The pending interrupt that gets serviced first (the lowest bit) and
+    /// the address of its handler
+    pub fn highest_priority(&self) -> Option<(u8, u16)> {
+        let pending = self.pending();
+        if pending == 0 {
+            return None;
+        }
+        let bit = pending.trailing_zeros() as u16;
+        Some((1 << bit, VECTORS + bit * 8))
+    }
+
+    /// Clears the request once the CPU jumps to the handler
+    pub fn acknowledge(&mut self, interrupt: u8) {
+        self.flag &= !interrupt;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            // only the five lower bits are backed by the register
+            IF => self.flag | 0xE0,
+            IE => self.enable,
+            _ => panic!("Invalid interrupt address: {:#06x}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            IF => self.flag = value & 0x1F,
+            IE => self.enable = value,
+            _ => panic!("Invalid interrupt address: {:#06x}", address),
+        }
+    }
+}
+
+impl Interrupts {
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("enable", Value::hex(self.enable as u64, 2)),
+            ("flag", Value::hex(self.flag as u64, 2)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            enable: value.get("enable")?.as_u8()?,
+            flag: value.get("flag")?.as_u8()? & 0x1F,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highest_priority() {
+        let mut interrupts = Interrupts::new();
+        interrupts.flag = 0x00;
+        assert_eq!(interrupts.highest_priority(), None);
+        interrupts.request(JOYPAD);
+        interrupts.request(TIMER);
+        // requested but not enabled
+        assert_eq!(interrupts.highest_priority(), None);
+        interrupts.enable = 0xFF;
+        assert_eq!(interrupts.highest_priority(), Some((TIMER, 0x0050)));
+        interrupts.acknowledge(TIMER);
+        assert_eq!(interrupts.highest_priority(), Some((JOYPAD, 0x0060)));
+        interrupts.request(VBLANK);
+        assert_eq!(interrupts.highest_priority(), Some((VBLANK, 0x0040)));
+    }
+}
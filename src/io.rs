@@ -0,0 +1,205 @@
+use crate::json::Value;
+
+/// Divider, counts up at 16384 Hz and resets when written
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+pub const LCDC: u16 = 0xFF40;
+/// LCDC bit turning the LCD and the PPU on
+pub const LCD_ENABLE: u8 = 0x80;
+/// LCDC bit taking the window tile map from 0x9C00 instead of 0x9800
+pub const WINDOW_MAP: u8 = 0x40;
+pub const WINDOW_ENABLE: u8 = 0x20;
+/// LCDC bit addressing the tiles from 0x8000 with unsigned indices, instead
+/// of from 0x9000 with signed ones
+pub const TILE_DATA: u8 = 0x10;
+/// LCDC bit taking the background tile map from 0x9C00 instead of 0x9800
+pub const BG_MAP: u8 = 0x08;
+/// LCDC bit making the objects 8x16 instead of 8x8
+pub const OBJ_SIZE: u8 = 0x04;
+pub const OBJ_ENABLE: u8 = 0x02;
+/// LCDC bit showing the background and the window on DMG. On CGB they
+/// always show, and the bit gives them priority over the objects instead.
+pub const BG_ENABLE: u8 = 0x01;
+pub const STAT: u16 = 0xFF41;
+/// STAT bits holding the PPU mode
+pub const STAT_MODE: u8 = 0x03;
+/// STAT bit set while LY equals LYC
+pub const STAT_COINCIDENCE: u8 = 0x04;
+/// STAT bits selecting the sources of the STAT interrupt: H-blank, V-blank,
+/// the OAM scan, and LY equal to LYC
+/// https://gbdev.io/pandocs/STAT.html#ff41--stat-lcd-status
+pub const STAT_HBLANK_INTERRUPT: u8 = 0x08;
+pub const STAT_VBLANK_INTERRUPT: u8 = 0x10;
+pub const STAT_OAM_INTERRUPT: u8 = 0x20;
+pub const STAT_LYC_INTERRUPT: u8 = 0x40;
+pub const SCY: u16 = 0xFF42;
+pub const SCX: u16 = 0xFF43;
+pub const LYC: u16 = 0xFF45;
+pub const BGP: u16 = 0xFF47;
+pub const OBP0: u16 = 0xFF48;
+pub const OBP1: u16 = 0xFF49;
+pub const WY: u16 = 0xFF4A;
+pub const WX: u16 = 0xFF4B;
+
+const IO_START: u16 = 0xFF00;
+const IO_SIZE: usize = 0x80;
+
+// DIV is the upper byte of a counter incremented every T-cycle
+const DIV_SHIFT: u32 = 8;
+
+/// An I/O register and which of its bits exist
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Register {
+    pub address: u16,
+    pub name: &'static str,
+    /// Bits the CPU can change
+    pub write_mask: u8,
+    /// Bits that read back, the others read as 1
+    pub read_mask: u8,
+}
+
+const fn register(address: u16, name: &'static str, write_mask: u8, read_mask: u8) -> Register {
+    Register {
+        address,
+        name,
+        write_mask,
+        read_mask,
+    }
+}
+
+/// The registers of 0xFF00-0xFF7F no device handles yet
+/// https://gbdev.io/pandocs/Hardware_Reg_List.html
+pub const REGISTERS: [Register; 14] = [
+    register(DIV, "DIV", 0x00, 0xFF),
+    register(TIMA, "TIMA", 0xFF, 0xFF),
+    register(TMA, "TMA", 0xFF, 0xFF),
+    register(TAC, "TAC", 0x07, 0x07),
+    register(LCDC, "LCDC", 0xFF, 0xFF),
+    // the mode and coincidence bits are driven by the PPU
+    register(STAT, "STAT", 0x78, 0x7F),
+    register(SCY, "SCY", 0xFF, 0xFF),
+    register(SCX, "SCX", 0xFF, 0xFF),
+    register(LYC, "LYC", 0xFF, 0xFF),
+    register(BGP, "BGP", 0xFF, 0xFF),
+    register(OBP0, "OBP0", 0xFF, 0xFF),
+    register(OBP1, "OBP1", 0xFF, 0xFF),
+    register(WY, "WY", 0xFF, 0xFF),
+    register(WX, "WX", 0xFF, 0xFF),
+];
+
+pub fn find(address: u16) -> Option<&'static Register> {
+    REGISTERS
+        .iter()
+        .find(|register| register.address == address)
+}
+
+/// Storage for the registers in `REGISTERS`, so that programs polling them
+/// read back what they wrote with the unused bits set like on hardware.
+/// Addresses that are not registers at all read as 0xFF.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IoRegisters {
+    values: Vec<u8>,
+    // internal counter DIV is the upper byte of
+    divider: u16,
+}
+
+impl Default for IoRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoRegisters {
+    /// As the DMG boot ROM leaves them
+    pub fn new() -> Self {
+        let mut values = vec![0x00; IO_SIZE];
+        values[(LCDC - IO_START) as usize] = 0x91;
+        values[(STAT - IO_START) as usize] = 0x05;
+        values[(BGP - IO_START) as usize] = 0xFC;
+        Self {
+            values,
+            divider: 0xABCC,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match find(address) {
+            Some(register) if register.address == DIV => (self.divider >> DIV_SHIFT) as u8,
+            Some(register) => {
+                let value = self.values[(address - IO_START) as usize];
+                value & register.read_mask | !register.read_mask
+            }
+            None => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match find(address) {
+            // whatever the value
+            Some(register) if register.address == DIV => self.divider = 0,
+            Some(register) => {
+                let current = &mut self.values[(address - IO_START) as usize];
+                *current = *current & !register.write_mask | value & register.write_mask;
+            }
+            None => {}
+        }
+    }
+
+    /// Advances the divider by `cycles` CPU T-cycles
+    pub fn tick(&mut self, cycles: u32) {
+        self.divider = self.divider.wrapping_add(cycles as u16);
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        Value::object([
+            ("values", Value::bytes(&self.values)),
+            ("divider", Value::hex(self.divider as u64, 4)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let mut values = vec![0x00; IO_SIZE];
+        value.get("values")?.read_bytes(&mut values)?;
+        Ok(Self {
+            values,
+            divider: value.get("divider")?.as_u16()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_back_masks() {
+        let mut io = IoRegisters::new();
+        io.write(TAC, 0xFF);
+        assert_eq!(io.read(TAC), 0xFF);
+        io.write(TAC, 0x05);
+        assert_eq!(io.read(TAC), 0xFD);
+        // the PPU owns the low bits
+        io.write(STAT, 0xFF);
+        assert_eq!(io.read(STAT), 0xFD);
+        io.write(SCX, 0x12);
+        assert_eq!(io.read(SCX), 0x12);
+        assert_eq!(io.read(0xFF03), 0xFF);
+        io.write(0xFF03, 0x00);
+        assert_eq!(io.read(0xFF03), 0xFF);
+    }
+
+    #[test]
+    fn test_div() {
+        let mut io = IoRegisters::new();
+        assert_eq!(io.read(DIV), 0xAB);
+        io.write(DIV, 0x42);
+        assert_eq!(io.read(DIV), 0x00);
+        io.tick(255);
+        assert_eq!(io.read(DIV), 0x00);
+        io.tick(1);
+        assert_eq!(io.read(DIV), 0x01);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::json::Value;
+
+/// Joypad input
+/// Following
+/// https://gbdev.io/pandocs/Joypad_Input.html
+pub const P1: u16 = 0xFF00;
+
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_BUTTONS: u8 = 1 << 5;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Bit of the button in `Joypad::state`, directions live in the lower
+    /// nibble and buttons in the upper one
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Joypad {
+    // P1 bits 4-5, active low
+    select: u8,
+    pressed: u8,
+    interrupt: bool,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            pressed: 0x00,
+            interrupt: false,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        if self.pressed & button.mask() == 0 {
+            self.interrupt = true;
+        }
+        self.pressed |= button.mask();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    /// Held buttons, one bit per `Button`
+    pub fn state(&self) -> u8 {
+        self.pressed
+    }
+
+    /// Replaces every button at once, as when playing back a movie
+    pub fn set_state(&mut self, pressed: u8) {
+        if pressed & !self.pressed != 0 {
+            self.interrupt = true;
+        }
+        self.pressed = pressed;
+    }
+
+    pub fn any_pressed(&self) -> bool {
+        self.pressed != 0
+    }
+
+    /// Whether a button was pressed since the last call
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    pub fn read(&self) -> u8 {
+        let mut lines = 0x00;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            lines |= self.pressed >> 4;
+        }
+        // pressed buttons pull their line low
+        0xC0 | self.select | (!lines & 0x0F)
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & 0x30;
+    }
+}
+
+impl Joypad {
+    pub(crate) fn to_json(&self) -> Value {
+        Value::object([
+            ("select", Value::hex(self.select as u64, 2)),
+            ("pressed", Value::hex(self.pressed as u64, 2)),
+            ("interrupt", Value::Bool(self.interrupt)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            select: value.get("select")?.as_u8()? & 0x30,
+            pressed: value.get("pressed")?.as_u8()?,
+            interrupt: value.get("interrupt")?.as_bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_joypad_read() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Start);
+        joypad.press(Button::Left);
+        assert_eq!(joypad.read(), 0xFF);
+        // a cleared select bit enables its group
+        joypad.write(SELECT_BUTTONS);
+        assert_eq!(joypad.read(), 0xE0 | 0b1101);
+        joypad.write(SELECT_DIRECTIONS);
+        assert_eq!(joypad.read(), 0xD0 | 0b0111);
+        joypad.release(Button::Start);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC0 | 0b1101);
+    }
+
+    #[test]
+    fn test_joypad_interrupt_on_press_only() {
+        let mut joypad = Joypad::new();
+        assert!(!joypad.take_interrupt());
+        joypad.press(Button::A);
+        assert!(joypad.take_interrupt());
+        joypad.press(Button::A);
+        joypad.release(Button::A);
+        assert!(!joypad.take_interrupt());
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+
+/// Bytes per line when dumping memory
+const BYTES_PER_ROW: usize = 32;
+
+/// Just enough JSON for the human-readable state dumps: no floats, and
+/// strings only support the `\"`, `\\` and `\uXXXX` escapes.
+///
+/// Integers can also be written as `"0x.."` strings, which is how registers
+/// are dumped, and memory is an array of rows of hex bytes so that dumps can
+/// be diffed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Value>),
+    // in insertion order, so dumps are stable
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// `value` as a `0x` prefixed string of `digits` hex digits
+    pub fn hex(value: u64, digits: usize) -> Self {
+        Value::String(format!("{:#0width$x}", value, width = digits + 2))
+    }
+
+    pub fn bytes(bytes: &[u8]) -> Self {
+        Value::Array(
+            bytes
+                .chunks(BYTES_PER_ROW)
+                .map(|row| {
+                    let row: Vec<_> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    Value::String(row.join(" "))
+                })
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Result<&Value, String> {
+        match self {
+            Value::Object(fields) => fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("Missing field: {}", key)),
+            _ => Err(format!("Expected an object around {}", key)),
+        }
+    }
+
+    pub fn as_u64(&self) -> Result<u64, String> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            Value::String(s) => s
+                .strip_prefix("0x")
+                .and_then(|digits| u64::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("Invalid number: {}", s)),
+            _ => Err(format!("Expected a number, got {}", self)),
+        }
+    }
+
+    pub fn as_u32(&self) -> Result<u32, String> {
+        let n = self.as_u64()?;
+        u32::try_from(n).map_err(|_| format!("Number too big: {}", n))
+    }
+
+    pub fn as_u16(&self) -> Result<u16, String> {
+        let n = self.as_u64()?;
+        u16::try_from(n).map_err(|_| format!("Number too big: {}", n))
+    }
+
+    pub fn as_u8(&self) -> Result<u8, String> {
+        let n = self.as_u64()?;
+        u8::try_from(n).map_err(|_| format!("Number too big: {}", n))
+    }
+
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(format!("Expected a boolean, got {}", self)),
+        }
+    }
+
+    /// Reads memory written by `Value::bytes`
+    pub fn as_bytes(&self) -> Result<Vec<u8>, String> {
+        let Value::Array(rows) = self else {
+            return Err("Expected an array of hex rows".to_string());
+        };
+        let mut bytes = Vec::new();
+        for row in rows {
+            let Value::String(row) = row else {
+                return Err(format!("Expected a row of hex bytes, got {}", row));
+            };
+            for byte in row.split_whitespace() {
+                bytes.push(
+                    u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid byte: {}", byte))?,
+                );
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Reads `expected.len()` bytes into `expected`
+    pub fn read_bytes(&self, expected: &mut [u8]) -> Result<(), String> {
+        let bytes = self.as_bytes()?;
+        if bytes.len() != expected.len() {
+            return Err(format!(
+                "Expected {} bytes, got {}",
+                expected.len(),
+                bytes.len()
+            ));
+        }
+        expected.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.chars.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "\"{}\"", escape(s)),
+            Value::Array(items) if items.is_empty() => write!(f, "[]"),
+            Value::Array(items) => {
+                writeln!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    item.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < items.len() { "," } else { "" })?;
+                }
+                write!(f, "{}]", "  ".repeat(indent))
+            }
+            Value::Object(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Value::Object(fields) => {
+                writeln!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    write!(f, "{}\"{}\": ", pad, escape(key))?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < fields.len() { "," } else { "" })?;
+                }
+                write!(f, "{}}}", "  ".repeat(indent))
+            }
+        }
+    }
+}
+
+// only what `Parser` reads back
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Pretty printed, two spaces per level
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON at character {}: {}", self.position, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.position += 1;
+        c
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value, String> {
+        for expected in keyword.chars() {
+            if self.next() != Some(expected) {
+                return Err(self.error(&format!("expected {}", keyword)));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        let digits: String = self.chars[start..self.position].iter().collect();
+        digits
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| self.error("number out of range"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('u') => {
+                        let digits: String = (0..4).filter_map(|_| self.next()).collect();
+                        let c = u32::from_str_radix(&digits, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error("invalid \\u escape"))?;
+                        s.push(c);
+                    }
+                    _ => return Err(self.error("unsupported escape")),
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(fields)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let value = Value::object([
+            ("pc", Value::hex(0x0150, 4)),
+            ("ime", Value::Bool(true)),
+            ("cycles", Value::Number(70224)),
+            ("name", Value::String("A \"B\" \u{1}".to_string())),
+            ("empty", Value::Array(Vec::new())),
+            ("nothing", Value::Null),
+            ("hram", Value::bytes(&[0xAB; 40])),
+        ]);
+        let text = value.to_string();
+        assert!(text.starts_with("{\n  \"pc\": \"0x0150\",\n  \"ime\": true,\n"));
+        assert_eq!(Value::parse(&text), Ok(value.clone()));
+        assert_eq!(value.get("pc").unwrap().as_u16(), Ok(0x0150));
+        assert_eq!(value.get("cycles").unwrap().as_u64(), Ok(70224));
+        assert_eq!(value.get("hram").unwrap().as_bytes(), Ok(vec![0xAB; 40]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Value::parse("").is_err());
+        assert!(Value::parse("{\"a\": 1,}").is_err());
+        assert!(Value::parse("[1 2]").is_err());
+        assert!(Value::parse("\"open").is_err());
+        assert!(Value::parse("1.5").is_err());
+        assert!(Value::parse("{} {}").is_err());
+        let value = Value::parse("{\"a\": 300, \"b\": \"0xzz\"}").unwrap();
+        assert!(value.get("a").unwrap().as_u8().is_err());
+        assert!(value.get("b").unwrap().as_u64().is_err());
+        assert!(value.get("c").is_err());
+    }
+}
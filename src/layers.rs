@@ -0,0 +1,55 @@
+use std::str::FromStr;
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// Layers drawn by the compositor. Hiding one only affects the output
+    /// frame, the emulated LCDC is left alone so games behave the same.
+    #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+    pub struct Layers: u8 {
+        const BACKGROUND = 1 << 0;
+        const WINDOW = 1 << 1;
+        const SPRITES = 1 << 2;
+    }
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Layers::all()
+    }
+}
+
+/// Accepts a comma separated list of `bg`, `window` and `sprites`
+impl FromStr for Layers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',').try_fold(Layers::empty(), |layers, name| {
+            let layer = match name.trim() {
+                "bg" => Layers::BACKGROUND,
+                "window" => Layers::WINDOW,
+                "sprites" => Layers::SPRITES,
+                _ => return Err(format!("Invalid layer: {}", name)),
+            };
+            Ok(layers | layer)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("bg".parse(), Ok(Layers::BACKGROUND));
+        assert_eq!(
+            "sprites,window".parse(),
+            Ok(Layers::SPRITES | Layers::WINDOW)
+        );
+        assert_eq!("bg,window,sprites".parse(), Ok(Layers::all()));
+        assert!("".parse::<Layers>().is_err());
+        assert!("bg,oam".parse::<Layers>().is_err());
+        assert_eq!(Layers::default(), Layers::all());
+    }
+}
@@ -0,0 +1,48 @@
+pub mod accuracy;
+pub mod apu;
+pub mod bus;
+pub mod cartdrige;
+pub mod clock;
+pub mod compat;
+pub mod cpu;
+pub mod debug;
+pub mod debugger;
+pub mod determinism;
+pub mod dma;
+pub mod doctor;
+pub mod emulator;
+pub mod fill;
+pub mod frame;
+// for a renderer on its own thread, the frontend has none yet
+#[allow(dead_code)]
+mod frame_queue;
+pub mod frames;
+pub mod hash;
+pub mod hdma;
+pub mod interrupt;
+pub mod io;
+pub mod joypad;
+pub mod json;
+pub mod layers;
+pub mod mmu;
+pub mod movie;
+pub mod notification;
+pub mod op;
+pub mod palette;
+pub mod persistence;
+pub mod ppu;
+pub mod register;
+pub mod renderer;
+pub mod rng;
+pub mod run_ahead;
+pub mod savestate;
+pub mod screenshot;
+pub mod selftest;
+pub mod serial;
+pub mod services;
+pub mod session;
+pub mod speed;
+pub mod summary;
+pub mod symbols;
+pub mod video_dump;
+pub mod watch;
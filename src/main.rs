@@ -1,24 +1,513 @@
-mod cartdrige;
-mod cpu;
-mod register;
+mod options;
 mod window;
 
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::info;
+use gameboy::{
+    cartdrige,
+    debug::{BankPanel, DmaPanel},
+    debugger::{self, Command},
+    determinism,
+    doctor::{self, Doctor},
+    emulator::Emulator,
+    layers::Layers,
+    movie::Movie,
+    notification::Notification,
+    persistence::FileStorage,
+    run_ahead::RunAhead,
+    savestate::Savestate,
+    screenshot, selftest,
+    serial::Capture,
+    services::Services,
+    session::Session,
+    speed::SpeedLimiter,
+    summary::{Summary, TestResult},
+    symbols::Symbols,
+    video_dump::VideoDump,
+    watch::Watcher,
+};
+use log::{debug, error, info, warn};
+use options::Options;
+
+// how often `--watch` looks at the ROM
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+fn exit_with_usage(error: &str) -> ! {
+    eprintln!("error: {}\n\n{}", error, options::USAGE);
+    process::exit(1);
+}
+
+/// Storage for the file at `path`, and its key in there
+fn file_storage(path: &Path) -> (FileStorage, String) {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let key = path.file_name().unwrap_or_default().to_string_lossy();
+    (FileStorage::new(dir), key.into_owned())
+}
+
+/// Seconds since the Unix epoch, for the clock of MBC3 cartridges
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Writes the battery backed RAM next to the ROM, if the cartridge has any
+fn save_battery(emulator: &mut Emulator, options: &Options) {
+    let path = cartdrige::save_path(&options.rom);
+    let (mut storage, key) = file_storage(&path);
+    match emulator.save_battery(&mut storage, &key, unix_time()) {
+        Ok(true) => emulator
+            .cpu
+            .bus
+            .notifier
+            .notify(Notification::SaveWritten { path }),
+        Ok(false) => {}
+        Err(e) => error!("{}: {}", path.display(), e),
+    }
+}
+
+/// Prints what the program wrote to the emulator services since last time
+fn print_messages(emulator: &mut Emulator) {
+    if let Some(services) = &mut emulator.cpu.bus.services {
+        for message in services.take_messages() {
+            println!("{}", message);
+        }
+    }
+}
+
+/// Loads the ROM and applies the start up options, the symbol file is read
+/// again every time so `--watch` picks up renamed labels
+fn power_on(options: &Options, now: u64) -> Emulator {
+    let rom = cartdrige::load(&options.rom, options.strict)
+        .unwrap_or_else(|e| exit_with_usage(&format!("{}: {}", options.rom.display(), e)));
+    let mut emulator = Emulator::new(rom);
+    emulator.cpu.fill_memory(options.fill);
+    emulator.set_layers(Layers::all() - options.hidden_layers);
+    emulator.set_accuracy(options.accuracy);
+    emulator.set_color_correction(options.color_correction);
+    emulator.set_trace_capacity(options.trace);
+    let save_path = cartdrige::save_path(&options.rom);
+    let (storage, key) = file_storage(&save_path);
+    match emulator.load_battery(&storage, &key, now) {
+        Ok(true) => info!("loaded {}", save_path.display()),
+        Ok(false) => {}
+        Err(e) => exit_with_usage(&format!("{}: {}", save_path.display(), e)),
+    }
+    if options.services {
+        emulator.cpu.bus.services = Some(Services::new());
+    }
+    if let Some(path) = &options.boot_rom {
+        let booted = fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|boot_rom| emulator.boot(boot_rom));
+        if let Err(e) = booted {
+            exit_with_usage(&format!("{}: {}", path.display(), e));
+        }
+    }
+    if let Some(preset) = &options.registers {
+        if let Err(e) = emulator.cpu.registers.apply_preset(preset) {
+            exit_with_usage(&e);
+        }
+    }
+    if let Some(pc) = options.pc {
+        emulator.cpu.registers.pc.0 = pc;
+    }
+    if let Some(name) = &options.skip_to {
+        let path = options.symbols_path();
+        let symbols = Symbols::load(&path)
+            .unwrap_or_else(|e| exit_with_usage(&format!("{}: {}", path.display(), e)));
+        let symbol = symbols
+            .get(name)
+            .unwrap_or_else(|| exit_with_usage(&format!("Unknown symbol: {}", name)));
+        let mapped = emulator.cpu.bus.cartdrige.bank_state().rom_bank;
+        if symbol.address >= 0x4000 && symbol.bank != mapped {
+            warn!(
+                "{} lives in bank {:#04x} but bank {:#04x} is mapped",
+                name, symbol.bank, mapped
+            );
+        }
+        emulator.cpu.registers.pc.0 = symbol.address;
+    }
+    if let Some(path) = &options.load_state {
+        let (storage, key) = file_storage(path);
+        let loaded = Savestate::load(&storage, &key)
+            .and_then(|state| state.ok_or_else(|| "No such file".to_string()))
+            .and_then(|state| emulator.try_load_state(&state));
+        if let Err(e) = loaded {
+            exit_with_usage(&format!("{}: {}", path.display(), e));
+        }
+    }
+    info!("starting at {:#06x}", emulator.cpu.registers.pc.value());
+    emulator
+}
+
+/// Runs `f`, and reports the recent instructions and the registers before
+/// passing on a panic
+fn crash_guard<T>(emulator: &mut Emulator, f: impl FnOnce(&mut Emulator) -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(emulator))) {
+        Ok(result) => result,
+        Err(payload) => {
+            error!("emulator crashed\n{}", emulator.crash_dump());
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+/// Reads debugger commands from the terminal until `run`
+fn prompt(history: &mut debugger::History, session: &mut Session) {
+    let stdin = io::stdin();
+    if let Err(e) = debugger::prompt(&mut stdin.lock(), &mut io::stdout(), history, session) {
+        warn!("debugger: {}", e);
+    }
+}
+
+/// Applies the cheats of `session` and the `init` script commands, and
+/// their breakpoints and watchpoints when the debugger is attached to resume
+/// from them
+fn arm(emulator: &mut Emulator, session: &Session, init: &[Command], debugger: bool) {
+    let mut armed = session.clone();
+    init.iter().for_each(|command| command.apply(&mut armed));
+    if !debugger {
+        armed.breakpoints.clear();
+        armed.watchpoints.clear();
+    }
+    emulator.set_session(&armed);
+}
+
+/// Writes the frame just completed to the video dump, which is dropped
+/// after the first error
+fn dump_frame(dump: &mut Option<VideoDump>, emulator: &Emulator) {
+    if let Some(out) = dump {
+        if let Err(e) = out.write_frame(emulator.frame_count(), emulator.frame()) {
+            error!("video dump: {}", e);
+            *dump = None;
+        }
+    }
+}
 
 pub fn main() {
+    // before the logger, the checks step through millions of instructions
+    if env::args().nth(1).as_deref() == Some("selftest") {
+        let report = selftest::run();
+        print!("{}", report);
+        process::exit(if report.passed() { 0 } else { 1 });
+    }
     env_logger::builder()
         .filter_level(log::LevelFilter::Debug)
         .init();
     // set log level default to info
     info!("starting up");
-    let args: Vec<String> = env::args().collect();
-    let rom_path = &args[1];
+    let options = Options::parse(env::args().skip(1)).unwrap_or_else(|e| exit_with_usage(&e));
+
+    let movie = match &options.movie {
+        Some(path) => Movie::load(path)
+            .unwrap_or_else(|e| exit_with_usage(&format!("{}: {}", path.display(), e))),
+        None => Movie::default(),
+    };
+    if let Some(frames) = options.check_determinism {
+        // both runs see the same host time
+        let now = unix_time();
+        // compare once per second of emulated time
+        match determinism::check(|| power_on(&options, now), &movie, frames, 60) {
+            Ok(ran) if ran < frames => {
+                eprintln!("both runs stopped on a CPU error at frame {}", ran);
+                process::exit(1);
+            }
+            Ok(ran) => info!("{} frames ran identically twice", ran),
+            Err(divergence) => {
+                let hash = |hash: Option<u64>| {
+                    hash.map_or("CPU error".to_string(), |hash| format!("{:#018x}", hash))
+                };
+                eprintln!(
+                    "runs diverged at frame {}: {} != {}",
+                    divergence.frame,
+                    hash(divergence.first),
+                    hash(divergence.second)
+                );
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut emulator = power_on(&options, unix_time());
+    // kept apart from the savestates so replays stay deterministic
+    let session_path = Session::path(&options.rom);
+    let mut session = Session::load(&session_path)
+        .unwrap_or_else(|e| exit_with_usage(&format!("{}: {}", session_path.display(), e)));
+    // run when the debugger attaches. The script is shared by every game,
+    // so what it sets up is armed on top of the session but never saved to
+    // it.
+    let init = if options.debugger {
+        debugger::init_script().unwrap_or_else(|e| {
+            warn!("{}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let history_path = debugger::History::path();
+    let mut history = options.debugger.then(|| {
+        history_path
+            .as_deref()
+            .map(|path| {
+                debugger::History::load(path).unwrap_or_else(|e| {
+                    warn!("{}: {}", path.display(), e);
+                    debugger::History::default()
+                })
+            })
+            .unwrap_or_default()
+    });
+    if let Some(history) = &mut history {
+        prompt(history, &mut session);
+    }
+    arm(&mut emulator, &session, &init, options.debugger);
+    if !session.is_empty() {
+        info!(
+            "session: {} cheats, {} breakpoints, {} watchpoints",
+            session.cheats.len(),
+            session.breakpoints.len(),
+            session.watchpoints.len()
+        );
+    }
+    if !init.is_empty() {
+        info!("debugger_init: {} commands", init.len());
+    }
+
+    let serial_output = options.json_summary.then(|| {
+        let capture = Capture::default();
+        let output = capture.output();
+        emulator.cpu.bus.serial.attach(Box::new(capture));
+        output
+    });
+    let notifications = options.json_summary.then(|| emulator.notifications());
+    let finished = |emulator: &Emulator, frames: u64| {
+        let services = emulator.cpu.bus.services.as_ref();
+        options.frames.is_some_and(|limit| frames >= limit)
+            || services.is_some_and(|services| services.exit().is_some())
+            || options.json_summary && services.is_some_and(|services| services.result().is_some())
+            || serial_output.as_ref().is_some_and(|output| {
+                TestResult::detect_all(emulator, &output.lock().unwrap()).is_some()
+            })
+    };
+
+    let mut doctor = options.doctor.as_ref().map(|path| {
+        let out: Box<dyn Write> = if path.as_os_str() == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = File::create(path)
+                .unwrap_or_else(|e| exit_with_usage(&format!("{}: {}", path.display(), e)));
+            Box::new(file)
+        };
+        emulator.set_rng_hook(Some(doctor::ly_hook()));
+        Doctor::new(BufWriter::new(out))
+    });
 
-    let rom = cartdrige::load(rom_path);
-    let mut cpu = cpu::Cpu::new(rom);
-    loop {
-        cpu.step();
+    let mut bank_panel = BankPanel::new(16);
+    let mut dma_panel = DmaPanel::new(16);
+    let mut limiter = SpeedLimiter::new(options.speed);
+    let mut dump = match (&options.dump_video, &options.encode_video) {
+        (_, Some(video)) => Some(VideoDump::ffmpeg(video)),
+        (Some(video), None) => Some(VideoDump::create(video)),
+        (None, None) => None,
+    }
+    .transpose()
+    .unwrap_or_else(|e| exit_with_usage(&format!("video dump: {}", e)));
+    let mut watcher = options
+        .watch
+        .then(|| Watcher::new(&options.rom, WATCH_INTERVAL));
+    let mut reload = |emulator: &mut Emulator, session: &Session| {
+        let changed = watcher.as_mut().is_some_and(Watcher::changed);
+        if changed {
+            info!("{} changed, reloading", options.rom.display());
+            save_battery(emulator, &options);
+            *emulator = power_on(&options, unix_time());
+            if options.doctor.is_some() {
+                emulator.set_rng_hook(Some(doctor::ly_hook()));
+            }
+            arm(emulator, session, &init, options.debugger);
+        }
+        changed
+    };
+    // nothing here polls the host for input and movies advance with the
+    // frames, so once the CPU executed STOP only a rebuilt ROM can go on
+    let stuck = |emulator: &Emulator, frames: u64| {
+        if emulator.cpu.stopped && !options.watch {
+            info!("CPU stopped waiting for input after {} frames", frames);
+        }
+        emulator.cpu.stopped && !options.watch
+    };
+    let mut frames = 0;
+    let mut fault = None;
+    if options.run_ahead > 0 {
+        // whole frames at a time, the debug panels need every step
+        let mut run_ahead = RunAhead::new(options.run_ahead);
+        while !finished(&emulator, frames) {
+            let input = if movie.is_empty() {
+                emulator.cpu.bus.joypad.state()
+            } else {
+                movie.input(frames)
+            };
+            let result = crash_guard(&mut emulator, |emulator| {
+                run_ahead.run_frame(emulator, input)
+            });
+            if let Err(e) = result {
+                fault = Some(e);
+                break;
+            }
+            if stuck(&emulator, frames) {
+                break;
+            }
+            // the frame never completed
+            if emulator.cpu.stopped {
+                thread::sleep(WATCH_INTERVAL);
+                if reload(&mut emulator, &session) {
+                    run_ahead = RunAhead::new(options.run_ahead);
+                    limiter = SpeedLimiter::new(options.speed);
+                    frames = 0;
+                }
+                continue;
+            }
+            limiter.throttle(emulator.cpu.clock.elapsed());
+            dump_frame(&mut dump, &emulator);
+            print_messages(&mut emulator);
+            frames += 1;
+            if reload(&mut emulator, &session) {
+                run_ahead = RunAhead::new(options.run_ahead);
+                limiter = SpeedLimiter::new(options.speed);
+                frames = 0;
+            }
+        }
+    } else {
+        loop {
+            if !movie.is_empty() {
+                emulator.cpu.bus.joypad.set_state(movie.input(frames));
+            }
+            if let Some(tracer) = &mut doctor {
+                if let Err(e) = tracer.trace(&emulator.cpu) {
+                    error!("--doctor: {}", e);
+                    doctor = None;
+                }
+            }
+            if let Err(e) = crash_guard(&mut emulator, Emulator::step) {
+                fault = Some(e);
+                break;
+            }
+            // only armed with the debugger attached, it resumes from there
+            if let Some(hit) = emulator.take_break() {
+                println!("{}", hit);
+                if let Some(history) = &mut history {
+                    prompt(history, &mut session);
+                }
+                arm(&mut emulator, &session, &init, options.debugger);
+            }
+            if stuck(&emulator, frames) {
+                break;
+            }
+            if emulator.cpu.stopped {
+                thread::sleep(WATCH_INTERVAL);
+                if reload(&mut emulator, &session) {
+                    limiter = SpeedLimiter::new(options.speed);
+                    frames = 0;
+                }
+                continue;
+            }
+            if emulator.frame_count() != frames {
+                frames = emulator.frame_count();
+                limiter.throttle(emulator.cpu.clock.elapsed());
+                dump_frame(&mut dump, &emulator);
+                print_messages(&mut emulator);
+                // checked once per frame, the serial output keeps growing
+                if finished(&emulator, frames) {
+                    break;
+                }
+                if reload(&mut emulator, &session) {
+                    limiter = SpeedLimiter::new(options.speed);
+                    frames = 0;
+                }
+            }
+            let cpu = &emulator.cpu;
+            let banks = cpu.bus.cartdrige.bank_state();
+            if bank_panel.observe(banks, cpu.registers.pc.value(), cpu.clock.cycles()) {
+                debug!("Bank mapping changed:\n{}", bank_panel);
+            }
+            if dma_panel.update(emulator.dma_transfers()) {
+                debug!("DMA:\n{}", dma_panel);
+            }
+        }
+    }
+
+    print_messages(&mut emulator);
+    if let Some(e) = dump.and_then(|dump| dump.finish().err()) {
+        error!("video dump: {}", e);
+    }
+    if let Some(e) = doctor.as_mut().and_then(|tracer| tracer.flush().err()) {
+        error!("--doctor: {}", e);
+    }
+    if let Some(e) = fault {
+        error!("{}\n{}", e, emulator.crash_dump());
+    }
+    save_battery(&mut emulator, &options);
+    if let (Some(history), Some(path)) = (&history, &history_path) {
+        if let Err(e) = history.save(path) {
+            warn!("{}: {}", path.display(), e);
+        }
+    }
+    if !session.is_empty() || session_path.exists() {
+        if let Err(e) = session.save(&session_path) {
+            error!("{}: {}", session_path.display(), e);
+        }
+    }
+    if let Some(path) = &options.screenshot {
+        if let Err(e) = screenshot::take(path, &emulator, options.dump_state.as_deref()) {
+            error!("{}: {}", path.display(), e);
+        }
+    }
+    if let Some(path) = &options.dump_state {
+        let (mut storage, key) = file_storage(path);
+        if let Err(e) = emulator.save_state().store(&mut storage, &key) {
+            error!("{}: {}", path.display(), e);
+        }
+    }
+
+    let services = emulator.cpu.bus.services.as_ref();
+    let mut result = None;
+    if let (Some(output), Some(notifications)) = (serial_output, notifications) {
+        let unsupported = notifications
+            .try_iter()
+            .filter_map(|notification| match notification {
+                Notification::Unsupported { feature } => Some(feature),
+                _ => None,
+            })
+            .collect();
+        let serial = output.lock().unwrap().clone();
+        let mut summary = Summary::new(&emulator, frames, unsupported, serial);
+        // an explicit verdict beats guessing from the serial output
+        if let Some(result) = services.and_then(Services::result) {
+            summary.result = Some(result);
+        }
+        println!("{}", summary.to_json());
+        result = summary.result;
+    }
+    if fault.is_some() {
+        process::exit(1);
+    }
+    if let Some(status) = services.and_then(Services::exit) {
+        process::exit(status as i32);
+    }
+    // unattended suites only look at the status
+    if options.json_summary {
+        match result {
+            Some(TestResult::Passed) => {}
+            Some(TestResult::Failed) => process::exit(1),
+            None => process::exit(2),
+        }
     }
 }
@@ -0,0 +1,713 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    apu::{self, Apu},
+    cartdrige::Cartdrige,
+    dma::{self, OamDma},
+    fill::MemoryFill,
+    hdma::{self, Hdma},
+    interrupt::{self, Interrupts},
+    io::{self, IoRegisters},
+    joypad::{self, Joypad},
+    notification::{Notification, Notifier},
+    palette::{self, CgbPalettes},
+    ppu::{self, Ppu},
+    renderer::Renderer,
+    serial::{self, Serial},
+    services::{self, Services},
+};
+
+pub(crate) const VRAM_START: u16 = 0x8000;
+pub(crate) const VRAM_END: u16 = 0x9FFF;
+pub(crate) const WRAM_START: u16 = 0xC000;
+pub(crate) const WRAM_END: u16 = 0xDFFF;
+// mirror of 0xC000-0xDDFF
+pub(crate) const ECHO_START: u16 = 0xE000;
+pub(crate) const ECHO_END: u16 = 0xFDFF;
+pub(crate) const OAM_START: u16 = 0xFE00;
+pub(crate) const OAM_END: u16 = 0xFE9F;
+// not usable, nothing is wired there
+pub(crate) const PROHIBITED_START: u16 = 0xFEA0;
+pub(crate) const PROHIBITED_END: u16 = 0xFEFF;
+pub(crate) const IO_START: u16 = 0xFF00;
+pub(crate) const IO_END: u16 = 0xFF7F;
+pub(crate) const HRAM_START: u16 = 0xFF80;
+pub(crate) const HRAM_END: u16 = 0xFFFE;
+// 0x8000-0x9FFF switches between banks 0 and 1 on CGB, the tile
+// attributes are in the latter
+pub(crate) const VRAM_BANK_SIZE: usize = (VRAM_END - VRAM_START + 1) as usize;
+pub(crate) const VRAM_BANKS: usize = 2;
+pub(crate) const VRAM_SIZE: usize = VRAM_BANK_SIZE * VRAM_BANKS;
+// 0xD000-0xDFFF switches between banks 1-7 on CGB, 0xC000-0xCFFF is bank 0
+pub(crate) const WRAM_BANK_SIZE: usize = 0x1000;
+pub(crate) const WRAM_BANKS: usize = 8;
+pub(crate) const WRAM_SIZE: usize = WRAM_BANK_SIZE * WRAM_BANKS;
+pub(crate) const OAM_SIZE: usize = (OAM_END - OAM_START + 1) as usize;
+pub(crate) const HRAM_SIZE: usize = (HRAM_END - HRAM_START + 1) as usize;
+
+/// Writing a non-zero value unmaps the boot ROM until the next reset
+/// https://gbdev.io/pandocs/Memory_Map.html#io-ranges
+pub const BOOT: u16 = 0xFF50;
+/// Size of the DMG boot ROM, mapped over the start of the cartridge
+pub const BOOT_ROM_SIZE: usize = 0x100;
+/// CGB VRAM bank mapped at 0x8000, in bit 0
+/// https://gbdev.io/pandocs/CGB_Registers.html#ff4f--vbk-cgb-mode-only-vram-bank
+pub const VBK: u16 = 0xFF4F;
+/// CGB WRAM bank mapped at 0xD000, in the low 3 bits
+/// https://gbdev.io/pandocs/CGB_Registers.html#ff70--svbk-cgb-mode-only-wram-bank
+pub const SVBK: u16 = 0xFF70;
+
+/// Routes the CPU address space to the cartridge, the memories and the I/O
+/// registers of the devices
+/// https://gbdev.io/pandocs/Memory_Map.html
+///
+/// Regions nothing answers to read as 0xFF and ignore writes. The area after
+/// OAM reads as 0x00 like on a DMG, except while OAM is unreachable.
+///
+/// While the LCD is on, the PPU takes VRAM when drawing and OAM when
+/// scanning it too, the CPU then reads 0xFF there and its writes are lost.
+pub struct Mmu {
+    pub cartdrige: Box<dyn Cartdrige>,
+    pub serial: Serial,
+    pub apu: Apu,
+    pub ppu: Ppu,
+    pub renderer: Renderer,
+    pub interrupts: Interrupts,
+    pub joypad: Joypad,
+    pub dma: OamDma,
+    // only reachable in CGB mode like the palettes
+    pub hdma: Hdma,
+    // only reachable in CGB mode, see `Cpu::read`
+    pub palettes: CgbPalettes,
+    pub notifier: Notifier,
+    /// The emulator services ports, unmapped unless set
+    pub services: Option<Services>,
+    // both banks, only the first one is reachable on DMG
+    pub(crate) vram: Vec<u8>,
+    // only writable in CGB mode, see `Cpu::write`
+    pub(crate) vbk: u8,
+    // all 8 banks, only the first two are reachable on DMG
+    pub(crate) wram: Vec<u8>,
+    // only writable in CGB mode, see `Cpu::write`
+    pub(crate) svbk: u8,
+    pub(crate) oam: Vec<u8>,
+    pub(crate) hram: Vec<u8>,
+    // the I/O registers no device handles
+    pub(crate) io: IoRegisters,
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
+    // unimplemented I/O registers already reported to the notifier
+    unsupported_io: HashSet<u16>,
+}
+
+impl Mmu {
+    pub fn new(cartdrige: Box<dyn Cartdrige>) -> Self {
+        Self {
+            cartdrige,
+            serial: Serial::new(),
+            apu: Apu::new(),
+            ppu: Ppu::new(),
+            renderer: Renderer::new(),
+            interrupts: Interrupts::new(),
+            joypad: Joypad::new(),
+            dma: OamDma::new(),
+            hdma: Hdma::new(),
+            palettes: CgbPalettes::new(),
+            notifier: Notifier::default(),
+            services: None,
+            vram: vec![0x00; VRAM_SIZE],
+            vbk: 0x00,
+            wram: vec![0x00; WRAM_SIZE],
+            svbk: 0x00,
+            oam: vec![0x00; OAM_SIZE],
+            hram: vec![0x00; HRAM_SIZE],
+            io: IoRegisters::new(),
+            boot_rom: Vec::new(),
+            boot_rom_mapped: false,
+            unsupported_io: HashSet::new(),
+        }
+    }
+
+    /// Maps `boot_rom` over 0x0000-0x00FF until the program writes to
+    /// `BOOT`
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        if boot_rom.len() != BOOT_ROM_SIZE {
+            return Err(format!(
+                "Invalid boot ROM size: {} bytes, expected {}",
+                boot_rom.len(),
+                BOOT_ROM_SIZE
+            ));
+        }
+        self.boot_rom = boot_rom;
+        self.boot_rom_mapped = true;
+        Ok(())
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    // a state saved while booting can only resume with the boot ROM loaded
+    pub(crate) fn set_boot_rom_mapped(&mut self, mapped: bool) {
+        self.boot_rom_mapped = mapped && !self.boot_rom.is_empty();
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x00FF if self.boot_rom_mapped => self.boot_rom[address as usize],
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartdrige.read(address),
+            VRAM_START..=VRAM_END if self.vram_blocked() => 0xFF,
+            VRAM_START..=VRAM_END => self.vram[self.vram_offset(address)],
+            WRAM_START..=WRAM_END => self.wram[self.wram_offset(address)],
+            ECHO_START..=ECHO_END => {
+                self.wram[self.wram_offset(address - (ECHO_START - WRAM_START))]
+            }
+            OAM_START..=OAM_END if self.oam_blocked() => 0xFF,
+            OAM_START..=OAM_END => self.oam[(address - OAM_START) as usize],
+            PROHIBITED_START..=PROHIBITED_END if self.oam_blocked() => 0xFF,
+            PROHIBITED_START..=PROHIBITED_END => 0x00,
+            dma::DMA => self.dma.read(),
+            hdma::HDMA1..=hdma::HDMA5 => self.hdma.read(address),
+            serial::SB | serial::SC => self.serial.read(address),
+            interrupt::IF | interrupt::IE => self.interrupts.read(address),
+            joypad::P1 => self.joypad.read(),
+            apu::NR10..=apu::END => self.apu.read(address),
+            palette::BCPS..=palette::OCPD => self.palettes.read(address),
+            ppu::LY => self.ppu.ly(),
+            io::STAT => self.read_stat(),
+            BOOT => 0xFE | !self.boot_rom_mapped as u8,
+            VBK => 0xFE | self.vbk,
+            SVBK => 0xF8 | self.svbk,
+            HRAM_START..=HRAM_END => self.hram[(address - HRAM_START) as usize],
+            _ if (IO_START..=IO_END).contains(&address) => self.io.read(address),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartdrige.set(address, value),
+            VRAM_START..=VRAM_END if self.vram_blocked() => {}
+            VRAM_START..=VRAM_END => {
+                let offset = self.vram_offset(address);
+                self.vram[offset] = value;
+            }
+            WRAM_START..=WRAM_END => {
+                let offset = self.wram_offset(address);
+                self.wram[offset] = value;
+            }
+            ECHO_START..=ECHO_END => {
+                let offset = self.wram_offset(address - (ECHO_START - WRAM_START));
+                self.wram[offset] = value;
+            }
+            OAM_START..=OAM_END if self.oam_blocked() => {}
+            OAM_START..=OAM_END => self.oam[(address - OAM_START) as usize] = value,
+            dma::DMA => self.dma.write(value),
+            hdma::HDMA1..=hdma::HDMA5 => {
+                self.hdma.write(address, value);
+                self.start_hdma();
+            }
+            serial::SB | serial::SC => self.serial.write(address, value),
+            interrupt::IF | interrupt::IE => self.interrupts.write(address, value),
+            joypad::P1 => self.joypad.write(value),
+            apu::NR10..=apu::END => self.apu.write(address, value),
+            palette::BCPS..=palette::OCPD => self.palettes.write(address, value),
+            // read-only
+            ppu::LY => {}
+            BOOT if value != 0 => self.boot_rom_mapped = false,
+            BOOT => {}
+            VBK => self.vbk = value & 0x01,
+            SVBK => self.svbk = value & 0x07,
+            services::PRINT..=services::EXIT if self.services.is_some() => {
+                if let Some(services) = &mut self.services {
+                    services.write(address, value);
+                }
+            }
+            HRAM_START..=HRAM_END => self.hram[(address - HRAM_START) as usize] = value,
+            // fully emulated
+            io::LCDC => {
+                let enabled = self.lcd_enabled();
+                self.io.write(address, value);
+                // it restarts from the top of the screen when turned on again
+                if enabled && !self.lcd_enabled() {
+                    self.ppu = Ppu::new();
+                    self.renderer.blank();
+                }
+            }
+            io::STAT | io::LYC => {
+                self.io.write(address, value);
+                self.update_stat_line();
+            }
+            io::DIV | io::SCY | io::SCX | io::BGP | io::OBP0 | io::OBP1 | io::WY | io::WX => {
+                self.io.write(address, value)
+            }
+            _ if (IO_START..=IO_END).contains(&address) => {
+                self.io.write(address, value);
+                self.unsupported_io(address);
+            }
+            _ => {}
+        }
+    }
+
+    // index in `vram` of an address in 0x8000-0x9FFF, in the bank VBK maps
+    fn vram_offset(&self, address: u16) -> usize {
+        self.vbk as usize * VRAM_BANK_SIZE + (address - VRAM_START) as usize
+    }
+
+    /// WRAM bank mapped at 0xD000, selecting bank 0 maps bank 1
+    pub fn wram_bank(&self) -> usize {
+        (self.svbk as usize).max(1)
+    }
+
+    // index in `wram` of an address in 0xC000-0xDFFF
+    fn wram_offset(&self, address: u16) -> usize {
+        let offset = (address - WRAM_START) as usize;
+        match offset.checked_sub(WRAM_BANK_SIZE) {
+            Some(offset) => self.wram_bank() * WRAM_BANK_SIZE + offset,
+            None => offset,
+        }
+    }
+
+    // General purpose transfers happen all at once. H-blank ones copy their
+    // first block right away when started during an H-blank or with the LCD
+    // off, the following ones as `tick` enters the next H-blanks.
+    fn start_hdma(&mut self) {
+        if !self.hdma.active() {
+            return;
+        }
+        if !self.hdma.hblank() {
+            while self.copy_hdma_block() {}
+        } else if !self.lcd_enabled() || self.ppu.mode() == ppu::Mode::HBlank {
+            self.copy_hdma_block();
+        }
+    }
+
+    // false once the transfer is over
+    fn copy_hdma_block(&mut self) -> bool {
+        let Some((source, destination)) = self.hdma.next_block() else {
+            return false;
+        };
+        for i in 0..hdma::BLOCK_SIZE {
+            let value = self.read(source.wrapping_add(i));
+            let offset = self.vram_offset(destination + i);
+            self.vram[offset] = value;
+        }
+        true
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.io.read(io::LCDC) & io::LCD_ENABLE != 0
+    }
+
+    // the mode reads as H-blank while the LCD is off
+    fn read_stat(&self) -> u8 {
+        let mode = if self.lcd_enabled() {
+            self.ppu.mode() as u8
+        } else {
+            0
+        };
+        let coincidence = if self.ppu.ly_compare() == Some(self.io.read(io::LYC)) {
+            io::STAT_COINCIDENCE
+        } else {
+            0
+        };
+        self.io.read(io::STAT) & !(io::STAT_MODE | io::STAT_COINCIDENCE) | mode | coincidence
+    }
+
+    // requests the STAT interrupt as one of the sources it is enabled for
+    // starts, unless another one already holds the line up
+    fn update_stat_line(&mut self) {
+        let stat = self.read_stat();
+        let sources = [
+            (io::STAT_HBLANK_INTERRUPT, ppu::Mode::HBlank),
+            (io::STAT_VBLANK_INTERRUPT, ppu::Mode::VBlank),
+            (io::STAT_OAM_INTERRUPT, ppu::Mode::OamScan),
+        ];
+        let level = self.lcd_enabled()
+            && (sources
+                .iter()
+                .any(|&(bit, mode)| stat & bit != 0 && self.ppu.mode() == mode)
+                || stat & io::STAT_LYC_INTERRUPT != 0 && stat & io::STAT_COINCIDENCE != 0);
+        if self.ppu.set_stat_line(level) {
+            self.interrupts.request(interrupt::STAT);
+        }
+    }
+
+    fn vram_blocked(&self) -> bool {
+        self.lcd_enabled() && !self.ppu.vram_accessible()
+    }
+
+    // by the PPU or the DMA, which has the bus
+    fn oam_blocked(&self) -> bool {
+        self.dma.active() || self.lcd_enabled() && !self.ppu.oam_accessible()
+    }
+
+    // reported once per register
+    fn unsupported_io(&mut self, address: u16) {
+        if self.unsupported_io.insert(address) {
+            self.notifier.notify(Notification::Unsupported {
+                feature: format!("I/O register {:#06x}", address),
+            });
+        }
+    }
+
+    /// Advances the devices by `cycles` CPU T-cycles, `dots` of which went
+    /// by on the master clock
+    pub fn tick(&mut self, cycles: u32, dots: u32) {
+        for offset in self.dma.tick(cycles) {
+            // past the end of WRAM the source wraps to its echo
+            let mut source = self.dma.source() + offset;
+            if source >= ECHO_START {
+                source -= ECHO_START - WRAM_START;
+            }
+            self.oam[offset as usize] = self.read(source);
+        }
+        self.io.tick(cycles);
+        self.cartdrige.tick(dots);
+        self.apu.tick(dots);
+        // the PPU waits on line 0 while the LCD is off
+        let mut left = if self.lcd_enabled() { dots } else { 0 };
+        while left > 0 {
+            let (run, mode) = if self.renderer.drawing() {
+                // a dot at a time, drawing lasts until the pixel FIFO is
+                // through the line
+                if self
+                    .renderer
+                    .draw_dot(&mut self.ppu, &self.vram, &self.io, &self.palettes)
+                {
+                    let dots = self.ppu.line_dot() + 1 - ppu::OAM_SCAN_DOTS;
+                    self.ppu.set_drawing_dots(dots);
+                }
+                self.ppu.step(1)
+            } else {
+                self.ppu.step(left)
+            };
+            left -= run;
+            match mode {
+                Some(ppu::Mode::Drawing) if self.renderer.pixel_fifo() => {
+                    self.renderer.start_line(&mut self.ppu, &self.oam, &self.io);
+                    self.ppu.set_drawing_dots(ppu::MAX_DRAWING_DOTS);
+                }
+                Some(ppu::Mode::Drawing) => {
+                    self.renderer.draw_line(
+                        &mut self.ppu,
+                        &self.vram,
+                        &self.oam,
+                        &self.io,
+                        &self.palettes,
+                    );
+                }
+                Some(ppu::Mode::HBlank) => {
+                    self.renderer
+                        .finish_line(&mut self.ppu, &self.vram, &self.io, &self.palettes);
+                    if self.hdma.active() && self.hdma.hblank() {
+                        self.copy_hdma_block();
+                    }
+                }
+                Some(ppu::Mode::VBlank) if self.ppu.line() == ppu::VBLANK_LINE => {
+                    self.interrupts.request(interrupt::VBLANK);
+                }
+                _ => {}
+            }
+            self.update_stat_line();
+        }
+        if self.serial.tick(cycles) {
+            self.interrupts.request(interrupt::SERIAL);
+        }
+    }
+
+    /// Initializes the memory that has no defined power-on value
+    pub fn fill_memory(&mut self, fill: MemoryFill) {
+        fill.fill(&mut self.vram);
+        fill.fill(&mut self.wram);
+        fill.fill(&mut self.oam);
+        fill.fill(&mut self.hram);
+    }
+
+    /// Feeds the devices and memories to `state`, see `Cpu::hash_state`
+    pub fn hash_state<H: Hasher>(&self, state: &mut H) {
+        self.interrupts.hash(state);
+        self.apu.hash(state);
+        self.ppu.hash(state);
+        self.renderer.fifo_line().hash(state);
+        self.joypad.state().hash(state);
+        self.joypad.read().hash(state);
+        self.serial.state().hash(state);
+        self.dma.hash(state);
+        self.hdma.hash(state);
+        self.palettes.hash(state);
+        self.cartdrige.bank_state().hash(state);
+        self.cartdrige.ram().hash(state);
+        self.cartdrige.mapper_state().hash(state);
+        self.vram.hash(state);
+        self.vbk.hash(state);
+        self.wram.hash(state);
+        self.svbk.hash(state);
+        self.oam.hash(state);
+        self.hram.hash(state);
+        self.io.hash(state);
+        self.boot_rom_mapped.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartdrige::RomOnly;
+
+    #[test]
+    fn test_memory_map() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x4000] = 0x42;
+        let mut mmu = Mmu::new(Box::new(RomOnly(rom)));
+        // the PPU lets go of VRAM and OAM
+        mmu.write(io::LCDC, 0x00);
+        assert_eq!(mmu.read(0x4000), 0x42);
+        for address in [
+            0x8000, 0x9FFF, 0xC000, 0xDFFF, 0xFE00, 0xFE9F, 0xFF80, 0xFFFE,
+        ] {
+            mmu.write(address, 0x5A);
+            assert_eq!(mmu.read(address), 0x5A, "{:#06x}", address);
+        }
+        mmu.write(interrupt::IE, 0x1F);
+        assert_eq!(mmu.read(interrupt::IE), 0x1F);
+        // nothing answers there yet
+        for address in [0xA000, 0xFF7F] {
+            mmu.write(address, 0x00);
+            assert_eq!(mmu.read(address), 0xFF, "{:#06x}", address);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        for i in 0..dma::OAM_DMA_LENGTH {
+            mmu.write(0xC100 + i, i as u8);
+        }
+        mmu.write(io::LCDC, 0x00);
+        mmu.write(dma::DMA, 0xC1);
+        mmu.tick(4, 4);
+        assert_eq!(mmu.read(OAM_START), 0xFF);
+        // one byte per machine cycle
+        mmu.tick(
+            dma::OAM_DMA_LENGTH as u32 * 4,
+            dma::OAM_DMA_LENGTH as u32 * 4,
+        );
+        assert!(!mmu.dma.active());
+        assert_eq!(mmu.read(OAM_START), 0x00);
+        assert_eq!(mmu.read(OAM_END), 159);
+        assert_eq!(mmu.read(dma::DMA), 0xC1);
+    }
+
+    #[test]
+    fn test_prohibited_area() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.write(io::LCDC, 0x00);
+        for address in PROHIBITED_START..=PROHIBITED_END {
+            mmu.write(address, 0x5A);
+            assert_eq!(mmu.read(address), 0x00, "{:#06x}", address);
+        }
+        mmu.write(dma::DMA, 0xC0);
+        assert_eq!(mmu.read(PROHIBITED_START), 0xFF);
+    }
+
+    #[test]
+    fn test_ppu_blocks_vram_and_oam() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        // OAM scan
+        mmu.write(VRAM_START, 0x11);
+        mmu.write(OAM_START, 0x22);
+        assert_eq!(mmu.read(VRAM_START), 0x11);
+        assert_eq!(mmu.read(OAM_START), 0xFF);
+        assert_eq!(mmu.read(PROHIBITED_START), 0xFF);
+        mmu.tick(0, ppu::OAM_SCAN_DOTS);
+        mmu.write(VRAM_START, 0x33);
+        assert_eq!(mmu.read(VRAM_START), 0xFF);
+        mmu.tick(0, ppu::DRAWING_DOTS);
+        // HBlank
+        assert_eq!(mmu.read(VRAM_START), 0x11);
+        assert_eq!(mmu.read(OAM_START), 0x00);
+        // everything is reachable with the LCD off
+        mmu.tick(0, ppu::DOTS_PER_LINE - ppu::OAM_SCAN_DOTS);
+        mmu.write(io::LCDC, 0x00);
+        mmu.write(VRAM_START, 0x33);
+        mmu.write(OAM_START, 0x44);
+        assert_eq!(mmu.read(VRAM_START), 0x33);
+        assert_eq!(mmu.read(OAM_START), 0x44);
+    }
+
+    #[test]
+    fn test_stat_mode() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        let mode = |mmu: &Mmu| mmu.read(io::STAT) & io::STAT_MODE;
+        assert_eq!(mode(&mmu), ppu::Mode::OamScan as u8);
+        mmu.tick(0, ppu::OAM_SCAN_DOTS);
+        assert_eq!(mode(&mmu), ppu::Mode::Drawing as u8);
+        // the CPU cannot change it
+        mmu.write(io::STAT, 0xFF);
+        assert_eq!(mode(&mmu), ppu::Mode::Drawing as u8);
+        mmu.tick(0, ppu::VBLANK_LINE * ppu::DOTS_PER_LINE);
+        assert_eq!(mode(&mmu), ppu::Mode::VBlank as u8);
+        mmu.write(io::LCDC, 0x00);
+        assert_eq!(mode(&mmu), ppu::Mode::HBlank as u8);
+    }
+
+    #[test]
+    fn test_pixel_fifo_drawing_length() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.renderer.set_pixel_fifo(true);
+        mmu.write(io::SCX, 0x05);
+        let mode = |mmu: &Mmu| mmu.read(io::STAT) & io::STAT_MODE;
+        mmu.tick(0, ppu::OAM_SCAN_DOTS + ppu::DRAWING_DOTS + 4);
+        assert_eq!(mode(&mmu), ppu::Mode::Drawing as u8);
+        mmu.tick(0, 1);
+        assert_eq!(mode(&mmu), ppu::Mode::HBlank as u8);
+        // the next line starts on time all the same
+        mmu.tick(
+            0,
+            ppu::DOTS_PER_LINE - ppu::OAM_SCAN_DOTS - ppu::DRAWING_DOTS - 6,
+        );
+        assert_eq!(mode(&mmu), ppu::Mode::HBlank as u8);
+        mmu.tick(0, 1);
+        assert_eq!(mode(&mmu), ppu::Mode::OamScan as u8);
+        assert_eq!(mmu.read(ppu::LY), 1);
+    }
+
+    #[test]
+    fn test_stat_interrupt() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.interrupts.flag = 0x00;
+        mmu.write(io::STAT, io::STAT_HBLANK_INTERRUPT);
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        mmu.tick(0, ppu::OAM_SCAN_DOTS + ppu::DRAWING_DOTS);
+        assert_eq!(mmu.interrupts.flag, interrupt::STAT);
+        // enabling another source while the line is up does not fire again
+        mmu.interrupts.flag = 0x00;
+        mmu.write(io::STAT, io::STAT_HBLANK_INTERRUPT | io::STAT_OAM_INTERRUPT);
+        mmu.tick(
+            0,
+            ppu::DOTS_PER_LINE - ppu::OAM_SCAN_DOTS - ppu::DRAWING_DOTS,
+        );
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        // the line drops during drawing
+        mmu.tick(0, ppu::OAM_SCAN_DOTS);
+        mmu.tick(0, ppu::DRAWING_DOTS);
+        assert_eq!(mmu.interrupts.flag, interrupt::STAT);
+
+        // LY = LYC, right away when written
+        mmu.interrupts.flag = 0x00;
+        mmu.write(io::STAT, io::STAT_LYC_INTERRUPT);
+        mmu.write(io::LYC, 1);
+        assert_eq!(mmu.read(ppu::LY), 1);
+        assert_eq!(
+            mmu.read(io::STAT) & io::STAT_COINCIDENCE,
+            io::STAT_COINCIDENCE
+        );
+        assert_eq!(mmu.interrupts.flag, interrupt::STAT);
+        mmu.interrupts.flag = 0x00;
+        mmu.write(io::LYC, 3);
+        mmu.tick(0, ppu::DOTS_PER_LINE);
+        assert_eq!(mmu.read(io::STAT) & io::STAT_COINCIDENCE, 0);
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        mmu.tick(0, ppu::DOTS_PER_LINE);
+        assert_eq!(mmu.interrupts.flag, interrupt::STAT);
+
+        // V-blank
+        mmu.interrupts.flag = 0x00;
+        mmu.write(io::STAT, io::STAT_VBLANK_INTERRUPT);
+        let hblank = ppu::OAM_SCAN_DOTS + ppu::DRAWING_DOTS;
+        mmu.tick(0, (ppu::VBLANK_LINE - 3) * ppu::DOTS_PER_LINE - hblank - 1);
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        mmu.tick(0, 1);
+        assert_eq!(mmu.interrupts.flag & interrupt::STAT, interrupt::STAT);
+    }
+
+    #[test]
+    fn test_vblank_interrupt() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.interrupts.flag = 0x00;
+        mmu.tick(0, ppu::VBLANK_LINE * ppu::DOTS_PER_LINE - 1);
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        mmu.tick(0, 1);
+        assert_eq!(mmu.interrupts.flag, interrupt::VBLANK);
+        // once per frame
+        mmu.interrupts.flag = 0x00;
+        mmu.tick(0, 10 * ppu::DOTS_PER_LINE - 1);
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        mmu.tick(0, ppu::VBLANK_LINE * ppu::DOTS_PER_LINE + 1);
+        assert_eq!(mmu.interrupts.flag, interrupt::VBLANK);
+    }
+
+    #[test]
+    fn test_lyc_timing() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.write(io::STAT, io::STAT_LYC_INTERRUPT);
+        mmu.write(io::LYC, 2);
+        mmu.tick(0, 2 * ppu::DOTS_PER_LINE);
+        mmu.interrupts.flag = 0x00;
+        // a machine cycle into the line, even when ticked past it at once
+        assert_eq!(mmu.read(io::STAT) & io::STAT_COINCIDENCE, 0);
+        mmu.tick(0, 3);
+        assert_eq!(mmu.interrupts.flag, 0x00);
+        mmu.tick(0, 20);
+        assert_eq!(mmu.interrupts.flag, interrupt::STAT);
+        // LYC = 0 matches on line 153 already
+        mmu.write(io::LYC, 0);
+        mmu.interrupts.flag = 0x00;
+        mmu.tick(0, (ppu::LAST_LINE - 2) * ppu::DOTS_PER_LINE - 23 + 8);
+        assert_eq!(mmu.ppu.line(), ppu::LAST_LINE);
+        assert_eq!(mmu.interrupts.flag & interrupt::STAT, interrupt::STAT);
+        assert_eq!(
+            mmu.read(io::STAT) & io::STAT_COINCIDENCE,
+            io::STAT_COINCIDENCE
+        );
+    }
+
+    #[test]
+    fn test_lcd_off() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.renderer.set_backdrop([0x00, 0x00, 0xFF]);
+        mmu.renderer.frame_mut().fill([0x00; 3]);
+        mmu.tick(0, 10 * ppu::DOTS_PER_LINE);
+        assert_eq!(mmu.read(ppu::LY), 10);
+        mmu.write(io::LCDC, 0x00);
+        assert_eq!(mmu.renderer.frame().pixel(0, 0), [0x00, 0x00, 0xFF]);
+        // nothing moves until it is turned back on
+        mmu.tick(0, ppu::DOTS_PER_LINE);
+        assert_eq!(mmu.read(ppu::LY), 0);
+        assert_eq!(mmu.read(io::STAT) & io::STAT_MODE, 0);
+        mmu.write(io::LCDC, io::LCD_ENABLE);
+        assert_eq!(mmu.read(io::STAT) & io::STAT_MODE, ppu::Mode::OamScan as u8);
+        mmu.tick(0, ppu::DOTS_PER_LINE);
+        assert_eq!(mmu.read(ppu::LY), 1);
+        // drawing starts over from the top, in white with the layers off
+        mmu.tick(0, ppu::OAM_SCAN_DOTS);
+        assert_eq!(mmu.renderer.frame().pixel(0, 1), [0xFF; 3]);
+        assert_eq!(mmu.renderer.frame().pixel(0, 2), [0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn test_echo_ram() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        mmu.write(0xC123, 0x11);
+        assert_eq!(mmu.read(0xE123), 0x11);
+        mmu.write(0xFDFF, 0x22);
+        assert_eq!(mmu.read(0xDDFF), 0x22);
+        // OAM follows, not a mirror of 0xDE00
+        mmu.write(0xDE00, 0x33);
+        assert_ne!(mmu.read(0xFE00), 0x33);
+    }
+
+    #[test]
+    fn test_services_are_opt_in() {
+        let mut mmu = Mmu::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        let notifications = mmu.notifier.subscribe();
+        mmu.write(services::EXIT, 0x00);
+        assert!(notifications.try_recv().is_ok());
+        mmu.services = Some(Services::new());
+        mmu.write(services::EXIT, 0x00);
+        assert!(notifications.try_recv().is_err());
+        assert_eq!(mmu.services.unwrap().exit(), Some(0x00));
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::joypad::Button;
+
+// order matches the `Button` discriminants
+const BUTTONS: [(char, Button); 8] = [
+    ('R', Button::Right),
+    ('L', Button::Left),
+    ('U', Button::Up),
+    ('D', Button::Down),
+    ('A', Button::A),
+    ('B', Button::B),
+    ('s', Button::Select),
+    ('S', Button::Start),
+];
+
+/// Recorded input, one joypad state per frame.
+///
+/// The text format has one line per frame listing the held buttons among
+/// `RLUDABsS` (s is Select, S is Start), `.` or an empty line for none.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Movie(Vec<u8>);
+
+impl Movie {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut frames = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let mut state = 0;
+            for c in line.trim().chars().filter(|c| *c != '.') {
+                let (_, button) = BUTTONS
+                    .iter()
+                    .find(|(name, _)| *name == c)
+                    .ok_or_else(|| format!("line {}: unknown button '{}'", number + 1, c))?;
+                state |= 1 << *button as u8;
+            }
+            frames.push(state);
+        }
+        Ok(Self(frames))
+    }
+
+    /// Joypad state for `frame`, nothing is held past the end of the movie
+    pub fn input(&self, frame: u64) -> u8 {
+        self.0.get(frame as usize).copied().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_movie_parse() {
+        let movie = Movie::parse(".\nA\n\nRS\n").unwrap();
+        assert_eq!(movie.len(), 4);
+        assert_eq!(movie.input(0), 0);
+        assert_eq!(movie.input(1), 1 << 4);
+        assert_eq!(movie.input(2), 0);
+        assert_eq!(movie.input(3), 1 << 0 | 1 << 7);
+        assert_eq!(movie.input(100), 0);
+        assert!(Movie::parse("AX").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use log::{info, warn};
+
+/// Something the frontend may want to show to the user
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Notification {
+    SaveWritten { path: PathBuf },
+    CheatApplied { code: String },
+    // the game used something the emulator does not implement
+    Unsupported { feature: String },
+    // the emulation is known to differ from hardware here
+    AccuracyWarning { message: String },
+}
+
+/// Sending end of the notification channel, owned by the core. Without a
+/// subscriber notifications go to the log instead.
+#[derive(Default)]
+pub struct Notifier(Option<Sender<Notification>>);
+
+impl Notifier {
+    /// Replaces the current subscriber, if any
+    pub fn subscribe(&mut self) -> Receiver<Notification> {
+        let (sender, receiver) = mpsc::channel();
+        self.0 = Some(sender);
+        receiver
+    }
+
+    pub fn notify(&mut self, notification: Notification) {
+        let Some(sender) = &self.0 else {
+            log_notification(&notification);
+            return;
+        };
+        // the frontend dropped its receiver, fall back to logging
+        if let Err(mpsc::SendError(notification)) = sender.send(notification) {
+            self.0 = None;
+            log_notification(&notification);
+        }
+    }
+}
+
+fn log_notification(notification: &Notification) {
+    match notification {
+        Notification::SaveWritten { path } => info!("Save written to {}", path.display()),
+        Notification::CheatApplied { code } => info!("Cheat applied: {}", code),
+        Notification::Unsupported { feature } => warn!("Unsupported: {}", feature),
+        Notification::AccuracyWarning { message } => warn!("Accuracy: {}", message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifier_delivers_to_subscriber() {
+        let mut notifier = Notifier::default();
+        // without subscriber this only logs
+        notifier.notify(Notification::CheatApplied {
+            code: "010FE1C1".to_string(),
+        });
+        let receiver = notifier.subscribe();
+        notifier.notify(Notification::Unsupported {
+            feature: "MBC7".to_string(),
+        });
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Notification::Unsupported {
+                feature: "MBC7".to_string()
+            })
+        );
+        assert!(receiver.try_recv().is_err());
+        drop(receiver);
+        notifier.notify(Notification::AccuracyWarning {
+            message: "dropped receiver".to_string(),
+        });
+    }
+}
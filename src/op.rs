@@ -0,0 +1,494 @@
+use std::fmt;
+
+use crate::cpu::RegisterPair;
+
+/// Typed form of an opcode, decoded from its bit fields
+/// Following
+/// https://gbdev.io/gb-opcodes/optables/ and
+/// https://archive.gbdev.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    /// 8-bit load, destination first
+    Ld(Operand, Operand),
+    /// LD rr,d16
+    LdWord(RegisterPair),
+    /// LD (a16),SP
+    LdAbsoluteSp,
+    /// LD HL,SP+r8
+    LdHlSpOffset,
+    LdSpHl,
+    Push(RegisterPair),
+    Pop(RegisterPair),
+    /// ALU operation on A and the operand, the result goes to A except for CP
+    Alu(AluOp, Operand),
+    Inc(Operand),
+    Dec(Operand),
+    IncWord(RegisterPair),
+    DecWord(RegisterPair),
+    AddHl(RegisterPair),
+    AddSp,
+    /// RLCA, RRCA, RLA and RRA
+    RotateA(Rotate),
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Jr(Condition),
+    Jp(Condition),
+    JpHl,
+    Call(Condition),
+    Ret(Condition),
+    Reti,
+    Rst(u8),
+    /// 0xCB, the prefixed instructions are not implemented yet
+    Prefix,
+    /// Opcodes that don't exist on the hardware
+    Illegal,
+}
+
+/// 8-bit operand of loads, ALU operations and INC/DEC
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operand {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    /// (BC), (DE) or (HL)
+    Indirect(RegisterPair),
+    /// (HL+), HL is incremented after the access
+    HlIncrement,
+    /// (HL-), HL is decremented after the access
+    HlDecrement,
+    /// d8
+    Immediate,
+    /// (a16)
+    Absolute,
+    /// (a8), in the 0xFF00 page
+    High,
+    /// (C), in the 0xFF00 page
+    HighC,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AluOp {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rotate {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Condition {
+    Always,
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+// register index used by the opcode bit fields, 6 is (HL)
+const fn r8(index: u8) -> Operand {
+    match index & 0x07 {
+        0 => Operand::B,
+        1 => Operand::C,
+        2 => Operand::D,
+        3 => Operand::E,
+        4 => Operand::H,
+        5 => Operand::L,
+        6 => Operand::Indirect(RegisterPair::HL),
+        _ => Operand::A,
+    }
+}
+
+const fn rp(index: u8) -> RegisterPair {
+    match index & 0x03 {
+        0 => RegisterPair::BC,
+        1 => RegisterPair::DE,
+        2 => RegisterPair::HL,
+        _ => RegisterPair::SP,
+    }
+}
+
+// PUSH and POP use AF in place of SP
+const fn rp2(index: u8) -> RegisterPair {
+    match index & 0x03 {
+        3 => RegisterPair::AF,
+        index => rp(index),
+    }
+}
+
+const fn condition(index: u8) -> Condition {
+    match index & 0x03 {
+        0 => Condition::NZ,
+        1 => Condition::Z,
+        2 => Condition::NC,
+        _ => Condition::C,
+    }
+}
+
+const fn alu(index: u8) -> AluOp {
+    match index & 0x07 {
+        0 => AluOp::Add,
+        1 => AluOp::Adc,
+        2 => AluOp::Sub,
+        3 => AluOp::Sbc,
+        4 => AluOp::And,
+        5 => AluOp::Xor,
+        6 => AluOp::Or,
+        _ => AluOp::Cp,
+    }
+}
+
+/// Decodes an opcode as laid out in the opcode table: bits 7-6 select the
+/// block, bits 5-3 and 2-0 usually the operands
+pub const fn decode(opcode: u8) -> Op {
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01 != 0;
+    match opcode >> 6 {
+        0 => match z {
+            0 => match y {
+                0 => Op::Nop,
+                1 => Op::LdAbsoluteSp,
+                2 => Op::Stop,
+                3 => Op::Jr(Condition::Always),
+                _ => Op::Jr(condition(y - 4)),
+            },
+            1 if q => Op::AddHl(rp(p)),
+            1 => Op::LdWord(rp(p)),
+            2 => {
+                let memory = match p {
+                    0 => Operand::Indirect(RegisterPair::BC),
+                    1 => Operand::Indirect(RegisterPair::DE),
+                    2 => Operand::HlIncrement,
+                    _ => Operand::HlDecrement,
+                };
+                if q {
+                    Op::Ld(Operand::A, memory)
+                } else {
+                    Op::Ld(memory, Operand::A)
+                }
+            }
+            3 if q => Op::DecWord(rp(p)),
+            3 => Op::IncWord(rp(p)),
+            4 => Op::Inc(r8(y)),
+            5 => Op::Dec(r8(y)),
+            6 => Op::Ld(r8(y), Operand::Immediate),
+            _ => match y {
+                0 => Op::RotateA(Rotate::Rlc),
+                1 => Op::RotateA(Rotate::Rrc),
+                2 => Op::RotateA(Rotate::Rl),
+                3 => Op::RotateA(Rotate::Rr),
+                4 => Op::Daa,
+                5 => Op::Cpl,
+                6 => Op::Scf,
+                _ => Op::Ccf,
+            },
+        },
+        // LD (HL),(HL) is where HALT lives
+        1 if y == 6 && z == 6 => Op::Halt,
+        1 => Op::Ld(r8(y), r8(z)),
+        2 => Op::Alu(alu(y), r8(z)),
+        _ => match opcode {
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => Op::Ret(condition(y)),
+            0xE0 => Op::Ld(Operand::High, Operand::A),
+            0xE8 => Op::AddSp,
+            0xF0 => Op::Ld(Operand::A, Operand::High),
+            0xF8 => Op::LdHlSpOffset,
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => Op::Pop(rp2(p)),
+            0xC9 => Op::Ret(Condition::Always),
+            0xD9 => Op::Reti,
+            0xE9 => Op::JpHl,
+            0xF9 => Op::LdSpHl,
+            0xC2 | 0xCA | 0xD2 | 0xDA => Op::Jp(condition(y)),
+            0xE2 => Op::Ld(Operand::HighC, Operand::A),
+            0xEA => Op::Ld(Operand::Absolute, Operand::A),
+            0xF2 => Op::Ld(Operand::A, Operand::HighC),
+            0xFA => Op::Ld(Operand::A, Operand::Absolute),
+            0xC3 => Op::Jp(Condition::Always),
+            0xCB => Op::Prefix,
+            0xF3 => Op::Di,
+            0xFB => Op::Ei,
+            0xC4 | 0xCC | 0xD4 | 0xDC => Op::Call(condition(y)),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => Op::Push(rp2(p)),
+            0xCD => Op::Call(Condition::Always),
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+                Op::Alu(alu(y), Operand::Immediate)
+            }
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Op::Rst(y * 8),
+            _ => Op::Illegal,
+        },
+    }
+}
+
+impl Operand {
+    /// Bytes following the opcode
+    const fn length(self) -> u8 {
+        match self {
+            Operand::Immediate | Operand::High => 1,
+            Operand::Absolute => 2,
+            _ => 0,
+        }
+    }
+
+    // cycles spent fetching the operand and accessing memory through it
+    const fn cycles(self) -> u8 {
+        match self {
+            Operand::Indirect(_)
+            | Operand::HlIncrement
+            | Operand::HlDecrement
+            | Operand::Immediate
+            | Operand::HighC => 4,
+            Operand::High => 8,
+            Operand::Absolute => 12,
+            _ => 0,
+        }
+    }
+}
+
+impl Op {
+    /// Size in bytes, opcode included
+    pub const fn length(self) -> u8 {
+        match self {
+            Op::Ld(destination, source) => 1 + destination.length() + source.length(),
+            Op::Alu(_, operand) => 1 + operand.length(),
+            Op::Stop | Op::LdHlSpOffset | Op::AddSp | Op::Jr(_) | Op::Prefix => 2,
+            Op::LdWord(_) | Op::LdAbsoluteSp | Op::Jp(_) | Op::Call(_) => 3,
+            _ => 1,
+        }
+    }
+
+    /// Clock cycles when a conditional branch is not taken, and when it is
+    pub const fn cycles(self) -> (u8, u8) {
+        let cycles = match self {
+            Op::Ld(destination, source) => 4 + destination.cycles() + source.cycles(),
+            Op::Alu(_, operand) => 4 + operand.cycles(),
+            // read, modify and write back
+            Op::Inc(operand) | Op::Dec(operand) => 4 + 2 * operand.cycles(),
+            Op::LdSpHl | Op::IncWord(_) | Op::DecWord(_) | Op::AddHl(_) => 8,
+            Op::LdWord(_) | Op::LdHlSpOffset | Op::Pop(_) => 12,
+            Op::Push(_) | Op::AddSp | Op::Reti | Op::Rst(_) => 16,
+            Op::LdAbsoluteSp => 20,
+            Op::Jr(Condition::Always) => 12,
+            Op::Jr(_) => return (8, 12),
+            Op::Jp(Condition::Always) => 16,
+            Op::Jp(_) => return (12, 16),
+            Op::Call(Condition::Always) => 24,
+            Op::Call(_) => return (12, 24),
+            Op::Ret(Condition::Always) => 16,
+            Op::Ret(_) => return (8, 20),
+            _ => 4,
+        };
+        (cycles, cycles)
+    }
+
+    /// Mnemonic with the operands filled in from `operands`, the bytes
+    /// following the opcode. Placeholders like `d8` are used when missing.
+    pub fn disassemble(self, operands: &[u8]) -> String {
+        let byte = |i: usize| operands.get(i).copied();
+        let word = || Some(u16::from_le_bytes([byte(0)?, byte(1)?]));
+        let d8 = || byte(0).map_or("d8".to_string(), |v| format!("${:02X}", v));
+        let d16 = || word().map_or("d16".to_string(), |v| format!("${:04X}", v));
+        let a16 = || word().map_or("a16".to_string(), |v| format!("${:04X}", v));
+        let r8 = || byte(0).map_or("r8".to_string(), |v| format!("{:+}", v as i8));
+        let operand = |operand: Operand| match operand {
+            Operand::Indirect(pair) => format!("({:?})", pair),
+            Operand::HlIncrement => "(HL+)".to_string(),
+            Operand::HlDecrement => "(HL-)".to_string(),
+            Operand::Immediate => d8(),
+            Operand::Absolute => format!("({})", a16()),
+            Operand::High => byte(0).map_or("(a8)".to_string(), |v| format!("($FF{:02X})", v)),
+            Operand::HighC => "(C)".to_string(),
+            register => format!("{:?}", register),
+        };
+        let condition = |condition: Condition| match condition {
+            Condition::Always => String::new(),
+            condition => format!("{:?},", condition),
+        };
+        match self {
+            Op::Nop => "NOP".to_string(),
+            Op::Stop => "STOP 0".to_string(),
+            Op::Halt => "HALT".to_string(),
+            Op::Di => "DI".to_string(),
+            Op::Ei => "EI".to_string(),
+            Op::Ld(destination @ Operand::High, source)
+            | Op::Ld(destination, source @ Operand::High) => {
+                format!("LDH {},{}", operand(destination), operand(source))
+            }
+            Op::Ld(destination, source) => {
+                format!("LD {},{}", operand(destination), operand(source))
+            }
+            Op::LdWord(pair) => format!("LD {:?},{}", pair, d16()),
+            Op::LdAbsoluteSp => format!("LD ({}),SP", a16()),
+            Op::LdHlSpOffset => match byte(0) {
+                Some(offset) => format!("LD HL,SP{:+}", offset as i8),
+                None => "LD HL,SP+r8".to_string(),
+            },
+            Op::LdSpHl => "LD SP,HL".to_string(),
+            Op::Push(pair) => format!("PUSH {:?}", pair),
+            Op::Pop(pair) => format!("POP {:?}", pair),
+            Op::Alu(op, source) => {
+                format!(
+                    "{} A,{}",
+                    format!("{:?}", op).to_uppercase(),
+                    operand(source)
+                )
+            }
+            Op::Inc(target) => format!("INC {}", operand(target)),
+            Op::Dec(target) => format!("DEC {}", operand(target)),
+            Op::IncWord(pair) => format!("INC {:?}", pair),
+            Op::DecWord(pair) => format!("DEC {:?}", pair),
+            Op::AddHl(pair) => format!("ADD HL,{:?}", pair),
+            Op::AddSp => format!("ADD SP,{}", r8()),
+            Op::RotateA(rotate) => format!("{:?}A", rotate).to_uppercase(),
+            Op::Daa => "DAA".to_string(),
+            Op::Cpl => "CPL".to_string(),
+            Op::Scf => "SCF".to_string(),
+            Op::Ccf => "CCF".to_string(),
+            Op::Jr(cc) => format!("JR {}{}", condition(cc), r8()),
+            Op::Jp(cc) => format!("JP {}{}", condition(cc), a16()),
+            Op::JpHl => "JP (HL)".to_string(),
+            Op::Call(cc) => format!("CALL {}{}", condition(cc), a16()),
+            Op::Ret(Condition::Always) => "RET".to_string(),
+            Op::Ret(cc) => format!("RET {:?}", cc),
+            Op::Reti => "RETI".to_string(),
+            Op::Rst(vector) => format!("RST {:02X}H", vector),
+            Op::Prefix => "PREFIX CB".to_string(),
+            Op::Illegal => "ILLEGAL".to_string(),
+        }
+    }
+}
+
+/// The mnemonic with placeholders for the operands, e.g. `LD B,d8`
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.disassemble(&[]))
+    }
+}
+
+/// Disassembles the instruction at the start of `bytes`, returns it with its
+/// length
+pub fn disassemble(bytes: &[u8]) -> (String, u8) {
+    let op = decode(bytes[0]);
+    let length = op.length();
+    let end = bytes.len().min(length as usize);
+    (op.disassemble(&bytes[1..end]), length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_operands() {
+        assert_eq!(decode(0x41), Op::Ld(Operand::B, Operand::C));
+        assert_eq!(
+            decode(0x36),
+            Op::Ld(Operand::Indirect(RegisterPair::HL), Operand::Immediate)
+        );
+        assert_eq!(decode(0x3A), Op::Ld(Operand::A, Operand::HlDecrement));
+        assert_eq!(
+            decode(0x12),
+            Op::Ld(Operand::Indirect(RegisterPair::DE), Operand::A)
+        );
+        assert_eq!(
+            decode(0x9E),
+            Op::Alu(AluOp::Sbc, Operand::Indirect(RegisterPair::HL))
+        );
+        assert_eq!(decode(0xEE), Op::Alu(AluOp::Xor, Operand::Immediate));
+        assert_eq!(decode(0x3C), Op::Inc(Operand::A));
+        assert_eq!(decode(0x31), Op::LdWord(RegisterPair::SP));
+        assert_eq!(decode(0x29), Op::AddHl(RegisterPair::HL));
+        assert_eq!(decode(0xF5), Op::Push(RegisterPair::AF));
+        assert_eq!(decode(0xC1), Op::Pop(RegisterPair::BC));
+        assert_eq!(decode(0x38), Op::Jr(Condition::C));
+        assert_eq!(decode(0xC4), Op::Call(Condition::NZ));
+        assert_eq!(decode(0xDF), Op::Rst(0x18));
+        assert_eq!(decode(0x76), Op::Halt);
+        assert_eq!(decode(0xCB), Op::Prefix);
+        assert_eq!(decode(0xDD), Op::Illegal);
+    }
+
+    #[test]
+    fn test_length_and_cycles() {
+        assert_eq!((decode(0x00).length(), decode(0x00).cycles()), (1, (4, 4)));
+        assert_eq!(
+            (decode(0x36).length(), decode(0x36).cycles()),
+            (2, (12, 12))
+        );
+        assert_eq!(
+            (decode(0x34).length(), decode(0x34).cycles()),
+            (1, (12, 12))
+        );
+        assert_eq!(
+            (decode(0xEA).length(), decode(0xEA).cycles()),
+            (3, (16, 16))
+        );
+        assert_eq!(
+            (decode(0xF0).length(), decode(0xF0).cycles()),
+            (2, (12, 12))
+        );
+        assert_eq!((decode(0xC0).length(), decode(0xC0).cycles()), (1, (8, 20)));
+        assert_eq!(
+            (decode(0xDC).length(), decode(0xDC).cycles()),
+            (3, (12, 24))
+        );
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(decode(0x06).to_string(), "LD B,d8");
+        assert_eq!(decode(0x22).to_string(), "LD (HL+),A");
+        assert_eq!(decode(0x0A).to_string(), "LD A,(BC)");
+        assert_eq!(decode(0x01).to_string(), "LD BC,d16");
+        assert_eq!(decode(0xE0).to_string(), "LDH (a8),A");
+        assert_eq!(decode(0xFA).to_string(), "LD A,(a16)");
+        assert_eq!(decode(0xF8).to_string(), "LD HL,SP+r8");
+        assert_eq!(decode(0x17).to_string(), "RLA");
+        assert_eq!(decode(0xBE).to_string(), "CP A,(HL)");
+        assert_eq!(decode(0x20).to_string(), "JR NZ,r8");
+        assert_eq!(decode(0xCD).to_string(), "CALL a16");
+        assert_eq!(decode(0xD0).to_string(), "RET NC");
+        assert_eq!(decode(0xEF).to_string(), "RST 28H");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(&[0x3E, 0x42]), ("LD A,$42".to_string(), 2));
+        assert_eq!(
+            disassemble(&[0xC3, 0x50, 0x01]),
+            ("JP $0150".to_string(), 3)
+        );
+        assert_eq!(disassemble(&[0x18, 0xFE]), ("JR -2".to_string(), 2));
+        assert_eq!(disassemble(&[0xF0, 0x44]), ("LDH A,($FF44)".to_string(), 2));
+        assert_eq!(disassemble(&[0xF8, 0x05]), ("LD HL,SP+5".to_string(), 2));
+        assert_eq!(
+            disassemble(&[0x08, 0x00, 0xC0]),
+            ("LD ($C000),SP".to_string(), 3)
+        );
+        // truncated operands fall back to placeholders
+        assert_eq!(disassemble(&[0x21, 0x00]), ("LD HL,d16".to_string(), 3));
+        assert_eq!(disassemble(&[0xAF]), ("XOR A,A".to_string(), 1));
+    }
+}
@@ -0,0 +1,311 @@
+use std::path::PathBuf;
+
+use gameboy::{
+    accuracy::Accuracy,
+    debugger::parse_address,
+    fill::MemoryFill,
+    layers::Layers,
+    palette::ColorCorrection,
+    speed::{MAX_SPEED, MIN_SPEED},
+};
+
+pub const USAGE: &str = "\
+usage: gameboy [options] <rom>
+       gameboy selftest     run the built-in checks and print a report
+
+options:
+    --pc <addr>          start executing at <addr> instead of 0x0100
+    --boot-rom <file>    run the 256 byte DMG boot ROM in <file> first
+    --skip-to <symbol>   start executing at <symbol>, looked up in the .sym file
+    --sym <file>         symbol file, defaults to the ROM path with a .sym extension
+    --regs <preset>      initial registers, e.g. A=11,F=80,SP=DFFF
+    --fill <policy>      power-on WRAM/VRAM/HRAM: zeros, ones, pattern or random:<seed>
+    --hide <layers>      don't draw bg, window and/or sprites, e.g. bg,sprites
+    --accuracy <profile> fast, balanced (default) or accurate
+    --color-correction <curve>
+                         CGB colors as stored (raw, default), or as on a cgb or
+                         gba LCD
+    --speed <percent>    run at <percent>% of the hardware speed (50-1000), there
+                         is no audio output yet so only the video is paced
+    --dump-video <file>  write every frame to <file> as raw 160x144 RGB24
+    --encode-video <file>
+                         encode every frame losslessly to <file> through ffmpeg,
+                         e.g. a .mkv
+    --movie <file>       play back the input recorded in <file>
+    --load-state <file>  start from a state dumped with --dump-state
+    --dump-state <file>  write the machine state to <file> as JSON at exit
+    --screenshot <file>  write the last frame to <file> as a PPM image at exit,
+                         with a .json sidecar naming the game, the frame and the
+                         --dump-state file to reproduce it from
+    --trace <count>      instructions kept for the crash dump, 32 by default
+    --doctor <file>      trace every instruction to <file> (- for stdout) in the
+                         Gameboy Doctor format
+    --frames <frames>    exit after running <frames> frames
+    --json-summary       print a JSON summary of the run at exit, stops once a
+                         test ROM reports its result and exits with 1 if it
+                         failed, 2 if it never did
+    --strict             refuse ROMs whose global checksum is wrong
+    --watch              reload the ROM whenever it is rebuilt
+    --services           map the emulator services ports at 0xFF7D-0xFF7F, for
+                         test ROMs written for this emulator
+    --debugger           set breakpoints, watchpoints and cheats at a prompt
+                         before running and whenever a breakpoint or watchpoint
+                         is hit, `run` resumes and `history` lists the previous
+                         commands. They are saved for the game, and its cheats
+                         apply without --debugger too
+    --run-ahead <frames> show the frame <frames> frames ahead to hide input latency
+    --check-determinism <frames>
+                         run <frames> frames twice and compare the machine state";
+
+/// Command line options
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub rom: PathBuf,
+    pub pc: Option<u16>,
+    pub boot_rom: Option<PathBuf>,
+    pub skip_to: Option<String>,
+    pub symbols: Option<PathBuf>,
+    pub registers: Option<String>,
+    pub fill: MemoryFill,
+    pub hidden_layers: Layers,
+    pub accuracy: Accuracy,
+    pub color_correction: ColorCorrection,
+    pub speed: u32,
+    pub dump_video: Option<PathBuf>,
+    pub encode_video: Option<PathBuf>,
+    pub movie: Option<PathBuf>,
+    pub load_state: Option<PathBuf>,
+    pub dump_state: Option<PathBuf>,
+    pub screenshot: Option<PathBuf>,
+    pub doctor: Option<PathBuf>,
+    pub trace: usize,
+    pub run_ahead: usize,
+    pub frames: Option<u64>,
+    pub json_summary: bool,
+    pub strict: bool,
+    pub watch: bool,
+    pub services: bool,
+    pub debugger: bool,
+    pub check_determinism: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            rom: PathBuf::new(),
+            pc: None,
+            boot_rom: None,
+            skip_to: None,
+            symbols: None,
+            registers: None,
+            fill: MemoryFill::default(),
+            hidden_layers: Layers::empty(),
+            accuracy: Accuracy::default(),
+            color_correction: ColorCorrection::default(),
+            speed: 100,
+            dump_video: None,
+            encode_video: None,
+            movie: None,
+            load_state: None,
+            dump_state: None,
+            screenshot: None,
+            doctor: None,
+            trace: 32,
+            run_ahead: 0,
+            frames: None,
+            json_summary: false,
+            strict: false,
+            watch: false,
+            services: false,
+            debugger: false,
+            check_determinism: None,
+        }
+    }
+}
+
+fn parse_speed(value: &str) -> Result<u32, String> {
+    match value.trim_end_matches('%').parse() {
+        Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => Ok(speed),
+        _ => Err(format!(
+            "Invalid speed: {}, expected {}-{}",
+            value, MIN_SPEED, MAX_SPEED
+        )),
+    }
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut rom = None;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--pc" => options.pc = Some(parse_address(&value()?)?),
+                "--boot-rom" => options.boot_rom = Some(PathBuf::from(value()?)),
+                "--skip-to" => options.skip_to = Some(value()?),
+                "--sym" => options.symbols = Some(PathBuf::from(value()?)),
+                "--regs" => options.registers = Some(value()?),
+                "--fill" => options.fill = value()?.parse()?,
+                "--hide" => options.hidden_layers = value()?.parse()?,
+                "--accuracy" => options.accuracy = value()?.parse()?,
+                "--color-correction" => options.color_correction = value()?.parse()?,
+                "--speed" => options.speed = parse_speed(&value()?)?,
+                "--dump-video" => options.dump_video = Some(PathBuf::from(value()?)),
+                "--encode-video" => options.encode_video = Some(PathBuf::from(value()?)),
+                "--movie" => options.movie = Some(PathBuf::from(value()?)),
+                "--load-state" => options.load_state = Some(PathBuf::from(value()?)),
+                "--dump-state" => options.dump_state = Some(PathBuf::from(value()?)),
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+                "--doctor" => options.doctor = Some(PathBuf::from(value()?)),
+                "--trace" => {
+                    let count = value()?;
+                    options.trace = count
+                        .parse()
+                        .map_err(|_| format!("Invalid instruction count: {}", count))?;
+                }
+                "--frames" => {
+                    let frames = value()?;
+                    options.frames = Some(
+                        frames
+                            .parse()
+                            .map_err(|_| format!("Invalid frame count: {}", frames))?,
+                    );
+                }
+                "--json-summary" => options.json_summary = true,
+                "--strict" => options.strict = true,
+                "--watch" => options.watch = true,
+                "--services" => options.services = true,
+                "--debugger" => options.debugger = true,
+                "--run-ahead" => {
+                    let frames = value()?;
+                    options.run_ahead = frames
+                        .parse()
+                        .map_err(|_| format!("Invalid frame count: {}", frames))?;
+                }
+                "--check-determinism" => {
+                    let frames = value()?;
+                    options.check_determinism = Some(
+                        frames
+                            .parse()
+                            .map_err(|_| format!("Invalid frame count: {}", frames))?,
+                    );
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ => rom = Some(PathBuf::from(arg)),
+            }
+        }
+        options.rom = rom.ok_or("Missing ROM path")?;
+        if options.pc.is_some() && options.skip_to.is_some() {
+            return Err("--pc and --skip-to are mutually exclusive".to_string());
+        }
+        if options.dump_video.is_some() && options.encode_video.is_some() {
+            return Err("--dump-video and --encode-video are mutually exclusive".to_string());
+        }
+        // the summary describes a single run
+        if options.watch && options.json_summary {
+            return Err("--watch and --json-summary are mutually exclusive".to_string());
+        }
+        // run-ahead steps whole frames
+        if options.doctor.is_some() && options.run_ahead > 0 {
+            return Err("--doctor and --run-ahead are mutually exclusive".to_string());
+        }
+        if options.debugger && options.run_ahead > 0 {
+            return Err("--debugger and --run-ahead are mutually exclusive".to_string());
+        }
+        Ok(options)
+    }
+
+    pub fn symbols_path(&self) -> PathBuf {
+        self.symbols
+            .clone()
+            .unwrap_or_else(|| self.rom.with_extension("sym"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse(&["--pc", "$c000", "game.gb", "--regs", "A=11"]).unwrap();
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.pc, Some(0xC000));
+        assert_eq!(options.registers.as_deref(), Some("A=11"));
+        assert_eq!(options.symbols_path(), PathBuf::from("game.sym"));
+        assert_eq!(parse(&["--pc", "0x150", "a.gb"]).unwrap().pc, Some(0x150));
+        assert_eq!(parse(&["a.gb"]).unwrap().speed, 100);
+        assert_eq!(parse(&["--speed", "250%", "a.gb"]).unwrap().speed, 250);
+        let options = parse(&["--encode-video", "run.mkv", "a.gb"]);
+        assert_eq!(
+            options.unwrap().encode_video,
+            Some(PathBuf::from("run.mkv"))
+        );
+        let options = parse(&["a.gb", "--movie", "a.txt", "--check-determinism", "600"]).unwrap();
+        assert_eq!(options.movie, Some(PathBuf::from("a.txt")));
+        assert_eq!(options.check_determinism, Some(600));
+        assert_eq!(parse(&["a.gb"]).unwrap().run_ahead, 0);
+        let options = parse(&["--fill", "random:7", "a.gb"]).unwrap();
+        assert_eq!(options.fill, MemoryFill::Random(7));
+        assert_eq!(parse(&["--run-ahead", "2", "a.gb"]).unwrap().run_ahead, 2);
+        let options = parse(&["--json-summary", "--frames", "3600", "a.gb"]).unwrap();
+        assert!(options.json_summary);
+        assert_eq!(options.frames, Some(3600));
+        assert!(parse(&["--watch", "a.gb"]).unwrap().watch);
+        assert!(parse(&["--strict", "a.gb"]).unwrap().strict);
+        let options = parse(&[
+            "--load-state",
+            "in.json",
+            "--dump-state",
+            "out.json",
+            "--screenshot",
+            "out.ppm",
+            "a.gb",
+        ])
+        .unwrap();
+        assert_eq!(options.load_state, Some(PathBuf::from("in.json")));
+        assert_eq!(options.dump_state, Some(PathBuf::from("out.json")));
+        assert_eq!(options.screenshot, Some(PathBuf::from("out.ppm")));
+        let options = parse(&["--hide", "bg,sprites", "a.gb"]).unwrap();
+        assert_eq!(options.hidden_layers, Layers::BACKGROUND | Layers::SPRITES);
+        let options = parse(&["--accuracy", "fast", "a.gb"]).unwrap();
+        assert_eq!(options.accuracy, Accuracy::Fast);
+        let options = parse(&["--color-correction", "cgb", "a.gb"]).unwrap();
+        assert_eq!(options.color_correction, ColorCorrection::Cgb);
+        let options = parse(&["--doctor", "-", "a.gb"]).unwrap();
+        assert_eq!(options.doctor, Some(PathBuf::from("-")));
+        assert_eq!(options.trace, 32);
+        let options = parse(&["--boot-rom", "dmg_boot.bin", "a.gb"]).unwrap();
+        assert_eq!(options.boot_rom, Some(PathBuf::from("dmg_boot.bin")));
+        assert!(parse(&["--services", "a.gb"]).unwrap().services);
+        assert!(parse(&["--debugger", "a.gb"]).unwrap().debugger);
+        assert_eq!(parse(&["--trace", "0", "a.gb"]).unwrap().trace, 0);
+    }
+
+    #[test]
+    fn test_parse_options_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["a.gb", "--pc"]).is_err());
+        assert!(parse(&["a.gb", "--pc", "zz"]).is_err());
+        assert!(parse(&["a.gb", "--frobnicate"]).is_err());
+        assert!(parse(&["a.gb", "--pc", "150", "--skip-to", "Main"]).is_err());
+        assert!(parse(&["a.gb", "--speed", "20"]).is_err());
+        assert!(parse(&["a.gb", "--speed", "fast"]).is_err());
+        assert!(parse(&["a.gb", "--dump-video", "a.rgb", "--encode-video", "a.mkv"]).is_err());
+        assert!(parse(&["a.gb", "--run-ahead", "-1"]).is_err());
+        assert!(parse(&["a.gb", "--fill", "random"]).is_err());
+        assert!(parse(&["a.gb", "--hide", "tiles"]).is_err());
+        assert!(parse(&["a.gb", "--accuracy", "max"]).is_err());
+        assert!(parse(&["a.gb", "--color-correction", "srgb"]).is_err());
+        assert!(parse(&["a.gb", "--watch", "--json-summary"]).is_err());
+        assert!(parse(&["a.gb", "--doctor", "-", "--run-ahead", "1"]).is_err());
+        assert!(parse(&["a.gb", "--debugger", "--run-ahead", "1"]).is_err());
+    }
+}
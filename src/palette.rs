@@ -0,0 +1,270 @@
+use std::str::FromStr;
+
+use crate::json::Value;
+
+/// CGB palette registers, an index (bit 7 to increment it after each data
+/// write) and the data at that index, for the background then the objects
+/// https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+pub const BCPS: u16 = 0xFF68;
+pub const BCPD: u16 = 0xFF69;
+pub const OCPS: u16 = 0xFF6A;
+pub const OCPD: u16 = 0xFF6B;
+
+/// 8 palettes of 4 colors of 2 bytes
+pub const PALETTE_RAM_SIZE: usize = 64;
+
+const AUTO_INCREMENT: u8 = 0x80;
+
+/// A color as the CGB stores it, 5 bits per channel with red in the low bits
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rgb555(pub u16);
+
+impl Rgb555 {
+    pub const WHITE: Rgb555 = Rgb555(0x7FFF);
+
+    pub fn red(self) -> u8 {
+        (self.0 & 0x1F) as u8
+    }
+
+    pub fn green(self) -> u8 {
+        (self.0 >> 5 & 0x1F) as u8
+    }
+
+    pub fn blue(self) -> u8 {
+        (self.0 >> 10 & 0x1F) as u8
+    }
+
+    /// Each channel scaled to 8 bits as is, without correcting for the LCD
+    pub fn to_rgb24(self) -> [u8; 3] {
+        [self.red(), self.green(), self.blue()].map(|channel| channel << 3 | channel >> 2)
+    }
+}
+
+/// How 15-bit CGB colors turn into sRGB. The LCDs were far less saturated
+/// than a modern screen showing the raw values.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ColorCorrection {
+    /// The channels as stored, what the games' authors picked
+    #[default]
+    Raw,
+    /// The CGB LCD: washed out colors bleeding into each other
+    Cgb,
+    /// The darker, gamma heavy GBA LCD, for games played on one
+    Gba,
+}
+
+impl ColorCorrection {
+    pub fn apply(self, color: Rgb555) -> [u8; 3] {
+        let [r, g, b] = [color.red(), color.green(), color.blue()].map(u32::from);
+        match self {
+            ColorCorrection::Raw => color.to_rgb24(),
+            // the curve used by Gambatte and higan, up to 240 per channel
+            ColorCorrection::Cgb => [
+                r * 26 + g * 4 + b * 2,
+                g * 24 + b * 8,
+                r * 6 + g * 4 + b * 22,
+            ]
+            .map(|channel| (channel.min(960) >> 2) as u8),
+            // higan's GBA curve: the LCD gamma, the channels mixed, then
+            // back to the sRGB gamma
+            ColorCorrection::Gba => {
+                let [r, g, b] = [r, g, b].map(|channel| (channel as f32 / 31.0).powf(4.0));
+                [
+                    50.0 * g + 255.0 * r,
+                    30.0 * b + 230.0 * g + 10.0 * r,
+                    220.0 * b + 10.0 * g + 50.0 * r,
+                ]
+                .map(|channel| {
+                    ((channel / 255.0).min(1.0).powf(1.0 / 2.2) * 255.0 * 255.0 / 280.0) as u8
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for ColorCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ColorCorrection::Raw),
+            "cgb" => Ok(ColorCorrection::Cgb),
+            "gba" => Ok(ColorCorrection::Gba),
+            _ => Err(format!("Invalid color correction: {}", s)),
+        }
+    }
+}
+
+/// One of the two palette memories with its index register
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PaletteRam {
+    // BCPS/OCPS, the index in the low 6 bits
+    spec: u8,
+    data: Vec<u8>,
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PaletteRam {
+    /// All white
+    pub fn new() -> Self {
+        Self {
+            spec: 0x00,
+            data: vec![0xFF; PALETTE_RAM_SIZE],
+        }
+    }
+
+    fn index(&self) -> usize {
+        (self.spec & 0x3F) as usize
+    }
+
+    fn read_spec(&self) -> u8 {
+        // bit 6 does not exist
+        self.spec | 0x40
+    }
+
+    fn write_spec(&mut self, value: u8) {
+        self.spec = value & !0x40;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index()]
+    }
+
+    fn write_data(&mut self, value: u8) {
+        let index = self.index();
+        self.data[index] = value;
+        if self.spec & AUTO_INCREMENT != 0 {
+            self.spec = AUTO_INCREMENT | (index as u8 + 1) & 0x3F;
+        }
+    }
+
+    /// Color `color` (0-3) of palette `palette` (0-7)
+    pub fn color(&self, palette: usize, color: usize) -> Rgb555 {
+        let i = (palette * 4 + color) * 2;
+        Rgb555(u16::from_le_bytes([self.data[i], self.data[i + 1]]) & 0x7FFF)
+    }
+
+    /// The whole memory, little endian colors one after the other
+    pub fn raw(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        Value::object([
+            ("spec", Value::hex(self.spec as u64, 2)),
+            ("data", Value::bytes(&self.data)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let mut data = vec![0x00; PALETTE_RAM_SIZE];
+        value.get("data")?.read_bytes(&mut data)?;
+        Ok(Self {
+            spec: value.get("spec")?.as_u8()? & !0x40,
+            data,
+        })
+    }
+}
+
+/// Background and object palettes of the CGB
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CgbPalettes {
+    pub background: PaletteRam,
+    pub objects: PaletteRam,
+}
+
+impl CgbPalettes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            BCPS => self.background.read_spec(),
+            BCPD => self.background.read_data(),
+            OCPS => self.objects.read_spec(),
+            OCPD => self.objects.read_data(),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            BCPS => self.background.write_spec(value),
+            BCPD => self.background.write_data(value),
+            OCPS => self.objects.write_spec(value),
+            OCPD => self.objects.write_data(value),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        Value::object([
+            ("background", self.background.to_json()),
+            ("objects", self.objects.to_json()),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            background: PaletteRam::from_json(value.get("background")?)?,
+            objects: PaletteRam::from_json(value.get("objects")?)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rgb555() {
+        let color = Rgb555(0x7C1F);
+        assert_eq!((color.red(), color.green(), color.blue()), (31, 0, 31));
+        assert_eq!(color.to_rgb24(), [0xFF, 0x00, 0xFF]);
+        assert_eq!(Rgb555::WHITE.to_rgb24(), [0xFF; 3]);
+    }
+
+    #[test]
+    fn test_color_correction() {
+        let red = Rgb555(0x001F);
+        assert_eq!(ColorCorrection::Raw.apply(red), [0xFF, 0x00, 0x00]);
+        assert_eq!(ColorCorrection::Cgb.apply(red), [0xC9, 0x00, 0x2E]);
+        assert_eq!(ColorCorrection::Cgb.apply(Rgb555::WHITE), [0xF0; 3]);
+        assert_eq!(ColorCorrection::Gba.apply(Rgb555(0x0000)), [0x00; 3]);
+        assert_eq!(ColorCorrection::Gba.apply(Rgb555::WHITE), [0xE8; 3]);
+        assert_eq!("gba".parse(), Ok(ColorCorrection::Gba));
+        assert!("srgb".parse::<ColorCorrection>().is_err());
+    }
+
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut palettes = CgbPalettes::new();
+        // palette 1, color 2
+        palettes.write(BCPS, AUTO_INCREMENT | 0x0C);
+        palettes.write(BCPD, 0x1F);
+        palettes.write(BCPD, 0x7C);
+        assert_eq!(palettes.read(BCPS), 0xC0 | 0x0E);
+        assert_eq!(palettes.background.color(1, 2), Rgb555(0x7C1F));
+        assert_eq!(palettes.objects.color(1, 2), Rgb555::WHITE);
+        palettes.write(OCPS, 0x3F);
+        palettes.write(OCPD, 0x00);
+        palettes.write(OCPD, 0x12);
+        // the index stays without auto increment
+        assert_eq!(palettes.read(OCPS), 0x7F);
+        assert_eq!(palettes.read(OCPD), 0x12);
+        assert_eq!(palettes.objects.raw()[63], 0x12);
+        // and wraps with it
+        palettes.write(OCPS, AUTO_INCREMENT | 0x3F);
+        palettes.write(OCPD, 0x34);
+        assert_eq!(palettes.read(OCPS), 0xC0);
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Where battery saves and savestates are kept, as blobs under a key such
+/// as `game.sav`. The core only talks to this trait so that the desktop
+/// frontend can use files and a web frontend localStorage or IndexedDB.
+pub trait Storage: Send {
+    /// `None` when nothing was stored under `key` yet
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    fn store(&mut self, key: &str, data: &[u8]) -> io::Result<()>;
+    /// Removing a missing key is not an error
+    fn remove(&mut self, key: &str) -> io::Result<()>;
+}
+
+/// One file per key in a directory
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    // keys are plain file names, they must not escape the directory
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || key == "." || key == ".." || key.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid storage key: {}", key),
+            ));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(path, data)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Kept in memory only, for tests and runs that must not leave files behind
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage(HashMap<String, Vec<u8>>);
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.0.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.0.remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &mut dyn Storage) {
+        assert_eq!(storage.load("game.sav").unwrap(), None);
+        storage.store("game.sav", &[0x01, 0x02]).unwrap();
+        assert_eq!(storage.load("game.sav").unwrap(), Some(vec![0x01, 0x02]));
+        storage.remove("game.sav").unwrap();
+        storage.remove("game.sav").unwrap();
+        assert_eq!(storage.load("game.sav").unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        exercise(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let dir = std::env::temp_dir().join(format!("gameboy-storage-{}", std::process::id()));
+        let mut storage = FileStorage::new(&dir);
+        exercise(&mut storage);
+        assert!(storage.store("../game.sav", &[]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
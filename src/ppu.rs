@@ -0,0 +1,375 @@
+use crate::{emulator::DOTS_PER_FRAME, json::Value};
+
+/// Current scanline, read-only
+/// https://gbdev.io/pandocs/STAT.html#ff44--ly-lcd-y-coordinate-read-only
+pub const LY: u16 = 0xFF44;
+
+pub const DOTS_PER_LINE: u32 = 456;
+/// First line of the vertical blanking period
+pub const VBLANK_LINE: u32 = 144;
+pub const LAST_LINE: u32 = 153;
+// LY reads 153 for one machine cycle only, then 0 for the rest of the line
+const LINE_153_DOTS: u32 = 4;
+// the comparison with LYC fails for a machine cycle each time LY changes
+const LY_UPDATE_DOTS: u32 = 4;
+/// Length of the OAM scan at the start of each visible line
+pub const OAM_SCAN_DOTS: u32 = 80;
+/// Shortest length of the drawing period, and its length on every line when
+/// rendering a line at a time
+pub const DRAWING_DOTS: u32 = 172;
+/// Longest the drawing period gets, with the fine scroll, the window and 10
+/// sprites on the line
+pub const MAX_DRAWING_DOTS: u32 = 289;
+
+/// What the PPU is doing, as reported in the low bits of STAT
+/// https://gbdev.io/pandocs/Rendering.html#ppu-modes
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    /// Searching OAM for the sprites on the line, OAM is unreachable
+    #[default]
+    OamScan = 2,
+    /// Sending pixels to the LCD, VRAM and OAM are unreachable
+    Drawing = 3,
+}
+
+/// Picture processing unit timing, and the state the window keeps across
+/// lines. The pixels are drawn by `Renderer` as the lines are reached.
+///
+/// Each visible line goes through OAM scan, drawing and H-blank, then the
+/// 10 lines of V-blank follow. The modes change as `tick` runs into their
+/// ends, so the length of each can depend on what happened on the line.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ppu {
+    mode: Mode,
+    // 0 to 153
+    line: u32,
+    // dots since the start of the line
+    dot: u32,
+    // length of the drawing period of the current line, once it started
+    drawing_dots: u32,
+    // WY matched LY on a line of this frame, the window can show from there
+    window_y_reached: bool,
+    // line of the window drawn next, only the lines it shows on count
+    window_line: u8,
+    // the STAT interrupt line, it fires as it goes up
+    stat_line: bool,
+    // a frame was completed and not reported yet
+    frame_ready: bool,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tick(&mut self, mut dots: u32) {
+        while dots > 0 {
+            dots -= self.step(dots).0;
+        }
+    }
+
+    /// Runs up to `dots` dots, stopping early as the next mode starts so the
+    /// devices can act on it. Returns the dots run, and the mode started if
+    /// one did.
+    pub fn step(&mut self, dots: u32) -> (u32, Option<Mode>) {
+        let end = self.mode_end();
+        // it also stops as the value LYC is compared with changes
+        let stop = [LY_UPDATE_DOTS, LINE_153_DOTS + LY_UPDATE_DOTS]
+            .into_iter()
+            .find(|&dot| dot > self.dot)
+            .map_or(end, |dot| dot.min(end));
+        let run = dots.min(stop - self.dot);
+        self.dot += run;
+        if self.dot < end {
+            return (run, None);
+        }
+        self.next_mode();
+        (run, Some(self.mode))
+    }
+
+    // dot of the line the current mode ends on
+    fn mode_end(&self) -> u32 {
+        match self.mode {
+            Mode::OamScan => OAM_SCAN_DOTS,
+            Mode::Drawing => OAM_SCAN_DOTS + self.drawing_dots,
+            Mode::HBlank | Mode::VBlank => DOTS_PER_LINE,
+        }
+    }
+
+    fn next_mode(&mut self) {
+        self.mode = match self.mode {
+            Mode::OamScan => {
+                self.drawing_dots = DRAWING_DOTS;
+                Mode::Drawing
+            }
+            Mode::Drawing => Mode::HBlank,
+            Mode::HBlank | Mode::VBlank => {
+                self.dot = 0;
+                self.drawing_dots = 0;
+                self.line = (self.line + 1) % (LAST_LINE + 1);
+                if self.line == 0 {
+                    self.window_y_reached = false;
+                    self.window_line = 0;
+                }
+                if self.line == VBLANK_LINE {
+                    self.frame_ready = true;
+                }
+                if self.line >= VBLANK_LINE {
+                    Mode::VBlank
+                } else {
+                    Mode::OamScan
+                }
+            }
+        };
+    }
+
+    /// Line being drawn, 0 to 153
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// Dots since the start of the line, 0 to 455
+    pub fn line_dot(&self) -> u32 {
+        self.dot
+    }
+
+    /// LY as the CPU reads it: line 153 already reads as 0 after its first
+    /// machine cycle
+    pub fn ly(&self) -> u8 {
+        if self.line == LAST_LINE && self.dot >= LINE_153_DOTS {
+            0
+        } else {
+            self.line as u8
+        }
+    }
+
+    /// The line LYC is compared with, `None` while LY changes at the start
+    /// of each line. Line 153 compares as 153 while LY reads so, then as 0
+    /// once LY settles on it.
+    /// https://gbdev.io/pandocs/STAT.html#ff45--lyc-ly-compare
+    pub fn ly_compare(&self) -> Option<u8> {
+        match (self.line, self.dot) {
+            (0, _) => Some(0),
+            (LAST_LINE, dot) if dot < LINE_153_DOTS => Some(LAST_LINE as u8),
+            (LAST_LINE, dot) if dot < LINE_153_DOTS + LY_UPDATE_DOTS => None,
+            (LAST_LINE, _) => Some(0),
+            (_, dot) if dot < LY_UPDATE_DOTS => None,
+            (line, _) => Some(line as u8),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Sets how long drawing lasts on this line, for a renderer that only
+    /// knows once it is through. Has no effect outside of drawing.
+    pub fn set_drawing_dots(&mut self, dots: u32) {
+        if self.mode == Mode::Drawing {
+            self.drawing_dots = dots;
+        }
+    }
+
+    /// How long the current mode lasts on this line, in dots. H-blank takes
+    /// whatever drawing left of the line.
+    pub fn mode_dots(&self) -> u32 {
+        match self.mode {
+            Mode::OamScan => OAM_SCAN_DOTS,
+            Mode::Drawing => self.drawing_dots,
+            Mode::HBlank => DOTS_PER_LINE - OAM_SCAN_DOTS - self.drawing_dots,
+            Mode::VBlank => DOTS_PER_LINE,
+        }
+    }
+
+    /// Compares WY with the line that starts, from then on the window shows
+    /// until the end of the frame wherever it is enabled
+    pub fn check_window_y(&mut self, wy: u8) {
+        if wy as u32 == self.line {
+            self.window_y_reached = true;
+        }
+    }
+
+    /// Sets the level of the STAT interrupt line, which the enabled sources
+    /// of STAT are ORed into. Returns whether it just went up and the
+    /// interrupt is requested, one source rising while another is already
+    /// up does not count.
+    pub fn set_stat_line(&mut self, level: bool) -> bool {
+        let rising = level && !self.stat_line;
+        self.stat_line = level;
+        rising
+    }
+
+    /// Whether a frame was completed since the last call, which happens as
+    /// V-blank starts. It stays whole until line 0 is drawn again.
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn window_y_reached(&self) -> bool {
+        self.window_y_reached
+    }
+
+    /// The line of the window to draw on the current line, moving on to the
+    /// next one. Lines the window is hidden on are skipped by not calling
+    /// it, so it resumes where it stopped when shown again.
+    pub fn next_window_line(&mut self) -> u8 {
+        let line = self.window_line;
+        self.window_line = self.window_line.wrapping_add(1);
+        line
+    }
+
+    /// Whether the CPU can reach VRAM
+    pub fn vram_accessible(&self) -> bool {
+        self.mode() != Mode::Drawing
+    }
+
+    /// Whether the CPU can reach OAM
+    pub fn oam_accessible(&self) -> bool {
+        !matches!(self.mode(), Mode::OamScan | Mode::Drawing)
+    }
+
+    /// True from the first dot of line 144 to the end of the frame
+    pub fn in_vblank(&self) -> bool {
+        self.line() >= VBLANK_LINE
+    }
+
+    // saved as the dot of the frame, which the modes follow from
+    pub(crate) fn to_json(self) -> Value {
+        let dot = self.line * DOTS_PER_LINE + self.dot;
+        Value::object([
+            ("dot", Value::Number(dot as u64)),
+            ("drawing_dots", Value::Number(self.drawing_dots as u64)),
+            ("window_y_reached", Value::Bool(self.window_y_reached)),
+            ("window_line", Value::Number(self.window_line as u64)),
+            ("stat_line", Value::Bool(self.stat_line)),
+            ("frame_ready", Value::Bool(self.frame_ready)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let dot = value.get("dot")?.as_u32()?;
+        if dot >= DOTS_PER_FRAME as u32 {
+            return Err(format!("Invalid dot: {}", dot));
+        }
+        let drawing_dots = value.get("drawing_dots")?.as_u32()?;
+        if drawing_dots > MAX_DRAWING_DOTS {
+            return Err(format!("Invalid drawing dots: {}", drawing_dots));
+        }
+        let mut ppu = Self::new();
+        // the modes of the line depend on how long drawing lasts
+        let line_dot = dot % DOTS_PER_LINE;
+        ppu.tick(dot - line_dot + line_dot.min(OAM_SCAN_DOTS));
+        ppu.set_drawing_dots(drawing_dots);
+        ppu.tick(line_dot.saturating_sub(OAM_SCAN_DOTS));
+        ppu.window_y_reached = value.get("window_y_reached")?.as_bool()?;
+        ppu.window_line = value.get("window_line")?.as_u8()?;
+        ppu.stat_line = value.get("stat_line")?.as_bool()?;
+        ppu.frame_ready = value.get("frame_ready")?.as_bool()?;
+        Ok(ppu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vblank_start() {
+        let mut ppu = Ppu::new();
+        ppu.tick(VBLANK_LINE * DOTS_PER_LINE - 1);
+        assert_eq!(ppu.ly(), 143);
+        assert!(!ppu.in_vblank());
+        assert!(!ppu.take_frame_ready());
+        ppu.tick(1);
+        assert_eq!(ppu.ly(), 144);
+        assert!(ppu.in_vblank());
+        assert!(ppu.take_frame_ready());
+        assert!(!ppu.take_frame_ready());
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut ppu = Ppu::new();
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        assert!(ppu.vram_accessible() && !ppu.oam_accessible());
+        ppu.tick(OAM_SCAN_DOTS);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        assert!(!ppu.vram_accessible() && !ppu.oam_accessible());
+        ppu.tick(DRAWING_DOTS);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert!(ppu.vram_accessible() && ppu.oam_accessible());
+        ppu.tick(DOTS_PER_LINE - OAM_SCAN_DOTS - DRAWING_DOTS);
+        assert_eq!((ppu.line(), ppu.mode()), (1, Mode::OamScan));
+        ppu.tick((VBLANK_LINE - 1) * DOTS_PER_LINE + OAM_SCAN_DOTS);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert!(ppu.vram_accessible() && ppu.oam_accessible());
+    }
+
+    #[test]
+    fn test_mode_durations() {
+        let mut ppu = Ppu::new();
+        for line in 0..=LAST_LINE {
+            let mut dots = 0;
+            while ppu.line() == line {
+                assert_eq!(ppu.line_dot(), dots);
+                let mode_dots = ppu.mode_dots();
+                dots += mode_dots;
+                ppu.tick(mode_dots);
+            }
+            assert_eq!(dots, DOTS_PER_LINE, "line {}", line);
+        }
+        assert_eq!((ppu.line(), ppu.mode()), (0, Mode::OamScan));
+        assert_eq!(Ppu::from_json(&ppu.to_json()), Ok(ppu));
+        ppu.tick(OAM_SCAN_DOTS + 10);
+        assert_eq!(Ppu::from_json(&ppu.to_json()), Ok(ppu));
+        // a drawing period longer than the usual one, before and after it
+        // ends
+        ppu.set_drawing_dots(DRAWING_DOTS + 20);
+        ppu.tick(DRAWING_DOTS);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        assert_eq!(Ppu::from_json(&ppu.to_json()), Ok(ppu));
+        ppu.tick(20);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert_eq!(Ppu::from_json(&ppu.to_json()), Ok(ppu));
+    }
+
+    #[test]
+    fn test_ly_compare() {
+        let mut ppu = Ppu::new();
+        assert_eq!(ppu.ly_compare(), Some(0));
+        ppu.tick(DOTS_PER_LINE);
+        assert_eq!(ppu.ly_compare(), None);
+        // stops there for the devices to see it
+        assert_eq!(ppu.step(10), (4, None));
+        assert_eq!(ppu.ly_compare(), Some(1));
+        ppu.tick((LAST_LINE - 1) * DOTS_PER_LINE - LY_UPDATE_DOTS);
+        assert_eq!((ppu.ly(), ppu.ly_compare()), (153, Some(153)));
+        ppu.tick(4);
+        assert_eq!((ppu.ly(), ppu.ly_compare()), (0, None));
+        ppu.tick(4);
+        assert_eq!((ppu.ly(), ppu.ly_compare()), (0, Some(0)));
+        ppu.tick(DOTS_PER_LINE - 8);
+        assert_eq!((ppu.line(), ppu.ly_compare()), (0, Some(0)));
+    }
+
+    #[test]
+    fn test_line_153_reads_as_0() {
+        let mut ppu = Ppu::new();
+        ppu.tick(LAST_LINE * DOTS_PER_LINE);
+        assert_eq!(ppu.ly(), 153);
+        ppu.tick(3);
+        assert_eq!(ppu.ly(), 153);
+        ppu.tick(1);
+        assert_eq!(ppu.line(), 153);
+        assert_eq!(ppu.ly(), 0);
+        assert!(ppu.in_vblank());
+        // the next frame starts on line 0 for real
+        ppu.tick(DOTS_PER_LINE - 4);
+        assert_eq!((ppu.line(), ppu.ly()), (0, 0));
+        assert!(!ppu.in_vblank());
+    }
+}
@@ -1,7 +1,10 @@
 use bitflags::bitflags;
 
+use crate::json::Value;
+
 #[repr(C)]
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Registers {
     pub a: u8, // Accumulator
     pub f: Flags,
@@ -33,9 +36,11 @@ impl std::fmt::Debug for Registers {
     }
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StackPointer(pub u16);
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProgramCounter(pub u16);
 impl ProgramCounter {
     pub fn value(&self) -> u16 {
@@ -45,7 +50,7 @@ impl ProgramCounter {
 
 bitflags! {
     #[repr(transparent)]
-    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
     pub struct Flags: u8 {
         // set only if the result of the operation is zero
         // used for conditional jumps
@@ -60,3 +65,205 @@ bitflags! {
         const CARRY = 1 << 4;
     }
 }
+
+impl Flags {
+    /// F as the CPU stores `value`: the low nibble is hardwired to zero, so
+    /// e.g. POP AF can't set it. Every write to F goes through here.
+    pub fn from_byte(value: u8) -> Self {
+        Self::from_bits_truncate(value & 0xF0)
+    }
+}
+
+// as the raw byte, which goes through `from_byte` when read back
+#[cfg(feature = "serde")]
+impl serde::Serialize for Flags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.bits())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Flags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u8::deserialize(deserializer).map(Flags::from_byte)
+    }
+}
+
+impl Registers {
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f.bits() & 0xF0])
+    }
+
+    /// The low nibble of F doesn't exist and is dropped
+    pub fn set_af(&mut self, value: u16) {
+        let [a, f] = value.to_be_bytes();
+        self.a = a;
+        self.f = Flags::from_byte(f);
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        [self.b, self.c] = value.to_be_bytes();
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        [self.d, self.e] = value.to_be_bytes();
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        [self.h, self.l] = value.to_be_bytes();
+    }
+
+    /// Overrides registers from a comma separated list of hex assignments,
+    /// e.g. `A=11,F=80,SP=DFFF`
+    pub fn apply_preset(&mut self, preset: &str) -> Result<(), String> {
+        for assignment in preset.split(',').filter(|a| !a.trim().is_empty()) {
+            let (name, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid register assignment: {}", assignment))?;
+            let value = u16::from_str_radix(value.trim(), 16)
+                .map_err(|_| format!("Invalid register value: {}", assignment))?;
+            let byte = || {
+                u8::try_from(value).map_err(|_| format!("Value too big for 8-bit: {}", assignment))
+            };
+            match name.trim().to_ascii_uppercase().as_str() {
+                "A" => self.a = byte()?,
+                "F" => self.f = Flags::from_byte(byte()?),
+                "B" => self.b = byte()?,
+                "C" => self.c = byte()?,
+                "D" => self.d = byte()?,
+                "E" => self.e = byte()?,
+                "H" => self.h = byte()?,
+                "L" => self.l = byte()?,
+                "SP" => self.sp.0 = value,
+                "PC" => self.pc.0 = value,
+                _ => return Err(format!("Unknown register: {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Registers {
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("a", Value::hex(self.a as u64, 2)),
+            ("f", Value::hex(self.f.bits() as u64, 2)),
+            ("b", Value::hex(self.b as u64, 2)),
+            ("c", Value::hex(self.c as u64, 2)),
+            ("d", Value::hex(self.d as u64, 2)),
+            ("e", Value::hex(self.e as u64, 2)),
+            ("h", Value::hex(self.h as u64, 2)),
+            ("l", Value::hex(self.l as u64, 2)),
+            ("sp", Value::hex(self.sp.0 as u64, 4)),
+            ("pc", Value::hex(self.pc.0 as u64, 4)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            a: value.get("a")?.as_u8()?,
+            f: Flags::from_byte(value.get("f")?.as_u8()?),
+            b: value.get("b")?.as_u8()?,
+            c: value.get("c")?.as_u8()?,
+            d: value.get("d")?.as_u8()?,
+            e: value.get("e")?.as_u8()?,
+            h: value.get("h")?.as_u8()?,
+            l: value.get("l")?.as_u8()?,
+            sp: StackPointer(value.get("sp")?.as_u16()?),
+            pc: ProgramCounter(value.get("pc")?.as_u16()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_preset() {
+        let mut registers = Registers {
+            a: 0,
+            f: Flags::empty(),
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            sp: StackPointer(0),
+            pc: ProgramCounter(0),
+        };
+        registers
+            .apply_preset("A=11, f=F0,SP=DFFF,pc=0150")
+            .unwrap();
+        assert_eq!(registers.a, 0x11);
+        assert_eq!(registers.f, Flags::all());
+        assert_eq!(registers.sp.0, 0xDFFF);
+        assert_eq!(registers.pc.0, 0x0150);
+        assert!(registers.apply_preset("A=100").is_err());
+        assert!(registers.apply_preset("X=1").is_err());
+        assert!(registers.apply_preset("A").is_err());
+    }
+
+    #[test]
+    fn test_register_pairs() {
+        let mut registers = Registers {
+            a: 0,
+            f: Flags::empty(),
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            sp: StackPointer(0),
+            pc: ProgramCounter(0),
+        };
+        registers.set_bc(0x1234);
+        registers.set_de(0x5678);
+        registers.set_hl(0x9ABC);
+        assert_eq!((registers.b, registers.c), (0x12, 0x34));
+        assert_eq!((registers.d, registers.e), (0x56, 0x78));
+        assert_eq!((registers.h, registers.l), (0x9A, 0xBC));
+        assert_eq!(registers.bc(), 0x1234);
+        assert_eq!(registers.de(), 0x5678);
+        assert_eq!(registers.hl(), 0x9ABC);
+        registers.set_af(0x12FF);
+        assert_eq!(registers.a, 0x12);
+        assert_eq!(registers.f, Flags::all());
+        assert_eq!(registers.af(), 0x12F0);
+    }
+
+    #[test]
+    fn test_flags_low_nibble_is_zero() {
+        for value in 0..=0xFF {
+            assert_eq!(Flags::from_byte(value).bits(), value & 0xF0);
+        }
+        let mut registers = Registers::from_json(
+            &Value::parse(
+                r#"{"a": "0x01", "f": "0xff", "b": "0x00", "c": "0x13", "d": "0x00",
+                    "e": "0xd8", "h": "0x01", "l": "0x4d", "sp": "0xfffe", "pc": "0x0100"}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(registers.f.bits(), 0xF0);
+        registers.apply_preset("F=0F").unwrap();
+        assert_eq!(registers.f.bits(), 0x00);
+        // bits that sneaked in some other way are still not visible
+        registers.f = Flags::from_bits_retain(0x0F);
+        assert_eq!(registers.af(), 0x0100);
+    }
+}
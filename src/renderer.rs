@@ -0,0 +1,835 @@
+use crate::{
+    frame::{Frame, SCREEN_WIDTH},
+    io::{self, IoRegisters},
+    json::Value,
+    layers::Layers,
+    mmu::VRAM_BANK_SIZE,
+    palette::{CgbPalettes, ColorCorrection, PaletteRam},
+    ppu::Ppu,
+};
+
+mod fifo;
+
+pub(crate) use fifo::FifoLine;
+
+/// The four shades of the DMG LCD, lightest first
+pub const SHADES: [[u8; 3]; 4] = [
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x00, 0x00, 0x00],
+];
+
+// offsets in VRAM of the two tile maps, 32x32 tile indices each
+const LOW_MAP: usize = 0x1800;
+const HIGH_MAP: usize = 0x1C00;
+const MAP_WIDTH: usize = 32;
+// 8x8 pixels, 2 bytes per row
+const TILE_SIZE: usize = 16;
+// tiles 0-127 in signed addressing, the others are shared with 0x8000
+const SIGNED_TILES: usize = 0x1000;
+// WX of a window starting at the left edge of the screen
+const WINDOW_X_OFFSET: usize = 7;
+
+// Y and X in OAM of an object at the top left corner of the screen
+const OBJECT_Y_OFFSET: usize = 16;
+const OBJECT_X_OFFSET: usize = 8;
+const OBJECT_WIDTH: usize = 8;
+// the OAM scan keeps the first ones it finds on the line, the others are
+// not drawn
+const MAX_LINE_OBJECTS: usize = 10;
+// object attributes
+// https://gbdev.io/pandocs/OAM.html#byte-3--attributes-flags
+const BEHIND_BG: u8 = 0x80;
+const Y_FLIP: u8 = 0x40;
+const X_FLIP: u8 = 0x20;
+const OBP1: u8 = 0x10;
+// the CGB ones, the tiles in VRAM bank 1 have their attributes laid out
+// the same, at the offset of their index in bank 0
+// https://gbdev.io/pandocs/Tile_Maps.html#bg-map-attributes-cgb-mode-only
+const BG_PRIORITY: u8 = 0x80;
+const TILE_BANK: u8 = 0x08;
+const CGB_PALETTE: u8 = 0x07;
+
+/// An entry of OAM
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Object {
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    // in OAM, what decides between overlapping objects on CGB unless OPRI
+    // orders them by X
+    index: usize,
+}
+
+impl Object {
+    fn from_oam(oam: &[u8], index: usize) -> Self {
+        let entry = &oam[index * 4..index * 4 + 4];
+        Self {
+            y: entry[0],
+            x: entry[1],
+            tile: entry[2],
+            flags: entry[3],
+            index,
+        }
+    }
+
+    fn to_json(self) -> Value {
+        Value::object([
+            ("y", Value::Number(self.y as u64)),
+            ("x", Value::Number(self.x as u64)),
+            ("tile", Value::hex(self.tile as u64, 2)),
+            ("flags", Value::hex(self.flags as u64, 2)),
+            ("index", Value::Number(self.index as u64)),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            y: value.get("y")?.as_u8()?,
+            x: value.get("x")?.as_u8()?,
+            tile: value.get("tile")?.as_u8()?,
+            flags: value.get("flags")?.as_u8()?,
+            index: value.get("index")?.as_u8()? as usize,
+        })
+    }
+
+    fn on_line(&self, line: usize, height: usize) -> bool {
+        (line + OBJECT_Y_OFFSET).wrapping_sub(self.y as usize) < height
+    }
+
+    // colors of its row on `line`, from left to right, from the bank its
+    // attributes select in CGB mode
+    fn row(&self, vram: &[u8], line: usize, height: usize, cgb: bool) -> [u8; OBJECT_WIDTH] {
+        let mut row = line + OBJECT_Y_OFFSET - self.y as usize;
+        if self.flags & Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        // the bottom half of an 8x16 object is the next tile
+        let tile = if height == 16 {
+            self.tile & 0xFE
+        } else {
+            self.tile
+        };
+        let bank = (cgb && self.flags & TILE_BANK != 0) as usize;
+        let address = bank * VRAM_BANK_SIZE + tile as usize * TILE_SIZE + row * 2;
+        let mut colors = [0; OBJECT_WIDTH];
+        for (i, color) in colors.iter_mut().enumerate() {
+            let bit = if self.flags & X_FLIP != 0 { i } else { 7 - i };
+            *color = (vram[address] >> bit & 1) | (vram[address + 1] >> bit & 1) << 1;
+        }
+        colors
+    }
+}
+
+// the objects the OAM scan selects for `line` in OAM order, or ordered by X
+// with those first in OAM first on a tie. Those off the screen horizontally
+// count too.
+fn line_objects(oam: &[u8], line: usize, height: usize, by_x: bool) -> Vec<Object> {
+    let mut objects: Vec<Object> = (0..oam.len() / 4)
+        .map(|index| Object::from_oam(oam, index))
+        .filter(|object| object.on_line(line, height))
+        .take(MAX_LINE_OBJECTS)
+        .collect();
+    if by_x {
+        objects.sort_by_key(|object| object.x);
+    }
+    objects
+}
+
+fn object_height(lcdc: u8) -> usize {
+    if lcdc & io::OBJ_SIZE != 0 {
+        16
+    } else {
+        8
+    }
+}
+
+/// Draws the lines of the LCD into the frame as the PPU reaches them,
+/// either a line at a time when drawing starts or dot by dot through the
+/// pixel FIFO
+/// https://gbdev.io/pandocs/Tile_Maps.html
+pub struct Renderer {
+    frame: Frame,
+    // shown while the LCD is off
+    backdrop: [u8; 3],
+    // CGB mode, a copy of `Cpu::cgb` set along with it
+    cgb: bool,
+    // OPRI bit 0, a copy of `Compat::priority_by_x` set along with it
+    priority_by_x: bool,
+    // for the CGB colors, a copy of the emulator's
+    color_correction: ColorCorrection,
+    // draw through the pixel FIFO, from the accuracy profile
+    pixel_fifo: bool,
+    // the line the pixel FIFO is drawing, if it is not through yet
+    fifo: Option<FifoLine>,
+    // debugging aid only, not part of the machine state
+    layers: Layers,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self {
+            frame: Frame::new(),
+            backdrop: SHADES[0],
+            cgb: false,
+            priority_by_x: false,
+            color_correction: ColorCorrection::default(),
+            pixel_fifo: false,
+            fifo: None,
+            layers: Layers::default(),
+        }
+    }
+
+    /// The lines drawn so far, the rest is left from the previous frame
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn frame_mut(&mut self) -> &mut Frame {
+        &mut self.frame
+    }
+
+    /// Paints the whole frame `rgb`, and blanks it with it from then on
+    /// when the LCD is turned off
+    pub fn set_backdrop(&mut self, rgb: [u8; 3]) {
+        self.backdrop = rgb;
+        self.blank();
+    }
+
+    /// What the LCD shows while off. The line being drawn is dropped.
+    pub fn blank(&mut self) {
+        self.frame.fill(self.backdrop);
+        self.fifo = None;
+    }
+
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// In CGB mode, overlapping objects are ordered by X coordinate rather
+    /// than by their position in OAM
+    pub fn set_priority_by_x(&mut self, priority_by_x: bool) {
+        self.priority_by_x = priority_by_x;
+    }
+
+    // a DMG always orders them by X
+    fn objects_by_x(&self) -> bool {
+        !self.cgb || self.priority_by_x
+    }
+
+    /// How the CGB colors from palette RAM end up in the frame
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+    }
+
+    pub fn pixel_fifo(&self) -> bool {
+        self.pixel_fifo
+    }
+
+    /// Draws the next lines dot by dot through the pixel FIFO, which makes
+    /// the drawing period as long as the line needs, rather than all at
+    /// once
+    pub fn set_pixel_fifo(&mut self, pixel_fifo: bool) {
+        self.pixel_fifo = pixel_fifo;
+    }
+
+    pub fn layers(&self) -> Layers {
+        self.layers
+    }
+
+    /// Hidden layers are left out of the frame, as if disabled in LCDC. The
+    /// background shows through a hidden window.
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.layers = layers;
+    }
+
+    /// Draws the line the PPU is on from `vram`, `oam`, the LCD registers
+    /// and in CGB mode `palettes`
+    pub fn draw_line(
+        &mut self,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        oam: &[u8],
+        io: &IoRegisters,
+        palettes: &CgbPalettes,
+    ) {
+        let line = ppu.ly() as usize;
+        let background = self.background_line(ppu, vram, io);
+        let objects = self.object_line(line, vram, oam, io);
+        for x in 0..SCREEN_WIDTH {
+            let rgb = self.pixel(io, palettes, background[x], objects[x]);
+            self.frame.set_pixel(x, line, rgb);
+        }
+    }
+
+    // colors and CGB attributes of the background and window on the line,
+    // 0 where hidden
+    fn background_line(
+        &self,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        io: &IoRegisters,
+    ) -> [(u8, u8); SCREEN_WIDTH] {
+        let line = ppu.ly() as usize;
+        ppu.check_window_y(io.read(io::WY));
+        let lcdc = io.read(io::LCDC);
+        let window_x = io.read(io::WX) as usize;
+        let enabled = self.background_enabled(lcdc);
+        // read once for the line, changes during H-blank show on the next
+        let (scroll_x, scroll_y) = (io.read(io::SCX) as usize, io.read(io::SCY) as usize);
+        // a window past the right edge does not count as drawn either
+        let window_line = (enabled
+            && lcdc & io::WINDOW_ENABLE != 0
+            && ppu.window_y_reached()
+            && window_x < SCREEN_WIDTH + WINDOW_X_OFFSET)
+            .then(|| ppu.next_window_line() as usize);
+        let mut pixels = [(0, 0); SCREEN_WIDTH];
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = match window_line {
+                Some(window_line)
+                    if x + WINDOW_X_OFFSET >= window_x && self.layers.contains(Layers::WINDOW) =>
+                {
+                    let x = x + WINDOW_X_OFFSET - window_x;
+                    self.map_pixel(vram, lcdc, window_map(lcdc), x, window_line)
+                }
+                _ if !enabled || !self.layers.contains(Layers::BACKGROUND) => (0, 0),
+                _ => self.map_pixel(
+                    vram,
+                    lcdc,
+                    background_map(lcdc),
+                    x + scroll_x,
+                    line + scroll_y,
+                ),
+            };
+        }
+        pixels
+    }
+
+    // the color and attributes of the object pixel shown at each X of the
+    // line, if any. Where objects overlap the one with the smallest X wins,
+    // the first in OAM on a tie, even when it hides behind the background.
+    // On CGB the first in OAM always wins.
+    fn object_line(
+        &self,
+        line: usize,
+        vram: &[u8],
+        oam: &[u8],
+        io: &IoRegisters,
+    ) -> [Option<(u8, u8)>; SCREEN_WIDTH] {
+        let mut pixels = [None; SCREEN_WIDTH];
+        let lcdc = io.read(io::LCDC);
+        if lcdc & io::OBJ_ENABLE == 0 || !self.layers.contains(Layers::SPRITES) {
+            return pixels;
+        }
+        let height = object_height(lcdc);
+        // the winning objects are drawn last, over the others
+        let objects = line_objects(oam, line, height, self.objects_by_x());
+        for object in objects.iter().rev() {
+            let row = object.row(vram, line, height, self.cgb);
+            for (i, color) in row.into_iter().enumerate() {
+                let x = (object.x as usize + i).wrapping_sub(OBJECT_X_OFFSET);
+                // color 0 is transparent
+                if x < SCREEN_WIDTH && color != 0 {
+                    pixels[x] = Some((color, object.flags));
+                }
+            }
+        }
+        pixels
+    }
+
+    // the background and window show, as the BG_ENABLE bit means on DMG
+    fn background_enabled(&self, lcdc: u8) -> bool {
+        self.cgb || lcdc & io::BG_ENABLE != 0
+    }
+
+    // the RGB color of a pixel with `background` color and attributes and
+    // maybe an object pixel over it, through their palettes
+    fn pixel(
+        &self,
+        io: &IoRegisters,
+        palettes: &CgbPalettes,
+        (background, attributes): (u8, u8),
+        object: Option<(u8, u8)>,
+    ) -> [u8; 3] {
+        let lcdc = io.read(io::LCDC);
+        // on CGB the objects can be put over everything
+        let background_priority = !self.cgb || lcdc & io::BG_ENABLE != 0;
+        let object = object.filter(|&(_, flags)| {
+            !background_priority
+                || background == 0
+                || flags & BEHIND_BG == 0 && attributes & BG_PRIORITY == 0
+        });
+        match object {
+            Some((color, flags)) if self.cgb => self.cgb_color(&palettes.objects, flags, color),
+            Some((color, flags)) => {
+                let palette = if flags & OBP1 != 0 {
+                    io::OBP1
+                } else {
+                    io::OBP0
+                };
+                SHADES[apply_palette(io.read(palette), color) as usize]
+            }
+            None if self.cgb => self.cgb_color(&palettes.background, attributes, background),
+            // a disabled background is white whatever BGP says
+            None if !self.background_enabled(lcdc) => SHADES[0],
+            None => SHADES[apply_palette(io.read(io::BGP), background) as usize],
+        }
+    }
+
+    // color `color` of the palette `attributes` select, as the LCD shows it
+    fn cgb_color(&self, palettes: &PaletteRam, attributes: u8, color: u8) -> [u8; 3] {
+        let palette = (attributes & CGB_PALETTE) as usize;
+        self.color_correction
+            .apply(palettes.color(palette, color as usize))
+    }
+
+    // color (0-3) and CGB attributes of pixel (x, y) of the 256x256 picture
+    // the tile map at `map` makes, wrapping around its edges
+    fn map_pixel(&self, vram: &[u8], lcdc: u8, map: usize, x: usize, y: usize) -> (u8, u8) {
+        let (x, y) = (x % 256, y % 256);
+        let index = map + y / 8 * MAP_WIDTH + x / 8;
+        let attributes = if self.cgb {
+            vram[VRAM_BANK_SIZE + index]
+        } else {
+            0
+        };
+        let (mut column, mut row) = (x % 8, y % 8);
+        if attributes & X_FLIP != 0 {
+            column = 7 - column;
+        }
+        if attributes & Y_FLIP != 0 {
+            row = 7 - row;
+        }
+        let tile = vram[index];
+        let start = if lcdc & io::TILE_DATA != 0 {
+            tile as usize * TILE_SIZE
+        } else {
+            (SIGNED_TILES as isize + tile as i8 as isize * TILE_SIZE as isize) as usize
+        };
+        let bank = (attributes & TILE_BANK != 0) as usize;
+        let address = bank * VRAM_BANK_SIZE + start + row * 2;
+        let bit = 7 - column;
+        let color = (vram[address] >> bit & 1) | (vram[address + 1] >> bit & 1) << 1;
+        (color, attributes)
+    }
+}
+
+// the shade (0-3) `palette` gives `color`, 2 bits per color from the lowest
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    palette >> (color * 2) & 0x03
+}
+
+fn background_map(lcdc: u8) -> usize {
+    if lcdc & io::BG_MAP != 0 {
+        HIGH_MAP
+    } else {
+        LOW_MAP
+    }
+}
+
+fn window_map(lcdc: u8) -> usize {
+    if lcdc & io::WINDOW_MAP != 0 {
+        HIGH_MAP
+    } else {
+        LOW_MAP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        emulator::DOTS_PER_FRAME,
+        mmu::{OAM_SIZE, VRAM_SIZE},
+        palette,
+        ppu::{DOTS_PER_LINE, MAX_DRAWING_DOTS, OAM_SCAN_DOTS},
+    };
+
+    const WHITE: [u8; 3] = SHADES[0];
+    const BLACK: [u8; 3] = SHADES[3];
+
+    // draws the next line the way the PPU does when it gets there
+    fn draw_next_line(renderer: &mut Renderer, ppu: &mut Ppu, vram: &[u8], io: &IoRegisters) {
+        draw_next_line_with_objects(renderer, ppu, vram, &[0x00; OAM_SIZE], io);
+    }
+
+    fn draw_next_line_with_objects(
+        renderer: &mut Renderer,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        oam: &[u8],
+        io: &IoRegisters,
+    ) {
+        draw_next_cgb_line(renderer, ppu, vram, oam, io, &CgbPalettes::new());
+    }
+
+    fn draw_next_cgb_line(
+        renderer: &mut Renderer,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        oam: &[u8],
+        io: &IoRegisters,
+        palettes: &CgbPalettes,
+    ) {
+        ppu.tick(OAM_SCAN_DOTS);
+        renderer.draw_line(ppu, vram, oam, io, palettes);
+        ppu.tick(DOTS_PER_LINE - OAM_SCAN_DOTS);
+    }
+
+    // every color of every palette different, the background ones red and
+    // the object ones blue
+    fn cgb_palettes() -> CgbPalettes {
+        let mut palettes = CgbPalettes::new();
+        palettes.write(palette::BCPS, 0x80);
+        palettes.write(palette::OCPS, 0x80);
+        for i in 0..32u16 {
+            for byte in i.to_le_bytes() {
+                palettes.write(palette::BCPD, byte);
+            }
+            for byte in (i << 10).to_le_bytes() {
+                palettes.write(palette::OCPD, byte);
+            }
+        }
+        palettes
+    }
+
+    fn cgb_rgb(palettes: &PaletteRam, palette: usize, color: usize) -> [u8; 3] {
+        ColorCorrection::Raw.apply(palettes.color(palette, color))
+    }
+
+    #[test]
+    fn test_background_tile_data() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        // black tiles 1 at 0x8010 and 0x80 at 0x8800, only the latter is
+        // reachable with signed indices
+        vram[0x0010..0x0020].fill(0xFF);
+        vram[0x0800..0x0810].fill(0xFF);
+        vram[LOW_MAP] = 0x01;
+        vram[LOW_MAP + 1] = 0x80;
+        let mut io = IoRegisters::new();
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        io.write(io::LCDC, io::LCD_ENABLE | io::BG_ENABLE);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        let frame = renderer.frame();
+        assert_eq!([frame.pixel(7, 0), frame.pixel(8, 0)], [BLACK, BLACK]);
+        assert_eq!([frame.pixel(7, 1), frame.pixel(8, 1)], [WHITE, BLACK]);
+        assert_eq!([frame.pixel(7, 2), frame.pixel(8, 2)], [WHITE, WHITE]);
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        // a black tile in the bottom right corner of the map, on its last
+        // row only
+        vram[0x001E..0x0020].fill(0xFF);
+        vram[LOW_MAP + MAP_WIDTH * MAP_WIDTH - 1] = 0x01;
+        let mut io = IoRegisters::new();
+        io.write(io::SCX, 0xFC);
+        io.write(io::SCY, 0xFF);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        let frame = renderer.frame();
+        assert_eq!([frame.pixel(0, 0), frame.pixel(3, 0)], [BLACK, BLACK]);
+        assert_eq!(frame.pixel(4, 0), WHITE);
+        // wrapped around to the top of the map
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        assert_eq!(renderer.frame().pixel(0, 1), WHITE);
+        io.write(io::SCY, 0xFD);
+        io.write(io::SCX, 0xF8);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        assert_eq!(renderer.frame().pixel(7, 2), BLACK);
+        assert_eq!(renderer.frame().pixel(8, 2), WHITE);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        // the high map is all black tiles, and only the second row of tile
+        // 2 is black
+        vram[0x0010..0x0020].fill(0xFF);
+        vram[0x0022..0x0024].fill(0xFF);
+        vram[HIGH_MAP..HIGH_MAP + MAP_WIDTH * MAP_WIDTH].fill(0x01);
+        vram[HIGH_MAP] = 0x02;
+        let mut io = IoRegisters::new();
+        let lcdc = io::LCD_ENABLE | io::WINDOW_MAP | io::WINDOW_ENABLE | io::TILE_DATA;
+        io.write(io::LCDC, lcdc | io::BG_ENABLE);
+        io.write(io::WY, 2);
+        io.write(io::WX, 80 + WINDOW_X_OFFSET as u8);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        for _ in 0..3 {
+            draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        }
+        let frame = renderer.frame();
+        assert_eq!([frame.pixel(80, 1), frame.pixel(80, 2)], [WHITE, WHITE]);
+        assert_eq!([frame.pixel(79, 2), frame.pixel(88, 2)], [WHITE, BLACK]);
+
+        // hidden for two lines, then it resumes on its second line
+        io.write(io::LCDC, lcdc);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        io.write(io::LCDC, lcdc | io::BG_ENABLE);
+        io.write(io::WX, 0xFF);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        io.write(io::WX, WINDOW_X_OFFSET as u8);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        let frame = renderer.frame();
+        assert_eq!(frame.pixel(80, 3), WHITE);
+        assert_eq!(frame.pixel(80, 4), WHITE);
+        assert_eq!([frame.pixel(0, 5), frame.pixel(8, 5)], [BLACK, BLACK]);
+
+        // and starts over on the next frame, once WY is reached again
+        renderer.set_layers(Layers::BACKGROUND);
+        ppu.tick(DOTS_PER_LINE * 148);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        assert_eq!(renderer.frame().pixel(8, 0), WHITE);
+        renderer.set_layers(Layers::all());
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+        assert_eq!(renderer.frame().pixel(0, 2), WHITE);
+    }
+
+    #[test]
+    fn test_objects() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        // tile 1 is color 1, tile 2 color 3, and tile 3 color 3 on its left
+        // half only
+        vram[0x0010..0x0020].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[0x0020..0x0030].fill(0xFF);
+        vram[0x0030..0x0040].fill(0xF0);
+        // black background behind X 16-23
+        vram[LOW_MAP + 2] = 0x02;
+        let mut oam = vec![0x00; OAM_SIZE];
+        for (index, object) in [
+            [16, 8, 1, 0x00],
+            // smaller X wins, its color 3 is white through OBP1
+            [16, 4, 2, OBP1],
+            [16, 24, 1, BEHIND_BG],
+            [16, 40, 3, X_FLIP],
+            // same X, the first one wins
+            [16, 56, 1, 0x00],
+            [16, 56, 2, 0x00],
+            // its bottom half is tile 3
+            [28, 88, 2, 0x00],
+        ]
+        .iter()
+        .enumerate()
+        {
+            oam[index * 4..index * 4 + 4].copy_from_slice(object);
+        }
+        let mut io = IoRegisters::new();
+        let lcdc = io::LCD_ENABLE | io::TILE_DATA | io::OBJ_ENABLE | io::BG_ENABLE;
+        io.write(io::LCDC, lcdc);
+        io.write(io::OBP0, 0xE4);
+        io.write(io::OBP1, 0x1B);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        draw_next_line_with_objects(&mut renderer, &mut ppu, &vram, &oam, &io);
+        let frame = renderer.frame();
+        let light = SHADES[1];
+        assert_eq!([frame.pixel(3, 0), frame.pixel(4, 0)], [WHITE, light]);
+        assert_eq!([frame.pixel(8, 0), frame.pixel(16, 0)], [WHITE, BLACK]);
+        assert_eq!([frame.pixel(32, 0), frame.pixel(36, 0)], [WHITE, BLACK]);
+        assert_eq!(frame.pixel(48, 0), light);
+
+        ppu.tick(19 * DOTS_PER_LINE);
+        io.write(io::LCDC, lcdc | io::OBJ_SIZE);
+        draw_next_line_with_objects(&mut renderer, &mut ppu, &vram, &oam, &io);
+        assert_eq!(renderer.frame().pixel(80, 20), BLACK);
+        assert_eq!(renderer.frame().pixel(84, 20), WHITE);
+        // hidden along with the layer
+        ppu.tick(DOTS_PER_FRAME as u32 - 21 * DOTS_PER_LINE);
+        renderer.set_layers(Layers::BACKGROUND | Layers::WINDOW);
+        draw_next_line_with_objects(&mut renderer, &mut ppu, &vram, &oam, &io);
+        assert_eq!(renderer.frame().pixel(4, 0), WHITE);
+    }
+
+    #[test]
+    fn test_object_limit() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        vram[0x0010..0x0020].fill(0xFF);
+        let mut oam = vec![0x00; OAM_SIZE];
+        // one ending on the line above, then 10 on the line, the first one
+        // off the screen, that hide the last one
+        oam[..4].copy_from_slice(&[8, 8, 1, 0x00]);
+        oam[4..8].copy_from_slice(&[16, 0, 1, 0x00]);
+        for index in 2..12 {
+            let x = 8 + 16 * (11 - index as u8);
+            oam[index * 4..index * 4 + 4].copy_from_slice(&[16, x, 1, 0x00]);
+        }
+        let mut io = IoRegisters::new();
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA | io::OBJ_ENABLE);
+        io.write(io::OBP0, 0xE4);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        draw_next_line_with_objects(&mut renderer, &mut ppu, &vram, &oam, &io);
+        let frame = renderer.frame();
+        assert_eq!(frame.pixel(0, 0), WHITE);
+        assert!((1..10).all(|i| frame.pixel(16 * i, 0) == BLACK));
+        // the pixel FIFO spends no time on it either
+        let mut fifo = Renderer::new();
+        let mut ppu = Ppu::new();
+        ppu.tick(OAM_SCAN_DOTS);
+        fifo.start_line(&mut ppu, &oam, &io);
+        let palettes = CgbPalettes::new();
+        let mut dots = 1;
+        while !fifo.draw_dot(&mut ppu, &vram, &io, &palettes) {
+            dots += 1;
+        }
+        assert_eq!(fifo.frame().pixel(0, 0), WHITE);
+        assert!(dots <= MAX_DRAWING_DOTS);
+    }
+
+    #[test]
+    fn test_palettes() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        // colors 0 to 3 from left to right
+        vram[0x0010..0x0020].copy_from_slice(&[0x33, 0x0F].repeat(8));
+        vram[LOW_MAP..LOW_MAP + MAP_WIDTH].fill(0x01);
+        let mut oam = vec![0x00; OAM_SIZE];
+        oam[..4].copy_from_slice(&[16, 8, 1, 0x00]);
+        oam[4..8].copy_from_slice(&[16, 16, 1, OBP1]);
+        let mut io = IoRegisters::new();
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA | io::BG_ENABLE);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        for (y, palette) in [0xE4, 0x1B, 0x00].into_iter().enumerate() {
+            io.write(io::BGP, palette);
+            draw_next_line(&mut renderer, &mut ppu, &vram, &io);
+            for color in 0..4 {
+                let shade = SHADES[apply_palette(palette, color) as usize];
+                let x = color as usize * 2;
+                assert_eq!(renderer.frame().pixel(x, y), shade, "{:#04x}", palette);
+            }
+        }
+        // color 0 of the objects is transparent whatever the palette
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA | io::OBJ_ENABLE);
+        io.write(io::OBP0, 0xFF);
+        io.write(io::OBP1, 0x00);
+        draw_next_line_with_objects(&mut renderer, &mut ppu, &vram, &oam, &io);
+        let frame = renderer.frame();
+        assert_eq!([frame.pixel(0, 3), frame.pixel(2, 3)], [WHITE, BLACK]);
+        assert_eq!([frame.pixel(8, 3), frame.pixel(10, 3)], [WHITE, WHITE]);
+    }
+
+    #[test]
+    fn test_cgb_bg_enable() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        vram[0x0010..0x0020].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[0x0020..0x0030].fill(0xFF);
+        vram[LOW_MAP..LOW_MAP + 2].copy_from_slice(&[0x02, 0x02]);
+        let mut oam = vec![0x00; OAM_SIZE];
+        oam[..4].copy_from_slice(&[16, 8, 1, BEHIND_BG]);
+        let mut io = IoRegisters::new();
+        let palettes = cgb_palettes();
+        let background = cgb_rgb(&palettes.background, 0, 3);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        renderer.set_cgb(true);
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA | io::OBJ_ENABLE);
+        draw_next_cgb_line(&mut renderer, &mut ppu, &vram, &oam, &io, &palettes);
+        // the background stays but loses its priority
+        let frame = renderer.frame();
+        assert_eq!(frame.pixel(0, 0), cgb_rgb(&palettes.objects, 0, 1));
+        assert_eq!(frame.pixel(8, 0), background);
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA | io::BG_ENABLE);
+        draw_next_cgb_line(&mut renderer, &mut ppu, &vram, &oam, &io, &palettes);
+        assert_eq!(renderer.frame().pixel(0, 1), background);
+        // no objects either without OBJ_ENABLE
+        oam[..4].copy_from_slice(&[17, 8, 1, 0x00]);
+        draw_next_cgb_line(&mut renderer, &mut ppu, &vram, &oam, &io, &palettes);
+        assert_eq!(renderer.frame().pixel(0, 2), background);
+    }
+
+    #[test]
+    fn test_cgb_attributes() {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        // tile 1 is color 1 on its left half, on the right half of its last
+        // row, and color 3 in bank 1. Tile 2 is color 2, and color 1 in
+        // bank 1.
+        vram[0x0010..0x001E].copy_from_slice(&[0xF0, 0x00].repeat(7));
+        vram[0x001E] = 0x0F;
+        vram[VRAM_BANK_SIZE + 0x0010..VRAM_BANK_SIZE + 0x0020].fill(0xFF);
+        vram[0x0020..0x0030].copy_from_slice(&[0x00, 0xFF].repeat(8));
+        vram[VRAM_BANK_SIZE + 0x0020..VRAM_BANK_SIZE + 0x0030]
+            .copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[LOW_MAP..LOW_MAP + 8].fill(0x01);
+        let attributes = VRAM_BANK_SIZE + LOW_MAP;
+        vram[attributes..attributes + 5].copy_from_slice(&[
+            0x00,
+            X_FLIP | 0x02,
+            TILE_BANK | 0x05,
+            BG_PRIORITY,
+            Y_FLIP,
+        ]);
+        let mut oam = vec![0x00; OAM_SIZE];
+        for (index, object) in [
+            [16, 32, 2, 0x00],
+            [16, 48, 2, TILE_BANK | 0x03],
+            // the first in OAM wins whatever their X
+            [16, 60, 2, 0x01],
+            [16, 56, 2, 0x04],
+        ]
+        .iter()
+        .enumerate()
+        {
+            oam[index * 4..index * 4 + 4].copy_from_slice(object);
+        }
+        let mut io = IoRegisters::new();
+        io.write(
+            io::LCDC,
+            io::LCD_ENABLE | io::TILE_DATA | io::OBJ_ENABLE | io::BG_ENABLE,
+        );
+        let palettes = cgb_palettes();
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        renderer.set_cgb(true);
+        draw_next_cgb_line(&mut renderer, &mut ppu, &vram, &oam, &io, &palettes);
+        let frame = renderer.frame();
+        let background = |palette, color| cgb_rgb(&palettes.background, palette, color);
+        let object = |palette, color| cgb_rgb(&palettes.objects, palette, color);
+        assert_eq!(
+            [frame.pixel(0, 0), frame.pixel(4, 0)],
+            [background(0, 1), background(0, 0)]
+        );
+        assert_eq!(
+            [frame.pixel(8, 0), frame.pixel(12, 0)],
+            [background(2, 0), background(2, 1)]
+        );
+        assert_eq!(frame.pixel(16, 0), background(5, 3));
+        assert_eq!(
+            [frame.pixel(24, 0), frame.pixel(28, 0)],
+            [background(0, 1), object(0, 2)]
+        );
+        assert_eq!(
+            [frame.pixel(32, 0), frame.pixel(36, 0)],
+            [background(0, 0), background(0, 1)]
+        );
+        assert_eq!(frame.pixel(40, 0), object(3, 1));
+        assert_eq!(
+            [frame.pixel(48, 0), frame.pixel(52, 0)],
+            [object(4, 2), object(1, 2)]
+        );
+
+        // the pixel FIFO draws the same
+        let mut fifo = Renderer::new();
+        fifo.set_cgb(true);
+        let mut ppu = Ppu::new();
+        ppu.tick(OAM_SCAN_DOTS);
+        fifo.start_line(&mut ppu, &oam, &io);
+        while !fifo.draw_dot(&mut ppu, &vram, &io, &palettes) {}
+        for x in 0..SCREEN_WIDTH {
+            assert_eq!(
+                fifo.frame().pixel(x, 0),
+                renderer.frame().pixel(x, 0),
+                "{}",
+                x
+            );
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use std::collections::VecDeque;
+
+use super::{
+    background_map, line_objects, object_height, window_map, Object, Renderer, OBJECT_WIDTH,
+    OBJECT_X_OFFSET, WINDOW_X_OFFSET,
+};
+use crate::{
+    frame::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    io::{self, IoRegisters},
+    json::Value,
+    layers::Layers,
+    palette::CgbPalettes,
+    ppu::Ppu,
+};
+
+// the fetcher reads the tile index, then the two bytes of its row, in 2
+// dots each
+const TILE_FETCH_DOTS: i32 = 6;
+// the first tile of each line is fetched twice
+const FIRST_FETCH_DOTS: i32 = -TILE_FETCH_DOTS;
+// an object fetch stalls the FIFO for 6 dots, the last dot of the
+// background fetch it waited for included
+const OBJECT_FETCH_DOTS: u32 = 6;
+
+/// A line being drawn dot by dot. The fetcher reads the background or the
+/// window 8 pixels at a time into the background FIFO, which shifts one
+/// pixel out to the LCD each dot, mixed with the object FIFO. The pixels of
+/// the first tile left of SCX are thrown away, the window starts the fetch
+/// over, and each object on the line stops the FIFO while it is fetched.
+/// https://gbdev.io/pandocs/pixel_fifo.html
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct FifoLine {
+    line: usize,
+    // pixels sent to the LCD
+    x: usize,
+    // pixels still to throw away before the first one is sent
+    discard: usize,
+    // dots into the fetch of the next tile, it is pushed once complete and
+    // the background FIFO is empty
+    fetch: i32,
+    // tiles fetched on the line, since the window started if it did
+    column: usize,
+    // the line of the window, once it started
+    window_line: Option<usize>,
+    // colors and CGB attributes
+    background: VecDeque<(u8, u8)>,
+    // the object pixels over the next background pixels if any, their
+    // colors, attributes and indices in OAM
+    objects: VecDeque<Option<(u8, u8, usize)>>,
+    // the objects of the line not fetched yet, ordered by X
+    pending: VecDeque<Object>,
+    // as the OAM scan saw it, the rows of the objects it picked are read
+    // with it even if OBJ_SIZE changes during the line
+    object_height: usize,
+    // dots left of the object fetch in progress
+    object_fetch: u32,
+}
+
+impl FifoLine {
+    pub(crate) fn to_json(&self) -> Value {
+        let background: Vec<u8> = self
+            .background
+            .iter()
+            .flat_map(|&(color, attributes)| [color, attributes])
+            .collect();
+        let objects = self.objects.iter().map(|pixel| match *pixel {
+            Some((color, flags, index)) => Value::object([
+                ("color", Value::Number(color as u64)),
+                ("flags", Value::hex(flags as u64, 2)),
+                ("index", Value::Number(index as u64)),
+            ]),
+            None => Value::Null,
+        });
+        Value::object([
+            ("line", Value::Number(self.line as u64)),
+            ("x", Value::Number(self.x as u64)),
+            ("discard", Value::Number(self.discard as u64)),
+            // counted from the start of the first fetch, which is negative
+            (
+                "fetch",
+                Value::Number((self.fetch - FIRST_FETCH_DOTS) as u64),
+            ),
+            ("column", Value::Number(self.column as u64)),
+            (
+                "window_line",
+                self.window_line
+                    .map_or(Value::Null, |line| Value::Number(line as u64)),
+            ),
+            ("background", Value::bytes(&background)),
+            ("objects", Value::Array(objects.collect())),
+            (
+                "pending",
+                Value::Array(self.pending.iter().map(|object| object.to_json()).collect()),
+            ),
+            ("object_height", Value::Number(self.object_height as u64)),
+            ("object_fetch", Value::Number(self.object_fetch as u64)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        let line = value.get("line")?.as_u8()? as usize;
+        let x = value.get("x")?.as_u8()? as usize;
+        if line >= SCREEN_HEIGHT || x >= SCREEN_WIDTH {
+            return Err(format!("Invalid FIFO position: {}, {}", x, line));
+        }
+        let list = |name: &str| match value.get(name)? {
+            Value::Array(items) => Ok(items),
+            _ => Err(format!("Expected an array of {}", name)),
+        };
+        let objects = list("objects")?
+            .iter()
+            .map(|pixel| match pixel {
+                Value::Null => Ok(None),
+                pixel => Ok(Some((
+                    pixel.get("color")?.as_u8()? & 0x03,
+                    pixel.get("flags")?.as_u8()?,
+                    pixel.get("index")?.as_u8()? as usize,
+                ))),
+            })
+            .collect::<Result<_, String>>()?;
+        let pending = list("pending")?
+            .iter()
+            .map(Object::from_json)
+            .collect::<Result<_, String>>()?;
+        let object_height = value.get("object_height")?.as_u8()? as usize;
+        if object_height != 8 && object_height != 16 {
+            return Err(format!("Invalid object height: {}", object_height));
+        }
+        let window_line = match value.get("window_line")? {
+            Value::Null => None,
+            line => Some(line.as_u8()? as usize),
+        };
+        Ok(Self {
+            line,
+            x,
+            discard: value.get("discard")?.as_u8()? as usize,
+            fetch: value.get("fetch")?.as_u8()? as i32 + FIRST_FETCH_DOTS,
+            column: value.get("column")?.as_u8()? as usize,
+            window_line,
+            background: value
+                .get("background")?
+                .as_bytes()?
+                .chunks_exact(2)
+                .map(|pixel| (pixel[0] & 0x03, pixel[1]))
+                .collect(),
+            objects,
+            pending,
+            object_height,
+            object_fetch: value.get("object_fetch")?.as_u32()?,
+        })
+    }
+}
+
+impl Renderer {
+    /// Starts drawing the line the PPU is on through the pixel FIFO, after
+    /// the OAM scan found its objects
+    pub fn start_line(&mut self, ppu: &mut Ppu, oam: &[u8], io: &IoRegisters) {
+        ppu.check_window_y(io.read(io::WY));
+        let line = ppu.ly() as usize;
+        let height = object_height(io.read(io::LCDC));
+        self.fifo = Some(FifoLine {
+            line,
+            x: 0,
+            discard: io.read(io::SCX) as usize % 8,
+            fetch: FIRST_FETCH_DOTS,
+            column: 0,
+            window_line: None,
+            background: VecDeque::new(),
+            objects: VecDeque::new(),
+            // fetched as the X of each is reached, whatever their priority.
+            // The ones past the right edge are never reached.
+            pending: line_objects(oam, line, height, true)
+                .into_iter()
+                .filter(|object| (object.x as usize) < SCREEN_WIDTH + OBJECT_X_OFFSET)
+                .collect(),
+            object_height: height,
+            object_fetch: 0,
+        });
+    }
+
+    /// A line is being drawn through the pixel FIFO
+    pub fn drawing(&self) -> bool {
+        self.fifo.is_some()
+    }
+
+    /// The line being drawn through the pixel FIFO, for savestates
+    pub(crate) fn fifo_line(&self) -> Option<&FifoLine> {
+        self.fifo.as_ref()
+    }
+
+    /// Resumes drawing a line taken from `fifo_line`
+    pub(crate) fn set_fifo_line(&mut self, fifo: Option<FifoLine>) {
+        self.fifo = fifo;
+    }
+
+    /// Runs the pixel FIFO for a dot, with the registers as they are now.
+    /// Returns true on the dot the last pixel of the line is sent.
+    pub fn draw_dot(
+        &mut self,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        io: &IoRegisters,
+        palettes: &CgbPalettes,
+    ) -> bool {
+        let Some(mut fifo) = self.fifo.take() else {
+            return false;
+        };
+        self.run_dot(&mut fifo, ppu, vram, io, palettes);
+        if fifo.x < SCREEN_WIDTH {
+            self.fifo = Some(fifo);
+            return false;
+        }
+        true
+    }
+
+    /// Sends what is left of the line at once, when drawing had to end
+    /// before the FIFO was through
+    pub fn finish_line(
+        &mut self,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        io: &IoRegisters,
+        palettes: &CgbPalettes,
+    ) {
+        while self.drawing() {
+            self.draw_dot(ppu, vram, io, palettes);
+        }
+    }
+
+    fn run_dot(
+        &mut self,
+        fifo: &mut FifoLine,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        io: &IoRegisters,
+        palettes: &CgbPalettes,
+    ) {
+        let lcdc = io.read(io::LCDC);
+        if fifo.object_fetch > 0 {
+            fifo.object_fetch -= 1;
+            if fifo.object_fetch == 0 {
+                self.push_object(fifo, vram);
+            }
+            return;
+        }
+        self.check_window(fifo, ppu, io, lcdc);
+        if fifo.fetch < TILE_FETCH_DOTS {
+            fifo.fetch += 1;
+        } else if fifo.background.is_empty() {
+            let colors = self.fetch_tile(fifo, vram, io, lcdc);
+            fifo.background.extend(colors);
+            fifo.column += 1;
+            // pushing takes the first dot of the next fetch
+            fifo.fetch = 1;
+        }
+
+        // objects are fetched once the background fetcher is done with a
+        // tile and has pushed one, which they wait for
+        let objects_enabled = lcdc & io::OBJ_ENABLE != 0;
+        while !objects_enabled && fifo.discard == 0 && object_reached(fifo) {
+            fifo.pending.pop_front();
+        }
+        if fifo.discard == 0 && object_reached(fifo) {
+            if fifo.fetch >= TILE_FETCH_DOTS && !fifo.background.is_empty() {
+                fifo.object_fetch = OBJECT_FETCH_DOTS - 1;
+            }
+            return;
+        }
+
+        let Some((color, attributes)) = fifo.background.pop_front() else {
+            return;
+        };
+        if fifo.discard > 0 {
+            fifo.discard -= 1;
+            return;
+        }
+        let object = fifo.objects.pop_front().flatten();
+        let object = object.map(|(color, flags, _)| (color, flags));
+        let background = if self.background_enabled(lcdc) {
+            (color, attributes)
+        } else {
+            (0, 0)
+        };
+        let rgb = self.pixel(io, palettes, background, object);
+        self.frame.set_pixel(fifo.x, fifo.line, rgb);
+        fifo.x += 1;
+    }
+
+    // the fetcher switches to the window as the pixels reach WX, throwing
+    // away what it fetched of the background
+    fn check_window(&self, fifo: &mut FifoLine, ppu: &mut Ppu, io: &IoRegisters, lcdc: u8) {
+        let window_x = io.read(io::WX) as usize;
+        if fifo.window_line.is_some()
+            || !self.background_enabled(lcdc)
+            || lcdc & io::WINDOW_ENABLE == 0
+            || !ppu.window_y_reached()
+            || fifo.x + WINDOW_X_OFFSET < window_x
+        {
+            return;
+        }
+        fifo.window_line = Some(ppu.next_window_line() as usize);
+        if !self.layers.contains(Layers::WINDOW) {
+            return;
+        }
+        fifo.background.clear();
+        fifo.column = 0;
+        fifo.fetch = fifo.fetch.min(0);
+        // a WX below 7 hides the left of the window
+        fifo.discard = WINDOW_X_OFFSET.saturating_sub(window_x);
+    }
+
+    // colors of the next 8 pixels of the background or the window, SCY and
+    // the tile map are read as each tile is fetched
+    fn fetch_tile(
+        &self,
+        fifo: &FifoLine,
+        vram: &[u8],
+        io: &IoRegisters,
+        lcdc: u8,
+    ) -> [(u8, u8); 8] {
+        let shown = self.layers.contains(Layers::WINDOW) && fifo.window_line.is_some()
+            || self.layers.contains(Layers::BACKGROUND);
+        let (map, x, y) = match fifo.window_line {
+            Some(window_line) if self.layers.contains(Layers::WINDOW) => {
+                (window_map(lcdc), fifo.column * 8, window_line)
+            }
+            _ => (
+                background_map(lcdc),
+                io.read(io::SCX) as usize / 8 * 8 + fifo.column * 8,
+                fifo.line + io.read(io::SCY) as usize,
+            ),
+        };
+        let mut pixels = [(0, 0); 8];
+        if shown {
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = self.map_pixel(vram, lcdc, map, x + i, y);
+            }
+        }
+        pixels
+    }
+
+    // mixes the row of the next object into the object FIFO, under the
+    // pixels of the objects already there, or over those later in OAM when
+    // they are not ordered by X
+    fn push_object(&self, fifo: &mut FifoLine, vram: &[u8]) {
+        let Some(object) = fifo.pending.pop_front() else {
+            return;
+        };
+        if !self.layers.contains(Layers::SPRITES) {
+            return;
+        }
+        let colors = object.row(vram, fifo.line, fifo.object_height, self.cgb);
+        // the part of an object left of the screen is never shown
+        let hidden = (fifo.x + OBJECT_X_OFFSET).saturating_sub(object.x as usize);
+        fifo.objects
+            .resize(fifo.objects.len().max(OBJECT_WIDTH), None);
+        for (i, &color) in colors.iter().skip(hidden).enumerate() {
+            let over = match fifo.objects[i] {
+                Some((_, _, index)) => !self.objects_by_x() && object.index < index,
+                None => true,
+            };
+            if color != 0 && over {
+                fifo.objects[i] = Some((color, object.flags, object.index));
+            }
+        }
+    }
+}
+
+// the next object starts at the pixel about to be sent, or left of it
+fn object_reached(fifo: &FifoLine) -> bool {
+    fifo.pending
+        .front()
+        .is_some_and(|object| object.x as usize <= fifo.x + OBJECT_X_OFFSET)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mmu::{OAM_SIZE, VRAM_SIZE},
+        ppu::{DOTS_PER_LINE, DRAWING_DOTS, OAM_SCAN_DOTS},
+        renderer::{HIGH_MAP, LOW_MAP, MAP_WIDTH, OBP1, SHADES, X_FLIP, Y_FLIP},
+    };
+
+    // draws the next line through the FIFO, returning how long it took
+    fn draw_next_line(
+        renderer: &mut Renderer,
+        ppu: &mut Ppu,
+        vram: &[u8],
+        oam: &[u8],
+        io: &IoRegisters,
+    ) -> u32 {
+        ppu.tick(OAM_SCAN_DOTS);
+        renderer.start_line(ppu, oam, io);
+        let mut dots = 1;
+        while !renderer.draw_dot(ppu, vram, io, &CgbPalettes::new()) {
+            dots += 1;
+        }
+        ppu.tick(DOTS_PER_LINE - OAM_SCAN_DOTS);
+        dots
+    }
+
+    fn scene() -> (Vec<u8>, Vec<u8>, IoRegisters) {
+        let mut vram = vec![0x00; VRAM_SIZE];
+        vram[0x0010..0x0020].copy_from_slice(&[0x33, 0x0F].repeat(8));
+        vram[0x0020..0x0030].fill(0xF0);
+        for (i, tile) in vram[LOW_MAP..LOW_MAP + MAP_WIDTH * MAP_WIDTH]
+            .iter_mut()
+            .enumerate()
+        {
+            *tile = (i % 3) as u8;
+        }
+        vram[HIGH_MAP..HIGH_MAP + MAP_WIDTH * MAP_WIDTH].fill(0x02);
+        let mut oam = vec![0x00; OAM_SIZE];
+        for (index, object) in [
+            [16, 3, 2, 0x00],
+            [16, 20, 1, OBP1],
+            [16, 24, 2, X_FLIP],
+            [17, 60, 1, 0x00],
+            [16, 100, 2, 0x00],
+        ]
+        .iter()
+        .enumerate()
+        {
+            oam[index * 4..index * 4 + 4].copy_from_slice(object);
+        }
+        let mut io = IoRegisters::new();
+        io.write(
+            io::LCDC,
+            io::LCD_ENABLE
+                | io::WINDOW_MAP
+                | io::WINDOW_ENABLE
+                | io::TILE_DATA
+                | io::OBJ_ENABLE
+                | io::BG_ENABLE,
+        );
+        io.write(io::BGP, 0xE4);
+        io.write(io::OBP0, 0xD2);
+        io.write(io::OBP1, 0x1B);
+        io.write(io::SCX, 13);
+        io.write(io::SCY, 5);
+        io.write(io::WY, 1);
+        io.write(io::WX, 90);
+        (vram, oam, io)
+    }
+
+    #[test]
+    fn test_same_picture_as_scanlines() {
+        let (vram, oam, io) = scene();
+        let (mut scanlines, mut ppu) = (Renderer::new(), Ppu::new());
+        for _ in 0..3 {
+            ppu.tick(OAM_SCAN_DOTS);
+            scanlines.draw_line(&mut ppu, &vram, &oam, &io, &CgbPalettes::new());
+            ppu.tick(DOTS_PER_LINE - OAM_SCAN_DOTS);
+        }
+        let (mut fifo, mut ppu) = (Renderer::new(), Ppu::new());
+        for _ in 0..3 {
+            draw_next_line(&mut fifo, &mut ppu, &vram, &oam, &io);
+        }
+        for y in 0..3 {
+            for x in 0..SCREEN_WIDTH {
+                assert_eq!(
+                    fifo.frame().pixel(x, y),
+                    scanlines.frame().pixel(x, y),
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_drawing_length() {
+        let (vram, mut oam, mut io) = scene();
+        io.write(io::LCDC, io::LCD_ENABLE | io::BG_ENABLE);
+        io.write(io::SCX, 0);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        let draw = |renderer: &mut Renderer, ppu: &mut Ppu, oam: &[u8], io: &IoRegisters| {
+            draw_next_line(renderer, ppu, &vram, oam, io)
+        };
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS);
+        // the fine scroll throws pixels away
+        io.write(io::SCX, 3);
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS + 3);
+        // the window starts the fetch over
+        io.write(io::SCX, 0);
+        io.write(io::LCDC, io::LCD_ENABLE | io::WINDOW_ENABLE | io::BG_ENABLE);
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS + 6);
+
+        // objects take 6 to 11 dots, depending on where the background
+        // fetch is
+        io.write(io::LCDC, io::LCD_ENABLE | io::OBJ_ENABLE | io::BG_ENABLE);
+        oam.fill(0x00);
+        oam[..4].copy_from_slice(&[19, 8, 0, 0x00]);
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS + 11);
+        oam[1] = 15;
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS + 6);
+        oam[4..8].copy_from_slice(&[19, 15, 0, 0x00]);
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS + 12);
+        // not without OBJ_ENABLE
+        io.write(io::LCDC, io::LCD_ENABLE | io::BG_ENABLE);
+        assert_eq!(draw(&mut renderer, &mut ppu, &oam, &io), DRAWING_DOTS);
+    }
+
+    #[test]
+    fn test_registers_read_mid_line() {
+        let (vram, oam, mut io) = scene();
+        io.write(io::LCDC, io::LCD_ENABLE | io::TILE_DATA | io::BG_ENABLE);
+        io.write(io::SCX, 0);
+        io.write(io::SCY, 0);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        let palettes = CgbPalettes::new();
+        ppu.tick(OAM_SCAN_DOTS);
+        renderer.start_line(&mut ppu, &oam, &io);
+        for _ in 0..DRAWING_DOTS / 2 {
+            renderer.draw_dot(&mut ppu, &vram, &io, &palettes);
+        }
+        io.write(io::BGP, 0x00);
+        while !renderer.draw_dot(&mut ppu, &vram, &io, &palettes) {}
+        // tile 1 has color 3 on its 2 right pixels
+        let frame = renderer.frame();
+        assert_eq!(frame.pixel(14, 0), SHADES[3]);
+        assert_eq!(frame.pixel(158, 0), SHADES[0]);
+    }
+
+    #[test]
+    fn test_object_size_changed_mid_line() {
+        let (mut vram, mut oam, mut io) = scene();
+        // only row 5 of tile 2 is set, the bottom half of the flipped
+        // object on line 10
+        vram[0x0020..0x0040].fill(0x00);
+        vram[0x0020 + 5 * 2..0x0020 + 5 * 2 + 2].fill(0xFF);
+        oam.fill(0x00);
+        oam[..4].copy_from_slice(&[16, 100, 2, Y_FLIP]);
+        let lcdc = io::LCD_ENABLE | io::OBJ_ENABLE | io::BG_ENABLE;
+        io.write(io::LCDC, lcdc | io::OBJ_SIZE);
+        io.write(io::SCX, 0);
+        let (mut renderer, mut ppu) = (Renderer::new(), Ppu::new());
+        for _ in 0..10 {
+            draw_next_line(&mut renderer, &mut ppu, &vram, &oam, &io);
+        }
+        let palettes = CgbPalettes::new();
+        ppu.tick(OAM_SCAN_DOTS);
+        renderer.start_line(&mut ppu, &oam, &io);
+        for _ in 0..DRAWING_DOTS / 2 {
+            renderer.draw_dot(&mut ppu, &vram, &io, &palettes);
+        }
+        // the object is fetched at the height the OAM scan used
+        io.write(io::LCDC, lcdc);
+        while !renderer.draw_dot(&mut ppu, &vram, &io, &palettes) {}
+        let frame = renderer.frame();
+        assert_eq!(frame.pixel(92, 10), SHADES[3]);
+        assert_eq!(frame.pixel(91, 10), SHADES[0]);
+    }
+}
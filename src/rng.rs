@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+pub use crate::io::{DIV, TIMA};
+use crate::ppu::LY;
+
+/// Registers games commonly seed their random number generators from, the
+/// reads the RNG hook gets to see
+pub const SOURCES: [u16; 3] = [DIV, TIMA, LY];
+
+/// Observes or overrides what the CPU reads from the RNG sources, for
+/// reproducible luck manipulation without patching the ROM.
+///
+/// Only reads made by the running program go through the hook, debugger
+/// reads see the hardware value.
+pub trait RngHook: Send {
+    /// Called when the program reads `address` during `frame` and would get
+    /// `value`, returns what it gets instead
+    fn read(&mut self, frame: u64, address: u16, value: u8) -> u8;
+}
+
+impl<F: FnMut(u64, u16, u8) -> u8 + Send> RngHook for F {
+    fn read(&mut self, frame: u64, address: u16, value: u8) -> u8 {
+        self(frame, address, value)
+    }
+}
+
+/// Replaces the RNG sources with fixed values on chosen frames and leaves
+/// them alone otherwise
+#[derive(Clone, Debug, Default)]
+pub struct Overrides(HashMap<(u64, u16), u8>);
+
+impl Overrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes every read of `address` during `frame` return `value`
+    pub fn set(&mut self, frame: u64, address: u16, value: u8) {
+        self.0.insert((frame, address), value);
+    }
+}
+
+impl RngHook for Overrides {
+    fn read(&mut self, frame: u64, address: u16, value: u8) -> u8 {
+        self.0.get(&(frame, address)).copied().unwrap_or(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let mut overrides = Overrides::new();
+        overrides.set(2, DIV, 0x42);
+        assert_eq!(overrides.read(2, DIV, 0x10), 0x42);
+        assert_eq!(overrides.read(1, DIV, 0x10), 0x10);
+        assert_eq!(overrides.read(2, LY, 0x90), 0x90);
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+
+use crate::{cpu::CpuError, emulator::Emulator, savestate::Savestate};
+
+/// Hides input latency by showing the frame the game would draw a few frames
+/// from now, assuming the buttons stay as they are.
+///
+/// Every frame emulated ahead is snapshotted. As long as the input doesn't
+/// change the prediction holds and only one frame is emulated per call; when
+/// it does, the machine is rolled back to the first predicted frame and
+/// re-run with the new input. The snapshots include the mapper state, so a
+/// cartridge clock or EEPROM rolls back with the rest. Side effects that
+/// leave the machine, like serial transfers, can happen more than once.
+pub struct RunAhead {
+    frames: usize,
+    input: Option<u8>,
+    // taken at the start of each frame emulated ahead, oldest first
+    snapshots: VecDeque<Savestate>,
+}
+
+impl RunAhead {
+    /// Runs `frames` frames ahead, 0 disables run-ahead
+    pub fn new(frames: usize) -> Self {
+        Self {
+            frames,
+            input: None,
+            snapshots: VecDeque::with_capacity(frames + 1),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Emulates the next frame with `input` held, leaving `emulator` showing
+    /// the frame `frames` later
+    pub fn run_frame(&mut self, emulator: &mut Emulator, input: u8) -> Result<(), CpuError> {
+        if self.frames == 0 {
+            emulator.cpu.bus.joypad.set_state(input);
+            return emulator.run_frame();
+        }
+        if self.input == Some(input) {
+            self.snapshots.push_back(emulator.save_state());
+            emulator.run_frame()?;
+        } else {
+            // the frames ahead were predicted with the old input
+            if let Some(start) = self.snapshots.front() {
+                emulator.load_state(start);
+            }
+            self.snapshots.clear();
+            self.input = Some(input);
+            emulator.cpu.bus.joypad.set_state(input);
+            while self.snapshots.len() <= self.frames {
+                self.snapshots.push_back(emulator.save_state());
+                emulator.run_frame()?;
+            }
+        }
+        // the oldest snapshot is the frame that was just confirmed
+        while self.snapshots.len() > self.frames {
+            self.snapshots.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cartdrige::Mbc3, joypad::Button};
+
+    // Counts in B the loop iterations run while Right is held, on a
+    // cartridge whose clock must not count the rolled back frames twice
+    fn emulator() -> Emulator {
+        let program = [
+            0x3E, 0x20, // LD A,0x20 (select the directions)
+            0xE0, 0x00, // LDH (P1),A
+            0xF0, 0x00, // loop: LDH A,(P1)
+            0xE6, 0x01, // AND A,0x01
+            0x20, 0x01, // JR NZ,+1
+            0x05, // DEC B
+            0x18, 0xF7, // JR loop
+        ];
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        Emulator::new(Box::new(Mbc3::new(rom, 0, true)))
+    }
+
+    // State after `inputs`, with the last one held for `ahead` more frames
+    fn expected_hash(inputs: &[u8], ahead: usize) -> u64 {
+        let mut emulator = emulator();
+        let last = *inputs.last().unwrap();
+        for &input in inputs.iter().chain(std::iter::repeat_n(&last, ahead)) {
+            emulator.cpu.bus.joypad.set_state(input);
+            emulator.run_frame().unwrap();
+        }
+        emulator.state_hash()
+    }
+
+    #[test]
+    fn test_run_ahead_matches_prediction() {
+        let right = Button::Right.mask();
+        let inputs = [0, 0, right, right, right, 0, right, 0, 0];
+        for frames in 0..3 {
+            let mut emulator = emulator();
+            let mut run_ahead = RunAhead::new(frames);
+            for i in 0..inputs.len() {
+                run_ahead.run_frame(&mut emulator, inputs[i]).unwrap();
+                assert_eq!(
+                    emulator.state_hash(),
+                    expected_hash(&inputs[..=i], frames),
+                    "{} frames ahead, frame {}",
+                    frames,
+                    i
+                );
+                assert_eq!(emulator.frame_count(), (i + 1 + frames) as u64);
+            }
+        }
+    }
+}
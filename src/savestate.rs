@@ -0,0 +1,74 @@
+use crate::{cpu::CpuState, frame::Frame, json::Value, persistence::Storage};
+
+/// In-memory snapshot of the whole machine, cheap enough to take every frame
+#[derive(Clone, Debug)]
+pub struct Savestate {
+    pub(crate) cpu: CpuState,
+    pub(crate) frame: Frame,
+    pub(crate) frame_count: u64,
+}
+
+impl Savestate {
+    /// Frame the machine was on when the snapshot was taken
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Pretty printed JSON, for debugging and for expected states in tests.
+    /// Registers are written in hex and memory as rows of hex bytes.
+    pub fn to_json(&self) -> String {
+        let state = Value::object([
+            ("frame_count", Value::Number(self.frame_count)),
+            ("cpu", self.cpu.to_json()),
+            ("frame", Value::bytes(&self.frame.pixels)),
+        ]);
+        format!("{}\n", state)
+    }
+
+    /// Reads a state written by `to_json`
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let state = Value::parse(json)?;
+        let mut frame = Frame::new();
+        state.get("frame")?.read_bytes(&mut frame.pixels)?;
+        Ok(Self {
+            cpu: CpuState::from_json(state.get("cpu")?)?,
+            frame,
+            frame_count: state.get("frame_count")?.as_u64()?,
+        })
+    }
+
+    /// Writes the state to `storage` as JSON under `key`
+    pub fn store(&self, storage: &mut dyn Storage, key: &str) -> Result<(), String> {
+        storage
+            .store(key, self.to_json().as_bytes())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Reads a state written by `store`, `None` if there is none
+    pub fn load(storage: &dyn Storage, key: &str) -> Result<Option<Self>, String> {
+        let Some(data) = storage.load(key).map_err(|e| e.to_string())? else {
+            return Ok(None);
+        };
+        let json = String::from_utf8(data).map_err(|e| e.to_string())?;
+        Self::from_json(&json).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cartdrige::RomOnly, emulator::Emulator, persistence::MemoryStorage};
+
+    #[test]
+    fn test_savestate_storage() {
+        let emulator = Emulator::new(Box::new(RomOnly(vec![0x00; 0x8000])));
+        let mut storage = MemoryStorage::new();
+        assert!(Savestate::load(&storage, "game.state").unwrap().is_none());
+        let state = emulator.save_state();
+        state.store(&mut storage, "game.state").unwrap();
+        let loaded = Savestate::load(&storage, "game.state").unwrap().unwrap();
+        assert_eq!(loaded.to_json(), state.to_json());
+        storage.store("game.state", b"{}").unwrap();
+        assert!(Savestate::load(&storage, "game.state").is_err());
+    }
+}
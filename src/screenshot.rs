@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    emulator::Emulator,
+    frame::{Frame, SCREEN_HEIGHT, SCREEN_WIDTH},
+    json::Value,
+};
+
+/// Everything needed to reproduce a screenshot later, written next to the
+/// image as `<name>.json`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub frame: u64,
+    pub savestate: Option<PathBuf>,
+}
+
+impl Metadata {
+    pub fn from_emulator(emulator: &Emulator, savestate: Option<&Path>) -> Self {
+        let header = emulator.cpu.bus.cartdrige.header();
+        Self {
+            title: header.title,
+            header_checksum: header.header_checksum,
+            global_checksum: header.global_checksum,
+            frame: emulator.frame_count(),
+            savestate: savestate.map(Path::to_path_buf),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let savestate = self.savestate.as_ref().map_or(Value::Null, |path| {
+            Value::String(path.to_string_lossy().into_owned())
+        });
+        Value::object([
+            ("title", Value::String(self.title.clone())),
+            (
+                "header_checksum",
+                Value::Number(self.header_checksum as u64),
+            ),
+            (
+                "global_checksum",
+                Value::Number(self.global_checksum as u64),
+            ),
+            ("frame", Value::Number(self.frame)),
+            ("savestate", savestate),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let title = match value.get("title")? {
+            Value::String(title) => title.clone(),
+            other => return Err(format!("Invalid title: {}", other)),
+        };
+        let savestate = match value.get("savestate")? {
+            Value::Null => None,
+            Value::String(path) => Some(PathBuf::from(path)),
+            other => return Err(format!("Invalid savestate: {}", other)),
+        };
+        Ok(Self {
+            title,
+            header_checksum: value.get("header_checksum")?.as_u8()?,
+            global_checksum: value.get("global_checksum")?.as_u16()?,
+            frame: value.get("frame")?.as_u64()?,
+            savestate,
+        })
+    }
+}
+
+/// Path of the sidecar written along `image`
+pub fn sidecar_path(image: &Path) -> PathBuf {
+    image.with_extension("json")
+}
+
+/// Writes `frame` as a binary PPM image, and the metadata sidecar if given
+pub fn save(image: &Path, frame: &Frame, metadata: Option<&Metadata>) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(image)?);
+    write!(w, "P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    w.write_all(&frame.pixels)?;
+    w.flush()?;
+    if let Some(metadata) = metadata {
+        std::fs::write(sidecar_path(image), format!("{}\n", metadata.to_json()))?;
+    }
+    Ok(())
+}
+
+/// Saves the last frame drawn to `image`, with a sidecar pointing at the
+/// state the caller writes to `savestate`
+pub fn take(image: &Path, emulator: &Emulator, savestate: Option<&Path>) -> io::Result<()> {
+    let metadata = Metadata::from_emulator(emulator, savestate);
+    save(image, emulator.frame(), Some(&metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartdrige::RomOnly;
+    use crate::persistence::FileStorage;
+    use crate::savestate::Savestate;
+
+    #[test]
+    fn test_metadata_to_json() {
+        let metadata = Metadata {
+            title: "TETRIS \"DX\"".to_string(),
+            header_checksum: 0x0A,
+            global_checksum: 0x16BF,
+            frame: 42,
+            savestate: None,
+        };
+        assert_eq!(
+            metadata.to_json().to_string(),
+            "{\n  \"title\": \"TETRIS \\\"DX\\\"\",\n  \"header_checksum\": 10,\n  \"global_checksum\": 5823,\n  \"frame\": 42,\n  \"savestate\": null\n}"
+        );
+    }
+
+    #[test]
+    fn test_save_writes_image_and_sidecar() {
+        let dir = std::env::temp_dir().join(format!("gameboy-screenshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("shot.ppm");
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, [0x12, 0x34, 0x56]);
+        let metadata = Metadata {
+            title: "TEST".to_string(),
+            header_checksum: 0,
+            global_checksum: 0,
+            frame: 1,
+            savestate: Some(PathBuf::from("test.state")),
+        };
+        save(&image, &frame, Some(&metadata)).unwrap();
+
+        let data = std::fs::read(&image).unwrap();
+        let header = b"P6\n160 144\n255\n";
+        assert_eq!(&data[..header.len()], header);
+        assert_eq!(&data[header.len()..header.len() + 3], &[0x12, 0x34, 0x56]);
+        assert_eq!(data.len(), header.len() + SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        let sidecar = std::fs::read_to_string(sidecar_path(&image)).unwrap();
+        assert!(sidecar.contains("\"savestate\": \"test.state\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_take_reproduces_from_the_savestate() {
+        let dir =
+            std::env::temp_dir().join(format!("gameboy-screenshot-take-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (image, state) = (dir.join("shot.ppm"), dir.join("shot.state"));
+        // JR -2
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::new(Box::new(RomOnly(rom.clone())));
+        emulator.frames().take(3).for_each(|frame| {
+            frame.unwrap();
+        });
+        take(&image, &emulator, Some(&state)).unwrap();
+        emulator
+            .save_state()
+            .store(&mut FileStorage::new(&dir), "shot.state")
+            .unwrap();
+
+        let sidecar = std::fs::read_to_string(sidecar_path(&image)).unwrap();
+        let metadata = Metadata::from_json(&Value::parse(&sidecar).unwrap()).unwrap();
+        assert_eq!(metadata, Metadata::from_emulator(&emulator, Some(&state)));
+        assert_eq!(metadata.frame, 3);
+        let path = metadata.savestate.unwrap();
+        let storage = FileStorage::new(path.parent().unwrap());
+        let key = path.file_name().unwrap().to_str().unwrap();
+        let saved = Savestate::load(&storage, key).unwrap().unwrap();
+        let mut replay = Emulator::new(Box::new(RomOnly(rom)));
+        replay.try_load_state(&saved).unwrap();
+        assert_eq!(replay.frame_count(), metadata.frame);
+        assert_eq!(replay.state_hash(), emulator.state_hash());
+        let data = std::fs::read(&image).unwrap();
+        assert!(data.ends_with(&replay.frame().pixels));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
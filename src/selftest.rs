@@ -0,0 +1,277 @@
+use std::fmt;
+
+use crate::{
+    bus::FlatBus,
+    cartdrige::RomOnly,
+    cpu::{Cpu, INSTRUCTIONS},
+    emulator::Emulator,
+    register::Flags,
+    savestate::Savestate,
+    services::{self, Services},
+    summary::TestResult,
+};
+
+// the opcodes the CPU does not define, they hang it
+const ILLEGAL_OPCODES: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+
+// frames the test ROM gets to report its result
+const ROM_FRAMES: u64 = 10;
+
+// what a check found, or what went wrong
+type CheckFn = fn() -> Result<String, String>;
+
+/// Outcome of one of the built-in checks, with what was checked or what
+/// went wrong
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
+
+/// What `gameboy selftest` prints, one line per check
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.result {
+                Ok(details) => writeln!(f, "ok    {:<10} {}", check.name, details)?,
+                Err(e) => writeln!(f, "FAIL  {:<10} {}", check.name, e)?,
+            }
+        }
+        let failed = self.checks.iter().filter(|check| check.result.is_err());
+        writeln!(f, "{} checks, {} failed", self.checks.len(), failed.count())
+    }
+}
+
+/// Runs the built-in checks, for packagers and users to make sure a build
+/// works on their platform
+pub fn run() -> Report {
+    let checks: [(&'static str, CheckFn); 4] = [
+        ("alu", check_alu),
+        ("decoder", check_decoder),
+        ("rom", check_rom),
+        ("savestate", check_savestate),
+    ];
+    Report {
+        checks: checks
+            .into_iter()
+            .map(|(name, check)| Check {
+                name,
+                result: check(),
+            })
+            .collect(),
+    }
+}
+
+// A and F after the 8-bit ALU operation `opcode` (ADD A,B to CP B), worked
+// out independently of the CPU
+fn alu_reference(opcode: u8, a: u8, b: u8, carry: bool) -> (u8, Flags) {
+    let carry = carry as u8;
+    let mut flags = Flags::empty();
+    let result = match opcode >> 3 & 0x07 {
+        // ADD, ADC
+        operation @ (0 | 1) => {
+            let carry = if operation == 1 { carry } else { 0 };
+            let sum = a as u16 + b as u16 + carry as u16;
+            flags.set(Flags::HALFCARRY, (a & 0x0F) + (b & 0x0F) + carry > 0x0F);
+            flags.set(Flags::CARRY, sum > 0xFF);
+            sum as u8
+        }
+        // SUB, SBC, CP
+        operation @ (2 | 3 | 7) => {
+            let carry = if operation == 3 { carry } else { 0 };
+            let difference = a as i16 - b as i16 - carry as i16;
+            flags.insert(Flags::SUBTRACTION);
+            flags.set(Flags::HALFCARRY, (a & 0x0F) < (b & 0x0F) + carry);
+            flags.set(Flags::CARRY, difference < 0);
+            difference as u8
+        }
+        4 => {
+            flags.insert(Flags::HALFCARRY);
+            a & b
+        }
+        5 => a ^ b,
+        _ => a | b,
+    };
+    flags.set(Flags::ZERO, result == 0);
+    // CP only sets the flags
+    if opcode >> 3 & 0x07 == 7 {
+        (a, flags)
+    } else {
+        (result, flags)
+    }
+}
+
+// every operand pair through every ALU operation, with and without carry
+fn check_alu() -> Result<String, String> {
+    let mut cpu = Cpu::with_bus(FlatBus::new());
+    let mut operations = 0;
+    for opcode in (0x80..=0xB8).step_by(8) {
+        cpu.bus.memory[0x0000] = opcode;
+        for carry in [false, true] {
+            for a in 0..=0xFF {
+                for b in 0..=0xFF {
+                    cpu.registers.pc.0 = 0x0000;
+                    cpu.registers.a = a;
+                    cpu.registers.b = b;
+                    cpu.registers.f = if carry { Flags::CARRY } else { Flags::empty() };
+                    cpu.step().map_err(|e| e.to_string())?;
+                    let expected = alu_reference(opcode, a, b, carry);
+                    if (cpu.registers.a, cpu.registers.f) != expected {
+                        return Err(format!(
+                            "{} with A={:#04x} B={:#04x} carry={}: got A={:#04x} F={:#04x}, expected A={:#04x} F={:#04x}",
+                            INSTRUCTIONS[opcode as usize].mnemonic(),
+                            a,
+                            b,
+                            carry,
+                            cpu.registers.a,
+                            cpu.registers.f.bits(),
+                            expected.0,
+                            expected.1.bits()
+                        ));
+                    }
+                    operations += 1;
+                }
+            }
+        }
+    }
+    Ok(format!("{} operations match", operations))
+}
+
+// every opcode decodes with a sane length and timing, and only the
+// undefined ones are illegal
+fn check_decoder() -> Result<String, String> {
+    for instruction in &INSTRUCTIONS {
+        let opcode = instruction.opcode;
+        if instruction.is_illegal() != ILLEGAL_OPCODES.contains(&opcode) {
+            return Err(format!(
+                "{:#04x} decodes to {}",
+                opcode,
+                instruction.mnemonic()
+            ));
+        }
+        if instruction.is_illegal() {
+            continue;
+        }
+        if !(1..=3).contains(&instruction.length)
+            || instruction.cycles == 0
+            || instruction.cycles % 4 != 0
+            || instruction.cycles_taken < instruction.cycles
+        {
+            return Err(format!(
+                "{:#04x} {}: length {}, {}/{} cycles",
+                opcode,
+                instruction.mnemonic(),
+                instruction.length,
+                instruction.cycles,
+                instruction.cycles_taken
+            ));
+        }
+    }
+    Ok(format!(
+        "{} opcodes decoded, {} illegal",
+        INSTRUCTIONS.len() - ILLEGAL_OPCODES.len(),
+        ILLEGAL_OPCODES.len()
+    ))
+}
+
+// A test ROM assembled here rather than a third-party one, which could not
+// ship with the sources: it sums 1 to 10 in a loop and reports whether it
+// got 55 through the emulator services.
+fn test_rom() -> RomOnly {
+    let mut rom = vec![0x00; 0x8000];
+    // JP 0x0150
+    rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+    #[rustfmt::skip]
+    let program = [
+        0x06, 0x0A,                           // LD B,10
+        0xAF,                                 // XOR A
+        0x80,                                 // loop: ADD A,B
+        0x05,                                 // DEC B
+        0x20, 0xFC,                           // JR NZ,loop
+        0xFE, 55,                             // CP 55
+        0x3E, services::PASSED,               // LD A,PASSED
+        0x28, 0x02,                           // JR Z,report
+        0x3E, services::FAILED,               // LD A,FAILED
+        0xE0, services::RESULT as u8,         // report: LDH (RESULT),A
+        0x18, 0xFE,                           // JR -2, loops forever
+    ];
+    rom[0x150..0x150 + program.len()].copy_from_slice(&program);
+    RomOnly(rom)
+}
+
+fn check_rom() -> Result<String, String> {
+    let mut emulator = Emulator::new(Box::new(test_rom()));
+    emulator.cpu.bus.services = Some(Services::new());
+    for _ in 0..ROM_FRAMES {
+        emulator.run_frame().map_err(|e| e.to_string())?;
+        let services = emulator.cpu.bus.services.as_ref();
+        match services.and_then(Services::result) {
+            Some(TestResult::Passed) => {
+                return Ok(format!("passed on frame {}", emulator.frame_count()))
+            }
+            Some(TestResult::Failed) => return Err("the test ROM failed".to_string()),
+            None => {}
+        }
+    }
+    Err(format!("no result after {} frames", ROM_FRAMES))
+}
+
+// a state saved as JSON and loaded back runs on exactly like the original
+fn check_savestate() -> Result<String, String> {
+    let mut original = Emulator::new(Box::new(test_rom()));
+    original.run_frame().map_err(|e| e.to_string())?;
+    let json = original.save_state().to_json();
+    let mut restored = Emulator::new(Box::new(test_rom()));
+    restored.try_load_state(&Savestate::from_json(&json)?)?;
+    for emulator in [&mut original, &mut restored] {
+        emulator.run_frame().map_err(|e| e.to_string())?;
+    }
+    if original.state_hash() != restored.state_hash() {
+        return Err("the restored state diverged".to_string());
+    }
+    Ok(format!("{} bytes of JSON round-tripped", json.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alu_reference() {
+        // ADD A,B
+        assert_eq!(
+            alu_reference(0x80, 0x0F, 0x01, true),
+            (0x10, Flags::HALFCARRY)
+        );
+        // SBC A,B
+        assert_eq!(
+            alu_reference(0x98, 0x00, 0x00, true),
+            (0xFF, Flags::SUBTRACTION | Flags::HALFCARRY | Flags::CARRY)
+        );
+        // CP B leaves A alone
+        assert_eq!(
+            alu_reference(0xB8, 0x42, 0x42, false),
+            (0x42, Flags::ZERO | Flags::SUBTRACTION)
+        );
+    }
+
+    #[test]
+    fn test_selftest_passes() {
+        let report = run();
+        assert!(report.passed(), "{}", report);
+        assert!(report.to_string().ends_with("4 checks, 0 failed\n"));
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::json::Value;
+
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
+const SC_TRANSFER_ENABLE: u8 = 1 << 7;
+const SC_INTERNAL_CLOCK: u8 = 1 << 0;
+
+// the internal clock shifts one bit every 512 T-cycles (8192 Hz)
+const CYCLES_PER_BIT: u32 = 512;
+pub const TRANSFER_CYCLES: u32 = 8 * CYCLES_PER_BIT;
+
+/// Something plugged at the other end of the link cable.
+pub trait SerialDevice: Send {
+    /// Called once per completed transfer with the byte shifted out by the
+    /// Game Boy, returns the byte shifted in from the device.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Whether the device is able to drive the clock, which is needed for
+    /// transfers where the Game Boy selected the external clock.
+    fn drives_clock(&self) -> bool {
+        false
+    }
+}
+
+/// Nothing plugged in, the data line is pulled up.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Records every byte sent by the Game Boy, test ROMs use it to print their results.
+#[derive(Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    /// Handle on the captured bytes that stays valid once the device is attached.
+    pub fn output(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.0.clone()
+    }
+}
+
+impl SerialDevice for Capture {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.0.lock().unwrap().push(byte);
+        0xFF
+    }
+}
+
+/// Serial port (link cable)
+/// Following
+/// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+pub struct Serial {
+    data: u8,
+    control: u8,
+    // cycles until the transfer in progress completes, 0 when idle
+    remaining: u32,
+    device: Box<dyn SerialDevice>,
+}
+
+/// SB, SC and the progress of the current transfer, for savestates
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SerialState {
+    data: u8,
+    control: u8,
+    remaining: u32,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            data: 0x00,
+            control: 0x7E,
+            remaining: 0,
+            device: Box::new(Disconnected),
+        }
+    }
+
+    /// Plugs `device` into the port and returns the previously attached one.
+    pub fn attach(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn detach(&mut self) -> Box<dyn SerialDevice> {
+        self.attach(Box::new(Disconnected))
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB => self.data,
+            // unused bits read as 1
+            SC => self.control | 0x7E,
+            _ => panic!("Invalid serial address: {:#06x}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            SB => self.data = value,
+            SC => {
+                self.control = value;
+                self.try_transfer();
+            }
+            _ => panic!("Invalid serial address: {:#06x}", address),
+        }
+    }
+
+    pub fn state(&self) -> SerialState {
+        SerialState {
+            data: self.data,
+            control: self.control,
+            remaining: self.remaining,
+        }
+    }
+
+    /// Restores the registers without the side effects of `write`
+    pub fn set_state(&mut self, state: SerialState) {
+        self.data = state.data;
+        self.control = state.control;
+        self.remaining = state.remaining;
+    }
+
+    /// Advances the transfer in progress by `cycles` T-cycles, returns whether
+    /// it completed and the serial interrupt has to be requested
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining = self.remaining.saturating_sub(cycles);
+        if self.remaining > 0 {
+            return false;
+        }
+        // the byte is exchanged as a whole once all 8 bits are shifted
+        debug!("Serial transfer: {:#04x}", self.data);
+        self.data = self.device.exchange(self.data);
+        self.control &= !SC_TRANSFER_ENABLE;
+        true
+    }
+
+    fn try_transfer(&mut self) {
+        self.remaining = 0;
+        if self.control & SC_TRANSFER_ENABLE == 0 {
+            return;
+        }
+        // with the external clock selected nothing happens until the peer
+        // clocks us, which may be never. A peer driving the clock runs it
+        // at the same 8192 Hz.
+        if self.control & SC_INTERNAL_CLOCK == 0 && !self.device.drives_clock() {
+            return;
+        }
+        self.remaining = TRANSFER_CYCLES;
+    }
+}
+
+impl SerialState {
+    pub(crate) fn to_json(self) -> Value {
+        Value::object([
+            ("data", Value::hex(self.data as u64, 2)),
+            ("control", Value::hex(self.control as u64, 2)),
+            ("remaining", Value::Number(self.remaining as u64)),
+        ])
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self, String> {
+        Ok(Self {
+            data: value.get("data")?.as_u8()?,
+            control: value.get("control")?.as_u8()?,
+            remaining: value.get("remaining")?.as_u32()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_internal_clock_transfer() {
+        let capture = Capture::default();
+        let output = capture.output();
+        let mut serial = Serial::new();
+        serial.attach(Box::new(capture));
+        serial.write(SB, b'O');
+        serial.write(SC, 0x81);
+        assert!(!serial.tick(TRANSFER_CYCLES - 4));
+        assert!(output.lock().unwrap().is_empty());
+        assert_ne!(serial.read(SC) & SC_TRANSFER_ENABLE, 0);
+        assert!(serial.tick(4));
+        serial.write(SB, b'K');
+        serial.write(SC, 0x81);
+        assert!(serial.tick(TRANSFER_CYCLES));
+        assert!(!serial.tick(TRANSFER_CYCLES));
+        assert_eq!(*output.lock().unwrap(), b"OK");
+        assert_eq!(serial.read(SB), 0xFF);
+        assert_eq!(serial.read(SC) & SC_TRANSFER_ENABLE, 0);
+    }
+
+    #[test]
+    fn test_serial_external_clock_waits_for_peer() {
+        let capture = Capture::default();
+        let output = capture.output();
+        let mut serial = Serial::new();
+        serial.attach(Box::new(capture));
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x80);
+        assert!(!serial.tick(TRANSFER_CYCLES * 100));
+        assert!(output.lock().unwrap().is_empty());
+        assert_eq!(serial.read(SB), 0x42);
+        assert_ne!(serial.read(SC) & SC_TRANSFER_ENABLE, 0);
+    }
+
+    struct Peer;
+
+    impl SerialDevice for Peer {
+        fn exchange(&mut self, byte: u8) -> u8 {
+            !byte
+        }
+
+        fn drives_clock(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_serial_external_clock_driven_by_peer() {
+        let mut serial = Serial::new();
+        serial.attach(Box::new(Peer));
+        serial.write(SB, 0x0F);
+        serial.write(SC, 0x80);
+        assert!(serial.tick(TRANSFER_CYCLES));
+        assert_eq!(serial.read(SB), 0xF0);
+    }
+
+    #[test]
+    fn test_serial_state_keeps_transfer_progress() {
+        let mut serial = Serial::new();
+        serial.write(SB, 0x12);
+        serial.write(SC, 0x81);
+        serial.tick(CYCLES_PER_BIT);
+        let state = serial.state();
+        let mut restored = Serial::new();
+        restored.set_state(state);
+        assert!(!restored.tick(TRANSFER_CYCLES - CYCLES_PER_BIT - 1));
+        assert!(restored.tick(1));
+    }
+}
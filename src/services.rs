@@ -0,0 +1,89 @@
+use std::mem;
+
+use crate::summary::TestResult;
+
+/// Emulator services, I/O ports that only exist in this emulator so that
+/// test ROMs written for it can report back without a serial link. They sit
+/// in the unused end of the I/O range and stay unmapped unless enabled.
+///
+/// Bytes written to PRINT form a message, a line feed ends it
+pub const PRINT: u16 = 0xFF7D;
+/// Write `PASSED` or `FAILED` to report the verdict of a test ROM
+pub const RESULT: u16 = 0xFF7E;
+/// Write the status code the emulator should exit with
+pub const EXIT: u16 = 0xFF7F;
+
+pub const PASSED: u8 = 0x01;
+pub const FAILED: u8 = 0x02;
+
+/// What the program reported through the ports. Not part of the machine
+/// state, the frontend collects it as it goes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Services {
+    // message being written
+    line: Vec<u8>,
+    messages: Vec<String>,
+    result: Option<TestResult>,
+    exit: Option<u8>,
+}
+
+impl Services {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            PRINT if value == b'\n' => {
+                let line = mem::take(&mut self.line);
+                self.messages
+                    .push(String::from_utf8_lossy(&line).into_owned());
+            }
+            PRINT => self.line.push(value),
+            RESULT => {
+                self.result = match value {
+                    PASSED => Some(TestResult::Passed),
+                    FAILED => Some(TestResult::Failed),
+                    _ => None,
+                }
+            }
+            EXIT => self.exit = Some(value),
+            _ => {}
+        }
+    }
+
+    /// Completed messages since the last call
+    pub fn take_messages(&mut self) -> Vec<String> {
+        mem::take(&mut self.messages)
+    }
+
+    /// Last verdict reported
+    pub fn result(&self) -> Option<TestResult> {
+        self.result
+    }
+
+    /// Status code the program asked to exit with
+    pub fn exit(&self) -> Option<u8> {
+        self.exit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_services() {
+        let mut services = Services::new();
+        for byte in b"timer: ok\nhalf" {
+            services.write(PRINT, *byte);
+        }
+        assert_eq!(services.take_messages(), ["timer: ok"]);
+        assert!(services.take_messages().is_empty());
+        services.write(RESULT, FAILED);
+        assert_eq!(services.result(), Some(TestResult::Failed));
+        assert_eq!(services.exit(), None);
+        services.write(EXIT, 3);
+        assert_eq!(services.exit(), Some(3));
+    }
+}
@@ -0,0 +1,359 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::debug::BankedAddress;
+use crate::json::Value;
+
+/// Kind of access that triggers a watchpoint
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn name(self) -> &'static str {
+        match self {
+            Access::Read => "r",
+            Access::Write => "w",
+            Access::ReadWrite => "rw",
+        }
+    }
+
+    /// Whether a read, or a write if `write`, triggers the watchpoint
+    pub fn covers(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "r" => Ok(Access::Read),
+            "w" => Ok(Access::Write),
+            "rw" => Ok(Access::ReadWrite),
+            _ => Err(format!("Invalid watchpoint access: {}", name)),
+        }
+    }
+}
+
+/// Replaces what the program reads from ROM at `address` with `value`, as
+/// long as the ROM holds `compare` there if set
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RomPatch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl RomPatch {
+    /// What a read of `address` returns instead of `value`, which is what
+    /// the ROM holds
+    pub fn apply(&self, address: u16, value: u8) -> u8 {
+        if address == self.address && self.compare.is_none_or(|compare| compare == value) {
+            self.value
+        } else {
+            value
+        }
+    }
+}
+
+/// A decoded cheat code
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Cheat {
+    /// `BBVVLLHH`, writes `value` at `HHLL` once per frame. `bank` selects
+    /// the external RAM bank for addresses in 0xA000-0xBFFF, elsewhere the
+    /// value goes where the CPU would write it.
+    GameShark { bank: u8, address: u16, value: u8 },
+    /// `VVA-AAA-CxC`, patches a ROM read. The address is scrambled with its
+    /// top digit last and inverted, and the optional compare value rotated
+    /// and XORed with 0xBA; the middle digit of the third group is not used.
+    GameGenie(RomPatch),
+}
+
+impl FromStr for Cheat {
+    type Err = String;
+
+    /// A GameShark code, 8 hex digits like `01FF16D0`, or a Game Genie one,
+    /// 2 or 3 groups of 3 like `00A-17B-C49`
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cheat: {}", code);
+        let hex = |digits: &str| {
+            if digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                u32::from_str_radix(digits, 16).map_err(|_| invalid())
+            } else {
+                Err(invalid())
+            }
+        };
+        let groups: Vec<_> = code.split('-').collect();
+        match groups[..] {
+            [gameshark] if gameshark.len() == 8 => {
+                let [bank, value, low, high] = hex(gameshark)?.to_be_bytes();
+                Ok(Cheat::GameShark {
+                    bank,
+                    address: u16::from_le_bytes([low, high]),
+                    value,
+                })
+            }
+            [_, _] | [_, _, _] if groups.iter().all(|group| group.len() == 3) => {
+                let first = hex(groups[0])?;
+                let second = hex(groups[1])?;
+                let compare = match groups.get(2) {
+                    Some(third) => {
+                        let third = hex(third)?;
+                        let scrambled = ((third >> 4) & 0xF0 | third & 0x0F) as u8;
+                        Some(scrambled.rotate_right(2) ^ 0xBA)
+                    }
+                    None => None,
+                };
+                Ok(Cheat::GameGenie(RomPatch {
+                    address: ((second & 0x0F ^ 0x0F) << 12 | (first & 0x0F) << 8 | second >> 4)
+                        as u16,
+                    value: (first >> 4) as u8,
+                    compare,
+                }))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Checks that `code` is a GameShark or Game Genie code, see `Cheat`
+pub fn validate_cheat(code: &str) -> Result<(), String> {
+    code.parse::<Cheat>().map(|_| ())
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub address: BankedAddress,
+    pub access: Access,
+}
+
+/// Cheats, breakpoints and watchpoints set up for a game. They are kept in
+/// their own file next to the ROM rather than in savestates, so loading a
+/// state or replaying a movie is not affected by them and they survive
+/// restarts.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Session {
+    // Game Genie or GameShark codes, as typed
+    pub cheats: Vec<String>,
+    pub breakpoints: Vec<BankedAddress>,
+    pub watchpoints: Vec<Watchpoint>,
+}
+
+impl Session {
+    /// Where the session of `rom` is stored
+    pub fn path(rom: &Path) -> PathBuf {
+        rom.with_extension("session.json")
+    }
+
+    /// Reads the session saved at `path`, a missing file is an empty session
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => Self::from_json(&json),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty() && self.breakpoints.is_empty() && self.watchpoints.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        let session = Value::object([
+            (
+                "cheats",
+                Value::Array(self.cheats.iter().cloned().map(Value::String).collect()),
+            ),
+            (
+                "breakpoints",
+                Value::Array(
+                    self.breakpoints
+                        .iter()
+                        .map(|address| Value::String(address.to_string()))
+                        .collect(),
+                ),
+            ),
+            (
+                "watchpoints",
+                Value::Array(
+                    self.watchpoints
+                        .iter()
+                        .map(|watchpoint| {
+                            Value::object([
+                                ("address", Value::String(watchpoint.address.to_string())),
+                                (
+                                    "access",
+                                    Value::String(watchpoint.access.name().to_string()),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ]);
+        format!("{}\n", session)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let session = Value::parse(json)?;
+        let list = |name: &str| match session.get(name)? {
+            Value::Array(items) => Ok(items.clone()),
+            _ => Err(format!("Expected an array of {}", name)),
+        };
+        let cheats = list("cheats")?
+            .into_iter()
+            .map(|cheat| match cheat {
+                Value::String(code) => validate_cheat(&code).map(|()| code),
+                _ => Err(format!("Invalid cheat: {}", cheat)),
+            })
+            .collect::<Result<_, String>>()?;
+        let address = |value: &Value| match value {
+            Value::String(address) => address.parse::<BankedAddress>(),
+            _ => Err(format!("Invalid address: {}", value)),
+        };
+        let breakpoints = list("breakpoints")?
+            .iter()
+            .map(address)
+            .collect::<Result<_, String>>()?;
+        let watchpoints = list("watchpoints")?
+            .iter()
+            .map(|watchpoint| {
+                let access = match watchpoint.get("access")? {
+                    Value::String(name) => Access::from_name(name)?,
+                    access => return Err(format!("Invalid watchpoint access: {}", access)),
+                };
+                Ok(Watchpoint {
+                    address: address(watchpoint.get("address")?)?,
+                    access,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            cheats,
+            breakpoints,
+            watchpoints,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_cheat() {
+        for code in ["01FF16D0", "00a-17B-C49", "00A-17B"] {
+            assert_eq!(validate_cheat(code), Ok(()), "{}", code);
+        }
+        for code in [
+            "",
+            "01FF16D",
+            "01FF16DG",
+            "00A17BC49",
+            "00A-17B-C4",
+            "00A-17B-C49-000",
+        ] {
+            assert!(validate_cheat(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_decode_cheats() {
+        assert_eq!(
+            "01FF16D0".parse(),
+            Ok(Cheat::GameShark {
+                bank: 0x01,
+                address: 0xD016,
+                value: 0xFF,
+            })
+        );
+        let patch = |address, value, compare| {
+            Ok(Cheat::GameGenie(RomPatch {
+                address,
+                value,
+                compare,
+            }))
+        };
+        assert_eq!("00A-17B-C49".parse(), patch(0x4A17, 0x00, Some(0xC8)));
+        assert_eq!("991-50f".parse(), patch(0x0150, 0x99, None));
+
+        let patch = RomPatch {
+            address: 0x0150,
+            value: 0x99,
+            compare: Some(0x11),
+        };
+        assert_eq!(patch.apply(0x0150, 0x11), 0x99);
+        // another bank is mapped
+        assert_eq!(patch.apply(0x0150, 0x12), 0x12);
+        assert_eq!(patch.apply(0x0151, 0x11), 0x11);
+    }
+
+    #[test]
+    fn test_session_roundtrip() {
+        let session = Session {
+            cheats: vec!["01FF16D0".to_string(), "00A-17B-C49".to_string()],
+            breakpoints: vec![
+                BankedAddress {
+                    bank: 0,
+                    address: 0x0150,
+                },
+                BankedAddress {
+                    bank: 3,
+                    address: 0x4000,
+                },
+            ],
+            watchpoints: vec![Watchpoint {
+                address: BankedAddress {
+                    bank: 1,
+                    address: 0xA000,
+                },
+                access: Access::Write,
+            }],
+        };
+        let json = session.to_json();
+        assert!(json.contains("\"03:4000\""));
+        assert!(json.contains("\"address\": \"01:a000\""));
+        assert_eq!(Session::from_json(&json), Ok(session));
+        assert!(Session::from_json("{}").is_err());
+        assert!(Session::from_json(
+            r#"{"cheats": [], "breakpoints": [], "watchpoints": [{"address": "c000", "access": "x"}]}"#
+        )
+        .is_err());
+        assert_eq!(
+            Session::from_json(r#"{"cheats": ["01FF16D"], "breakpoints": [], "watchpoints": []}"#),
+            Err("Invalid cheat: 01FF16D".to_string())
+        );
+        // which bank is meant
+        assert!(Session::from_json(
+            r#"{"cheats": [], "breakpoints": ["4000"], "watchpoints": []}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_session_load_save() {
+        let dir = std::env::temp_dir().join(format!("gameboy-session-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = Session::path(&dir.join("game.gb"));
+        assert_eq!(path, dir.join("game.session.json"));
+        assert!(Session::load(&path).unwrap().is_empty());
+        let session = Session {
+            breakpoints: vec!["0150".parse().unwrap()],
+            ..Session::default()
+        };
+        session.save(&path).unwrap();
+        assert_eq!(Session::load(&path), Ok(session));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const MIN_SPEED: u32 = 50;
+pub const MAX_SPEED: u32 = 1000;
+
+// falling further behind than this (slow host, debugger pause) resyncs
+// instead of running flat out to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Keeps emulated time in sync with the host at `percent`% of the hardware speed
+pub struct SpeedLimiter {
+    percent: u32,
+    host_start: Instant,
+    emulated_start: Duration,
+}
+
+impl SpeedLimiter {
+    pub fn new(percent: u32) -> Self {
+        assert!(
+            (MIN_SPEED..=MAX_SPEED).contains(&percent),
+            "Speed must be between {}% and {}%",
+            MIN_SPEED,
+            MAX_SPEED
+        );
+        Self {
+            percent,
+            host_start: Instant::now(),
+            emulated_start: Duration::ZERO,
+        }
+    }
+
+    pub fn percent(&self) -> u32 {
+        self.percent
+    }
+
+    /// Host time that `emulated` should take at the configured speed
+    pub fn host_time(&self, emulated: Duration) -> Duration {
+        emulated * 100 / self.percent
+    }
+
+    /// Sleeps until the host catches up with `emulated`, the current emulated time
+    pub fn throttle(&mut self, emulated: Duration) {
+        let target = self.host_start + self.host_time(emulated.saturating_sub(self.emulated_start));
+        let now = Instant::now();
+        if target > now {
+            thread::sleep(target - now);
+        } else if now - target > MAX_LAG {
+            self.host_start = now;
+            self.emulated_start = emulated;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_time() {
+        let second = Duration::from_secs(1);
+        assert_eq!(SpeedLimiter::new(100).host_time(second), second);
+        assert_eq!(SpeedLimiter::new(50).host_time(second), second * 2);
+        assert_eq!(SpeedLimiter::new(400).host_time(second), second / 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_speed_out_of_range() {
+        SpeedLimiter::new(1001);
+    }
+}
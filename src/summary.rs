@@ -0,0 +1,204 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{emulator::Emulator, hash::Fnv1a, json::Value, register::Registers};
+
+// Mooneye test ROMs send the Fibonacci numbers on success and 0x42 six times
+// on failure, and leave the same values in B, C, D, E, H and L
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED: [u8; 6] = [0x42; 6];
+
+// Blargg test ROMs with cartridge RAM report there: a status at 0xA000, 0x80
+// while running and 0 on success, once the signature follows it
+const BLARGG_STATUS: u16 = 0xA000;
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+/// Verdict reported by a test ROM
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TestResult {
+    Passed,
+    Failed,
+}
+
+impl TestResult {
+    /// Recognizes the Blargg ("Passed"/"Failed" text) and Mooneye conventions
+    pub fn detect(serial: &[u8]) -> Option<Self> {
+        let text = String::from_utf8_lossy(serial);
+        if serial.ends_with(&MOONEYE_PASSED) || text.contains("Passed") {
+            Some(TestResult::Passed)
+        } else if serial.ends_with(&MOONEYE_FAILED) || text.contains("Failed") {
+            Some(TestResult::Failed)
+        } else {
+            None
+        }
+    }
+
+    /// Recognizes the register fingerprint Mooneye test ROMs leave when done
+    pub fn from_registers(registers: &Registers) -> Option<Self> {
+        let values = [
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+        ];
+        match values {
+            MOONEYE_PASSED => Some(TestResult::Passed),
+            MOONEYE_FAILED => Some(TestResult::Failed),
+            _ => None,
+        }
+    }
+
+    /// Recognizes the Blargg status in cartridge RAM, `read` reads the
+    /// address space
+    pub fn from_memory(read: impl Fn(u16) -> u8) -> Option<Self> {
+        let signature = [1, 2, 3].map(|offset| read(BLARGG_STATUS + offset));
+        if signature != BLARGG_SIGNATURE {
+            return None;
+        }
+        match read(BLARGG_STATUS) {
+            BLARGG_RUNNING => None,
+            0x00 => Some(TestResult::Passed),
+            _ => Some(TestResult::Failed),
+        }
+    }
+
+    /// Every convention above, the serial output first
+    pub fn detect_all(emulator: &Emulator, serial: &[u8]) -> Option<Self> {
+        Self::detect(serial)
+            .or_else(|| Self::from_registers(&emulator.cpu.registers))
+            .or_else(|| Self::from_memory(|address| emulator.read(address)))
+    }
+}
+
+/// What happened during a run, printed by `--json-summary` for scripts
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Summary {
+    pub frames: u64,
+    pub unsupported: Vec<String>,
+    pub serial: Vec<u8>,
+    pub frame_hash: u64,
+    pub result: Option<TestResult>,
+}
+
+impl Summary {
+    pub fn new(
+        emulator: &Emulator,
+        frames: u64,
+        unsupported: Vec<String>,
+        serial: Vec<u8>,
+    ) -> Self {
+        let mut hasher = Fnv1a::default();
+        emulator.frame().pixels.hash(&mut hasher);
+        Self {
+            frames,
+            unsupported,
+            result: TestResult::detect_all(emulator, &serial),
+            serial,
+            frame_hash: hasher.finish(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let unsupported = self
+            .unsupported
+            .iter()
+            .map(|feature| Value::String(feature.clone()));
+        let result = match self.result {
+            Some(TestResult::Passed) => Value::String("passed".to_string()),
+            Some(TestResult::Failed) => Value::String("failed".to_string()),
+            None => Value::Null,
+        };
+        Value::object([
+            ("frames", Value::Number(self.frames)),
+            ("unsupported", Value::Array(unsupported.collect())),
+            (
+                "serial",
+                Value::String(String::from_utf8_lossy(&self.serial).into_owned()),
+            ),
+            (
+                "frame_hash",
+                Value::String(format!("{:016x}", self.frame_hash)),
+            ),
+            ("result", result),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartdrige::RomOnly;
+
+    #[test]
+    fn test_detect_result() {
+        assert_eq!(
+            TestResult::detect(b"cpu_instrs\n\nPassed\n"),
+            Some(TestResult::Passed)
+        );
+        assert_eq!(
+            TestResult::detect(b"01:ok 02:01\nFailed 1 tests"),
+            Some(TestResult::Failed)
+        );
+        assert_eq!(
+            TestResult::detect(&[3, 5, 8, 13, 21, 34]),
+            Some(TestResult::Passed)
+        );
+        assert_eq!(TestResult::detect(&[0x42; 6]), Some(TestResult::Failed));
+        assert_eq!(TestResult::detect(b"01:ok 02:ok"), None);
+        assert_eq!(TestResult::detect(b""), None);
+    }
+
+    #[test]
+    fn test_detect_result_in_machine_state() {
+        let mut registers = Emulator::new(Box::new(RomOnly(vec![0x00; 0x8000])))
+            .cpu
+            .registers;
+        assert_eq!(TestResult::from_registers(&registers), None);
+        [registers.b, registers.c, registers.d] = [3, 5, 8];
+        [registers.e, registers.h, registers.l] = [13, 21, 34];
+        assert_eq!(
+            TestResult::from_registers(&registers),
+            Some(TestResult::Passed)
+        );
+
+        let mut memory = [0x80, 0xDE, 0xB0, 0x61];
+        let read = |memory: [u8; 4]| {
+            move |address: u16| {
+                memory
+                    .get((address - BLARGG_STATUS) as usize)
+                    .copied()
+                    .unwrap_or(0)
+            }
+        };
+        assert_eq!(TestResult::from_memory(read(memory)), None);
+        memory[0] = 0x00;
+        assert_eq!(
+            TestResult::from_memory(read(memory)),
+            Some(TestResult::Passed)
+        );
+        memory[0] = 0x03;
+        assert_eq!(
+            TestResult::from_memory(read(memory)),
+            Some(TestResult::Failed)
+        );
+        memory[1] = 0x00;
+        assert_eq!(TestResult::from_memory(read(memory)), None);
+    }
+
+    #[test]
+    fn test_summary_to_json() {
+        let summary = Summary {
+            frames: 600,
+            unsupported: vec!["I/O register 0xff40".to_string(), "MBC \"5\"".to_string()],
+            serial: b"Passed\n".to_vec(),
+            frame_hash: 0xCBF29CE484222325,
+            result: Some(TestResult::Passed),
+        };
+        assert_eq!(
+            summary.to_json().to_string(),
+            "{\n  \"frames\": 600,\n  \"unsupported\": [\n    \"I/O register 0xff40\",\n    \"MBC \\\"5\\\"\"\n  ],\n  \"serial\": \"Passed\\u000a\",\n  \"frame_hash\": \"cbf29ce484222325\",\n  \"result\": \"passed\"\n}"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Location of a symbol in the ROM
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub bank: u16,
+    pub address: u16,
+}
+
+/// Symbols exported by the assembler, in the RGBDS `.sym` format:
+/// one `BB:AAAA Name` entry per line, `;` starts a comment
+/// https://rgbds.gbdev.io/sym/
+#[derive(Clone, Debug, Default)]
+pub struct Symbols(HashMap<String, Symbol>);
+
+impl Symbols {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Malformed lines are skipped, other tools emit slightly different dialects
+    pub fn parse(content: &str) -> Self {
+        let mut symbols = HashMap::new();
+        for line in content.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            let Some((location, name)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let Some((bank, address)) = location.split_once(':') else {
+                continue;
+            };
+            if let (Ok(bank), Ok(address)) = (
+                u16::from_str_radix(bank, 16),
+                u16::from_str_radix(address, 16),
+            ) {
+                symbols.insert(name.trim().to_string(), Symbol { bank, address });
+            }
+        }
+        Self(symbols)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.0.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbols_parse() {
+        let symbols = Symbols::parse(
+            "; File generated by rgblink\n\
+             00:0150 Start\n\
+             01:4000 Level.init ; comment\n\
+             garbage\n\
+             1F:7FF0 TestCase2\n",
+        );
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(
+            symbols.get("Start"),
+            Some(Symbol {
+                bank: 0,
+                address: 0x0150
+            })
+        );
+        assert_eq!(
+            symbols.get("Level.init"),
+            Some(Symbol {
+                bank: 1,
+                address: 0x4000
+            })
+        );
+        assert_eq!(symbols.get("TestCase2").unwrap().bank, 0x1F);
+        assert_eq!(symbols.get("Missing"), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use crate::{
+    emulator::DOTS_PER_FRAME,
+    frame::{Frame, SCREEN_HEIGHT, SCREEN_WIDTH},
+};
+
+/// Dots per second, the frame rate is `DOTS_PER_SECOND / DOTS_PER_FRAME`
+const DOTS_PER_SECOND: u64 = 4194304;
+
+/// Writes every frame as raw RGB24, for lossless encodes that can be
+/// checked frame by frame.
+///
+/// Frame N of the emulation is always the Nth frame of the dump: frames
+/// that are never reported, while the CPU is stopped for example, repeat
+/// the previous one.
+pub struct VideoDump {
+    // None once closed
+    video: Option<Box<dyn Write>>,
+    ffmpeg: Option<Child>,
+    frames: u64,
+    last: Frame,
+}
+
+impl VideoDump {
+    pub fn new(video: Box<dyn Write>) -> Self {
+        Self {
+            video: Some(video),
+            ffmpeg: None,
+            frames: 0,
+            last: Frame::new(),
+        }
+    }
+
+    /// Raw frames to `video`
+    pub fn create(video: &Path) -> io::Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(video)?))))
+    }
+
+    /// Pipes the frames to `ffmpeg`, which encodes them to `video` with the
+    /// lossless FFV1 codec, so `video` should be a .mkv, .avi or .nut file
+    pub fn ffmpeg(video: &Path) -> io::Result<Self> {
+        let size = format!("{}x{}", SCREEN_WIDTH, SCREEN_HEIGHT);
+        let rate = format!("{}/{}", DOTS_PER_SECOND, DOTS_PER_FRAME);
+        let mut child = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y", "-f", "rawvideo"])
+            .args(["-pixel_format", "rgb24", "-video_size", &size])
+            .args(["-framerate", &rate, "-i", "-", "-c:v", "ffv1"])
+            .arg(video)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("ffmpeg stdin is piped");
+        let mut dump = Self::new(Box::new(stdin));
+        dump.ffmpeg = Some(child);
+        Ok(dump)
+    }
+
+    /// Frames written so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Writes frame `number`, counted from 1 like `Emulator::frame_count`
+    pub fn write_frame(&mut self, number: u64, frame: &Frame) -> io::Result<()> {
+        if number <= self.frames {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Frame {} written after frame {}", number, self.frames),
+            ));
+        }
+        while self.frames + 1 < number {
+            let last = self.last.pixels.clone();
+            self.write_video(&last)?;
+        }
+        self.write_video(&frame.pixels)?;
+        self.last.pixels.copy_from_slice(&frame.pixels);
+        Ok(())
+    }
+
+    fn write_video(&mut self, pixels: &[u8]) -> io::Result<()> {
+        if let Some(out) = &mut self.video {
+            out.write_all(pixels)?;
+            out.flush()?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Closes the files, and waits for `ffmpeg` to finish the encode
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        // ffmpeg only finishes once its input is closed
+        self.video = None;
+        if let Some(mut child) = self.ffmpeg.take() {
+            if !child.wait()?.success() {
+                return Err(io::Error::other("ffmpeg failed"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VideoDump {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_numbering() {
+        let dir = std::env::temp_dir().join(format!("gameboy-video-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let video = dir.join("video.rgb");
+        let mut dump = VideoDump::create(&video).unwrap();
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, [1, 2, 3]);
+        dump.write_frame(1, &frame).unwrap();
+        frame.set_pixel(0, 0, [4, 5, 6]);
+        // frame 2 was never reported, frame 1 stands in for it
+        dump.write_frame(3, &frame).unwrap();
+        assert!(dump.write_frame(3, &frame).is_err());
+        assert_eq!(dump.frames(), 3);
+        dump.finish().unwrap();
+
+        let video = std::fs::read(&video).unwrap();
+        let size = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
+        assert_eq!(video.len(), 3 * size);
+        assert_eq!(&video[..3], &[1, 2, 3]);
+        assert_eq!(&video[size..size + 3], &[1, 2, 3]);
+        assert_eq!(&video[2 * size..2 * size + 3], &[4, 5, 6]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Polls a file for modifications, used to reload the ROM whenever the
+/// assembler rebuilds it.
+pub struct Watcher {
+    path: PathBuf,
+    interval: Duration,
+    last_poll: Instant,
+    // modification time and size, None while the file is missing
+    stamp: Option<(SystemTime, u64)>,
+    settling: bool,
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl Watcher {
+    /// Looks at `path` at most once per `interval`
+    pub fn new(path: &Path, interval: Duration) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            interval,
+            last_poll: Instant::now(),
+            stamp: stamp(path),
+            settling: false,
+        }
+    }
+
+    /// Whether the file changed since the last reported change. A change is
+    /// only reported once the file stayed the same for a whole interval, so
+    /// a ROM still being written by the linker is not picked up half way.
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < self.interval {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        let stamp = stamp(&self.path);
+        if stamp != self.stamp {
+            self.stamp = stamp;
+            self.settling = true;
+            return false;
+        }
+        if self.settling && stamp.is_some() {
+            self.settling = false;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watcher_reports_settled_changes() {
+        let path = std::env::temp_dir().join(format!("gameboy-watch-{}.gb", std::process::id()));
+        fs::write(&path, [0x00; 16]).unwrap();
+        let mut watcher = Watcher::new(&path, Duration::ZERO);
+        assert!(!watcher.changed());
+        fs::write(&path, [0x00; 32]).unwrap();
+        // first seen, waits for the file to settle
+        assert!(!watcher.changed());
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        // a missing file is never reported
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+        assert!(!watcher.changed());
+        fs::write(&path, [0x00; 8]).unwrap();
+        assert!(!watcher.changed());
+        assert!(watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watcher_respects_interval() {
+        let path = std::env::temp_dir().join(format!("gameboy-watch-{}.sym", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let mut watcher = Watcher::new(&path, Duration::from_secs(3600));
+        fs::write(&path, "00:0150 Main\n").unwrap();
+        assert!(!watcher.changed());
+        assert!(!watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
+}